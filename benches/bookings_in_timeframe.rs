@@ -0,0 +1,51 @@
+//! Run with `cargo bench`. A regression here usually means the indices on the bookings are no
+//! longer used, see `db::tests::timeframe_query_uses_index`.
+
+use chrono::{NaiveDate, TimeDelta};
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::sqlite::SqlitePoolOptions;
+
+// the crate is a binary only, so pull in the modules under test directly
+#[allow(dead_code)]
+#[path = "../src/booking.rs"]
+mod booking;
+// benches are built without the test harness, so the imports of the tests are unused
+#[allow(dead_code, unused_imports)]
+#[path = "../src/db.rs"]
+mod db;
+
+use booking::Booking;
+
+fn bookings_in_timeframe(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let pool = rt.block_on(async {
+        // a single connection, so all queries see the same in-memory DB
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        db::sync_bookings(&pool, &db::archive::archive(50_000), [])
+            .await
+            .unwrap();
+        pool
+    });
+    let start = NaiveDate::from_ymd_opt(2025, 6, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let end = start + TimeDelta::days(7);
+    // one week of the 50000 bookings
+    c.bench_function("get_bookings_in_timeframe", |b| {
+        b.to_async(&rt).iter(|| async {
+            let bookings = db::get_bookings_in_timeframe(&pool, start, end)
+                .await
+                .unwrap();
+            assert!(!bookings.is_empty());
+        })
+    });
+}
+
+criterion_group!(benches, bookings_in_timeframe);
+criterion_main!(benches);
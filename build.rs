@@ -0,0 +1,69 @@
+//! Embed build information (git hash, build date, enabled features) into the binary, make sqlx
+//! use the prepared queries in .sqlx unless `live-queries` is enabled and generate the gRPC
+//! service with the `grpc` feature.
+
+use std::process::Command;
+
+fn main() {
+    // builds without a git checkout (e.g. in docker) may pass the hash in
+    println!("cargo:rerun-if-env-changed=CT_TA_SYNC_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let git_hash = std::env::var("CT_TA_SYNC_GIT_HASH")
+        .ok()
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()
+                .filter(|x| x.status.success())
+                .and_then(|x| String::from_utf8(x.stdout).ok())
+                .map(|x| x.trim().to_owned())
+        })
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=CT_TA_SYNC_GIT_HASH={git_hash}");
+
+    // reproducible builds set SOURCE_DATE_EPOCH
+    let build_date = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .and_then(|x| chrono::DateTime::from_timestamp(x, 0))
+        .unwrap_or_else(chrono::Utc::now);
+    println!(
+        "cargo:rustc-env=CT_TA_SYNC_BUILD_DATE={}",
+        build_date.format("%Y-%m-%dT%H:%M:%SZ")
+    );
+
+    let mut features = std::env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(|x| x.to_lowercase()))
+        .collect::<Vec<_>>();
+    features.sort();
+    println!("cargo:rustc-env=CT_TA_SYNC_FEATURES={}", features.join(","));
+
+    // so the crate builds without a DB, even if DATABASE_URL is set (e.g. in .env)
+    if std::env::var_os("CARGO_FEATURE_LIVE_QUERIES").is_none() {
+        println!("cargo:rustc-env=SQLX_OFFLINE=true");
+    }
+
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+/// Generate the server of proto/ct_ta_sync.proto with the vendored protoc, so no protoc has to be
+/// installed
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto");
+    let protoc =
+        protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored for this target");
+    let include = protoc_bin_vendored::include_path().expect("protoc is vendored for this target");
+    std::env::set_var("PROTOC", protoc);
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(
+            &["proto/ct_ta_sync.proto"],
+            &[std::path::Path::new("proto"), &include],
+        )
+        .expect("proto/ct_ta_sync.proto compiles");
+}
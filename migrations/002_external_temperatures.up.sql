@@ -0,0 +1,5 @@
+-- UP external temperature history
+CREATE TABLE external_temperatures (
+	recorded_at DATETIME PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL
+);
@@ -0,0 +1,6 @@
+-- UP cached weather forecasts
+CREATE TABLE forecasts (
+	forecast_for DATETIME PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL,
+	fetched_at DATETIME NOT NULL
+);
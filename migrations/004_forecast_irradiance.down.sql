@@ -0,0 +1 @@
+ALTER TABLE forecasts DROP COLUMN irradiance;
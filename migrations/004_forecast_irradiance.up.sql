@@ -0,0 +1,2 @@
+-- UP forecast solar irradiance
+ALTER TABLE forecasts ADD COLUMN irradiance INTEGER;
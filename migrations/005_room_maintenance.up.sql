@@ -0,0 +1,5 @@
+-- UP rooms in maintenance mode
+CREATE TABLE room_maintenance (
+	room TEXT PRIMARY KEY NOT NULL,
+	since DATETIME NOT NULL
+);
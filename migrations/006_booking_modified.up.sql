@@ -0,0 +1,2 @@
+-- UP when the booking was last modified in CT
+ALTER TABLE bookings ADD COLUMN modified_at DATETIME;
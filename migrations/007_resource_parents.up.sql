@@ -0,0 +1,5 @@
+-- UP the parent of each CT resource that has one
+CREATE TABLE resource_parents (
+	resource_id INTEGER PRIMARY KEY NOT NULL,
+	parent_id INTEGER NOT NULL
+);
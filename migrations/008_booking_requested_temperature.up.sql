@@ -0,0 +1,2 @@
+-- UP setpoint requested in the booking note, in tenths of a Degree Centigrade
+ALTER TABLE bookings ADD COLUMN requested_temperature INTEGER;
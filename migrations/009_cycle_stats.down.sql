@@ -0,0 +1 @@
+DROP TABLE cycle_stats;
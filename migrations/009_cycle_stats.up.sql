@@ -0,0 +1,9 @@
+-- UP per-cycle statistics of the pull and push tasks
+CREATE TABLE cycle_stats (
+	recorded_at DATETIME NOT NULL,
+	task TEXT NOT NULL,
+	duration_ms INTEGER NOT NULL,
+	items INTEGER NOT NULL,
+	errors INTEGER NOT NULL
+);
+CREATE INDEX cycle_stats_recorded_at ON cycle_stats (recorded_at);
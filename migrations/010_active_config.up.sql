@@ -0,0 +1,6 @@
+-- UP the (redacted) config of the last start
+CREATE TABLE active_config (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	content TEXT NOT NULL,
+	loaded_at DATETIME NOT NULL
+);
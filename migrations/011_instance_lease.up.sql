@@ -0,0 +1,6 @@
+-- UP the instance currently syncing with this DB
+CREATE TABLE instance_lease (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	owner TEXT NOT NULL,
+	heartbeat DATETIME NOT NULL
+);
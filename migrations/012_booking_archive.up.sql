@@ -0,0 +1,8 @@
+-- UP archive of past bookings, used for reports but never for control
+CREATE TABLE booking_archive (
+	booking_id INTEGER PRIMARY KEY NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL
+);
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
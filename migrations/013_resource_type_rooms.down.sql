@@ -0,0 +1 @@
+DROP TABLE resource_type_rooms;
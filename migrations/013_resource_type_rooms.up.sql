@@ -0,0 +1,9 @@
+-- UP rooms added for CT resource types, so their pdo indices stay the same across restarts
+CREATE TABLE resource_type_rooms (
+	cmi_host TEXT NOT NULL,
+	resource_id INTEGER NOT NULL,
+	type_id INTEGER NOT NULL,
+	name TEXT NOT NULL,
+	pdo_index INTEGER NOT NULL,
+	PRIMARY KEY (cmi_host, resource_id)
+);
@@ -0,0 +1,2 @@
+DELETE FROM bookings WHERE source = 'local';
+ALTER TABLE bookings DROP COLUMN source;
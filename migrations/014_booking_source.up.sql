@@ -0,0 +1,2 @@
+-- UP where a booking comes from: 'ct', or 'local' if it was created via the HTTP API
+ALTER TABLE bookings ADD COLUMN source TEXT NOT NULL DEFAULT 'ct';
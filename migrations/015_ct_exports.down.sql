@@ -0,0 +1 @@
+DROP TABLE ct_exports;
@@ -0,0 +1,5 @@
+-- UP the heating window last written back to each CT booking, so it is only written on changes
+CREATE TABLE ct_exports (
+	booking_id INTEGER PRIMARY KEY NOT NULL,
+	text TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP INDEX bookings_resource;
+DROP INDEX bookings_start_end;
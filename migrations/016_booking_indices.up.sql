@@ -0,0 +1,3 @@
+-- UP indices for the time window and resource queries, which run on every push and pull
+CREATE INDEX bookings_start_end ON bookings (start_time, end_time);
+CREATE INDEX bookings_resource ON bookings (resource_id);
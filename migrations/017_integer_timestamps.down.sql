@@ -0,0 +1,98 @@
+CREATE TABLE bookings_new (
+	booking_id INTEGER PRIMARY KEY,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL,
+	modified_at DATETIME,
+	requested_temperature INTEGER,
+	source TEXT NOT NULL DEFAULT 'ct'
+);
+INSERT INTO bookings_new
+	SELECT booking_id, resource_id, strftime('%Y-%m-%dT%H:%M:%S', start_time, 'unixepoch'),
+		strftime('%Y-%m-%dT%H:%M:%S', end_time, 'unixepoch'),
+		strftime('%Y-%m-%dT%H:%M:%S', modified_at, 'unixepoch'), requested_temperature, source
+	FROM bookings;
+DROP TABLE bookings;
+ALTER TABLE bookings_new RENAME TO bookings;
+CREATE INDEX bookings_start_end ON bookings (start_time, end_time);
+CREATE INDEX bookings_resource ON bookings (resource_id);
+
+CREATE TABLE external_temperatures_new (
+	recorded_at DATETIME PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL
+);
+INSERT INTO external_temperatures_new
+	SELECT strftime('%Y-%m-%dT%H:%M:%S', recorded_at, 'unixepoch'), temperature
+	FROM external_temperatures;
+DROP TABLE external_temperatures;
+ALTER TABLE external_temperatures_new RENAME TO external_temperatures;
+
+CREATE TABLE forecasts_new (
+	forecast_for DATETIME PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL,
+	fetched_at DATETIME NOT NULL,
+	irradiance INTEGER
+);
+INSERT INTO forecasts_new
+	SELECT strftime('%Y-%m-%dT%H:%M:%S', forecast_for, 'unixepoch'), temperature,
+		strftime('%Y-%m-%dT%H:%M:%S', fetched_at, 'unixepoch'), irradiance
+	FROM forecasts;
+DROP TABLE forecasts;
+ALTER TABLE forecasts_new RENAME TO forecasts;
+
+CREATE TABLE room_maintenance_new (
+	room TEXT PRIMARY KEY NOT NULL,
+	since DATETIME NOT NULL
+);
+INSERT INTO room_maintenance_new
+	SELECT room, strftime('%Y-%m-%dT%H:%M:%S', since, 'unixepoch') FROM room_maintenance;
+DROP TABLE room_maintenance;
+ALTER TABLE room_maintenance_new RENAME TO room_maintenance;
+
+CREATE TABLE cycle_stats_new (
+	recorded_at DATETIME NOT NULL,
+	task TEXT NOT NULL,
+	duration_ms INTEGER NOT NULL,
+	items INTEGER NOT NULL,
+	errors INTEGER NOT NULL
+);
+INSERT INTO cycle_stats_new
+	SELECT strftime('%Y-%m-%dT%H:%M:%S', recorded_at, 'unixepoch'), task, duration_ms, items, errors
+	FROM cycle_stats;
+DROP TABLE cycle_stats;
+ALTER TABLE cycle_stats_new RENAME TO cycle_stats;
+CREATE INDEX cycle_stats_recorded_at ON cycle_stats (recorded_at);
+
+CREATE TABLE active_config_new (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	content TEXT NOT NULL,
+	loaded_at DATETIME NOT NULL
+);
+INSERT INTO active_config_new
+	SELECT id, content, strftime('%Y-%m-%dT%H:%M:%S', loaded_at, 'unixepoch') FROM active_config;
+DROP TABLE active_config;
+ALTER TABLE active_config_new RENAME TO active_config;
+
+CREATE TABLE instance_lease_new (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	owner TEXT NOT NULL,
+	heartbeat DATETIME NOT NULL
+);
+INSERT INTO instance_lease_new
+	SELECT id, owner, strftime('%Y-%m-%dT%H:%M:%S', heartbeat, 'unixepoch') FROM instance_lease;
+DROP TABLE instance_lease;
+ALTER TABLE instance_lease_new RENAME TO instance_lease;
+
+CREATE TABLE booking_archive_new (
+	booking_id INTEGER PRIMARY KEY NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time DATETIME NOT NULL,
+	end_time DATETIME NOT NULL
+);
+INSERT INTO booking_archive_new
+	SELECT booking_id, resource_id, strftime('%Y-%m-%dT%H:%M:%S', start_time, 'unixepoch'),
+		strftime('%Y-%m-%dT%H:%M:%S', end_time, 'unixepoch')
+	FROM booking_archive;
+DROP TABLE booking_archive;
+ALTER TABLE booking_archive_new RENAME TO booking_archive;
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
@@ -0,0 +1,98 @@
+-- UP store all datetimes as INTEGER Unix timestamps (seconds, UTC) instead of formatted strings
+CREATE TABLE bookings_new (
+	booking_id INTEGER PRIMARY KEY NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time INTEGER NOT NULL,
+	end_time INTEGER NOT NULL,
+	modified_at INTEGER,
+	requested_temperature INTEGER,
+	source TEXT NOT NULL DEFAULT 'ct'
+);
+INSERT INTO bookings_new
+	SELECT booking_id, resource_id, CAST(strftime('%s', start_time) AS INTEGER),
+		CAST(strftime('%s', end_time) AS INTEGER), CAST(strftime('%s', modified_at) AS INTEGER),
+		requested_temperature, source
+	FROM bookings;
+DROP TABLE bookings;
+ALTER TABLE bookings_new RENAME TO bookings;
+CREATE INDEX bookings_start_end ON bookings (start_time, end_time);
+CREATE INDEX bookings_resource ON bookings (resource_id);
+
+CREATE TABLE external_temperatures_new (
+	recorded_at INTEGER PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL
+);
+INSERT INTO external_temperatures_new
+	SELECT CAST(strftime('%s', recorded_at) AS INTEGER), temperature FROM external_temperatures;
+DROP TABLE external_temperatures;
+ALTER TABLE external_temperatures_new RENAME TO external_temperatures;
+
+CREATE TABLE forecasts_new (
+	forecast_for INTEGER PRIMARY KEY NOT NULL,
+	temperature INTEGER NOT NULL,
+	fetched_at INTEGER NOT NULL,
+	irradiance INTEGER
+);
+INSERT INTO forecasts_new
+	SELECT CAST(strftime('%s', forecast_for) AS INTEGER), temperature,
+		CAST(strftime('%s', fetched_at) AS INTEGER), irradiance
+	FROM forecasts;
+DROP TABLE forecasts;
+ALTER TABLE forecasts_new RENAME TO forecasts;
+
+CREATE TABLE room_maintenance_new (
+	room TEXT PRIMARY KEY NOT NULL,
+	since INTEGER NOT NULL
+);
+INSERT INTO room_maintenance_new
+	SELECT room, CAST(strftime('%s', since) AS INTEGER) FROM room_maintenance;
+DROP TABLE room_maintenance;
+ALTER TABLE room_maintenance_new RENAME TO room_maintenance;
+
+CREATE TABLE cycle_stats_new (
+	recorded_at INTEGER NOT NULL,
+	task TEXT NOT NULL,
+	duration_ms INTEGER NOT NULL,
+	items INTEGER NOT NULL,
+	errors INTEGER NOT NULL
+);
+INSERT INTO cycle_stats_new
+	SELECT CAST(strftime('%s', recorded_at) AS INTEGER), task, duration_ms, items, errors
+	FROM cycle_stats;
+DROP TABLE cycle_stats;
+ALTER TABLE cycle_stats_new RENAME TO cycle_stats;
+CREATE INDEX cycle_stats_recorded_at ON cycle_stats (recorded_at);
+
+CREATE TABLE active_config_new (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	content TEXT NOT NULL,
+	loaded_at INTEGER NOT NULL
+);
+INSERT INTO active_config_new
+	SELECT id, content, CAST(strftime('%s', loaded_at) AS INTEGER) FROM active_config;
+DROP TABLE active_config;
+ALTER TABLE active_config_new RENAME TO active_config;
+
+CREATE TABLE instance_lease_new (
+	id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0),
+	owner TEXT NOT NULL,
+	heartbeat INTEGER NOT NULL
+);
+INSERT INTO instance_lease_new
+	SELECT id, owner, CAST(strftime('%s', heartbeat) AS INTEGER) FROM instance_lease;
+DROP TABLE instance_lease;
+ALTER TABLE instance_lease_new RENAME TO instance_lease;
+
+CREATE TABLE booking_archive_new (
+	booking_id INTEGER PRIMARY KEY NOT NULL,
+	resource_id INTEGER NOT NULL,
+	start_time INTEGER NOT NULL,
+	end_time INTEGER NOT NULL
+);
+INSERT INTO booking_archive_new
+	SELECT booking_id, resource_id, CAST(strftime('%s', start_time) AS INTEGER),
+		CAST(strftime('%s', end_time) AS INTEGER)
+	FROM booking_archive;
+DROP TABLE booking_archive;
+ALTER TABLE booking_archive_new RENAME TO booking_archive;
+CREATE INDEX booking_archive_start_time ON booking_archive (start_time);
@@ -0,0 +1,166 @@
+//! Alerts raised when heating control degrades
+//!
+//! Tasks raise and resolve alerts through a channel. With the `alerting` feature, the notifier
+//! forwards them to all configured channels, see [crate::notifier]. Without it, nobody receives
+//! them.
+
+use tokio::sync::mpsc;
+use tracing::{trace, warn};
+
+/// Everything worth a push notification
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// pulling bookings from CT failed this many times in a row
+    CtPullFailing { failures: u32, error: String },
+    /// accessing the DB failed in this task
+    Db { task: DbTask, error: String },
+    /// no external temperature was received from the sensor of a site (None: the default sensor)
+    /// for this many minutes
+    ExtTempMissing { site: Option<String>, minutes: i64 },
+    /// the feedback of a room did not follow the commanded state
+    FeedbackMismatch { cmi: String, room: String },
+}
+impl Alert {
+    /// The condition this alert is about
+    #[cfg(feature = "alerting")]
+    pub fn key(&self) -> AlertKey {
+        match self {
+            Self::CtPullFailing { .. } => AlertKey::CtPull,
+            Self::Db { task, .. } => AlertKey::Db(*task),
+            Self::ExtTempMissing { site, .. } => AlertKey::ExtTempMissing(site.clone()),
+            Self::FeedbackMismatch { cmi, room } => AlertKey::FeedbackMismatch {
+                cmi: cmi.clone(),
+                room: room.clone(),
+            },
+        }
+    }
+}
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::CtPullFailing { failures, error } => write!(
+                f,
+                "Pulling bookings from ChurchTools failed {failures} times in a row. Bookings are not updated. Last error: {error}"
+            ),
+            Self::Db { task, error } => {
+                write!(f, "Accessing the database failed ({}): {error}", task.name())
+            }
+            Self::ExtTempMissing { site, minutes } => write!(
+                f,
+                "No external temperature was received from the {} sensor for {minutes} minutes. Preheat times are not scaled.",
+                site.as_deref().unwrap_or("default")
+            ),
+            Self::FeedbackMismatch { cmi, room } => write!(
+                f,
+                "The feedback of room {room} on CMI {cmi} does not follow the commanded state. Check the wiring and function chart."
+            ),
+        }
+    }
+}
+
+/// The task a DB alert comes from. Each task resolves its own alert once the DB works for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbTask {
+    /// pulling bookings from CT and pruning old ones
+    Pull,
+    /// sending CoE to the CMIs
+    Push,
+    /// recording the external temperature
+    ExtTemp,
+}
+impl DbTask {
+    /// short name of the task, as in the metrics and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pull => "pull",
+            Self::Push => "push",
+            Self::ExtTemp => "ext_temp",
+        }
+    }
+}
+
+/// Identifies the condition an alert is about, so it can be deduplicated and resolved
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AlertKey {
+    CtPull,
+    Db(DbTask),
+    ExtTempMissing(Option<String>),
+    FeedbackMismatch { cmi: String, room: String },
+}
+
+/// Sent by the tasks to the notifier
+#[derive(Debug, PartialEq)]
+pub enum AlertEvent {
+    Raised(Alert),
+    /// the condition cleared
+    Resolved(AlertKey),
+}
+
+/// Raise an alert without waiting for it to be sent
+pub fn raise(alerts: &mpsc::Sender<AlertEvent>, alert: Alert) {
+    send_event(alerts, AlertEvent::Raised(alert));
+}
+
+/// Mark the condition of an alert as cleared. Nothing is sent if no such alert was raised.
+pub fn resolve(alerts: &mpsc::Sender<AlertEvent>, key: AlertKey) {
+    send_event(alerts, AlertEvent::Resolved(key));
+}
+
+fn send_event(alerts: &mpsc::Sender<AlertEvent>, event: AlertEvent) {
+    match alerts.try_send(event) {
+        Ok(()) => (),
+        Err(mpsc::error::TrySendError::Full(event)) => {
+            warn!("Too many pending alerts. Dropping alert event: {event:?}");
+        }
+        // alerting is not configured
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            trace!("Not sending alert, the notifier is not running.");
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alerts_are_dropped_without_notifier() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        // must neither block nor panic
+        raise(
+            &tx,
+            Alert::Db {
+                task: DbTask::Pull,
+                error: "test".to_owned(),
+            },
+        );
+    }
+
+    #[test]
+    fn alerts_are_dropped_when_full() {
+        let (tx, mut rx) = mpsc::channel(1);
+        raise(
+            &tx,
+            Alert::ExtTempMissing {
+                site: None,
+                minutes: 60,
+            },
+        );
+        raise(
+            &tx,
+            Alert::ExtTempMissing {
+                site: None,
+                minutes: 70,
+            },
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            AlertEvent::Raised(Alert::ExtTempMissing {
+                site: None,
+                minutes: 60,
+            })
+        );
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! A read-only JSON API, e.g. for "room is heated" badges on the website of the congregation.
+//!
+//! GET /api/openapi.json describes all endpoints. Its schemas are generated from the response
+//! types in this module, so the document cannot drift from what is served.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use schemars::{generate::SchemaSettings, JsonSchema};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    config::Config,
+    db::DBError,
+    ics::{heating_events, HeatingEvent},
+    metrics::{Metrics, Task},
+    read_ext_temp::ExternalTemperatures,
+};
+
+/// /api/bookings lists the bookings of the next ... days by default
+pub(crate) const DEFAULT_BOOKING_DAYS: i64 = 7;
+/// /api/bookings lists the bookings of at most the next ... days
+pub(crate) const MAX_BOOKING_DAYS: i64 = 31;
+
+/// A time the room is heated for a booking, including preheat and preshutdown
+#[derive(Debug, PartialEq, Serialize, JsonSchema)]
+pub(crate) struct HeatingWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// A room synced to a CMI
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct ApiRoom {
+    /// the name of the room in the config
+    pub name: String,
+    /// the CMI the room is on
+    pub cmi: String,
+    /// the site (building) of the CMI
+    pub site: Option<String>,
+    /// whether HEATING was sent last. Null before the first push.
+    pub heating: Option<bool>,
+    /// the next heating window within the next 7 days that is not over
+    pub next_window: Option<HeatingWindow>,
+}
+
+/// An upcoming booking with its heating window
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct ApiBooking {
+    /// the booking id in CT. Local bookings have negative ids.
+    pub id: i64,
+    pub room: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub heating: HeatingWindow,
+}
+impl From<HeatingEvent> for ApiBooking {
+    fn from(event: HeatingEvent) -> Self {
+        Self {
+            id: event.booking_id,
+            room: event.room,
+            start: event.booking_start,
+            end: event.booking_end,
+            heating: HeatingWindow {
+                start: event.start,
+                end: event.end,
+            },
+        }
+    }
+}
+
+/// The state of the whole installation
+#[derive(Debug, Serialize, JsonSchema)]
+pub(crate) struct ApiState {
+    /// sensor (`default` or the site) -> outdoor temperature in Degree Centigrade, if known
+    pub outdoor_temperatures: BTreeMap<String, Option<f64>>,
+    /// whether rooms may be heated today (see heating_season in the config)
+    pub in_heating_season: bool,
+    /// rooms that HEATING was sent to last
+    pub heating_rooms: usize,
+    /// whether the last pull from CT succeeded. Null before the first pull.
+    pub last_pull_ok: Option<bool>,
+    /// whether the last push to the CMIs succeeded. Null before the first push.
+    pub last_push_ok: Option<bool>,
+}
+
+/// All rooms of all CMIs
+pub(crate) async fn rooms(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    metrics: &Metrics,
+) -> Result<Vec<ApiRoom>, DBError> {
+    let now = Utc::now();
+    let events = heating_events(
+        config,
+        ext_temps,
+        now,
+        now + TimeDelta::days(DEFAULT_BOOKING_DAYS),
+    )
+    .await?;
+    let heating = metrics.room_heating();
+    Ok(config
+        .cmis
+        .iter()
+        .flat_map(|cmi| cmi.rooms.iter().map(move |room| (cmi, room)))
+        .map(|(cmi, room)| ApiRoom {
+            name: room.name.clone(),
+            cmi: cmi.host.clone(),
+            site: cmi.site.clone(),
+            heating: heating
+                .get(&(cmi.host.clone(), room.name.clone()))
+                .map(|(_, heating)| *heating),
+            // events are sorted by start
+            next_window: events
+                .iter()
+                .find(|event| event.room == room.name && event.end > now)
+                .map(|event| HeatingWindow {
+                    start: event.start,
+                    end: event.end,
+                }),
+        })
+        .collect())
+}
+
+/// The bookings starting within the next `days` days that are not over
+pub(crate) async fn bookings(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    days: i64,
+) -> Result<Vec<ApiBooking>, DBError> {
+    let now = Utc::now();
+    let events = heating_events(config, ext_temps, now, now + TimeDelta::days(days)).await?;
+    Ok(events.into_iter().map(ApiBooking::from).collect())
+}
+
+/// The state of the whole installation
+pub(crate) async fn state(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    metrics: &Metrics,
+) -> ApiState {
+    ApiState {
+        outdoor_temperatures: ext_temps
+            .by_sensor()
+            .await
+            .into_iter()
+            .map(|(sensor, temp)| (sensor, temp.map(|x| x as f64 / 10_f64)))
+            .collect(),
+        in_heating_season: config.in_heating_season(None, Utc::now()),
+        heating_rooms: metrics
+            .room_heating()
+            .values()
+            .filter(|(_, heating)| *heating)
+            .count(),
+        last_pull_ok: metrics.last_run_ok(Task::Pull),
+        last_push_ok: metrics.last_run_ok(Task::Push),
+    }
+}
+
+/// The OpenAPI document of all endpoints under /api
+pub(crate) fn openapi() -> Value {
+    let mut generator = SchemaSettings::draft2020_12()
+        .with(|s| s.definitions_path = "/components/schemas".into())
+        .for_serialize()
+        .into_generator();
+    let rooms = generator.subschema_for::<Vec<ApiRoom>>();
+    let bookings = generator.subschema_for::<Vec<ApiBooking>>();
+    let state = generator.subschema_for::<ApiState>();
+    let ok = |description: &str, schema| {
+        json!({ "200": {
+            "description": description,
+            "content": { "application/json": { "schema": schema } }
+        }})
+    };
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "ct-ta-sync",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Read-only state of the heating synced from ChurchTools to TA CMIs",
+        },
+        "paths": {
+            "/api/rooms": { "get": {
+                "summary": "All rooms with their state and next heating window",
+                "responses": ok("the rooms", rooms),
+            }},
+            "/api/bookings": { "get": {
+                "summary": "Upcoming bookings with their heating windows",
+                "parameters": [{
+                    "name": "days",
+                    "in": "query",
+                    "description": format!(
+                        "list the bookings starting within the next ... days (default {DEFAULT_BOOKING_DAYS}, at most {MAX_BOOKING_DAYS})"
+                    ),
+                    "schema": { "type": "integer", "minimum": 1, "maximum": MAX_BOOKING_DAYS },
+                }],
+                "responses": ok("the bookings, sorted by the start of heating", bookings),
+            }},
+            "/api/state": { "get": {
+                "summary": "The state of the whole installation",
+                "responses": ok("the state", state),
+            }},
+        },
+        "components": { "schemas": generator.take_definitions(true) },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn openapi_references_resolve() {
+        let document = openapi();
+        let schemas = document["components"]["schemas"].as_object().unwrap();
+        for name in ["ApiRoom", "ApiBooking", "ApiState", "HeatingWindow"] {
+            assert!(schemas.contains_key(name), "{name} is missing");
+        }
+        let text = document.to_string();
+        for reference in text.split("\"$ref\":\"").skip(1) {
+            let name = reference
+                .split('"')
+                .next()
+                .unwrap()
+                .strip_prefix("#/components/schemas/")
+                .unwrap();
+            assert!(schemas.contains_key(name), "{name} is not defined");
+        }
+        assert_eq!(
+            document["paths"]["/api/rooms"]["get"]["responses"]["200"]["content"]
+                ["application/json"]["schema"]["items"]["$ref"],
+            "#/components/schemas/ApiRoom"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! The bookings heating is decided on, from CT and all other sources
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Bookings longer than this are broken data, not events.
+///
+/// Whether the dates themselves are plausible is checked when pulling from CT (`ct.plausible_days`).
+const MAX_DURATION: TimeDelta = TimeDelta::days(2 * 366);
+
+/// A booking whose interval cannot be heated for
+#[derive(Debug, PartialEq)]
+pub enum InvalidBooking {
+    /// (start, end)
+    EndNotAfterStart(DateTime<Utc>, DateTime<Utc>),
+    /// (start, end)
+    TooLong(DateTime<Utc>, DateTime<Utc>),
+}
+impl std::fmt::Display for InvalidBooking {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::EndNotAfterStart(start, end) => {
+                write!(
+                    f,
+                    "The booking ends at {end}, which is not after its start {start}."
+                )
+            }
+            Self::TooLong(start, end) => {
+                write!(
+                    f,
+                    "The booking from {start} to {end} is longer than {} days.",
+                    MAX_DURATION.num_days()
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for InvalidBooking {}
+
+/// Check that a booking from `start` to `end` can be heated for
+pub fn check_interval(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<(), InvalidBooking> {
+    if end <= start {
+        return Err(InvalidBooking::EndNotAfterStart(start, end));
+    };
+    if end - start > MAX_DURATION {
+        return Err(InvalidBooking::TooLong(start, end));
+    };
+    Ok(())
+}
+
+/// A single booking for a room
+///
+/// The fields are private, so every booking is built through [Booking::new] and its interval is
+/// checked.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedBooking")]
+pub struct Booking {
+    /// the ID of the resource for this booking.
+    /// NOTE: this is NOT the ID of the booking, but of the resource in CT.
+    /// This ID is used for matching ressources against rooms defined in the config.
+    resource_id: i64,
+    /// The ID of this booking. This is used to update bookings when they are updated in CT.
+    booking_id: i64,
+    /// The booking starts at...
+    /// ALL DATETIMES ARE UTC.
+    #[serde(rename = "start")]
+    start_time: DateTime<Utc>,
+    /// The booking ends at...
+    #[serde(rename = "end")]
+    end_time: DateTime<Utc>,
+    /// When the booking was last modified in CT (meta.modifiedDate).
+    /// This is None if CT did not send one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_at: Option<DateTime<Utc>>,
+    /// The setpoint requested in the booking note, in tenths of a Degree Centigrade
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requested_temperature: Option<i32>,
+}
+impl Booking {
+    /// A booking without modification date or requested setpoint, if its interval is valid
+    pub fn new(
+        booking_id: i64,
+        resource_id: i64,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Self, InvalidBooking> {
+        check_interval(start_time, end_time)?;
+        Ok(Self {
+            resource_id,
+            booking_id,
+            start_time,
+            end_time,
+            modified_at: None,
+            requested_temperature: None,
+        })
+    }
+
+    pub fn with_modified_at(self, modified_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            modified_at,
+            ..self
+        }
+    }
+
+    pub fn with_requested_temperature(self, requested_temperature: Option<i32>) -> Self {
+        Self {
+            requested_temperature,
+            ..self
+        }
+    }
+
+    pub fn resource_id(&self) -> i64 {
+        self.resource_id
+    }
+
+    pub fn booking_id(&self) -> i64 {
+        self.booking_id
+    }
+
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.start_time
+    }
+
+    pub fn end_time(&self) -> DateTime<Utc> {
+        self.end_time
+    }
+
+    pub fn modified_at(&self) -> Option<DateTime<Utc>> {
+        self.modified_at
+    }
+
+    pub fn requested_temperature(&self) -> Option<i32> {
+        self.requested_temperature
+    }
+}
+
+/// A [Booking] as deserialized, before its interval is checked
+#[derive(Deserialize)]
+struct UncheckedBooking {
+    resource_id: i64,
+    booking_id: i64,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    modified_at: Option<DateTime<Utc>>,
+    requested_temperature: Option<i32>,
+}
+impl TryFrom<UncheckedBooking> for Booking {
+    type Error = InvalidBooking;
+
+    fn try_from(value: UncheckedBooking) -> Result<Self, Self::Error> {
+        Ok(
+            Self::new(value.booking_id, value.resource_id, value.start, value.end)?
+                .with_modified_at(value.modified_at)
+                .with_requested_temperature(value.requested_temperature),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    #[test]
+    fn intervals() {
+        let start = time("2025-01-12T09:00:00Z");
+        assert!(Booking::new(1, 2, start, start + TimeDelta::hours(3)).is_ok());
+        assert_eq!(
+            Booking::new(1, 2, start, start),
+            Err(InvalidBooking::EndNotAfterStart(start, start))
+        );
+        assert_eq!(
+            Booking::new(1, 2, start, start - TimeDelta::minutes(1)),
+            Err(InvalidBooking::EndNotAfterStart(
+                start,
+                start - TimeDelta::minutes(1)
+            ))
+        );
+        assert!(Booking::new(1, 2, start, start + MAX_DURATION).is_ok());
+        assert_eq!(
+            Booking::new(1, 2, start, start + MAX_DURATION + TimeDelta::seconds(1)),
+            Err(InvalidBooking::TooLong(
+                start,
+                start + MAX_DURATION + TimeDelta::seconds(1)
+            ))
+        );
+    }
+
+    #[test]
+    fn serde() {
+        let booking = Booking::new(
+            -1,
+            12,
+            time("2025-01-12T09:00:00Z"),
+            time("2025-01-12T12:00:00Z"),
+        )
+        .unwrap();
+        let json = serde_json::to_string(&booking).unwrap();
+        assert_eq!(
+            json,
+            r#"{"resource_id":12,"booking_id":-1,"start":"2025-01-12T09:00:00Z","end":"2025-01-12T12:00:00Z"}"#
+        );
+        assert_eq!(serde_json::from_str::<Booking>(&json).unwrap(), booking);
+
+        let backwards = r#"{"resource_id":12,"booking_id":-1,"start":"2025-01-12T12:00:00Z","end":"2025-01-12T09:00:00Z"}"#;
+        assert!(serde_json::from_str::<Booking>(backwards).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Calendars besides CT that rooms are booked in, e.g. on a CalDAV server or in Microsoft 365.
+//!
+//! Each source has one calendar per room. The events of a calendar are stored as bookings of the
+//! room's resource under the source `<kind>:<calendar>` and replaced on each pull, next to the
+//! ones pulled from CT.
+
+use std::{future::Future, sync::Arc};
+
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, db::DBError, Booking, InShutdown};
+
+/// A kind of calendar bookings are pulled from
+pub(crate) trait BookingSource: Sync {
+    /// one calendar, booking one room
+    type Calendar: Sync;
+    /// what is needed to pull the calendars, e.g. an access token
+    type Session: Sync;
+    type Error: std::fmt::Display + From<DBError> + Send;
+    /// prefix of the source of the bookings in the db, e.g. `caldav`
+    const KIND: &'static str;
+    /// the name used in logs
+    const NAME: &'static str;
+
+    fn calendars(&self) -> &[Self::Calendar];
+    /// Identifies `calendar` among those of this kind, e.g. its URL
+    fn key(calendar: &Self::Calendar) -> &str;
+    /// The name of the room `calendar` books
+    fn room(calendar: &Self::Calendar) -> &str;
+    /// pull the calendars every ... seconds
+    fn pull_frequency(&self) -> u64;
+    /// Set up what is needed for pulling all calendars once
+    fn connect(&self) -> impl Future<Output = Result<Self::Session, Self::Error>> + Send;
+    /// Get the bookings of `calendar`
+    fn get_bookings(
+        &self,
+        session: &Self::Session,
+        calendar: &Self::Calendar,
+    ) -> impl Future<Output = Result<Vec<Booking>, Self::Error>> + Send;
+}
+
+/// Pull all calendars of `source` into the db. Returns the number of bookings pulled.
+///
+/// The bookings of a calendar that cannot be pulled are kept, but the first error is returned.
+pub async fn pull_once<S: BookingSource>(
+    config: &Config,
+    source: Option<&S>,
+) -> Result<usize, S::Error> {
+    let Some(source) = source else {
+        return Ok(0);
+    };
+    let session = source.connect().await.inspect_err(|e| {
+        warn!(
+            "Failed to connect to {}. Keeping the old bookings. Error encountered: {e}",
+            S::NAME
+        );
+    })?;
+    let mut pulled = 0;
+    let mut first_error = None;
+    for calendar in source.calendars() {
+        let res = match source.get_bookings(&session, calendar).await {
+            Ok(bookings) => crate::db::replace_external_bookings(
+                &config.db,
+                &format!("{}:{}", S::KIND, S::key(calendar)),
+                &bookings,
+            )
+            .await
+            .map(|()| bookings.len())
+            .map_err(S::Error::from),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(x) => {
+                config.bookings_changed.notify_one();
+                debug!(
+                    "Pulled {x} bookings of room {} from {}.",
+                    S::room(calendar),
+                    S::NAME
+                );
+                pulled += x;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to pull the calendar of room {} from {}. Keeping its old bookings. Error encountered: {e}",
+                    S::room(calendar),
+                    S::NAME
+                );
+                first_error.get_or_insert(e);
+            }
+        };
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(pulled),
+    }
+}
+
+/// Continually pull the calendars of the source `get_source` returns from the config into the db.
+///
+/// The bookings of calendars that are no longer configured are deleted first.
+pub async fn keep_up_to_date<S: BookingSource>(
+    config: Arc<Config>,
+    get_source: fn(&Config) -> Option<&S>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(source) = get_source(&config) else {
+        debug!("No {} calendars configured.", S::NAME);
+        return;
+    };
+    info!("Starting {} task", S::NAME);
+    let keys = source.calendars().iter().map(S::key).collect::<Vec<_>>();
+    match crate::db::prune_external_bookings(&config.db, S::KIND, &keys).await {
+        Ok(0) => {}
+        Ok(x) => info!(
+            "Deleted {x} bookings of {} calendars that are no longer configured.",
+            S::NAME
+        ),
+        Err(e) => warn!(
+            "Failed to delete the bookings of old {} calendars: {e}",
+            S::NAME
+        ),
+    };
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(source.pull_frequency()));
+    interval.tick().await;
+    loop {
+        if let Ok(x) = pull_once(&config, Some(source)).await {
+            debug!("Successfully pulled {x} bookings from {}.", S::NAME);
+        };
+        // stop on cancellation or continue after the next tick
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down {} task now.", S::NAME);
+                return;
+            }
+            _ = interval.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::{TimeDelta, Utc};
+    use sqlx::SqlitePool;
+
+    /// Calendars with the ids of their bookings. Those without ids cannot be pulled.
+    struct FakeSource(Vec<(&'static str, Option<Vec<i64>>)>);
+    impl BookingSource for FakeSource {
+        type Calendar = (&'static str, Option<Vec<i64>>);
+        type Session = ();
+        type Error = DBError;
+        const KIND: &'static str = "fake";
+        const NAME: &'static str = "the fake calendar";
+
+        fn calendars(&self) -> &[Self::Calendar] {
+            &self.0
+        }
+
+        fn key(calendar: &Self::Calendar) -> &str {
+            calendar.0
+        }
+
+        fn room(calendar: &Self::Calendar) -> &str {
+            calendar.0
+        }
+
+        fn pull_frequency(&self) -> u64 {
+            60
+        }
+
+        async fn connect(&self) -> Result<(), DBError> {
+            Ok(())
+        }
+
+        async fn get_bookings(
+            &self,
+            _session: &(),
+            calendar: &Self::Calendar,
+        ) -> Result<Vec<Booking>, DBError> {
+            let now = Utc::now();
+            let ids = calendar
+                .1
+                .as_ref()
+                .ok_or(DBError::ReplaceExternalBookings(sqlx::Error::PoolClosed))?;
+            Ok(ids
+                .iter()
+                .map(|id| Booking::new(*id, 31, now, now + TimeDelta::hours(1)).unwrap())
+                .collect())
+        }
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn failed_calendars_keep_their_bookings(pool: SqlitePool) {
+        let config = crate::config::test_empty_config("", pool);
+        let source = FakeSource(vec![("a", Some(vec![-10])), ("b", Some(vec![-20, -21]))]);
+        assert_eq!(pull_once(&config, Some(&source)).await.unwrap(), 3);
+
+        let source = FakeSource(vec![("a", None), ("b", Some(vec![]))]);
+        assert!(pull_once(&config, Some(&source)).await.is_err());
+        let ids = crate::db::get_all_bookings(&config.db)
+            .await
+            .unwrap()
+            .iter()
+            .map(|b| b.booking_id())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![-10]);
+
+        assert_eq!(pull_once::<FakeSource>(&config, None).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,47 @@
+//! Information about this binary, so bug reports identify the exact build.
+
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("CT_TA_SYNC_GIT_HASH");
+pub const BUILD_DATE: &str = env!("CT_TA_SYNC_BUILD_DATE");
+/// comma separated list of the enabled cargo features
+pub const FEATURES: &str = env!("CT_TA_SYNC_FEATURES");
+
+/// Build information and the hash of the active config, as served on GET /status
+#[derive(Debug, Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    pub build_date: &'static str,
+    pub features: Vec<&'static str>,
+    pub config_hash: String,
+}
+impl BuildInfo {
+    pub fn new(config_hash: &str) -> Self {
+        Self {
+            version: VERSION,
+            git_hash: GIT_HASH,
+            build_date: BUILD_DATE,
+            features: FEATURES.split(',').filter(|x| !x.is_empty()).collect(),
+            config_hash: config_hash.to_owned(),
+        }
+    }
+}
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "ct-ta-sync {} (git {}, built {}, features: {}, config {})",
+            self.version,
+            self.git_hash,
+            self.build_date,
+            if self.features.is_empty() {
+                "none".to_owned()
+            } else {
+                self.features.join(",")
+            },
+            self.config_hash
+        )
+    }
+}
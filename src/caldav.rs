@@ -0,0 +1,456 @@
+//! Pull bookings from calendars on a CalDAV server (e.g. Nextcloud), one calendar per room.
+//!
+//! See [crate::booking_source] for how they are stored.
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+use tracing::debug;
+
+use crate::{
+    booking_source::BookingSource,
+    config::{CalDavCalendarConfig, CalDavConfig},
+    db::DBError,
+    Booking,
+};
+
+const CALDAV_NS: &str = "urn:ietf:params:xml:ns:caldav";
+
+#[derive(Debug)]
+pub enum CalDavError {
+    Request(reqwest::Error),
+    Status(String, reqwest::StatusCode),
+    Xml(roxmltree::Error),
+    DB(DBError),
+}
+impl std::fmt::Display for CalDavError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "Cannot query the calendar. reqwest Error: {e}"),
+            Self::Status(url, status) => {
+                write!(f, "The CalDAV server returned {status} for {url}.")
+            }
+            Self::Xml(e) => write!(f, "Cannot parse the CalDAV response. XML Error: {e}"),
+            Self::DB(e) => write!(f, "DBError: {e}"),
+        }
+    }
+}
+impl std::error::Error for CalDavError {}
+impl From<DBError> for CalDavError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<reqwest::Error> for CalDavError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Request(value)
+    }
+}
+
+/// An event from a calendar
+#[derive(Debug, PartialEq)]
+struct Event {
+    uid: String,
+    /// set for the instances of recurring events
+    recurrence_id: Option<String>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/// The body of a calendar-query REPORT for the events between `start` and `end`.
+///
+/// Recurring events are expanded into their instances by the server.
+fn calendar_query(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+    let start = start.format("%Y%m%dT%H%M%SZ");
+    let end = end.format("%Y%m%dT%H%M%SZ");
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<C:calendar-query xmlns:D="DAV:" xmlns:C="{CALDAV_NS}">
+  <D:prop>
+    <C:calendar-data>
+      <C:expand start="{start}" end="{end}"/>
+    </C:calendar-data>
+  </D:prop>
+  <C:filter>
+    <C:comp-filter name="VCALENDAR">
+      <C:comp-filter name="VEVENT">
+        <C:time-range start="{start}" end="{end}"/>
+      </C:comp-filter>
+    </C:comp-filter>
+  </C:filter>
+</C:calendar-query>"#
+    )
+}
+
+/// The iCalendar objects in a multistatus response
+fn calendar_data(xml: &str) -> Result<Vec<String>, roxmltree::Error> {
+    let doc = roxmltree::Document::parse(xml)?;
+    Ok(doc
+        .descendants()
+        .filter(|node| node.has_tag_name((CALDAV_NS, "calendar-data")))
+        .map(|node| {
+            node.children()
+                .filter_map(|child| child.text())
+                .collect::<String>()
+        })
+        .collect())
+}
+
+/// A content line, split into name, parameters and value
+struct ContentLine<'a> {
+    name: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+    value: &'a str,
+}
+impl<'a> ContentLine<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        // the value starts at the first colon outside of quoted parameter values
+        let mut quoted = false;
+        let colon = line.char_indices().find_map(|(idx, c)| match c {
+            '"' => {
+                quoted = !quoted;
+                None
+            }
+            ':' if !quoted => Some(idx),
+            _ => None,
+        })?;
+        let mut parts = line[..colon].split(';');
+        let name = parts.next()?;
+        Some(Self {
+            name,
+            params: parts.filter_map(|x| x.split_once('=')).collect(),
+            value: &line[colon + 1..],
+        })
+    }
+
+    fn param(&self, key: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| *v)
+    }
+
+    /// The value as a time. Dates are local midnight.
+    ///
+    /// Times without a trailing Z are taken as local time, even if they have a TZID.
+    fn time(&self) -> Option<DateTime<Utc>> {
+        let local = |x: NaiveDateTime| Local.from_local_datetime(&x).earliest();
+        if self.param("VALUE") == Some("DATE") || self.value.len() == 8 {
+            let date = NaiveDate::parse_from_str(self.value, "%Y%m%d").ok()?;
+            return local(date.and_hms_opt(0, 0, 0)?).map(|x| x.to_utc());
+        };
+        match self.value.strip_suffix('Z') {
+            Some(utc) => NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+                .ok()
+                .map(|x| x.and_utc()),
+            None => local(NaiveDateTime::parse_from_str(self.value, "%Y%m%dT%H%M%S").ok()?)
+                .map(|x| x.to_utc()),
+        }
+    }
+}
+
+/// Unfold the content lines of an iCalendar object (RFC 5545, 3.1)
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some(last)) => last.push_str(continued),
+            _ => lines.push(line.to_owned()),
+        };
+    }
+    lines
+}
+
+/// Parse a duration like `PT1H30M`, `P1D` or `P2W` (RFC 5545, 3.3.6)
+fn parse_duration(value: &str) -> Option<TimeDelta> {
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(x) => (-1, x),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let mut seconds = 0_i64;
+    let mut number = 0_i64;
+    for c in value.strip_prefix('P')?.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number = number.checked_mul(10)? + i64::from(c.to_digit(10)?);
+                continue;
+            }
+            'T' => continue,
+            'W' => 7 * 86400,
+            'D' => 86400,
+            'H' => 3600,
+            'M' => 60,
+            'S' => 1,
+            _ => return None,
+        };
+        seconds = seconds.checked_add(number.checked_mul(unit)?)?;
+        number = 0;
+    }
+    TimeDelta::try_seconds(sign * seconds)
+}
+
+/// The events in an iCalendar object. Cancelled events are skipped.
+fn parse_events(ics: &str) -> Vec<Event> {
+    let mut events = vec![];
+    let mut components = vec![];
+    let (mut uid, mut recurrence_id, mut start, mut end, mut duration, mut cancelled) =
+        (None, None, None, None, None, false);
+    for line in unfold(ics) {
+        let Some(line) = ContentLine::parse(&line) else {
+            continue;
+        };
+        match line.name.to_ascii_uppercase().as_str() {
+            "BEGIN" => {
+                if line.value.eq_ignore_ascii_case("VEVENT") {
+                    (uid, recurrence_id, start, end, duration, cancelled) =
+                        (None, None, None, None, None, false);
+                };
+                components.push(line.value.to_ascii_uppercase());
+            }
+            "END" => {
+                if components.pop().as_deref() != Some("VEVENT") || cancelled {
+                    continue;
+                };
+                let (Some(uid), Some((start, is_date))) = (uid.take(), start.take()) else {
+                    continue;
+                };
+                let end = end.take().unwrap_or_else(|| {
+                    // without DTEND or DURATION, all-day events last one day
+                    start
+                        + duration.take().unwrap_or(if is_date {
+                            TimeDelta::days(1)
+                        } else {
+                            TimeDelta::zero()
+                        })
+                });
+                if end > start {
+                    events.push(Event {
+                        uid,
+                        recurrence_id: recurrence_id.take(),
+                        start,
+                        end,
+                    });
+                };
+            }
+            // properties of nested components (e.g. VALARM) do not belong to the event
+            _ if components.last().map(String::as_str) != Some("VEVENT") => {}
+            "UID" => uid = Some(line.value.to_owned()),
+            "RECURRENCE-ID" => recurrence_id = Some(line.value.to_owned()),
+            "DTSTART" => {
+                let is_date = line.param("VALUE") == Some("DATE") || line.value.len() == 8;
+                start = line.time().map(|x| (x, is_date));
+            }
+            "DTEND" => end = line.time(),
+            "DURATION" => duration = parse_duration(line.value),
+            "STATUS" => cancelled = line.value.eq_ignore_ascii_case("CANCELLED"),
+            _ => {}
+        };
+    }
+    events
+}
+
+/// The booking id of an event instance, see [crate::db::external_booking_id]
+fn booking_id(url: &str, event: &Event) -> i64 {
+    crate::db::external_booking_id(
+        &format!("{}:{url}", CalDavConfig::KIND),
+        &format!(
+            "{}\n{}",
+            event.uid,
+            event.recurrence_id.as_deref().unwrap_or_default()
+        ),
+    )
+}
+
+/// Get the bookings from a calendar for the next `days` days
+async fn get_bookings(
+    client: &reqwest::Client,
+    calendar: &CalDavCalendarConfig,
+    days: u16,
+) -> Result<Vec<Booking>, CalDavError> {
+    let start = Utc::now() - TimeDelta::days(1);
+    let end = Utc::now() + TimeDelta::days(days.into());
+    let mut request = client
+        .request(
+            reqwest::Method::from_bytes(b"REPORT").expect("REPORT is a valid method"),
+            &calendar.url,
+        )
+        .header("Depth", "1")
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(calendar_query(start, end));
+    if let Some(username) = &calendar.username {
+        request = request.basic_auth(username, calendar.password.as_ref());
+    };
+    if let Some(token) = &calendar.token {
+        request = request.bearer_auth(token);
+    };
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(CalDavError::Status(calendar.url.clone(), response.status()));
+    };
+    let text = response.text().await?;
+    Ok(calendar_data(&text)
+        .map_err(CalDavError::Xml)?
+        .iter()
+        .flat_map(|ics| parse_events(ics))
+        // servers that cannot expand recurring events return them whole
+        .filter(|event| event.end > start && event.start < end)
+        .filter_map(|event| {
+            Booking::new(
+                booking_id(&calendar.url, &event),
+                calendar.resource_id,
+                event.start,
+                event.end,
+            )
+            .inspect_err(|e| debug!("Skipping an event in {}: {e}", calendar.url))
+            .ok()
+        })
+        .collect())
+}
+
+impl BookingSource for CalDavConfig {
+    type Calendar = CalDavCalendarConfig;
+    type Session = reqwest::Client;
+    type Error = CalDavError;
+    const KIND: &'static str = "caldav";
+    const NAME: &'static str = "CalDAV";
+
+    fn calendars(&self) -> &[CalDavCalendarConfig] {
+        &self.calendars
+    }
+
+    fn key(calendar: &CalDavCalendarConfig) -> &str {
+        &calendar.url
+    }
+
+    fn room(calendar: &CalDavCalendarConfig) -> &str {
+        &calendar.room
+    }
+
+    fn pull_frequency(&self) -> u64 {
+        self.pull_frequency
+    }
+
+    async fn connect(&self) -> Result<reqwest::Client, CalDavError> {
+        Ok(reqwest::Client::new())
+    }
+
+    async fn get_bookings(
+        &self,
+        client: &reqwest::Client,
+        calendar: &CalDavCalendarConfig,
+    ) -> Result<Vec<Booking>, CalDavError> {
+        get_bookings(client, calendar, self.days).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extract_calendar_data() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:cal="urn:ietf:params:xml:ns:caldav">
+  <d:response>
+    <d:href>/remote.php/dav/calendars/hall/personal/a.ics</d:href>
+    <d:propstat>
+      <d:prop><cal:calendar-data>BEGIN:VCALENDAR
+END:VCALENDAR</cal:calendar-data></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:propstat>
+      <d:prop><cal:calendar-data><![CDATA[BEGIN:VCALENDAR]]></cal:calendar-data></d:prop>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            calendar_data(xml).unwrap(),
+            vec!["BEGIN:VCALENDAR\nEND:VCALENDAR", "BEGIN:VCALENDAR"]
+        );
+        assert!(calendar_data("<d:multistatus").is_err());
+    }
+
+    #[test]
+    fn parse_ics_events() {
+        let utc = |x: &str| DateTime::parse_from_rfc3339(x).unwrap().to_utc();
+        let ics = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:choir\r\n\
+            RECURRENCE-ID:20250112T100000Z\r\n\
+            DTSTART:20250112T100000Z\r\n\
+            DTEND:20250112T120000Z\r\n\
+            BEGIN:VALARM\r\n\
+            DURATION:PT15M\r\n\
+            END:VALARM\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:long-uid-\r\n \
+            folded\r\n\
+            DTSTART;TZID=\"Europe/Berlin: local\":20250113T100000Z\r\n\
+            DURATION:PT1H30M\r\n\
+            END:VEVENT\r\n\
+            BEGIN:VEVENT\r\n\
+            UID:cancelled\r\n\
+            STATUS:CANCELLED\r\n\
+            DTSTART:20250114T100000Z\r\n\
+            DTEND:20250114T120000Z\r\n\
+            END:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        assert_eq!(
+            parse_events(ics),
+            vec![
+                Event {
+                    uid: "choir".to_owned(),
+                    recurrence_id: Some("20250112T100000Z".to_owned()),
+                    start: utc("2025-01-12T10:00:00Z"),
+                    end: utc("2025-01-12T12:00:00Z"),
+                },
+                Event {
+                    uid: "long-uid-folded".to_owned(),
+                    recurrence_id: None,
+                    start: utc("2025-01-13T10:00:00Z"),
+                    end: utc("2025-01-13T11:30:00Z"),
+                },
+            ]
+        );
+
+        // all-day events last from local midnight to local midnight
+        let events = parse_events("BEGIN:VEVENT\nUID:a\nDTSTART;VALUE=DATE:20250112\nEND:VEVENT\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].end - events[0].start, TimeDelta::days(1));
+        assert_eq!(
+            events[0].start.with_timezone(&Local).naive_local(),
+            NaiveDate::from_ymd_opt(2025, 1, 12)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap()
+        );
+        // events without a duration are dropped
+        assert!(
+            parse_events("BEGIN:VEVENT\nUID:a\nDTSTART:20250112T100000Z\nEND:VEVENT\n").is_empty()
+        );
+
+        assert_eq!(parse_duration("P1W"), Some(TimeDelta::days(7)));
+        assert_eq!(parse_duration("-P1DT2H"), Some(-TimeDelta::hours(26)));
+        assert_eq!(parse_duration("PT90S"), Some(TimeDelta::seconds(90)));
+        assert_eq!(parse_duration("1H"), None);
+    }
+
+    #[test]
+    fn booking_ids_are_stable_and_do_not_collide() {
+        let event = |recurrence_id: Option<&str>| Event {
+            uid: "choir".to_owned(),
+            recurrence_id: recurrence_id.map(str::to_owned),
+            start: Utc::now(),
+            end: Utc::now(),
+        };
+        let a = booking_id("https://cloud/hall", &event(None));
+        assert_eq!(a, booking_id("https://cloud/hall", &event(None)));
+        assert!(a < -(1 << 32));
+        assert_ne!(a, booking_id("https://cloud/chapel", &event(None)));
+        assert_ne!(
+            a,
+            booking_id("https://cloud/hall", &event(Some("20250112T100000Z")))
+        );
+    }
+}
@@ -0,0 +1,485 @@
+//! Command line interface
+
+use std::{collections::HashMap, path::PathBuf, process::ExitCode, sync::Arc};
+
+use chrono::{NaiveDate, TimeDelta, Utc};
+use clap::{Parser, Subcommand};
+use tracing::{error, info};
+
+use crate::{
+    config::Config,
+    instance_lease::InstanceLease,
+    metrics::{Metrics, Task},
+    migrate::backup_path,
+    preheat::{priority_order, Conditions},
+    push_to_ta::{get_booking_history, unused_for},
+    read_ext_temp::ExternalTemperatures,
+    resource_hierarchy::implying_resources,
+    selftest::SelftestOptions,
+};
+
+/// shown by --version
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (git ",
+    env!("CT_TA_SYNC_GIT_HASH"),
+    ", built ",
+    env!("CT_TA_SYNC_BUILD_DATE"),
+    ")"
+);
+
+/// Sync room bookings from ChurchTools to CMIs
+#[derive(Debug, Parser)]
+#[command(version, long_version = LONG_VERSION, about)]
+pub(crate) struct Cli {
+    /// Start even if another instance seems to be syncing with this DB
+    #[arg(long, global = true)]
+    pub force: bool,
+    /// Heat for synthetic bookings and a synthetic external temperature instead of CT data.
+    ///
+    /// Uses its own DB, so the real bookings are untouched. Requires --fake-cmi, so the simulated
+    /// heating never reaches the real CMIs.
+    #[arg(long, global = true, requires = "fake_cmi")]
+    pub simulate: bool,
+    /// synthetic bookings per room and day
+    #[arg(long, default_value_t = 3.0)]
+    pub simulate_density: f64,
+    /// Send CoE to a fake CMI on this host, which logs what it receives, instead of the CMIs
+    #[arg(long)]
+    pub fake_cmi: bool,
+    /// Pull from CT and push to the CMIs once, then exit.
+    ///
+    /// For deployments driven by cron or systemd timers. Exits with 2 if the pull failed (the push
+    /// then uses the bookings in the DB) and with 3 if the push failed.
+    #[arg(long, conflicts_with_all = ["simulate", "fake_cmi"])]
+    pub once: bool,
+    /// Only serve this tenant of the `tenants:` section of the config.
+    ///
+    /// Required for one-off commands and --once if several tenants are configured.
+    #[arg(long, global = true)]
+    pub tenant: Option<String>,
+    /// Number of worker threads of the async runtime. 1 runs everything on the main thread.
+    ///
+    /// Defaults to the number of cores, i.e. a single thread on single-core boards like the Pi Zero.
+    #[arg(long, global = true)]
+    pub worker_threads: Option<usize>,
+    /// Run a one-off command instead of the sync
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Print the recorded external temperatures as CSV
+    ExportTemperatures {
+        /// export the samples of the last ... days
+        #[arg(long, default_value_t = 7)]
+        days: i64,
+    },
+    /// Print the recorded statistics of each pull and push as CSV
+    ExportMetrics {
+        /// export the statistics of the last ... days
+        #[arg(long, default_value_t = 7)]
+        days: i64,
+    },
+    /// Print the rooms in the order they get to heat, with their next heating times
+    ///
+    /// Heating times assume the full preheat, as if it was cold and cloudy, unless an external
+    /// temperature is given with --assume-temp.
+    Preview {
+        /// show bookings within the next ... hours
+        #[arg(long, default_value_t = 24)]
+        hours: i64,
+        /// scale preheat and preshutdown for this external temperature in Degree Centigrade,
+        /// e.g. -5.0
+        #[arg(long, allow_negative_numbers = true)]
+        assume_temp: Option<f64>,
+    },
+    /// Import past bookings from CT into the archive used for reports.
+    ///
+    /// Archived bookings are never used to decide whether to heat.
+    Backfill {
+        /// the first day to import, e.g. 2024-01-01
+        #[arg(long)]
+        from: NaiveDate,
+        /// the last day to import (default: today)
+        #[arg(long)]
+        until: Option<NaiveDate>,
+    },
+    /// Print a JSON Schema of the config file
+    ///
+    /// Does not need a config file. Editors can use the schema to validate the config.
+    PrintConfigSchema,
+    /// Check that CMIs, ChurchTools and the DB are reachable, e.g. after the installation.
+    ///
+    /// Sends a test value to each CMI, optionally waits for the CMI to echo it back, binds the
+    /// sockets for receiving CoE and checks that the DB is writable. Stop the daemon first.
+    Selftest {
+        /// send the test value on this PDO index (1-64) of each CMI. CMIs that get an output of a
+        /// room on it are skipped.
+        #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u8).range(1..=64))]
+        pdo: u8,
+        /// the test value, sent without a unit
+        #[arg(long, default_value_t = 42, allow_negative_numbers = true)]
+        value: i32,
+        /// expect each CMI to send the test value back on this PDO index (1-64) from its
+        /// feedback_can_id
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=64))]
+        loopback_pdo: Option<u8>,
+        /// wait at most ... seconds for each echo
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Confirm which PDO each room is sent on, one room at a time.
+    ///
+    /// Each room blinks on its PDO while you watch the CAN monitor of the CMI. The confirmed
+    /// mapping is printed as snippet for the `cmis:` section of the config, with the other settings
+    /// of the CMIs and rooms taken from the config, but without their resource_types. Stop the
+    /// daemon first.
+    MapPdo {
+        /// only map the rooms of the CMI with this host
+        #[arg(long)]
+        host: Option<String>,
+        /// write the snippet to this file instead of printing it
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Print all incoming CoE with CAN id, PDO, unit and value until Ctrl-C.
+    ///
+    /// Binds the CoE port, so stop the daemon first.
+    CoeMonitor {
+        /// listen on this address (default: bind_addr of the external temperature sensor, or
+        /// 0.0.0.0)
+        #[arg(long)]
+        bind_addr: Option<String>,
+        /// only print values from this CAN id
+        #[arg(long)]
+        can_id: Option<u8>,
+        /// only print values on this PDO index (1-64)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=64))]
+        pdo: Option<u8>,
+    },
+    /// Inspect and apply DB migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum MigrateAction {
+    /// List all migrations and whether they are applied
+    Status,
+    /// Back up the DB and apply all pending migrations
+    Up,
+    /// Back up the DB, then revert and reapply the last migration.
+    ///
+    /// Reverting may drop the data of the migrated tables.
+    Redo {
+        /// really redo the migration
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+/// Run a one-off command
+pub(crate) async fn run_command(
+    config: &Config,
+    command: Command,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::ExportTemperatures { days } => export_temperatures(config, days).await,
+        Command::ExportMetrics { days } => export_metrics(config, days).await,
+        Command::Preview { hours, assume_temp } => preview(config, hours, assume_temp).await,
+        Command::Backfill { from, until } => backfill(config, from, until).await,
+        Command::PrintConfigSchema => print_config_schema(),
+        Command::Migrate { action } => migrate(config, action).await,
+        Command::CoeMonitor {
+            bind_addr,
+            can_id,
+            pdo,
+        } => {
+            let bind_addr = bind_addr
+                .or_else(|| {
+                    let coe = config.external_temperature_sensor.coe.as_ref();
+                    coe.map(|coe| coe.bind_addr.clone())
+                })
+                .unwrap_or_else(|| "0.0.0.0".to_owned());
+            let filter = crate::coe_monitor::Filter {
+                can_id,
+                pdo_index: pdo,
+            };
+            crate::coe_monitor::run(&bind_addr, filter).await
+        }
+        Command::MapPdo { host, output } => {
+            crate::map_pdo::run(config, host.as_deref(), output.as_deref()).await
+        }
+        Command::Selftest {
+            pdo,
+            value,
+            loopback_pdo,
+            timeout,
+        } => {
+            selftest(
+                config,
+                SelftestOptions {
+                    pdo_index: pdo,
+                    value,
+                    loopback_pdo_index: loopback_pdo,
+                    timeout: std::time::Duration::from_secs(timeout),
+                },
+            )
+            .await
+        }
+    }
+}
+
+/// Exit status of `--once` if the pull failed
+const EXIT_PULL_FAILED: u8 = 2;
+/// Exit status of `--once` if the push failed
+const EXIT_PUSH_FAILED: u8 = 3;
+
+/// Pull and push once, see [Cli::once]
+pub(crate) async fn run_once(config: Arc<Config>) -> ExitCode {
+    let metrics = Metrics::default();
+    let pull_start = std::time::Instant::now();
+    let pull_res = crate::pull_from_ct::pull_once(config.clone()).await;
+    metrics
+        .record(
+            &config,
+            Task::Pull,
+            pull_start.elapsed(),
+            *pull_res.as_ref().unwrap_or(&0),
+            pull_res.is_ok(),
+        )
+        .await;
+    if let Err(e) = &pull_res {
+        error!(
+            "Failed to update db from CT. Pushing the bookings in the db. Error encountered: {e}"
+        );
+    };
+    // failures are logged per calendar
+    let caldav_res = crate::booking_source::pull_once(&config, config.caldav.as_ref()).await;
+    let m365_res = crate::booking_source::pull_once(&config, config.m365.as_ref()).await;
+
+    let push_start = std::time::Instant::now();
+    let push_res = match ExternalTemperatures::from_history(&config).await {
+        Ok(ext_temps) => crate::push_to_ta::push_once(&config, &ext_temps, pull_res.is_ok()).await,
+        Err(e) => Err(e.into()),
+    };
+    metrics
+        .record(
+            &config,
+            Task::Push,
+            push_start.elapsed(),
+            *push_res.as_ref().unwrap_or(&0),
+            push_res.is_ok(),
+        )
+        .await;
+    let pulled = pull_res
+        .ok()
+        .zip(caldav_res.ok())
+        .zip(m365_res.ok())
+        .map(|((ct, caldav), m365)| ct + caldav + m365);
+    match (pulled, push_res) {
+        (_, Err(e)) => {
+            error!("Failed to emit CoE packets. Error encountered: {e}");
+            ExitCode::from(EXIT_PUSH_FAILED)
+        }
+        (None, Ok(_)) => ExitCode::from(EXIT_PULL_FAILED),
+        (Some(pulled), Ok(pushed)) => {
+            info!("Pulled {pulled} bookings and sent {pushed} CoE packets.");
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Print the cycle stats recorded in the last `days` days as CSV to stdout
+async fn export_metrics(config: &Config, days: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let end = Utc::now().naive_utc();
+    let start = end - TimeDelta::days(days);
+    let stats = crate::db::get_cycle_stats_in_timeframe(&config.db, start, end).await?;
+    println!("recorded_at,task,duration_ms,items,errors");
+    for stat in stats {
+        println!(
+            "{},{},{},{},{}",
+            stat.recorded_at.to_rfc3339(),
+            stat.task,
+            stat.duration_ms,
+            stat.items,
+            stat.errors
+        );
+    }
+    Ok(())
+}
+
+/// Print all rooms in priority order with their next heating times.
+///
+/// With `assume_temp` (in Degree Centigrade), the heating times are those at this external
+/// temperature, which helps to tune the preheat of each room without waiting for a cold snap.
+async fn preview(
+    config: &Config,
+    hours: i64,
+    assume_temp: Option<f64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // in tenths of a Degree Centigrade, like the measured temperatures
+    let assumed = assume_temp.map(|x| (x * 10_f64).round() as i32);
+    let conditions = Conditions {
+        preheat_temp: assumed,
+        external_temp: assumed,
+        preheat_irradiance: None,
+        unused_for: None,
+    };
+    let now = Utc::now();
+    let until = now + TimeDelta::hours(hours);
+    let bookings =
+        crate::db::get_bookings_in_timeframe(&config.db, now.naive_utc(), until.naive_utc())
+            .await?;
+    let history = get_booking_history(config, now, until).await?;
+    let parents = if config.resource_hierarchy.is_some() {
+        crate::db::get_resource_parents(&config.db).await?
+    } else {
+        HashMap::new()
+    };
+    // rooms may be on multiple CMIs, but are configured the same on all of them
+    let mut rooms = HashMap::new();
+    for room in config.cmis.iter().flat_map(|cmi| &cmi.rooms) {
+        rooms.entry(room.name.as_str()).or_insert(room);
+    }
+    let mut rows = rooms
+        .into_values()
+        .map(|room| {
+            let implying = implying_resources(
+                config.resource_hierarchy.as_ref(),
+                &parents,
+                room.churchtools_id,
+            );
+            let next = bookings
+                .iter()
+                .filter(|b| b.end_time() > now && implying.contains(&b.resource_id()))
+                .min_by_key(|b| b.start_time());
+            let unused_for = next.and_then(|b| unused_for(room, &history, &implying, b));
+            (room, next, unused_for)
+        })
+        .collect::<Vec<_>>();
+    let start_of = |next: &Option<&crate::Booking>| next.map(|b| b.start_time()).unwrap_or(until);
+    rows.sort_by(|(room_a, next_a, _), (room_b, next_b, _)| {
+        priority_order(
+            (room_a.priority, start_of(next_a), &room_a.name),
+            (room_b.priority, start_of(next_b), &room_b.name),
+        )
+    });
+    println!("rank,priority,room,circuit,heating_start,heating_end,preheat_minutes");
+    for (rank, (room, next, unused_for)) in rows.into_iter().enumerate() {
+        let (start, end, preheat) = match next {
+            Some(b) => {
+                let (start, end) = room.apply_preheat_and_preshutdown(
+                    b.start_time(),
+                    b.end_time(),
+                    &Conditions {
+                        unused_for,
+                        ..conditions
+                    },
+                );
+                (
+                    start.to_rfc3339(),
+                    end.to_rfc3339(),
+                    (b.start_time() - start).num_minutes().to_string(),
+                )
+            }
+            None => (String::new(), String::new(), String::new()),
+        };
+        println!(
+            "{},{},{},{},{start},{end},{preheat}",
+            rank + 1,
+            room.priority,
+            room.name,
+            room.circuit.as_deref().unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
+
+/// Import the bookings from `from` to `until` into the archive
+async fn backfill(
+    config: &Config,
+    from: NaiveDate,
+    until: Option<NaiveDate>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let until = until.unwrap_or(Utc::now().date_naive());
+    if from > until {
+        return Err(format!("--from {from} is after --until {until}").into());
+    };
+    let archived = crate::pull_from_ct::backfill(config, from, until).await?;
+    println!("Archived {archived} bookings from {from} to {until}.");
+    Ok(())
+}
+
+/// Run a migration command
+async fn migrate(config: &Config, action: MigrateAction) -> Result<(), Box<dyn std::error::Error>> {
+    let db_path = &config.db_path;
+    match action {
+        MigrateAction::Status => {
+            for m in crate::migrate::status(&config.db).await? {
+                println!(
+                    "{:03} {} {}",
+                    m.version,
+                    if m.applied { "applied" } else { "pending" },
+                    m.description
+                );
+            }
+        }
+        MigrateAction::Up => {
+            if !crate::migrate::pending(&config.db).await?.is_empty() {
+                InstanceLease::ensure_not_held(&config.db, false).await?;
+            };
+            crate::migrate::up(&config.db, &backup_path(db_path)).await?
+        }
+        MigrateAction::Redo { yes: false } => {
+            println!("Redoing a migration may drop data. Rerun with --yes to do it anyway.");
+        }
+        MigrateAction::Redo { yes: true } => {
+            InstanceLease::ensure_not_held(&config.db, false).await?;
+            let version = crate::migrate::redo(&config.db, &backup_path(db_path)).await?;
+            println!("Redid migration {version:03}.");
+        }
+    };
+    Ok(())
+}
+
+/// Run all checks of the selftest and print them as table
+async fn selftest(
+    config: &Config,
+    options: SelftestOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let checks = crate::selftest::run(config, &options).await;
+    print!("{}", crate::selftest::render(&checks));
+    let failed = checks.iter().filter(|c| c.result.is_err()).count();
+    if failed > 0 {
+        return Err(format!("{failed} of {} checks failed", checks.len()).into());
+    };
+    Ok(())
+}
+
+/// Print the JSON Schema of the config file to stdout
+pub(crate) fn print_config_schema() -> Result<(), Box<dyn std::error::Error>> {
+    let schema = schemars::schema_for!(crate::config::ConfigFileData);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
+}
+
+/// Print the external temperatures recorded in the last `days` days as CSV to stdout
+async fn export_temperatures(config: &Config, days: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let end = Utc::now().naive_utc();
+    let start = end - TimeDelta::days(days);
+    let samples = crate::db::get_external_temperatures_in_timeframe(&config.db, start, end).await?;
+    println!("recorded_at,temperature");
+    for sample in samples {
+        println!(
+            "{},{:.1}",
+            sample.recorded_at.to_rfc3339(),
+            sample.temperature as f32 / 10_f32
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,600 @@
+//! Receive CoE for all purposes.
+//!
+//! All CoE is received on port 5442, so there is one socket per bind address. Each payload is
+//! handed to the consumers registered for its (CAN id, PDO index): the external temperature and
+//! humidity of the sensors and the feedback of rooms.
+//!
+//! The sockets may also be shared with the [CoeSender](crate::coe_sender::CoeSender), so CMIs
+//! see the same port in both directions.
+//!
+//! On busy CAN networks, most packets are for someone else. They are not logged one by one, but
+//! summarized per sender every [IGNORED_SUMMARY_INTERVAL] and counted in the metrics.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use coe::{AnalogueCOEValue, COEValue, DigitalCOEValue, Packet};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, Mutex},
+    task::JoinSet,
+};
+use tracing::{debug, error, info, trace, Instrument};
+
+use crate::{
+    coe_sender::COE_PORT,
+    config::{Config, ExtTempConfig, TemperatureUnit},
+    feedback::{FeedbackTracker, RoomKey},
+    metrics::Metrics,
+    read_ext_temp::{normalize_temperature, Humidity},
+    InShutdown,
+};
+
+/// Where CoE packets are received from. This is a UdpSocket, except in tests.
+pub(crate) trait DatagramSource {
+    /// Wait for the next datagram and write it to `buf`, returning its length and sender
+    fn recv_datagram(
+        &self,
+        buf: &mut [u8],
+    ) -> impl std::future::Future<Output = std::io::Result<(usize, SocketAddr)>> + Send;
+}
+impl DatagramSource for UdpSocket {
+    async fn recv_datagram(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf).await
+    }
+}
+impl<T: DatagramSource + Send + Sync> DatagramSource for Arc<T> {
+    fn recv_datagram(
+        &self,
+        buf: &mut [u8],
+    ) -> impl std::future::Future<Output = std::io::Result<(usize, SocketAddr)>> + Send {
+        self.as_ref().recv_datagram(buf)
+    }
+}
+
+/// Summarize the ignored datagrams of each sender at most this often
+pub(crate) const IGNORED_SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Datagrams that are not CoE or whose payloads nobody consumes, by sender
+#[derive(Debug)]
+pub(crate) struct IgnoredPackets {
+    metrics: Option<Arc<Metrics>>,
+    /// sender -> datagrams ignored since `since`
+    by_source: BTreeMap<IpAddr, u64>,
+    since: Instant,
+}
+impl IgnoredPackets {
+    pub fn new(metrics: Option<Arc<Metrics>>) -> Self {
+        Self {
+            metrics,
+            by_source: BTreeMap::new(),
+            since: Instant::now(),
+        }
+    }
+
+    /// Count a datagram from `source` that is not parsable as CoE
+    fn malformed(&mut self, source: IpAddr, error: String) {
+        if let Some(metrics) = &self.metrics {
+            metrics.count_malformed_coe(source, error);
+        };
+        self.ignore(source);
+    }
+
+    /// Count a datagram from `source` and log the summary if it is due
+    fn ignore(&mut self, source: IpAddr) {
+        if let Some(metrics) = &self.metrics {
+            metrics.count_ignored_coe(source);
+        };
+        *self.by_source.entry(source).or_default() += 1;
+        for line in self.take_summary(Instant::now()) {
+            info!("{line}");
+        }
+    }
+
+    /// One line per sender if the last summary is older than [IGNORED_SUMMARY_INTERVAL]
+    fn take_summary(&mut self, now: Instant) -> Vec<String> {
+        let elapsed = now.duration_since(self.since);
+        if elapsed < IGNORED_SUMMARY_INTERVAL {
+            return vec![];
+        };
+        self.since = now;
+        std::mem::take(&mut self.by_source)
+            .into_iter()
+            .map(|(source, count)| {
+                format!(
+                    "Ignored {} CoE packets from {source} in the last {}min.",
+                    short_count(count),
+                    elapsed.as_secs() / 60
+                )
+            })
+            .collect()
+    }
+}
+
+/// 1234 -> 1.2k
+fn short_count(count: u64) -> String {
+    match count {
+        0..1_000 => count.to_string(),
+        1_000..1_000_000 => format!("{:.1}k", count as f64 / 1e3),
+        _ => format!("{:.1}M", count as f64 / 1e6),
+    }
+}
+
+/// Wait for the next well-formed CoE packet
+pub(crate) async fn read_next_packet(sock: &impl DatagramSource) -> Packet {
+    read_next_packet_from(sock, &mut IgnoredPackets::new(None))
+        .await
+        .0
+}
+
+/// Wait for the next well-formed CoE packet and return it with its sender. Other datagrams are
+/// counted in `ignored`.
+async fn read_next_packet_from(
+    sock: &impl DatagramSource,
+    ignored: &mut IgnoredPackets,
+) -> (Packet, IpAddr) {
+    // all well-formed COE packets are at most 252 bytes long
+    let mut buf = [0_u8; 252];
+    loop {
+        let bytes = sock.recv_datagram(&mut buf).await;
+        match bytes {
+            Ok((len, source)) => match TryInto::<Packet>::try_into(&buf[0..len]) {
+                Ok(packet) => return (packet, source.ip()),
+                Err(e) => ignored.malformed(source.ip(), e.to_string()),
+            },
+            Err(e) => {
+                trace!("Failed to read a CoE packet: {e}");
+            }
+        }
+    }
+}
+
+/// Something interested in the payloads of one (CAN id, PDO index)
+#[derive(Debug)]
+pub enum Consumer {
+    /// the external temperature of a sensor, forwarded in tenths of a Degree Centigrade
+    ExtTemp {
+        tx: mpsc::Sender<i32>,
+        accepted_units: Vec<TemperatureUnit>,
+    },
+    /// the relative humidity of a sensor, in tenths of a percent
+    Humidity(Humidity),
+    /// the actual state of a room
+    Feedback {
+        room: RoomKey,
+        tracker: Arc<Mutex<FeedbackTracker>>,
+    },
+}
+impl Consumer {
+    async fn consume(&self, payload: &coe::Payload) {
+        match self {
+            Self::ExtTemp { tx, accepted_units } => {
+                let COEValue::Analogue(value) = payload.value() else {
+                    return;
+                };
+                let Some(temp) = normalize_temperature(value, accepted_units) else {
+                    trace!(
+                        "Got Payload for an external temperature, but the Unit was not an accepted temperature unit ({}).",
+                        payload.unit_id()
+                    );
+                    return;
+                };
+                debug!("Got the external temperature: {} °C", temp as f32 / 10_f32);
+                // the receiver is only gone during shutdown
+                let _ = tx.send(temp).await;
+            }
+            Self::Humidity(humidity) => {
+                let COEValue::Analogue(AnalogueCOEValue::Percent_Tens(value)) = payload.value()
+                else {
+                    trace!(
+                        "Got Payload for a humidity, but the Unit was not percent ({}).",
+                        payload.unit_id()
+                    );
+                    return;
+                };
+                debug!("Got the humidity: {} %", value as f32 / 10_f32);
+                *humidity.write().await = Some((value, Utc::now()));
+            }
+            Self::Feedback { room, tracker } => {
+                let COEValue::Digital(DigitalCOEValue::OnOff(state)) = payload.value() else {
+                    return;
+                };
+                trace!("Room {} on CMI {} reports {state}.", room.1, room.0);
+                tracker
+                    .lock()
+                    .await
+                    .set_actual(room.clone(), state, Utc::now());
+            }
+        }
+    }
+}
+
+/// A consumer and the senders it accepts payloads from
+#[derive(Debug)]
+struct Route {
+    consumer: Consumer,
+    /// all senders are accepted if None
+    allowed_senders: Option<Vec<IpAddr>>,
+}
+impl Route {
+    fn accepts(&self, source: IpAddr) -> bool {
+        self.allowed_senders
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(&source))
+    }
+}
+
+/// (CAN id, PDO index) -> consumers
+type Routes = HashMap<(u8, u8), Vec<Route>>;
+
+/// All consumers of CoE, by the address they receive on
+#[derive(Debug, Default)]
+pub struct CoeHub {
+    /// bind address -> routes
+    sockets: HashMap<String, Routes>,
+}
+impl CoeHub {
+    /// Hand the payloads for `pdo_index` (already shifted to 0-63) of `can_id` received on
+    /// `bind_addr` to `consumer`
+    pub fn register(&mut self, bind_addr: &str, can_id: u8, pdo_index: u8, consumer: Consumer) {
+        self.register_from(bind_addr, can_id, pdo_index, None, consumer);
+    }
+
+    /// Like [register](Self::register), but only hand over payloads from `allowed_senders`
+    pub fn register_from(
+        &mut self,
+        bind_addr: &str,
+        can_id: u8,
+        pdo_index: u8,
+        allowed_senders: Option<Vec<IpAddr>>,
+        consumer: Consumer,
+    ) {
+        self.sockets
+            .entry(bind_addr.to_owned())
+            .or_default()
+            .entry((can_id, pdo_index))
+            .or_default()
+            .push(Route {
+                consumer,
+                allowed_senders,
+            });
+    }
+
+    /// Forward the temperatures of `sensor` to `tx` and its humidity to `humidity`, if it
+    /// receives via CoE
+    pub fn register_sensor(
+        &mut self,
+        sensor: &ExtTempConfig,
+        tx: mpsc::Sender<i32>,
+        humidity: Option<Humidity>,
+    ) {
+        if let Some(coe) = &sensor.coe {
+            if let (Some(pdo_index), Some(humidity)) = (coe.humidity_pdo_index, humidity) {
+                self.register_from(
+                    &coe.bind_addr,
+                    coe.can_id,
+                    pdo_index,
+                    coe.allowed_senders.clone(),
+                    Consumer::Humidity(humidity),
+                );
+            };
+            self.register_from(
+                &coe.bind_addr,
+                coe.can_id,
+                coe.pdo_index,
+                coe.allowed_senders.clone(),
+                Consumer::ExtTemp {
+                    tx,
+                    accepted_units: coe.accepted_units.clone(),
+                },
+            );
+        };
+    }
+
+    /// Record the feedback of all rooms that have one in `tracker`.
+    ///
+    /// Feedback is received on the bind address of the default sensor.
+    pub fn register_feedback(&mut self, config: &Config, tracker: Arc<Mutex<FeedbackTracker>>) {
+        let Some(coe) = &config.external_temperature_sensor.coe else {
+            return;
+        };
+        for cmi in &config.cmis {
+            let Some(can_id) = cmi.feedback_can_id else {
+                continue;
+            };
+            for room in &cmi.rooms {
+                if let Some(pdo_index) = room.feedback_pdo_index {
+                    self.register(
+                        &coe.bind_addr,
+                        can_id,
+                        pdo_index,
+                        Consumer::Feedback {
+                            room: (cmi.host.clone(), room.name.clone()),
+                            tracker: tracker.clone(),
+                        },
+                    );
+                };
+            }
+        }
+    }
+
+    /// Also bind a socket on `bind_addr` if nothing is registered there, so it can be shared
+    pub fn share(&mut self, bind_addr: &str) {
+        self.sockets.entry(bind_addr.to_owned()).or_default();
+    }
+
+    /// Bind the sockets for all addresses consumers are registered on
+    pub async fn bind(self) -> Result<BoundCoeHub, std::io::Error> {
+        let mut sockets = HashMap::new();
+        for (bind_addr, routes) in self.sockets {
+            let sock = match UdpSocket::bind((bind_addr.clone(), COE_PORT)).await {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Unable to open Udp Socket on {bind_addr} to receive CoE: {e}");
+                    return Err(e);
+                }
+            };
+            sockets.insert(bind_addr, (Arc::new(sock), routes));
+        }
+        Ok(BoundCoeHub { sockets })
+    }
+}
+
+/// A [CoeHub] with all its sockets bound
+#[derive(Debug)]
+pub struct BoundCoeHub {
+    /// bind address -> (socket, routes)
+    sockets: HashMap<String, (Arc<UdpSocket>, Routes)>,
+}
+impl BoundCoeHub {
+    /// The socket bound on `bind_addr`, if there is one
+    pub fn socket(&self, bind_addr: &str) -> Option<Arc<UdpSocket>> {
+        self.sockets.get(bind_addr).map(|(sock, _)| sock.clone())
+    }
+
+    /// Dispatch the received payloads until shutdown
+    pub async fn run(
+        self,
+        metrics: Arc<Metrics>,
+        mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    ) {
+        // the receivers are aborted when this is dropped
+        let mut receivers = JoinSet::new();
+        for (bind_addr, (sock, routes)) in self.sockets {
+            info!(
+                "Receiving CoE for {} CAN id and PDO pairs on {bind_addr}.",
+                routes.len()
+            );
+            receivers.spawn(
+                dispatch(sock, routes, IgnoredPackets::new(Some(metrics.clone())))
+                    .in_current_span(),
+            );
+        }
+        let _ = watcher.changed().await;
+        debug!("Shutting down the CoE receivers now.");
+    }
+}
+
+/// Hand each payload received on `sock` to its consumers
+async fn dispatch(sock: impl DatagramSource, routes: Routes, mut ignored: IgnoredPackets) {
+    loop {
+        let (packet, source) = read_next_packet_from(&sock, &mut ignored).await;
+        let mut consumed = false;
+        for payload in packet.iter() {
+            let Some(routes) = routes.get(&(payload.node(), payload.pdo_index())) else {
+                continue;
+            };
+            for route in routes.iter().filter(|route| route.accepts(source)) {
+                consumed = true;
+                route.consumer.consume(payload).await;
+            }
+        }
+        if !consumed {
+            ignored.ignore(source);
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use chrono::TimeDelta;
+    use coe::AnalogueCOEValue;
+
+    /// Hands out prepared datagrams, then waits forever
+    struct FakeSocket {
+        datagrams: std::sync::Mutex<VecDeque<Vec<u8>>>,
+    }
+    impl DatagramSource for FakeSocket {
+        async fn recv_datagram(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+            let next = self.datagrams.lock().unwrap().pop_front();
+            match next {
+                Some(datagram) => {
+                    // like UDP, datagrams longer than buf are truncated
+                    let len = datagram.len().min(buf.len());
+                    buf[..len].copy_from_slice(&datagram[..len]);
+                    Ok((len, SocketAddr::from(([192, 0, 2, 1], COE_PORT))))
+                }
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    fn serialize(payloads: &[coe::Payload]) -> Vec<Vec<u8>> {
+        coe::packets_from_payloads(payloads)
+            .into_iter()
+            .map(Into::<Vec<u8>>::into)
+            .collect()
+    }
+
+    fn temp(node: u8, pdo_index: u8, value: AnalogueCOEValue) -> coe::Payload {
+        coe::Payload::new(node, pdo_index, COEValue::Analogue(value))
+    }
+
+    #[tokio::test]
+    async fn payloads_reach_their_consumers() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let (site_tx, mut site_rx) = mpsc::channel(16);
+        let tracker = Arc::new(Mutex::new(FeedbackTracker::new(TimeDelta::minutes(10))));
+        let room = ("cmi".to_owned(), "hall".to_owned());
+        let start = Utc::now();
+        tracker
+            .lock()
+            .await
+            .set_commanded(room.clone(), false, start);
+        let mut hub = CoeHub::default();
+        let ext_temp = |tx| Consumer::ExtTemp {
+            tx,
+            accepted_units: vec![TemperatureUnit::CelsiusTenths],
+        };
+        hub.register("127.0.0.1", 1, 0, ext_temp(tx));
+        // a second sensor on the same socket
+        hub.register("127.0.0.1", 2, 0, ext_temp(site_tx));
+        let humidity = Arc::new(tokio::sync::RwLock::new(None));
+        hub.register("127.0.0.1", 1, 3, Consumer::Humidity(humidity.clone()));
+        hub.register(
+            "127.0.0.1",
+            2,
+            5,
+            Consumer::Feedback {
+                room: room.clone(),
+                tracker: tracker.clone(),
+            },
+        );
+        let routes = hub.sockets.remove("127.0.0.1").unwrap();
+
+        let mut datagrams = vec![];
+        // wrong CAN id
+        datagrams.extend(serialize(&[temp(
+            3,
+            0,
+            AnalogueCOEValue::DegreeCentigrade_Tens(100),
+        )]));
+        // wrong unit
+        datagrams.extend(serialize(&[temp(
+            1,
+            0,
+            AnalogueCOEValue::Percent_Tens(100),
+        )]));
+        // not CoE at all
+        datagrams.push(vec![0xff; 7]);
+        // truncated
+        let mut truncated =
+            serialize(&[temp(1, 0, AnalogueCOEValue::DegreeCentigrade_Tens(999))]).remove(0);
+        truncated.truncate(truncated.len() - 3);
+        datagrams.push(truncated);
+        // the temperature is in the second of two packets
+        let mut payloads = (0..40)
+            .map(|i| temp(1, 1 + i % 8, AnalogueCOEValue::DegreeCentigrade_Tens(0)))
+            .collect::<Vec<_>>();
+        payloads.push(temp(1, 0, AnalogueCOEValue::DegreeCentigrade_Tens(-52)));
+        let fragmented = serialize(&payloads);
+        assert!(fragmented.len() > 1);
+        datagrams.extend(fragmented);
+        datagrams.extend(serialize(&[
+            temp(1, 0, AnalogueCOEValue::DegreeCentigrade_Tens(215)),
+            temp(2, 0, AnalogueCOEValue::DegreeCentigrade_Tens(-10)),
+            coe::Payload::new(2, 5, COEValue::Digital(DigitalCOEValue::OnOff(true))),
+            temp(1, 3, AnalogueCOEValue::Percent_Tens(655)),
+        ]));
+
+        let sock = FakeSocket {
+            datagrams: std::sync::Mutex::new(datagrams.into()),
+        };
+        let metrics = Arc::new(Metrics::default());
+        let ignored = IgnoredPackets::new(Some(metrics.clone()));
+        let receiver = tokio::spawn(dispatch(sock, routes, ignored).in_current_span());
+        assert_eq!(rx.recv().await, Some(-52));
+        assert_eq!(rx.recv().await, Some(215));
+        assert_eq!(site_rx.recv().await, Some(-10));
+        tokio::task::yield_now().await;
+        assert!(rx.try_recv().is_err());
+        receiver.abort();
+        assert_eq!(humidity.read().await.map(|(x, _)| x), Some(655));
+        // the packet for another CAN id, the one that is not CoE and the truncated one
+        #[cfg(feature = "http")]
+        {
+            let rendered = metrics.render();
+            assert!(rendered
+                .contains("ct_ta_sync_coe_ignored_packets_total{source=\"192.0.2.1\"} 3\n"));
+            assert!(rendered
+                .contains("ct_ta_sync_coe_malformed_packets_total{source=\"192.0.2.1\"} 2\n"));
+            let malformed = metrics.malformed_coe();
+            assert_eq!(
+                malformed[&crate::metrics::CoeSource::Addr(IpAddr::from([192, 0, 2, 1]))].count,
+                2
+            );
+        };
+
+        // the room reports heating, but was commanded off
+        assert_eq!(
+            tracker.lock().await.check(start + TimeDelta::minutes(11)),
+            vec![room]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_allowed_senders_reach_a_sensor() {
+        let (spoofed_tx, mut spoofed_rx) = mpsc::channel(16);
+        let (tx, mut rx) = mpsc::channel(16);
+        let ext_temp = |tx| Consumer::ExtTemp {
+            tx,
+            accepted_units: vec![TemperatureUnit::CelsiusTenths],
+        };
+        let mut hub = CoeHub::default();
+        // the FakeSocket receives everything from 192.0.2.1
+        let other = vec![IpAddr::from([192, 0, 2, 2])];
+        hub.register_from("127.0.0.1", 1, 0, Some(other), ext_temp(spoofed_tx));
+        let cmi = vec![IpAddr::from([192, 0, 2, 1])];
+        hub.register_from("127.0.0.1", 2, 0, Some(cmi), ext_temp(tx));
+        let routes = hub.sockets.remove("127.0.0.1").unwrap();
+
+        let datagrams = serialize(&[
+            temp(1, 0, AnalogueCOEValue::DegreeCentigrade_Tens(300)),
+            temp(2, 0, AnalogueCOEValue::DegreeCentigrade_Tens(-10)),
+        ]);
+        let sock = FakeSocket {
+            datagrams: std::sync::Mutex::new(datagrams.into()),
+        };
+        let receiver = tokio::spawn(
+            dispatch(sock, routes, IgnoredPackets::new(None)).in_current_span(),
+        );
+        assert_eq!(rx.recv().await, Some(-10));
+        tokio::task::yield_now().await;
+        assert!(spoofed_rx.try_recv().is_err());
+        receiver.abort();
+    }
+
+    #[test]
+    fn ignored_packets_are_summarized_per_sender() {
+        let mut ignored = IgnoredPackets::new(None);
+        let start = ignored.since;
+        let noisy = IpAddr::from([10, 0, 0, 5]);
+        for _ in 0..1234 {
+            ignored.ignore(noisy);
+        }
+        ignored.ignore(IpAddr::from([10, 0, 0, 6]));
+        assert!(ignored
+            .take_summary(start + Duration::from_secs(60))
+            .is_empty());
+        assert_eq!(
+            ignored.take_summary(start + IGNORED_SUMMARY_INTERVAL),
+            vec![
+                "Ignored 1.2k CoE packets from 10.0.0.5 in the last 10min.",
+                "Ignored 1 CoE packets from 10.0.0.6 in the last 10min.",
+            ]
+        );
+        // counted anew after each summary
+        assert!(ignored
+            .take_summary(start + 2 * IGNORED_SUMMARY_INTERVAL)
+            .is_empty());
+    }
+}
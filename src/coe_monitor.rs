@@ -0,0 +1,125 @@
+//! Print all incoming CoE, to debug why values do not arrive.
+//!
+//! Every payload is printed with its sender, CAN id, PDO index, unit and value, so no packet
+//! capture and manual decoding is needed.
+
+use std::net::SocketAddr;
+
+use chrono::Local;
+use coe::{COEValue, Packet, Payload};
+use tokio::net::UdpSocket;
+
+use crate::coe_sender::COE_PORT;
+
+/// Only print payloads matching all of these
+#[derive(Debug, Default)]
+pub(crate) struct Filter {
+    /// CAN id of the sender
+    pub can_id: Option<u8>,
+    /// PDO index (1-64)
+    pub pdo_index: Option<u8>,
+}
+impl Filter {
+    fn matches(&self, payload: &Payload) -> bool {
+        self.can_id.is_none_or(|can_id| payload.node() == can_id)
+            && self
+                .pdo_index
+                .is_none_or(|pdo_index| payload.pdo_index() + 1 == pdo_index)
+    }
+}
+
+/// Format a payload for humans. The PDO index is shown as 1-64, like on the CMI.
+fn describe(payload: &Payload) -> String {
+    let (format, value) = match payload.value() {
+        COEValue::Analogue(value) => ("analogue", format!("{value:?}")),
+        COEValue::Digital(value) => ("digital", format!("{value:?}")),
+    };
+    format!(
+        "CAN id {:>2}  PDO {:>2}  {format:<8}  unit {:>2}  {value}",
+        payload.node(),
+        payload.pdo_index() + 1,
+        payload.unit_id(),
+    )
+}
+
+/// Print the payloads of a received datagram matching `filter`, or why it is not CoE
+fn print_datagram(from: SocketAddr, datagram: &[u8], filter: &Filter) {
+    let time = Local::now().format("%H:%M:%S%.3f");
+    match Packet::try_from(datagram) {
+        Ok(packet) => {
+            for payload in packet.iter().filter(|p| filter.matches(p)) {
+                println!("{time}  {from}  {}", describe(payload));
+            }
+        }
+        Err(e) => println!("{time}  {from}  not CoE ({} bytes): {e}", datagram.len()),
+    };
+}
+
+/// Bind the CoE port on `bind_addr` and print everything received until Ctrl-C
+pub(crate) async fn run(bind_addr: &str, filter: Filter) -> Result<(), Box<dyn std::error::Error>> {
+    let sock = UdpSocket::bind((bind_addr, COE_PORT))
+        .await
+        .map_err(|e| format!("Unable to bind {bind_addr}:{COE_PORT}: {e}. Is the sync running?"))?;
+    eprintln!("Listening for CoE on {bind_addr}:{COE_PORT}. Stop with Ctrl-C.");
+    // all well-formed COE packets are at most 252 bytes long, read more to report others
+    let mut buf = [0_u8; 1500];
+    loop {
+        tokio::select! {
+            received = sock.recv_from(&mut buf) => {
+                let (len, from) = received?;
+                print_datagram(from, &buf[..len], &filter);
+            }
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use coe::{AnalogueCOEValue, DigitalCOEValue};
+
+    use super::*;
+
+    #[test]
+    fn describe_payloads() {
+        let temp = Payload::new(
+            2,
+            0,
+            COEValue::Analogue(AnalogueCOEValue::DegreeCentigrade_Tens(215)),
+        );
+        assert_eq!(
+            describe(&temp),
+            "CAN id  2  PDO  1  analogue  unit  1  DegreeCentigrade_Tens(215)"
+        );
+        let on = Payload::new(59, 63, COEValue::Digital(DigitalCOEValue::OnOff(true)));
+        assert_eq!(
+            describe(&on),
+            "CAN id 59  PDO 64  digital   unit 43  OnOff(true)"
+        );
+    }
+
+    #[test]
+    fn filter_by_can_id_and_pdo() {
+        let payload = Payload::new(
+            2,
+            4,
+            COEValue::Analogue(AnalogueCOEValue::DegreeCentigrade_Tens(215)),
+        );
+        assert!(Filter::default().matches(&payload));
+        let filter = Filter {
+            can_id: Some(2),
+            pdo_index: Some(5),
+        };
+        assert!(filter.matches(&payload));
+        assert!(!Filter {
+            can_id: Some(3),
+            ..filter
+        }
+        .matches(&payload));
+        assert!(!Filter {
+            can_id: None,
+            pdo_index: Some(4),
+        }
+        .matches(&payload));
+    }
+}
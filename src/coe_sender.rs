@@ -0,0 +1,417 @@
+//! A reusable UDP socket for sending CoE packets
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::config::IpPreference;
+
+/// CMIs receive CoE on this port
+pub const COE_PORT: u16 = 5442;
+/// A failed send is retried this often before the packet is given up for this cycle
+const SEND_RETRIES: u32 = 3;
+/// Wait before the first retry, doubled for each further retry
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+/// Never wait longer than this between retries
+const BACKOFF_MAX: Duration = Duration::from_secs(4);
+/// A CoE packet holds at most this many payloads (255 bytes)
+const PAYLOADS_PER_PACKET: usize = 31;
+
+/// Pack `payloads` into as few CoE packets as possible.
+///
+/// In CoE 2.0, every value takes its own 8 byte frame, digital or analogue, so the fewest packets
+/// are full ones. Payloads for the same input of the CMI (node, output and format) are coalesced
+/// first, keeping the position of the first and the value of the last, which is the one the CMI
+/// would end up with.
+pub fn packets(payloads: &[coe::Payload]) -> Vec<coe::Packet> {
+    let mut coalesced = Vec::<coe::Payload>::with_capacity(payloads.len());
+    let mut positions = HashMap::new();
+    for payload in payloads {
+        let input = (payload.node(), payload.pdo_index(), payload.format());
+        match positions.get(&input) {
+            Some(&i) => {
+                trace!(
+                    "Node {} sends output {} twice in one push. Only the last value is sent.",
+                    payload.node(),
+                    payload.pdo_index() + 1
+                );
+                coalesced[i] = *payload;
+            }
+            None => {
+                positions.insert(input, coalesced.len());
+                coalesced.push(*payload);
+            }
+        };
+    }
+    coalesced
+        .chunks(PAYLOADS_PER_PACKET)
+        .map(|chunk| {
+            coe::Packet::try_from_payloads(chunk).expect("chunks fit into a single packet")
+        })
+        .collect()
+}
+
+/// The wait before retry number `attempt` (starting at 0).
+///
+/// `jitter` in [0, 1) adds up to half the wait, so several instances that lost the same interface
+/// do not retry in lockstep.
+fn backoff(attempt: u32, jitter: f64) -> Duration {
+    let wait = BACKOFF_BASE
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(BACKOFF_MAX);
+    wait + wait.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// A number in [0, 1) that is different enough between calls for jitter
+fn jitter() -> f64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    f64::from(nanos % 1000) / 1000.0
+}
+
+/// Choose the address to send to from all addresses a host resolved to
+fn select_address(
+    addrs: impl IntoIterator<Item = SocketAddr>,
+    preference: IpPreference,
+) -> Option<SocketAddr> {
+    let addrs = addrs.into_iter().collect::<Vec<_>>();
+    let preferred = match preference {
+        IpPreference::Any => addrs.first(),
+        IpPreference::V4 => addrs.iter().find(|a| a.is_ipv4()),
+        IpPreference::V6 => addrs.iter().find(|a| a.is_ipv6()),
+    };
+    preferred.or(addrs.first()).copied()
+}
+
+/// Sends CoE packets from a single socket per address family, which is kept between sends.
+///
+/// Sockets are bound lazily. After a network error they are dropped and bound again on the next
+/// send, e.g. after an interface restart.
+/// Hostnames are resolved again after `dns_refresh` or a failed send, so CMIs behind dyndns or
+/// with a changed DHCP lease are found without a restart.
+/// A socket shared with the [CoeHub](crate::coe_hub::CoeHub) is used for its address family
+/// instead and kept after errors.
+/// Failed sends are retried a few times with backoff within the same cycle, so a short outage
+/// does not cost a whole `ta_push_frequency`.
+#[derive(Debug)]
+pub struct CoeSender {
+    bind_addr: String,
+    /// 0 lets the OS choose a port
+    bind_port: u16,
+    dns_refresh: Duration,
+    shared: Option<Arc<UdpSocket>>,
+    sock_v4: Option<UdpSocket>,
+    sock_v6: Option<UdpSocket>,
+    /// host -> (address, time of resolution)
+    resolved: HashMap<String, (SocketAddr, Instant)>,
+    /// host -> sends that failed since the last successful one
+    failures: HashMap<String, u64>,
+}
+impl CoeSender {
+    pub fn new(bind_addr: String, bind_port: u16, dns_refresh: Duration) -> Self {
+        Self {
+            bind_addr,
+            bind_port,
+            dns_refresh,
+            shared: None,
+            sock_v4: None,
+            sock_v6: None,
+            resolved: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Send from `sock` (e.g. the socket receiving CoE on port 5442)
+    pub fn with_socket(mut self, sock: Arc<UdpSocket>) -> Self {
+        if let Ok(addr) = sock.local_addr() {
+            info!("Sending CoE from the shared socket {addr}");
+        };
+        self.shared = Some(sock);
+        self
+    }
+
+    /// The local address to bind on to reach target.
+    ///
+    /// If bind_addr is of the other address family, the unspecified address is used instead.
+    fn local_addr_for(&self, target: &SocketAddr) -> SocketAddr {
+        let configured = self.bind_addr.parse::<IpAddr>().ok();
+        let ip = match (configured, target) {
+            (Some(ip @ IpAddr::V4(_)), SocketAddr::V4(_)) => ip,
+            (Some(ip @ IpAddr::V6(_)), SocketAddr::V6(_)) => ip,
+            (_, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (_, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        SocketAddr::new(ip, self.bind_port)
+    }
+
+    /// Get the socket to reach target, binding it if required
+    async fn socket(&mut self, target: &SocketAddr) -> Result<&UdpSocket, std::io::Error> {
+        if let Some(sock) = &self.shared {
+            if sock
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv4() == target.is_ipv4())
+            {
+                return Ok(sock);
+            };
+        };
+        let local = self.local_addr_for(target);
+        let slot = if target.is_ipv4() {
+            &mut self.sock_v4
+        } else {
+            &mut self.sock_v6
+        };
+        if slot.is_none() {
+            let sock = UdpSocket::bind(local).await?;
+            info!("Bound CoE sender to {}", sock.local_addr()?);
+            *slot = Some(sock);
+        };
+        Ok(slot.as_ref().expect("socket was just bound"))
+    }
+
+    /// Get the address of host, resolving it again if the cached address is too old
+    async fn resolve(
+        &mut self,
+        host: &str,
+        preference: IpPreference,
+    ) -> Result<SocketAddr, std::io::Error> {
+        if let Some((addr, resolved_at)) = self.resolved.get(host) {
+            if resolved_at.elapsed() < self.dns_refresh {
+                return Ok(*addr);
+            };
+        };
+        let addr = select_address(lookup_host((host, COE_PORT)).await?, preference).ok_or(
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{host} did not resolve to any address"),
+            ),
+        )?;
+        match self
+            .resolved
+            .insert(host.to_owned(), (addr, Instant::now()))
+        {
+            Some((old, _)) if old != addr => {
+                info!("CMI {host} now resolves to {addr} (was {old})");
+            }
+            None => debug!("CMI {host} resolves to {addr}"),
+            Some(_) => (),
+        };
+        Ok(addr)
+    }
+
+    /// Sends to `host` that failed since the last successful one, including retries
+    pub fn consecutive_failures(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.failures
+            .iter()
+            .map(|(host, failures)| (host.as_str(), *failures))
+    }
+
+    /// Send `buf` to the CMI at host once
+    async fn try_send(
+        &mut self,
+        buf: &[u8],
+        host: &str,
+        preference: IpPreference,
+    ) -> Result<(), std::io::Error> {
+        let target = self.resolve(host, preference).await?;
+        let res = self.socket(&target).await?.send_to(buf, target).await;
+        match res {
+            Ok(_) => {
+                trace!("Sent a CoE packet to {host} ({target})");
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Dropping the CoE sender socket and address of {host} after an error. They are renewed on the next send.");
+                if target.is_ipv4() {
+                    self.sock_v4 = None;
+                } else {
+                    self.sock_v6 = None;
+                };
+                self.resolved.remove(host);
+                Err(e)
+            }
+        }
+    }
+
+    /// Send a single packet to the CMI at host, retrying with backoff if that fails
+    pub async fn send_to(
+        &mut self,
+        packet: coe::Packet,
+        host: &str,
+        preference: IpPreference,
+    ) -> Result<(), std::io::Error> {
+        let buf = Into::<Vec<u8>>::into(packet);
+        let mut attempt = 0;
+        loop {
+            match self.try_send(&buf, host, preference).await {
+                Ok(()) => {
+                    self.failures.insert(host.to_owned(), 0);
+                    return Ok(());
+                }
+                Err(e) => {
+                    *self.failures.entry(host.to_owned()).or_default() += 1;
+                    if attempt == SEND_RETRIES {
+                        return Err(e);
+                    };
+                    let wait = backoff(attempt, jitter());
+                    warn!(
+                        "Sending CoE to {host} failed: {e}. Retrying in {}ms.",
+                        wait.as_millis()
+                    );
+                    tokio::time::sleep(wait).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sender(bind_addr: &str) -> CoeSender {
+        CoeSender::new(bind_addr.to_owned(), 0, Duration::from_secs(600))
+    }
+
+    fn digital(node: u8, pdo_index: u8, value: bool) -> coe::Payload {
+        coe::Payload::new(
+            node,
+            pdo_index,
+            coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(value)),
+        )
+    }
+
+    #[test]
+    fn packets_are_full() {
+        let rooms = |n: u8| (0..n).map(|i| digital(59, i, true)).collect::<Vec<_>>();
+        assert!(packets(&[]).is_empty());
+        assert_eq!(
+            packets(&rooms(31))
+                .iter()
+                .map(|p| p.len())
+                .collect::<Vec<_>>(),
+            vec![31]
+        );
+        assert_eq!(
+            packets(&rooms(32))
+                .iter()
+                .map(|p| p.len())
+                .collect::<Vec<_>>(),
+            vec![31, 1]
+        );
+        // 40 rooms with a setpoint each
+        let mut payloads = rooms(40);
+        payloads.extend((0..40).map(|i| {
+            coe::Payload::new(
+                59,
+                i,
+                coe::COEValue::Analogue(coe::AnalogueCOEValue::DegreeCentigrade_Tens(200)),
+            )
+        }));
+        assert_eq!(
+            packets(&payloads)
+                .iter()
+                .map(|p| p.len())
+                .collect::<Vec<_>>(),
+            vec![31, 31, 18]
+        );
+    }
+
+    #[test]
+    fn payloads_for_the_same_input_are_coalesced() {
+        let payloads = [
+            digital(59, 0, true),
+            digital(59, 1, true),
+            digital(59, 0, false),
+            // another node
+            digital(58, 0, true),
+        ];
+        let packets = packets(&payloads);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(
+            packets[0].iter().copied().collect::<Vec<_>>(),
+            vec![
+                digital(59, 0, false),
+                digital(59, 1, true),
+                digital(58, 0, true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_is_reused() {
+        let mut sender = sender("127.0.0.1");
+        let target = "127.0.0.1:5442".parse().unwrap();
+        let first = sender.socket(&target).await.unwrap().local_addr().unwrap();
+        let second = sender.socket(&target).await.unwrap().local_addr().unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn shared_socket_is_used_for_its_family() {
+        let shared = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut sender = sender("127.0.0.1").with_socket(shared.clone());
+        let v4 = "127.0.0.1:5442".parse().unwrap();
+        assert_eq!(
+            sender.socket(&v4).await.unwrap().local_addr().unwrap(),
+            shared.local_addr().unwrap()
+        );
+        let v6 = "[::1]:5442".parse().unwrap();
+        if let Ok(sock) = sender.socket(&v6).await {
+            assert!(sock.local_addr().unwrap().is_ipv6());
+        };
+    }
+
+    #[test]
+    fn address_preference() {
+        let v4: SocketAddr = "192.0.2.1:5442".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5442".parse().unwrap();
+        assert_eq!(select_address([v6, v4], IpPreference::Any), Some(v6));
+        assert_eq!(select_address([v6, v4], IpPreference::V4), Some(v4));
+        assert_eq!(select_address([v4, v6], IpPreference::V6), Some(v6));
+        // fall back to the other family
+        assert_eq!(select_address([v4], IpPreference::V6), Some(v4));
+        assert_eq!(select_address([], IpPreference::Any), None);
+    }
+
+    #[test]
+    fn bind_addr_matches_family() {
+        let v4: SocketAddr = "192.0.2.1:5442".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:5442".parse().unwrap();
+        let sender = sender("0.0.0.0");
+        assert_eq!(
+            sender.local_addr_for(&v4),
+            "0.0.0.0:0".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            sender.local_addr_for(&v6),
+            "[::]:0".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn backoff_is_bounded() {
+        assert_eq!(backoff(0, 0.0), Duration::from_millis(250));
+        assert_eq!(backoff(2, 0.0), Duration::from_secs(1));
+        assert_eq!(backoff(2, 0.5), Duration::from_millis(1250));
+        assert_eq!(backoff(10, 0.0), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX, 1.0), BACKOFF_MAX.mul_f64(1.5));
+    }
+
+    #[tokio::test]
+    async fn ipv6_literal_is_resolved() {
+        let mut sender = sender("::");
+        let addr = sender.resolve("::1", IpPreference::Any).await.unwrap();
+        assert_eq!(addr, "[::1]:5442".parse::<SocketAddr>().unwrap());
+    }
+}
@@ -1,252 +1,2307 @@
-use std::{collections::HashMap, fs::File, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeDelta, Utc, Weekday};
+use schemars::JsonSchema;
 use serde::Deserialize;
 use sqlx::{Pool, Sqlite};
-use tracing::{event, Level};
+use tracing::{debug, event, info, warn, Level};
+
+use crate::i18n::Language;
+use crate::preheat::{self, Conditions, PreheatCurve};
+use crate::season::{in_season, SeasonRange};
 
 #[derive(Debug)]
 pub enum CreateConfigError {
     RoomNotFoundError(String),
     PDOIndexOutOfBounds(u8),
+    SunExposureOutOfBounds(String),
+    IncompleteCoeExtTempSource,
+    InvalidPreheatCurve(String, &'static str),
+    IncompleteFeedback(String),
+    FeedbackWithoutCoeReceiver,
+    SiteNotFound(String),
+    InvalidSetpoint(String),
+    IncompleteSetpoint(String),
+    IncompleteCooling(String),
+    InvalidCooling(String),
+    CanIdOutOfBounds(u8),
+    /// (CMI host, CAN id)
+    CanIdIsFeedback(String, u8),
+    /// (CMI host, CAN id, pdo index)
+    OutputCollision(String, u8, u8),
+    PushSecondOutOfBounds(u8),
+    InvalidFallbackSchedule(String),
+    InvalidWeekdayFactor(String),
+    InvalidColdStart(String),
+    StateDirNotWritable(PathBuf, std::io::Error),
+    InvalidTenantName(String),
+    NoTenants,
+    /// (state directory, the tenants using it)
+    SharedStateDir(PathBuf, Vec<String>),
+    SeveralDaemonSections,
+}
+impl std::fmt::Display for CreateConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::RoomNotFoundError(x) => {
+                write!(
+                    f,
+                    "Room {x} was not found in the `rooms:` section of the config."
+                )
+            }
+            Self::PDOIndexOutOfBounds(x) => {
+                write!(f, "PDO Index {x} is not within 1-64")
+            }
+            Self::CanIdOutOfBounds(x) => {
+                write!(f, "Virtual CAN id {x} is not within 1-62")
+            }
+            Self::CanIdIsFeedback(host, x) => {
+                write!(
+                    f,
+                    "Virtual CAN id {x} is the feedback_can_id of the CMI {host}. Data sent from it would collide with the CMI."
+                )
+            }
+            Self::OutputCollision(host, can_id, pdo_index) => {
+                write!(
+                    f,
+                    "Output {pdo_index} of virtual CAN id {can_id} is used more than once on the CMI {host}."
+                )
+            }
+            Self::SunExposureOutOfBounds(x) => {
+                write!(f, "sun_exposure of room {x} is not within 0.0-1.0")
+            }
+            Self::IncompleteCoeExtTempSource => {
+                write!(
+                    f,
+                    "bind_addr, can_id and pdo_index of the external_temperature_sensor need to be set together, and are needed for humidity_pdo_index and allowed_senders."
+                )
+            }
+            Self::InvalidPreheatCurve(room, reason) => {
+                write!(f, "The preheat curve of room {room} is invalid: {reason}")
+            }
+            Self::IncompleteFeedback(x) => {
+                write!(
+                    f,
+                    "Room {x} has a feedback_pdo_index, but its CMI has no feedback_can_id."
+                )
+            }
+            Self::FeedbackWithoutCoeReceiver => {
+                write!(
+                    f,
+                    "Feedback from CMIs is received via CoE and requires bind_addr, can_id and pdo_index of the external_temperature_sensor."
+                )
+            }
+            Self::SiteNotFound(x) => {
+                write!(
+                    f,
+                    "Site {x} was not found in the `sites:` section of the config."
+                )
+            }
+            Self::InvalidSetpoint(x) => {
+                write!(f, "Room {x} needs setpoint min <= default <= max.")
+            }
+            Self::IncompleteSetpoint(x) => {
+                write!(
+                    f,
+                    "Room {x} has a setpoint_pdo_index, but no setpoint section."
+                )
+            }
+            Self::IncompleteCooling(x) => {
+                write!(
+                    f,
+                    "Room {x} has a cooling_pdo_index, but no cooling section."
+                )
+            }
+            Self::InvalidCooling(x) => {
+                write!(f, "Room {x} needs cooling below <= above.")
+            }
+            Self::StateDirNotWritable(dir, e) => {
+                write!(
+                    f,
+                    "The state directory {} is not writable: {e}. Set global.state_dir or STATE_DIRECTORY to a writable directory, or use `database: memory`.",
+                    dir.display()
+                )
+            }
+            Self::PushSecondOutOfBounds(x) => {
+                write!(f, "ta_push_second {x} is not within 0-59")
+            }
+            Self::InvalidTenantName(x) => {
+                write!(
+                    f,
+                    "Tenant name {x:?} may only contain letters, digits, - and _."
+                )
+            }
+            Self::NoTenants => write!(f, "The `tenants:` section is empty."),
+            Self::SharedStateDir(dir, tenants) => {
+                write!(
+                    f,
+                    "The tenants {} share the DB in {}. Give each its own global.state_dir.",
+                    tenants.join(", "),
+                    dir.display()
+                )
+            }
+            Self::SeveralDaemonSections => {
+                write!(
+                    f,
+                    "Only one tenant may have a `daemon:` section, since it applies to the whole process."
+                )
+            }
+            Self::InvalidFallbackSchedule(x) => {
+                write!(
+                    f,
+                    "The fallback_schedule of room {x} has a slot that does not start before it ends."
+                )
+            }
+            Self::InvalidWeekdayFactor(x) => {
+                write!(
+                    f,
+                    "The preheat_weekday_factors of room {x} need to be 0.0 or more."
+                )
+            }
+            Self::InvalidColdStart(x) => {
+                write!(
+                    f,
+                    "extra_per_day and max_extra in the cold_start of room {x} need to be 0.0 or more."
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for CreateConfigError {}
+
+/// Why the config could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(std::io::Error),
+    Parse(serde_yaml::Error),
+    Invalid(CreateConfigError),
+    OpenDb(sqlx::Error),
+    /// the config of a tenant could not be loaded
+    Tenant(String, Box<ConfigError>),
+}
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Read(e) => write!(f, "The config file {CONFIG_PATH} is not readable: {e}"),
+            Self::Parse(e) => write!(f, "The config file has syntax errors: {e}"),
+            Self::Invalid(e) => write!(f, "The config is invalid: {e}"),
+            Self::OpenDb(e) => write!(f, "Unable to open the DB: {e}"),
+            Self::Tenant(tenant, e) => write!(f, "Tenant {tenant}: {e}"),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+impl ConfigError {
+    /// The error itself, without the tenant it occured for
+    pub fn root(&self) -> &ConfigError {
+        match self {
+            Self::Tenant(_, e) => e.root(),
+            _ => self,
+        }
+    }
+}
+impl From<CreateConfigError> for ConfigError {
+    fn from(value: CreateConfigError) -> Self {
+        Self::Invalid(value)
+    }
+}
+
+/// The config file
+const CONFIG_PATH: &str = "/etc/ct-ta-sync/config.yaml";
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ConfigData {
+    pub cmis: Vec<CMIConfigData>,
+    pub external_temperature_sensor: ExtTempConfigData,
+    #[serde(default)]
+    pub sites: HashMap<String, SiteConfigData>,
+    pub ct: ChurchToolsConfig,
+    pub global: GlobalConfig,
+    pub rooms: HashMap<String, RoomConfig>,
+    pub http: Option<HttpConfig>,
+    pub forecast: Option<ForecastConfig>,
+    pub alerting: Option<AlertingConfig>,
+    pub daemon: Option<DaemonConfig>,
+    pub resource_hierarchy: Option<ResourceHierarchyConfig>,
+    pub energy_prices: Option<EnergyPricesConfig>,
+    pub status_leds: Option<StatusLedsConfig>,
+    pub caldav: Option<CalDavConfig>,
+    pub m365: Option<M365Config>,
+    #[serde(default)]
+    pub database: DatabaseMode,
+}
+/// A config file for several independent tenants (e.g. parishes) served by one process.
+///
+/// Each tenant has a complete config of its own. Tenants only share the process: each has its
+/// own DB, sockets and tasks, and its name in the `tenant` field of all logs and the `tenant`
+/// label of all metrics.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TenantsConfigData {
+    /// tenant name -> its config. Names may only contain letters, digits, - and _.
+    ///
+    /// The DB of a tenant is kept in the subdirectory of the state directory named after it,
+    /// unless the tenant sets global.state_dir. Only one tenant may have a `daemon:` section.
+    pub tenants: BTreeMap<String, ConfigData>,
+}
+/// The config file: a single config, or one per tenant.
+///
+/// This only describes the file for the JSON Schema. It is parsed by [config_sections], which
+/// reports errors with the line they occur in.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+#[allow(dead_code)]
+pub(crate) enum ConfigFileData {
+    Tenants(TenantsConfigData),
+    Single(Box<ConfigData>),
+}
+#[derive(Debug)]
+pub(crate) struct Config {
+    /// the tenant this config is for, if the config file has a `tenants:` section
+    pub tenant: Option<String>,
+    pub cmis: Vec<CMIConfig>,
+    /// the default sensor, used by all CMIs without a site or whose site has no own sensor
+    pub external_temperature_sensor: ExtTempConfig,
+    pub sites: HashMap<String, SiteConfig>,
+    pub ct: ChurchToolsConfig,
+    pub db: Pool<Sqlite>,
+    pub global: GlobalConfig,
+    pub http: Option<HttpConfig>,
+    pub forecast: Option<ForecastConfig>,
+    pub alerting: Option<AlertingConfig>,
+    pub daemon: Option<DaemonConfig>,
+    pub resource_hierarchy: Option<ResourceHierarchyConfig>,
+    pub energy_prices: Option<EnergyPricesConfig>,
+    pub status_leds: Option<StatusLedsConfig>,
+    pub caldav: Option<CalDavConfig>,
+    pub m365: Option<M365Config>,
+    pub database: DatabaseMode,
+    /// the config file with all secrets redacted
+    pub redacted: String,
+    /// identifies the active config in bug reports, see config_hash
+    pub hash: String,
+    /// the file the DB is stored in
+    pub db_path: PathBuf,
+    /// notified when bookings or maintenance modes change outside of the CT pull (e.g. via HTTP
+    /// or from a calendar), so the emitter sends them right away
+    pub bookings_changed: tokio::sync::Notify,
+}
+impl Config {
+    fn from_config_data(cd: ConfigData, db: Pool<Sqlite>) -> Result<Config, CreateConfigError> {
+        let cmis = cd
+            .cmis
+            .into_iter()
+            .map(|cmi| {
+                let cmi = CMIConfig {
+                    host: cmi.host,
+                    backup_hosts: cmi.backup_hosts,
+                    ip_version: cmi.ip_version.unwrap_or_default(),
+                    feedback_can_id: cmi.feedback_can_id,
+                    site: cmi.site,
+                    our_virtual_can_id: cmi.our_virtual_can_id,
+                    rooms: cmi
+                        .rooms
+                        .into_iter()
+                        .map(|room| {
+                            associated_room(
+                                room,
+                                &cd.rooms,
+                                cmi.feedback_can_id,
+                                cmi.our_virtual_can_id,
+                            )
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                    resource_types: cmi
+                        .resource_types
+                        .into_iter()
+                        .map(|x| {
+                            ResourceTypeRooms::from_config_data(
+                                x,
+                                &cd.rooms,
+                                cmi.our_virtual_can_id,
+                            )
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                };
+                cmi.check_can_ids()?;
+                Ok::<CMIConfig, CreateConfigError>(cmi)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let ext_temp_config = ExtTempConfig::from_config_data(cd.external_temperature_sensor)?;
+        let feedback_configured = cmis
+            .iter()
+            .flat_map(|cmi| &cmi.rooms)
+            .any(|room| room.feedback_pdo_index.is_some());
+        if feedback_configured && ext_temp_config.coe.is_none() {
+            return Err(CreateConfigError::FeedbackWithoutCoeReceiver);
+        };
+        if ext_temp_config.coe.is_none() && ext_temp_config.mqtt.is_none() {
+            event!(
+                Level::WARN,
+                "No source for the external temperature is configured. Preheat times will not be scaled."
+            );
+        };
+
+        let sites = cd
+            .sites
+            .into_iter()
+            .map(|(name, site)| {
+                Ok::<(String, SiteConfig), CreateConfigError>((
+                    name,
+                    SiteConfig {
+                        external_temperature_sensor: site
+                            .external_temperature_sensor
+                            .map(ExtTempConfig::from_config_data)
+                            .transpose()?,
+                        ct_status_ids: site.ct_status_ids.unwrap_or(vec![APPROVED_STATUS_ID]),
+                        max_preheating_rooms: site.max_preheating_rooms,
+                        heating_season: site.heating_season,
+                    },
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        if let Some(site) = cmis
+            .iter()
+            .filter_map(|cmi| cmi.site.as_ref())
+            .find(|site| !sites.contains_key(*site))
+        {
+            return Err(CreateConfigError::SiteNotFound(site.clone()));
+        };
+        if let Some(x) = cd.global.ta_push_second.filter(|x| *x > 59) {
+            return Err(CreateConfigError::PushSecondOutOfBounds(x));
+        };
+        let mut caldav = cd.caldav;
+        for calendar in caldav.iter_mut().flat_map(|x| &mut x.calendars) {
+            calendar.resource_id = cd
+                .rooms
+                .get(&calendar.room)
+                .ok_or_else(|| CreateConfigError::RoomNotFoundError(calendar.room.clone()))?
+                .churchtools_id;
+        }
+        let mut m365 = cd.m365;
+        for room in m365.iter_mut().flat_map(|x| &mut x.rooms) {
+            room.resource_id = cd
+                .rooms
+                .get(&room.room)
+                .ok_or_else(|| CreateConfigError::RoomNotFoundError(room.room.clone()))?
+                .churchtools_id;
+        }
+
+        Ok(Config {
+            tenant: None,
+            cmis,
+            external_temperature_sensor: ext_temp_config,
+            sites,
+            ct: cd.ct,
+            db,
+            global: cd.global,
+            http: cd.http,
+            forecast: cd.forecast,
+            alerting: cd.alerting,
+            daemon: cd.daemon,
+            resource_hierarchy: cd.resource_hierarchy,
+            energy_prices: cd.energy_prices,
+            status_leds: cd.status_leds,
+            caldav,
+            m365,
+            database: cd.database,
+            redacted: String::new(),
+            db_path: PathBuf::new(),
+            hash: String::new(),
+            bookings_changed: tokio::sync::Notify::new(),
+        })
+    }
+
+    /// The site whose sensor measures the external temperature for CMIs of `site`.
+    ///
+    /// None is the default sensor, which is used for CMIs without a site and sites without own
+    /// sensor.
+    pub fn sensor_site<'a>(&self, site: Option<&'a str>) -> Option<&'a str> {
+        site.filter(|s| {
+            self.sites
+                .get(*s)
+                .is_some_and(|x| x.external_temperature_sensor.is_some())
+        })
+    }
+
+    /// The external temperature sensor of a site, see [Config::sensor_site]
+    pub fn ext_temp_sensor(&self, site: Option<&str>) -> &ExtTempConfig {
+        self.sensor_site(site)
+            .and_then(|s| self.sites.get(s))
+            .and_then(|x| x.external_temperature_sensor.as_ref())
+            .unwrap_or(&self.external_temperature_sensor)
+    }
+
+    /// Whether rooms of CMIs of `site` may be heated at `time` (see heating_season)
+    pub fn in_heating_season(&self, site: Option<&str>, time: DateTime<Utc>) -> bool {
+        let ranges = site
+            .and_then(|s| self.sites.get(s))
+            .and_then(|x| x.heating_season.as_ref())
+            .or(self.global.heating_season.as_ref());
+        match ranges {
+            Some(ranges) => in_season(ranges, time.with_timezone(&Local).date_naive()),
+            None => true,
+        }
+    }
+
+    /// The next time the heating season may begin or end (local midnight), if one is configured
+    pub fn next_season_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let configured = self.global.heating_season.is_some()
+            || self.sites.values().any(|x| x.heating_season.is_some());
+        if !configured {
+            return None;
+        };
+        now.with_timezone(&Local)
+            .date_naive()
+            .succ_opt()?
+            .and_time(chrono::NaiveTime::MIN)
+            .and_local_timezone(Local)
+            .earliest()
+            .map(|x| x.to_utc())
+    }
+
+    /// The longest any room keeps running after a booking ended: its overrun, or the post-run of
+    /// its cooling
+    pub fn max_overrun(&self) -> TimeDelta {
+        let minutes = self
+            .cmis
+            .iter()
+            .flat_map(|cmi| &cmi.rooms)
+            .map(|room| {
+                room.overrun_minutes
+                    .max(room.cooling.map(|x| x.post_run_minutes).unwrap_or(0))
+            })
+            .max()
+            .unwrap_or(0);
+        TimeDelta::minutes(minutes.into())
+    }
+
+    /// The names of the rooms of a CT resource for logs, or the resource id if it has no room
+    pub fn resource_label(&self, resource_id: i64) -> String {
+        let mut names = self
+            .cmis
+            .iter()
+            .flat_map(|cmi| &cmi.rooms)
+            .filter(|room| room.churchtools_id == resource_id)
+            .map(|room| room.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        if names.is_empty() {
+            format!("resource {resource_id}")
+        } else {
+            names.join(", ")
+        }
+    }
+
+    /// The CT status ids of bookings relevant for the rooms of a site
+    pub fn ct_status_ids(&self, site: Option<&str>) -> &[u8] {
+        site.and_then(|s| self.sites.get(s))
+            .map(|x| x.ct_status_ids.as_slice())
+            .unwrap_or(&[APPROVED_STATUS_ID])
+    }
+
+    /// Log what changed in the config since the last start and remember the active config.
+    ///
+    /// The config is reloaded by restarting, so this lets operators confirm that their edits
+    /// were picked up.
+    pub async fn log_changes_since_last_start(&self) -> Result<(), crate::db::DBError> {
+        match crate::db::get_active_config(&self.db).await? {
+            None => info!("Starting with a new config."),
+            Some(previous) if previous == self.redacted => {
+                debug!("The config did not change since the last start.")
+            }
+            Some(previous) => match config_diff(&previous, &self.redacted) {
+                Ok(changes) => {
+                    info!("The config changed since the last start:");
+                    for change in changes {
+                        info!("  {change}");
+                    }
+                }
+                Err(e) => warn!(
+                    "The config changed since the last start, but the previous one cannot be compared: {e}"
+                ),
+            },
+        };
+        crate::db::set_active_config(&self.db, &self.redacted).await?;
+        Ok(())
+    }
+
+    /// Read the config file and open the DB `db_name` in the state directory of each tenant.
+    ///
+    /// Without a `tenants:` section, this is a single config without tenant.
+    pub async fn create(db_name: &str) -> Result<Vec<Config>, ConfigError> {
+        let text = std::fs::read_to_string(CONFIG_PATH).map_err(ConfigError::Read)?;
+        let sections = config_sections(&text)?;
+        let env = std::env::var_os("STATE_DIRECTORY");
+        let state_dirs = sections
+            .iter()
+            .map(|(tenant, config_data, _)| {
+                tenant_state_dir(
+                    config_data.global.state_dir.as_deref(),
+                    env.clone(),
+                    tenant.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        check_tenants_separate(&sections, &state_dirs)?;
+        let mut configs = vec![];
+        for ((tenant, config_data, redacted), state_dir) in sections.into_iter().zip(state_dirs) {
+            let Some(tenant) = tenant else {
+                configs.push(Config::open(config_data, redacted, &state_dir, db_name).await?);
+                continue;
+            };
+            let config = async {
+                // the subdirectory of a tenant is created on its first start
+                if config_data.database == DatabaseMode::File
+                    && config_data.global.state_dir.is_none()
+                {
+                    std::fs::create_dir_all(&state_dir).map_err(|e| {
+                        CreateConfigError::StateDirNotWritable(state_dir.clone(), e)
+                    })?;
+                };
+                Config::open(config_data, redacted, &state_dir, db_name).await
+            }
+            .await;
+            let mut config =
+                config.map_err(|e| ConfigError::Tenant(tenant.clone(), Box::new(e)))?;
+            config.tenant = Some(tenant);
+            configs.push(config);
+        }
+        Ok(configs)
+    }
+
+    /// Open the DB `db_name` in `state_dir` and create the config from `config_data`
+    async fn open(
+        config_data: ConfigData,
+        redacted: String,
+        state_dir: &Path,
+        db_name: &str,
+    ) -> Result<Config, ConfigError> {
+        if config_data.database == DatabaseMode::File {
+            check_writable(state_dir)?;
+        };
+        let db_path = state_dir.join(db_name);
+        let db = open_db(config_data.database, &db_path)
+            .await
+            .map_err(ConfigError::OpenDb)?;
+        let mut config = Config::from_config_data(config_data, db)?;
+        config.hash = config_hash(&redacted);
+        config.redacted = redacted;
+        config.db_path = db_path;
+        Ok(config)
+    }
+}
+
+/// The sections of the config file `text` as (tenant, config, redacted config).
+///
+/// This is one section per tenant, or a single section without tenant if there is no
+/// `tenants:` section.
+fn config_sections(text: &str) -> Result<Vec<(Option<String>, ConfigData, String)>, ConfigError> {
+    let value: serde_yaml::Value = serde_yaml::from_str(text).map_err(ConfigError::Parse)?;
+    let Some(tenant_values) = value.get("tenants") else {
+        let config_data = serde_yaml::from_str(text).map_err(ConfigError::Parse)?;
+        let redacted = redacted_yaml(text).map_err(ConfigError::Parse)?;
+        return Ok(vec![(None, config_data, redacted)]);
+    };
+    // parsed from the text again, so errors point to the line
+    let file: TenantsConfigData = serde_yaml::from_str(text).map_err(ConfigError::Parse)?;
+    if file.tenants.is_empty() {
+        return Err(CreateConfigError::NoTenants.into());
+    };
+    file.tenants
+        .into_iter()
+        .map(|(tenant, config_data)| {
+            if tenant.is_empty()
+                || !tenant
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(CreateConfigError::InvalidTenantName(tenant).into());
+            };
+            let section = serde_yaml::to_string(&tenant_values[tenant.as_str()])
+                .map_err(ConfigError::Parse)?;
+            let redacted = redacted_yaml(&section).map_err(ConfigError::Parse)?;
+            Ok((Some(tenant), config_data, redacted))
+        })
+        .collect()
+}
+
+/// Make sure tenants do not share their DB or the daemon settings
+fn check_tenants_separate(
+    sections: &[(Option<String>, ConfigData, String)],
+    state_dirs: &[PathBuf],
+) -> Result<(), CreateConfigError> {
+    let mut by_state_dir = BTreeMap::<&Path, Vec<String>>::new();
+    for ((tenant, config_data, _), state_dir) in sections.iter().zip(state_dirs) {
+        if config_data.database == DatabaseMode::File {
+            by_state_dir
+                .entry(state_dir)
+                .or_default()
+                .push(tenant.clone().unwrap_or_default());
+        };
+    }
+    if let Some((dir, tenants)) = by_state_dir.into_iter().find(|(_, x)| x.len() > 1) {
+        return Err(CreateConfigError::SharedStateDir(dir.to_owned(), tenants));
+    };
+    if sections
+        .iter()
+        .filter(|(_, x, _)| x.daemon.is_some())
+        .count()
+        > 1
+    {
+        return Err(CreateConfigError::SeveralDaemonSections);
+    };
+    Ok(())
+}
+
+/// Create a config from a YAML string, using `db` as database
+#[cfg(test)]
+pub(crate) fn test_config(yaml: &str, db: Pool<Sqlite>) -> Config {
+    let config_data = serde_yaml::from_str(yaml).expect("test config is valid YAML");
+    Config::from_config_data(config_data, db).expect("test config is valid")
+}
+
+/// A config without rooms or CMIs, with the YAML sections in `extra` added
+#[cfg(test)]
+pub(crate) fn test_empty_config(extra: &str, db: Pool<Sqlite>) -> Config {
+    let yaml = format!(
+        "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms: {{}}
+cmis: []
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  bind_addr: 127.0.0.1
+  can_id: 1
+  pdo_index: 1
+  timeout: 5
+{extra}"
+    );
+    test_config(&yaml, db)
+}
+
+/// A room without preheat, preshutdown or any of the optional settings
+#[cfg(test)]
+pub(crate) fn test_room(name: &str) -> AssociatedRoomConfig {
+    AssociatedRoomConfig {
+        name: name.to_owned(),
+        churchtools_id: 0,
+        can_id: 1,
+        pdo_index: 0,
+        preheat_minutes: 0,
+        preshutdown_minutes: 0,
+        overrun_minutes: 0,
+        preheat_curve: PreheatCurve::default(),
+        sun_exposure: 0.0,
+        maintenance_value: false,
+        feedback_pdo_index: None,
+        setpoint: None,
+        price_flexibility_minutes: 0,
+        priority: 0,
+        circuit: None,
+        fallback_schedule: vec![],
+        preheat_weekday_factors: HashMap::new(),
+        cold_start: None,
+        cooling: None,
+    }
+}
+
+/// A CT config with all optional settings unset
+#[cfg(test)]
+pub(crate) fn test_ct_config() -> ChurchToolsConfig {
+    ChurchToolsConfig {
+        host: "".to_owned(),
+        login_token: "".to_owned(),
+        no_heating_keyword: None,
+        no_heating_field: None,
+        open_ended_bookings: OpenEndedBookingPolicy::AllDay,
+        open_ended_booking_hours: None,
+        heating_field: None,
+        plausible_days: None,
+        implausible_bookings: Default::default(),
+    }
+}
+
+/// Keys whose values are secrets
+const SECRET_KEYS: [&str; 6] = [
+    "login_token",
+    "token",
+    "password",
+    "bot_token",
+    "client_secret",
+    "signing_secret",
+];
+
+/// The config file with all secrets redacted.
+///
+/// Only the parsed content remains, so comments and formatting are dropped.
+fn redacted_yaml(text: &str) -> Result<String, serde_yaml::Error> {
+    fn redact(value: &mut serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::Mapping(map) => {
+                for (key, value) in map.iter_mut() {
+                    if key.as_str().is_some_and(|k| SECRET_KEYS.contains(&k)) {
+                        *value = serde_yaml::Value::String("[redacated]".to_owned());
+                    } else {
+                        redact(value);
+                    };
+                }
+            }
+            serde_yaml::Value::Sequence(seq) => seq.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+    let mut value: serde_yaml::Value = serde_yaml::from_str(text)?;
+    redact(&mut value);
+    serde_yaml::to_string(&value)
+}
+
+/// Hash a redacted config
+fn config_hash(redacted: &str) -> String {
+    // FNV-1a, which is stable across builds unlike the std hasher
+    let hash = redacted.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+/// Describe what changed between two redacted configs, one line per changed value.
+///
+/// Lines look like `rooms.room3: added`, `cmis[0].rooms: removed [1]` or
+/// `global.ct_pull_frequency: 60 -> 120`.
+pub fn config_diff(old: &str, new: &str) -> Result<Vec<String>, serde_yaml::Error> {
+    use serde_yaml::Value;
+
+    fn show(value: &Value) -> String {
+        match value {
+            Value::Mapping(_) | Value::Sequence(_) | Value::Tagged(_) => "{...}".to_owned(),
+            _ => serde_yaml::to_string(value)
+                .map(|x| x.trim_end().to_owned())
+                .unwrap_or_default(),
+        }
+    }
+    fn walk(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+        let child = |key: &str| {
+            if path.is_empty() {
+                key.to_owned()
+            } else {
+                format!("{path}.{key}")
+            }
+        };
+        match (old, new) {
+            (Value::Mapping(old), Value::Mapping(new)) => {
+                for (key, old_value) in old {
+                    let name = child(&show(key));
+                    match new.get(key) {
+                        Some(new_value) => walk(&name, old_value, new_value, changes),
+                        None => changes.push(format!("{name}: removed")),
+                    };
+                }
+                for key in new.keys().filter(|k| !old.contains_key(*k)) {
+                    changes.push(format!("{}: added", child(&show(key))));
+                }
+            }
+            (Value::Sequence(old), Value::Sequence(new)) => {
+                for (i, (old_value, new_value)) in old.iter().zip(new).enumerate() {
+                    walk(&format!("{path}[{i}]"), old_value, new_value, changes);
+                }
+                for i in new.len()..old.len() {
+                    changes.push(format!("{path}[{i}]: removed"));
+                }
+                for i in old.len()..new.len() {
+                    changes.push(format!("{path}[{i}]: added"));
+                }
+            }
+            (old, new) if old != new => {
+                changes.push(format!("{path}: {} -> {}", show(old), show(new)));
+            }
+            _ => {}
+        }
+    }
+    let old: Value = serde_yaml::from_str(old)?;
+    let new: Value = serde_yaml::from_str(new)?;
+    let mut changes = vec![];
+    walk("", &old, &new, &mut changes);
+    Ok(changes)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct RoomConfig {
+    pub preheat_minutes: Option<u8>,
+    pub preshutdown_minutes: Option<u8>,
+    pub overrun_minutes: Option<u8>,
+    pub preheat_curve: Option<Vec<PreheatCurvePointData>>,
+    pub sun_exposure: Option<f64>,
+    pub maintenance_value: Option<bool>,
+    /// setpoint sent to CMIs with a setpoint_pdo_index for this room
+    pub setpoint: Option<SetpointConfigData>,
+    /// preheating may start up to ... minutes earlier if energy is cheaper then
+    pub price_flexibility_minutes: Option<u8>,
+    /// rooms with a higher priority preheat first if max_preheating_rooms is reached
+    pub priority: Option<i32>,
+    /// only one room of a circuit is heated at a time
+    pub circuit: Option<String>,
+    /// heat at these times while CT is unreachable for longer than fallback_after
+    pub fallback_schedule: Option<Vec<FallbackSlot>>,
+    /// preheat of bookings starting on these days (local time) is multiplied by ...
+    #[schemars(with = "Option<HashMap<String, f64>>")]
+    pub preheat_weekday_factors: Option<HashMap<Weekday, f64>>,
+    /// extend preheat if the room was not used for a while
+    pub cold_start: Option<ColdStart>,
+    /// cool or ventilate for bookings on CMIs with a cooling_pdo_index, when it is hot outside
+    pub cooling: Option<CoolingConfigData>,
+    pub churchtools_id: i64,
+}
+
+/// Extend the preheat of a room by the time since it was last booked
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+pub(crate) struct ColdStart {
+    /// proportion of the preheat added for each day the room was not booked
+    pub extra_per_day: f64,
+    /// preheat is extended by at most this proportion
+    pub max_extra: f64,
+}
+impl ColdStart {
+    /// How long before a booking the past use of a room is looked at.
+    ///
+    /// A room unused for longer gets the full max_extra.
+    pub fn lookback(&self) -> TimeDelta {
+        if self.extra_per_day <= 0_f64 {
+            return TimeDelta::zero();
+        };
+        let days = (self.max_extra / self.extra_per_day).min(MAX_COLD_START_LOOKBACK_DAYS);
+        TimeDelta::minutes((days * 24_f64 * 60_f64).ceil() as i64)
+    }
+}
+
+/// The past use of rooms is looked at for at most ... days before a booking
+const MAX_COLD_START_LOOKBACK_DAYS: f64 = 90_f64;
+
+/// A recurring time a room is heated while CT is unreachable
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub(crate) struct FallbackSlot {
+    /// e.g. Sun or Sunday
+    #[schemars(with = "Vec<String>")]
+    pub days: Vec<Weekday>,
+    /// local time, e.g. 09:00
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+impl FallbackSlot {
+    /// The time this slot heats on `day`, if it does
+    pub fn window_on(&self, day: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.days.contains(&day.weekday()) {
+            return None;
+        };
+        let local = |time| {
+            day.and_time(time)
+                .and_local_timezone(Local)
+                .earliest()
+                .map(|t| t.to_utc())
+        };
+        Some((local(self.start)?, local(self.end)?))
+    }
+
+    /// The times this slot heats from the day before `now` to the day after, in local time
+    pub fn windows_around(
+        &self,
+        now: DateTime<Utc>,
+    ) -> impl Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + '_ {
+        let today = now.with_timezone(&Local).date_naive();
+        [-1, 0, 1]
+            .into_iter()
+            .filter_map(move |offset| self.window_on(today + TimeDelta::days(offset)))
+    }
+}
+
+/// the setpoint of a room, as defined in the config (Degree Centigrade)
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct SetpointConfigData {
+    /// sent if no booking requests a temperature
+    pub default: f64,
+    /// requested temperatures are clamped to min..=max
+    pub min: f64,
+    pub max: f64,
+}
+
+/// cooling or ventilation of a room, as defined in the config
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct CoolingConfigData {
+    /// cool for bookings instead of heating while the external temperature is above ... (Degree
+    /// Centigrade)
+    pub above: f64,
+    /// once cooling, keep cooling for bookings until the external temperature drops to ... or
+    /// below (Degree Centigrade, default: above)
+    pub below: Option<f64>,
+    /// start cooling ... minutes before a booking (default 0)
+    pub pre_run_minutes: Option<u8>,
+    /// keep cooling ... minutes after a booking (default 0)
+    pub post_run_minutes: Option<u8>,
+    /// do not cool while the external dew point is above ... (Degree Centigrade).
+    /// Needs the humidity_pdo_index of the external temperature sensor.
+    pub max_dew_point: Option<f64>,
+}
+
+/// a single point of a preheat curve, as defined in the config
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct PreheatCurvePointData {
+    /// external temperature in Degree Centigrade
+    pub temperature: f64,
+    /// proportion of preheat_minutes to preheat at this temperature (0.0 - 1.0)
+    pub factor: f64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct GlobalConfig {
+    pub ct_pull_frequency: u64,
+    /// Pull from CT at the times matching any of these cron expressions (local time), instead of
+    /// every ct_pull_frequency seconds
+    #[schemars(with = "Option<Vec<String>>")]
+    pub ct_pull_schedule: Option<Vec<crate::schedule::CronExpr>>,
+    pub ta_push_frequency: u64,
+    /// Push to CMIs at the minutes matching any of these cron expressions (local time), instead
+    /// of every ta_push_frequency minutes
+    #[schemars(with = "Option<Vec<String>>")]
+    pub ta_push_schedule: Option<Vec<crate::schedule::CronExpr>>,
+    /// Align pushes to the wall clock: push ... seconds into the minute (0-59), every
+    /// ta_push_frequency minutes counted from midnight
+    pub ta_push_second: Option<u8>,
+    /// Only push to CMIs when a room switches or the bookings change, and otherwise resend
+    /// every ... minutes to keep the CMI inputs alive, instead of every ta_push_frequency minutes
+    pub ta_keepalive_frequency: Option<u64>,
+    pub log_level: String,
+    pub emiter_bind_addr: String,
+    /// Source port to send data to CMIs from. The OS chooses one if this is not set.
+    /// With 5442, the socket receiving CoE on emiter_bind_addr is shared.
+    pub emiter_bind_port: Option<u16>,
+    /// Resolve CMI hostnames again every ... minutes (default 10)
+    pub cmi_dns_refresh: Option<u64>,
+    /// Warn if the feedback of a room does not match the commanded state for ... minutes
+    /// (default 10)
+    pub feedback_timeout: Option<u64>,
+    /// Report overlapping bookings for the same resource every ... minutes.
+    /// The report is disabled if this is not set.
+    pub overlap_report_frequency: Option<u64>,
+    /// Apply pending DB migrations at startup (default true).
+    /// If this is false, run `ct-ta-sync migrate up` by hand.
+    pub auto_migrate: Option<bool>,
+    /// Keep the statistics of each pull and push for ... days (default 28)
+    pub metrics_retention: Option<u64>,
+    /// What to do when a booking in progress is deleted in CT (default turn_off)
+    #[serde(default)]
+    pub deleted_booking_in_progress: DeletedBookingPolicy,
+    /// Preheat at most ... rooms at the same time. Rooms in use are not limited.
+    pub max_preheating_rooms: Option<usize>,
+    /// Heat for at most ... hours from the start of a single booking. Default: no limit
+    pub max_heating_hours: Option<u32>,
+    /// Log a summary of the previous day at this time (local time of the host).
+    /// Default: no summary
+    pub daily_summary_time: Option<chrono::NaiveTime>,
+    /// Only heat on these days of the year (local time). Outside of them, all rooms get OFF.
+    /// Default: all year
+    pub heating_season: Option<Vec<SeasonRange>>,
+    /// Wait up to ... seconds for the first successful pull from CT before the first push
+    /// (default 120)
+    pub first_pull_timeout: Option<u64>,
+    /// Use the fallback_schedule of rooms once no pull from CT succeeded for ... minutes
+    /// (default 120)
+    pub fallback_after: Option<u64>,
+    /// Send this to all rooms while no pull from CT succeeded (after first_pull_timeout).
+    /// Without it, the bookings already in the DB are sent.
+    pub not_ready_value: Option<bool>,
+    /// Shut down all tasks of the tenant when one of them panics (default true).
+    /// If this is false, panics are only logged and the other tasks keep running.
+    pub shutdown_on_panic: Option<bool>,
+    /// Keep the DB and its backups in this directory.
+    /// Default: $STATE_DIRECTORY (set by systemd), then the working directory.
+    pub state_dir: Option<PathBuf>,
+    /// Language of notifications, the heating calendar and the texts written to CT (default en)
+    #[serde(default)]
+    pub language: Language,
+    /// Which bookings count for a room listed under several CMIs (default any)
+    #[serde(default)]
+    pub shared_room_decision: SharedRoomDecision,
+}
+
+#[derive(Debug)]
+pub(crate) struct CMIConfig {
+    /// hostname, IPv4 or IPv6 address of the CMI. It also names the CMI in logs and metrics.
+    pub host: String,
+    /// other addresses of the CMI (e.g. via VPN), tried in this order when sending to host fails
+    /// locally
+    pub backup_hosts: Vec<String>,
+    /// address family to use if host resolves to both
+    pub ip_version: IpPreference,
+    /// CAN id the CMI sends the feedback of its rooms from
+    pub feedback_can_id: Option<u8>,
+    /// the site (building) this CMI is in
+    pub site: Option<String>,
+    pub our_virtual_can_id: u8,
+    pub rooms: Vec<AssociatedRoomConfig>,
+    /// CT resource types whose resources are added to rooms at startup
+    pub resource_types: Vec<ResourceTypeRooms>,
+}
+impl CMIConfig {
+    /// The addresses to send to, in the order to try them
+    pub fn hosts(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.host.as_str()).chain(self.backup_hosts.iter().map(String::as_str))
+    }
+
+    /// Check that all virtual CAN ids are valid and that no input of this CMI gets data from two
+    /// rooms or outputs.
+    ///
+    /// Digital (state, cooling) and analogue (setpoint) outputs are separate inputs on the CMI.
+    fn check_can_ids(&self) -> Result<(), CreateConfigError> {
+        let can_ids = std::iter::once(self.our_virtual_can_id)
+            .chain(self.rooms.iter().map(|room| room.can_id));
+        for can_id in can_ids {
+            if !(1..=62).contains(&can_id) {
+                return Err(CreateConfigError::CanIdOutOfBounds(can_id));
+            };
+            if self.feedback_can_id == Some(can_id) {
+                return Err(CreateConfigError::CanIdIsFeedback(
+                    self.host.clone(),
+                    can_id,
+                ));
+            };
+        }
+        // (CAN id, pdo index, whether analogue)
+        let mut used = HashSet::new();
+        for room in &self.rooms {
+            let outputs = std::iter::once((room.pdo_index, false))
+                .chain(room.cooling.map(|x| (x.pdo_index, false)))
+                .chain(room.setpoint.map(|x| (x.pdo_index, true)));
+            for (pdo_index, analogue) in outputs {
+                if !used.insert((room.can_id, pdo_index, analogue)) {
+                    return Err(CreateConfigError::OutputCollision(
+                        self.host.clone(),
+                        room.can_id,
+                        pdo_index + 1,
+                    ));
+                };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which address family to use when a CMI host resolves to both
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IpPreference {
+    /// use whatever address is returned first
+    #[default]
+    Any,
+    /// prefer IPv4, fall back to IPv6
+    V4,
+    /// prefer IPv6, fall back to IPv4
+    V6,
+}
+
+/// What to do when a booking disappears from CT while it is in progress
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DeletedBookingPolicy {
+    /// stop heating at the next push
+    #[default]
+    TurnOff,
+    /// keep heating until the next full hour, but not past the original end
+    FinishHour,
+    /// keep heating until the original end
+    KeepUntilEnd,
+}
+
+/// How the state of a room listed under several CMIs (e.g. redundant controllers) is decided.
+///
+/// All of its CMIs get the same values either way.
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SharedRoomDecision {
+    /// heat if the bookings heat the room on any of its CMIs
+    #[default]
+    Any,
+    /// heat only if the bookings heat the room on all of its CMIs
+    All,
+    /// the CMI listed first decides
+    First,
+}
+
+/// Where the bookings and all other state are kept
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DatabaseMode {
+    /// in the DB file
+    #[default]
+    File,
+    /// in RAM only, so nothing is written to the filesystem. Everything is lost on restart.
+    Memory,
+}
+
+/// The directory to keep the DB in: `configured`, then the first directory in
+/// `STATE_DIRECTORY` (set by systemd's StateDirectory=), then the working directory
+fn state_dir(configured: Option<&Path>, env: Option<std::ffi::OsString>) -> PathBuf {
+    if let Some(dir) = configured {
+        return dir.to_owned();
+    };
+    env.and_then(|x| {
+        x.to_str()?
+            .split(':')
+            .find(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    })
+    .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// The state directory of `tenant`: the subdirectory named after it, unless it configures its own
+fn tenant_state_dir(
+    configured: Option<&Path>,
+    env: Option<std::ffi::OsString>,
+    tenant: Option<&str>,
+) -> PathBuf {
+    let dir = state_dir(configured, env);
+    match tenant {
+        Some(tenant) if configured.is_none() => dir.join(tenant),
+        _ => dir,
+    }
+}
+
+/// Fail early with a clear error if the DB cannot be written to `dir`
+fn check_writable(dir: &Path) -> Result<(), CreateConfigError> {
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .and_then(|()| std::fs::remove_file(&probe))
+        .map_err(|e| CreateConfigError::StateDirNotWritable(dir.to_owned(), e))
+}
+
+/// Open the DB at `db_path`, or an empty DB in RAM
+async fn open_db(mode: DatabaseMode, db_path: &Path) -> Result<Pool<Sqlite>, sqlx::Error> {
+    match mode {
+        DatabaseMode::File => {
+            let connect_options = sqlx::sqlite::SqliteConnectOptions::new()
+                .filename(db_path)
+                .create_if_missing(true);
+            sqlx::SqlitePool::connect_with(connect_options).await
+        }
+        // every connection would open its own DB, so keep a single one forever
+        DatabaseMode::Memory => {
+            sqlx::sqlite::SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with("sqlite::memory:".parse()?)
+                .await
+        }
+    }
+}
+
+/// What to do with bookings CT returns with implausible dates
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ImplausibleBookingPolicy {
+    /// do not heat for them, and remove them from the DB
+    #[default]
+    Drop,
+    /// heat for them anyway, but warn
+    Warn,
+}
+
+/// What to do with bookings CT returns without an end
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OpenEndedBookingPolicy {
+    /// heat until the end of the day the booking starts on (local time)
+    #[default]
+    AllDay,
+    /// do not heat for them
+    Ignore,
+    /// heat for open_ended_booking_hours after the start
+    Cap,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AssociatedRoomConfig {
+    pub name: String,
+    pub churchtools_id: i64,
+    /// the virtual CAN id all outputs of this room are sent from
+    pub can_id: u8,
+    pub pdo_index: u8,
+    pub preheat_minutes: u8,
+    pub preshutdown_minutes: u8,
+    /// number of minutes to keep heating after the (preshutdown-adjusted) end of a booking
+    pub overrun_minutes: u8,
+    pub preheat_curve: PreheatCurve,
+    /// proportion of preheat time the sun can replace on a sunny morning (0.0 - 1.0)
+    pub sun_exposure: f64,
+    /// value sent once when the room is put into maintenance mode
+    pub maintenance_value: bool,
+    /// pdo index the CMI mirrors the actual state of this room on (already shifted to 0-63)
+    pub feedback_pdo_index: Option<u8>,
+    /// analogue setpoint sent alongside the digital state
+    pub setpoint: Option<Setpoint>,
+    /// preheating may start up to ... minutes earlier if energy is cheaper then
+    pub price_flexibility_minutes: u8,
+    /// rooms with a higher priority heat first if they compete for the heat source
+    pub priority: i32,
+    /// only one room of a circuit is heated at a time
+    pub circuit: Option<String>,
+    /// heat at these times while CT is unreachable for longer than fallback_after
+    pub fallback_schedule: Vec<FallbackSlot>,
+    /// preheat of bookings starting on these days (local time) is multiplied by ...
+    pub preheat_weekday_factors: HashMap<Weekday, f64>,
+    /// extend preheat if the room was not used for a while
+    pub cold_start: Option<ColdStart>,
+    /// digital cooling output sent alongside the heating state
+    pub cooling: Option<Cooling>,
+}
+impl AssociatedRoomConfig {
+    /// Calculate the amount of minutes a room should be preheated, depending on the the
+    /// base_preheating time set in the config, the preheat curve and the external temperature
+    ///
+    /// external temperature is expected in tenths of a Degree Centigrade
+    /// if external_temp is None, we do not scale the base shutdowns at all.
+    fn preheat_time(&self, external_temp: Option<i32>) -> u8 {
+        preheat::preheat_time(self.preheat_minutes, &self.preheat_curve, external_temp)
+    }
+
+    /// The longest this room may preheat, at any temperature and on any day
+    pub fn max_preheat_minutes(&self) -> u8 {
+        let max_factor = self
+            .preheat_weekday_factors
+            .values()
+            .copied()
+            .fold(1_f64, f64::max);
+        let cold_start = self
+            .cold_start
+            .map(|x| 1_f64 + x.max_extra)
+            .unwrap_or(1_f64);
+        preheat::scale_minutes(self.preheat_minutes, max_factor * cold_start)
+    }
+
+    /// Calculate the amount of minutes a rooms heating may be shut down BEFORE the end of a booking
+    /// base_preshutdown time set in the config and the external temperature
+    ///
+    /// external temperature is expected in tenths of a Degree Centigrade
+    /// if external_temp is None, we do not scale the base shutdowns at all.
+    fn preshutdown_time(&self, external_temp: Option<i32>) -> u8 {
+        preheat::preshutdown_time(self.preshutdown_minutes, &self.preheat_curve, external_temp)
+    }
+
+    /// Apply both prehead and preshutdown times, depending on this rooms configuration.
+    /// Return the real start and real end time (i.e. the times where we have to start heating or
+    /// are allowed to stop heating).
+    ///
+    /// Preheat is scaled with the temperature and reduced by the solar gain expected while
+    /// preheating, preshutdown is scaled with the current temperature.
+    pub fn apply_preheat_and_preshutdown(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        conditions: &Conditions,
+    ) -> (DateTime<Utc>, DateTime<Utc>) {
+        let weekday_factor = self
+            .preheat_weekday_factors
+            .get(&start.with_timezone(&Local).weekday())
+            .copied()
+            .unwrap_or(1_f64);
+        let cold_start_factor = match (self.cold_start, conditions.unused_for) {
+            (Some(cold_start), Some(unused_for)) => preheat::cold_start_factor(
+                cold_start.extra_per_day,
+                cold_start.max_extra,
+                unused_for,
+            ),
+            _ => 1_f64,
+        };
+        preheat::adjust_booking_times(
+            start,
+            end,
+            preheat::apply_solar_gain(
+                preheat::scale_minutes(
+                    self.preheat_time(conditions.preheat_temp),
+                    weekday_factor * cold_start_factor,
+                ),
+                self.sun_exposure,
+                conditions.preheat_irradiance,
+            ),
+            self.preshutdown_time(conditions.external_temp),
+            self.overrun_minutes,
+        )
+    }
+}
+
+/// The analogue setpoint of a room on a CMI. All temperatures in tenths of a Degree Centigrade.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Setpoint {
+    /// already shifted to 0-63
+    pub pdo_index: u8,
+    pub default: i32,
+    pub min: i32,
+    pub max: i32,
+}
+impl Setpoint {
+    fn from_config_data(pdo_index: u8, data: &SetpointConfigData) -> Option<Self> {
+        let setpoint = Self {
+            pdo_index,
+            default: (data.default * 10_f64).round() as i32,
+            min: (data.min * 10_f64).round() as i32,
+            max: (data.max * 10_f64).round() as i32,
+        };
+        (setpoint.min <= setpoint.default && setpoint.default <= setpoint.max).then_some(setpoint)
+    }
+
+    /// The setpoint to send if `requested` was requested by a booking
+    pub fn value(&self, requested: Option<i32>) -> i32 {
+        match requested {
+            Some(x) => x.clamp(self.min, self.max),
+            None => self.default,
+        }
+    }
+}
+
+/// The cooling or ventilation of a room on a CMI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Cooling {
+    /// already shifted to 0-63
+    pub pdo_index: u8,
+    /// bookings cool instead of heat above this external temperature, in tenths of a Degree
+    /// Centigrade
+    pub above: i32,
+    /// once cooling, bookings keep cooling down to this external temperature
+    pub below: i32,
+    pub pre_run_minutes: u8,
+    pub post_run_minutes: u8,
+    /// cooling is suppressed above this external dew point, in tenths of a Degree Centigrade
+    pub max_dew_point: Option<i32>,
+}
+impl Cooling {
+    fn from_config_data(pdo_index: u8, data: &CoolingConfigData) -> Option<Self> {
+        let above = (data.above * 10_f64).round() as i32;
+        let cooling = Self {
+            pdo_index,
+            above,
+            below: data.below.map_or(above, |x| (x * 10_f64).round() as i32),
+            pre_run_minutes: data.pre_run_minutes.unwrap_or(0),
+            post_run_minutes: data.post_run_minutes.unwrap_or(0),
+            max_dew_point: data.max_dew_point.map(|x| (x * 10_f64).round() as i32),
+        };
+        (cooling.below <= cooling.above).then_some(cooling)
+    }
+
+    /// Whether cooling would cause condensation at the external `dew_point` (tenths of a Degree
+    /// Centigrade).
+    ///
+    /// Without a known dew point, there is no risk.
+    pub fn condensation_risk(&self, dew_point: Option<i32>) -> bool {
+        matches!((self.max_dew_point, dew_point), (Some(max), Some(x)) if x > max)
+    }
+
+    /// Whether bookings cool instead of heat at `external_temp` (tenths of a Degree Centigrade).
+    ///
+    /// `was_active` is whether they cooled on the last push. Between below and above, that does
+    /// not change.
+    /// Without a known external temperature, bookings heat.
+    pub fn active(&self, external_temp: Option<i32>, was_active: bool) -> bool {
+        let threshold = if was_active { self.below } else { self.above };
+        external_temp.is_some_and(|x| x > threshold)
+    }
+
+    /// The time to cool for a booking from `start` to `end`
+    pub fn window(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> (DateTime<Utc>, DateTime<Utc>) {
+        (
+            start - TimeDelta::minutes(self.pre_run_minutes.into()),
+            end + TimeDelta::minutes(self.post_run_minutes.into()),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct CMIConfigData {
+    pub host: String,
+    #[serde(default)]
+    pub backup_hosts: Vec<String>,
+    pub ip_version: Option<IpPreference>,
+    pub feedback_can_id: Option<u8>,
+    pub site: Option<String>,
+    pub our_virtual_can_id: u8,
+    pub rooms: Vec<AssociatedRoomConfigData>,
+    #[serde(default)]
+    pub resource_types: Vec<ResourceTypeRoomsData>,
+}
+
+/// All CT resources of a type, synced to a CMI without listing each of them as a room
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ResourceTypeRoomsData {
+    /// the resource type id in CT
+    pub type_id: i64,
+    /// the room (from the `rooms:` section) whose settings are used for all resources of the type
+    pub template: String,
+    /// the pdo index (1-64) of a resource, by resource id
+    #[serde(default)]
+    pub pdo_indices: HashMap<i64, u8>,
+    /// pdo indices (1-64) handed out to the other resources of the type, in this order
+    #[serde(default)]
+    pub auto_pdo_indices: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ResourceTypeRooms {
+    pub type_id: i64,
+    /// the settings of all rooms of this type. Name, churchtools_id and pdo_index are replaced.
+    pub template: AssociatedRoomConfig,
+    /// the pdo index of a resource, by resource id (already shifted to 0-63)
+    pub pdo_indices: HashMap<i64, u8>,
+    /// pdo indices for other resources (already shifted to 0-63)
+    pub auto_pdo_indices: Vec<u8>,
+}
+impl ResourceTypeRooms {
+    fn from_config_data(
+        data: ResourceTypeRoomsData,
+        rooms: &HashMap<String, RoomConfig>,
+        can_id: u8,
+    ) -> Result<Self, CreateConfigError> {
+        let shift = |x: u8| {
+            if (1..=64).contains(&x) {
+                Ok(x - 1)
+            } else {
+                Err(CreateConfigError::PDOIndexOutOfBounds(x))
+            }
+        };
+        let template = AssociatedRoomConfigData {
+            name: data.template,
+            pdo_index: 1,
+            feedback_pdo_index: None,
+            setpoint_pdo_index: None,
+            cooling_pdo_index: None,
+            virtual_can_id: None,
+        };
+        Ok(Self {
+            type_id: data.type_id,
+            template: associated_room(template, rooms, None, can_id)?,
+            pdo_indices: data
+                .pdo_indices
+                .into_iter()
+                .map(|(id, x)| Ok((id, shift(x)?)))
+                .collect::<Result<_, CreateConfigError>>()?,
+            auto_pdo_indices: data
+                .auto_pdo_indices
+                .into_iter()
+                .map(shift)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Combine a room of a CMI with the settings from the `rooms:` section
+///
+/// `can_id` is the virtual CAN id of the CMI, used unless the room has its own.
+fn associated_room(
+    room: AssociatedRoomConfigData,
+    rooms: &HashMap<String, RoomConfig>,
+    feedback_can_id: Option<u8>,
+    can_id: u8,
+) -> Result<AssociatedRoomConfig, CreateConfigError> {
+    let room_data = rooms
+        .get(&room.name)
+        .ok_or(CreateConfigError::RoomNotFoundError(room.name.clone()))?;
+    let preheat_curve = match &room_data.preheat_curve {
+        Some(points) => PreheatCurve::from_points(
+            &points
+                .iter()
+                .map(|p| (p.temperature, p.factor))
+                .collect::<Vec<_>>(),
+        )
+        .map_err(|e| CreateConfigError::InvalidPreheatCurve(room.name.clone(), e))?,
+        None => PreheatCurve::default(),
+    };
+    let sun_exposure = room_data.sun_exposure.unwrap_or(0_f64);
+    if !(0_f64..=1_f64).contains(&sun_exposure) {
+        return Err(CreateConfigError::SunExposureOutOfBounds(room.name));
+    };
+    let feedback_pdo_index = match room.feedback_pdo_index {
+        Some(_) if feedback_can_id.is_none() => {
+            return Err(CreateConfigError::IncompleteFeedback(room.name));
+        }
+        Some(x) if (1..=64).contains(&x) => Some(x - 1),
+        Some(x) => return Err(CreateConfigError::PDOIndexOutOfBounds(x)),
+        None => None,
+    };
+    let setpoint = match (room.setpoint_pdo_index, &room_data.setpoint) {
+        (Some(_), None) => {
+            return Err(CreateConfigError::IncompleteSetpoint(room.name));
+        }
+        (Some(x), Some(_)) if !(1..=64).contains(&x) => {
+            return Err(CreateConfigError::PDOIndexOutOfBounds(x));
+        }
+        (Some(x), Some(data)) => Some(
+            Setpoint::from_config_data(x - 1, data)
+                .ok_or(CreateConfigError::InvalidSetpoint(room.name.clone()))?,
+        ),
+        // this CMI has no analogue output for the room
+        (None, _) => None,
+    };
+    let cooling = match (room.cooling_pdo_index, &room_data.cooling) {
+        (Some(_), None) => {
+            return Err(CreateConfigError::IncompleteCooling(room.name));
+        }
+        (Some(x), Some(_)) if !(1..=64).contains(&x) => {
+            return Err(CreateConfigError::PDOIndexOutOfBounds(x));
+        }
+        (Some(x), Some(data)) => Some(
+            Cooling::from_config_data(x - 1, data)
+                .ok_or(CreateConfigError::InvalidCooling(room.name.clone()))?,
+        ),
+        // this CMI does not cool the room
+        (None, _) => None,
+    };
+    let fallback_schedule = room_data.fallback_schedule.clone().unwrap_or_default();
+    if fallback_schedule.iter().any(|slot| slot.start >= slot.end) {
+        return Err(CreateConfigError::InvalidFallbackSchedule(room.name));
+    };
+    let preheat_weekday_factors = room_data
+        .preheat_weekday_factors
+        .clone()
+        .unwrap_or_default();
+    if preheat_weekday_factors
+        .values()
+        .any(|factor| !(factor.is_finite() && *factor >= 0_f64))
+    {
+        return Err(CreateConfigError::InvalidWeekdayFactor(room.name));
+    };
+    if let Some(cold_start) = room_data.cold_start {
+        if [cold_start.extra_per_day, cold_start.max_extra]
+            .iter()
+            .any(|x| !(x.is_finite() && *x >= 0_f64))
+        {
+            return Err(CreateConfigError::InvalidColdStart(room.name));
+        };
+    };
+    Ok(AssociatedRoomConfig {
+        name: room.name,
+        pdo_index: if room.pdo_index >= 1 && room.pdo_index <= 64 {
+            room.pdo_index - 1
+        } else {
+            return Err(CreateConfigError::PDOIndexOutOfBounds(room.pdo_index));
+        },
+        churchtools_id: room_data.churchtools_id,
+        can_id: room.virtual_can_id.unwrap_or(can_id),
+        preheat_minutes: room_data.preheat_minutes.unwrap_or(30),
+        preshutdown_minutes: room_data.preshutdown_minutes.unwrap_or(10),
+        overrun_minutes: room_data.overrun_minutes.unwrap_or(0),
+        preheat_curve,
+        sun_exposure,
+        maintenance_value: room_data.maintenance_value.unwrap_or(false),
+        feedback_pdo_index,
+        setpoint,
+        price_flexibility_minutes: room_data.price_flexibility_minutes.unwrap_or(0),
+        priority: room_data.priority.unwrap_or(0),
+        circuit: room_data.circuit.clone(),
+        fallback_schedule,
+        preheat_weekday_factors,
+        cold_start: room_data.cold_start,
+        cooling,
+    })
+}
+
+/// a single room defined in the config
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct AssociatedRoomConfigData {
+    name: String,
+    pub pdo_index: u8,
+    pub feedback_pdo_index: Option<u8>,
+    pub setpoint_pdo_index: Option<u8>,
+    pub cooling_pdo_index: Option<u8>,
+    /// send the outputs of this room from this virtual CAN id instead of our_virtual_can_id
+    pub virtual_can_id: Option<u8>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ExtTempConfigData {
+    pub bind_addr: Option<String>,
+    pub can_id: Option<u8>,
+    pub pdo_index: Option<u8>,
+    /// also receive the relative humidity (in percent) from the same CAN id on this output index
+    pub humidity_pdo_index: Option<u8>,
+    /// only accept CoE from these addresses, e.g. the CMI sending the temperature
+    pub allowed_senders: Option<Vec<IpAddr>>,
+    pub timeout: u8,
+    #[serde(default = "default_accepted_units")]
+    pub accepted_units: Vec<TemperatureUnit>,
+    pub mqtt: Option<MqttExtTempConfig>,
+    pub filter: Option<ExtTempFilterConfig>,
+    #[serde(default = "default_history_interval")]
+    pub history_interval: u64,
+    #[serde(default = "default_history_retention")]
+    pub history_retention: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct ExtTempConfig {
+    /// Receive the external temperature via CoE
+    pub coe: Option<CoeExtTempConfig>,
+    /// Receive the external temperature via MQTT
+    pub mqtt: Option<MqttExtTempConfig>,
+    /// number of minutes to wait for a new temperature from any source.
+    /// After this time, the external temperature is not considered anymore
+    pub timeout: u8,
+    /// Smoothing and outlier rejection for received temperatures
+    pub filter: Option<ExtTempFilterConfig>,
+    /// record at most one external temperature sample every ... minutes
+    pub history_interval: u64,
+    /// keep recorded external temperature samples for ... days
+    pub history_retention: u64,
+}
+
+impl ExtTempConfig {
+    fn from_config_data(data: ExtTempConfigData) -> Result<Self, CreateConfigError> {
+        let coe = match (data.bind_addr, data.can_id, data.pdo_index) {
+            (Some(bind_addr), Some(can_id), Some(pdo_index)) => Some(CoeExtTempConfig {
+                bind_addr,
+                can_id,
+                // shift the pdo_offset for the external_temperature_sensor data by one:
+                pdo_index: if (1..=64).contains(&pdo_index) {
+                    pdo_index - 1
+                } else {
+                    return Err(CreateConfigError::PDOIndexOutOfBounds(pdo_index));
+                },
+                accepted_units: data.accepted_units,
+                humidity_pdo_index: match data.humidity_pdo_index {
+                    Some(x) if (1..=64).contains(&x) => Some(x - 1),
+                    Some(x) => return Err(CreateConfigError::PDOIndexOutOfBounds(x)),
+                    None => None,
+                },
+                allowed_senders: data.allowed_senders,
+            }),
+            (None, None, None)
+                if data.humidity_pdo_index.is_none() && data.allowed_senders.is_none() =>
+            {
+                None
+            }
+            _ => return Err(CreateConfigError::IncompleteCoeExtTempSource),
+        };
+        Ok(ExtTempConfig {
+            coe,
+            mqtt: data.mqtt,
+            timeout: data.timeout,
+            filter: data.filter,
+            history_interval: data.history_interval,
+            history_retention: data.history_retention,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CoeExtTempConfig {
+    /// IP Address to bind a receiving UDP socket on. Port is 5442
+    pub bind_addr: String,
+    /// Can ID to expect - other ids are ignored
+    pub can_id: u8,
+    /// PDO Index to expect - other ids are ignored
+    pub pdo_index: u8,
+    /// The CoE units we accept as external temperature. Other units are ignored.
+    pub accepted_units: Vec<TemperatureUnit>,
+    /// PDO Index the relative humidity is expected on, if any
+    pub humidity_pdo_index: Option<u8>,
+    /// Only accept the temperature and humidity from these addresses. All are accepted if None.
+    pub allowed_senders: Option<Vec<IpAddr>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct MqttExtTempConfig {
+    /// hostname or ip of the broker
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// the topic the temperature is published on
+    pub topic: String,
+    /// if set, the payload is parsed as JSON object and the temperature read from this field.
+    /// Otherwise the payload is expected to be a plain number.
+    /// In both cases, the temperature is expected in Degree Centigrade.
+    pub json_field: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+impl std::fmt::Debug for MqttExtTempConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MqttExtTempConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("topic", &self.topic)
+            .field("json_field", &self.json_field)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[redacated]"))
+            .finish()
+    }
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_history_interval() -> u64 {
+    10
+}
+
+fn default_history_retention() -> u64 {
+    365
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ExtTempFilterConfig {
+    /// how to combine the last `window` samples
+    pub kind: ExtTempFilterKind,
+    /// number of samples to combine
+    #[serde(default = "default_filter_window")]
+    pub window: usize,
+    /// maximum plausible change between consecutive samples in Kelvin.
+    /// Larger jumps are rejected as outliers.
+    pub max_jump: Option<f64>,
+}
+
+fn default_filter_window() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExtTempFilterKind {
+    MovingAverage,
+    Median,
+}
+
+fn default_accepted_units() -> Vec<TemperatureUnit> {
+    vec![TemperatureUnit::CelsiusTenths]
+}
+
+/// The encodings of a temperature in CoE we understand
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TemperatureUnit {
+    /// Tenths of a Degree Centigrade (CoE unit 1)
+    CelsiusTenths,
+    /// Tenths of a Degree Kelvin, as absolute temperature (CoE unit 7)
+    KelvinTenths,
+    /// Whole Degrees Centigrade, sent without a unit (CoE unit 0)
+    Dimensionless,
+    /// Tenths of a Degree Centigrade, sent without a unit (CoE unit 58)
+    DimensionlessTenths,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "weather"), allow(dead_code))]
+pub(crate) struct ForecastConfig {
+    /// location of the building
+    pub latitude: f64,
+    pub longitude: f64,
+    /// get a new forecast every ... minutes
+    #[serde(default = "default_forecast_refresh_interval")]
+    pub refresh_interval: u64,
+    /// an open-meteo compatible forecast API
+    #[serde(default = "default_forecast_url")]
+    pub url: String,
+}
+
+/// Calendars on a CalDAV server (e.g. Nextcloud) whose events book rooms
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct CalDavConfig {
+    /// pull the calendars every ... seconds
+    #[serde(default = "default_caldav_pull_frequency")]
+    pub pull_frequency: u64,
+    /// pull the events of the next ... days
+    #[serde(default = "default_caldav_days")]
+    pub days: u16,
+    pub calendars: Vec<CalDavCalendarConfig>,
+}
+
+/// A calendar whose events book one room
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct CalDavCalendarConfig {
+    /// URL of the calendar collection
+    pub url: String,
+    /// name of the room in the `rooms:` section
+    pub room: String,
+    /// for basic auth
+    pub username: Option<String>,
+    /// password or app token, for basic auth
+    pub password: Option<String>,
+    /// bearer token, instead of username and password
+    pub token: Option<String>,
+    /// churchtools_id of the room, events are stored as bookings of this resource
+    #[serde(skip)]
+    pub resource_id: i64,
+}
+impl std::fmt::Debug for CalDavCalendarConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CalDavCalendarConfig")
+            .field("url", &self.url)
+            .field("room", &self.room)
+            .field("username", &self.username)
+            .field("password", &"[redacated]")
+            .field("token", &"[redacated]")
+            .field("resource_id", &self.resource_id)
+            .finish()
+    }
+}
+
+fn default_caldav_pull_frequency() -> u64 {
+    300
+}
+
+fn default_caldav_days() -> u16 {
+    14
 }
-impl std::fmt::Display for CreateConfigError {
+
+/// Room mailboxes in Microsoft 365, read via the Graph API with an app registration
+#[derive(Deserialize, JsonSchema)]
+pub(crate) struct M365Config {
+    /// directory (tenant) id of the organisation
+    pub tenant_id: String,
+    /// application (client) id of the app registration
+    pub client_id: String,
+    /// client secret of the app registration
+    pub client_secret: String,
+    /// pull the calendars every ... seconds
+    #[serde(default = "default_m365_pull_frequency")]
+    pub pull_frequency: u64,
+    /// pull the events of the next ... days
+    #[serde(default = "default_m365_days")]
+    pub days: u16,
+    /// the Microsoft identity platform
+    #[serde(default = "default_m365_login_url")]
+    pub login_url: String,
+    /// the Graph API
+    #[serde(default = "default_m365_graph_url")]
+    pub graph_url: String,
+    pub rooms: Vec<M365RoomConfig>,
+}
+impl std::fmt::Debug for M365Config {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::RoomNotFoundError(x) => {
-                write!(
-                    f,
-                    "Room {x} was not found in the `rooms:` section of the config."
-                )
-            }
-            Self::PDOIndexOutOfBounds(x) => {
-                write!(f, "PDO Index {x} is not within 1-64")
-            }
-        }
+        f.debug_struct("M365Config")
+            .field("tenant_id", &self.tenant_id)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"[redacated]")
+            .field("pull_frequency", &self.pull_frequency)
+            .field("days", &self.days)
+            .field("login_url", &self.login_url)
+            .field("graph_url", &self.graph_url)
+            .field("rooms", &self.rooms)
+            .finish()
     }
 }
-impl std::error::Error for CreateConfigError {}
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct ConfigData {
-    pub cmis: Vec<CMIConfigData>,
-    pub external_temperature_sensor: ExtTempConfig,
-    pub ct: ChurchToolsConfig,
-    pub global: GlobalConfig,
-    pub rooms: HashMap<String, RoomConfig>,
+/// A room mailbox whose events book one room
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct M365RoomConfig {
+    /// address of the room mailbox, e.g. hall@example.com
+    pub mailbox: String,
+    /// name of the room in the `rooms:` section
+    pub room: String,
+    /// churchtools_id of the room, events are stored as bookings of this resource
+    #[serde(skip)]
+    pub resource_id: i64,
 }
-#[derive(Debug)]
-pub(crate) struct Config {
-    pub cmis: Vec<CMIConfig>,
-    pub external_temperature_sensor: ExtTempConfig,
-    pub ct: ChurchToolsConfig,
-    pub db: Pool<Sqlite>,
-    pub global: GlobalConfig,
+
+fn default_m365_pull_frequency() -> u64 {
+    300
 }
-impl Config {
-    async fn from_config_data(cd: ConfigData) -> Result<Config, Box<dyn std::error::Error>> {
-        let connect_options = sqlx::sqlite::SqliteConnectOptions::new()
-            .filename(crate::BOOKING_DATABASE_NAME)
-            .create_if_missing(true);
-        let db = sqlx::SqlitePool::connect_with(connect_options).await?;
 
-        let cmis = cd
-            .cmis
-            .into_iter()
-            .map(|cmi| {
-                Ok::<CMIConfig, CreateConfigError>(CMIConfig {
-                    host: cmi.host,
-                    our_virtual_can_id: cmi.our_virtual_can_id,
-                    rooms: cmi
-                        .rooms
-                        .into_iter()
-                        .map(|room| {
-                            let room_data = cd
-                                .rooms
-                                .get(&room.name)
-                                .ok_or(CreateConfigError::RoomNotFoundError(room.name.clone()))?;
-                            Ok(AssociatedRoomConfig {
-                                name: room.name,
-                                pdo_index: if room.pdo_index >= 1 && room.pdo_index <= 64 {
-                                    room.pdo_index - 1
-                                } else {
-                                    return Err(CreateConfigError::PDOIndexOutOfBounds(
-                                        room.pdo_index,
-                                    ));
-                                },
-                                churchtools_id: room_data.churchtools_id,
-                                preheat_minutes: room_data.preheat_minutes.unwrap_or(30),
-                                preshutdown_minutes: room_data.preshutdown_minutes.unwrap_or(10),
-                            })
-                        })
-                        .collect::<Result<Vec<_>, _>>()?,
-                })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
+fn default_m365_days() -> u16 {
+    14
+}
 
-        // shift the pdo_offset for the external_temperature_sensor data by one:
-        let ext_temp_config = ExtTempConfig {
-            bind_addr: cd.external_temperature_sensor.bind_addr,
-            can_id: cd.external_temperature_sensor.can_id,
-            pdo_index: if (1..=64).contains(&cd.external_temperature_sensor.pdo_index) {
-                cd.external_temperature_sensor.pdo_index - 1
-            } else {
-                return Err(Box::new(CreateConfigError::PDOIndexOutOfBounds(
-                    cd.external_temperature_sensor.pdo_index,
-                )));
-            },
-            timeout: cd.external_temperature_sensor.timeout,
-        };
+fn default_m365_login_url() -> String {
+    "https://login.microsoftonline.com".to_owned()
+}
 
-        Ok(Config {
-            cmis,
-            external_temperature_sensor: ext_temp_config,
-            ct: cd.ct,
-            db,
-            global: cd.global,
-        })
+fn default_m365_graph_url() -> String {
+    "https://graph.microsoft.com/v1.0".to_owned()
+}
+
+/// A time-of-use tariff, in any unit (e.g. ct/kWh)
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct EnergyPricesConfig {
+    /// the price outside of all periods
+    pub default: f64,
+    /// the first matching period wins
+    #[serde(default)]
+    pub periods: Vec<PricePeriod>,
+}
+impl EnergyPricesConfig {
+    /// The price at this local time of day
+    fn price_at_local(&self, time: chrono::NaiveTime) -> f64 {
+        self.periods
+            .iter()
+            .find(|p| {
+                if p.start <= p.end {
+                    p.start <= time && time < p.end
+                } else {
+                    // spans midnight
+                    p.start <= time || time < p.end
+                }
+            })
+            .map(|p| p.price)
+            .unwrap_or(self.default)
     }
 
-    pub async fn create() -> Result<Config, Box<dyn std::error::Error>> {
-        let path = Path::new("/etc/ct-ta-sync/config.yaml");
-        let f = match File::open(path) {
-            Ok(x) => x,
-            Err(e) => {
-                event!(
-                    Level::ERROR,
-                    "config file /etc/asterconf/config.yaml not readable: {e}"
-                );
-                return Err(Box::new(e));
-            }
-        };
-        let config_data: ConfigData = match serde_yaml::from_reader(f) {
-            Ok(x) => x,
-            Err(e) => {
-                event!(Level::ERROR, "config file had syntax errors: {e}");
-                return Err(Box::new(e));
-            }
-        };
-        Config::from_config_data(config_data).await
+    /// The price at this time
+    pub fn price_at(&self, time: DateTime<Utc>) -> f64 {
+        self.price_at_local(time.with_timezone(&chrono::Local).time())
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct RoomConfig {
-    pub preheat_minutes: Option<u8>,
-    pub preshutdown_minutes: Option<u8>,
-    pub churchtools_id: i64,
+/// A daily period with its own price, in the local time of the host. It may span midnight.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct PricePeriod {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+    pub price: f64,
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct GlobalConfig {
-    pub ct_pull_frequency: u64,
-    pub ta_push_frequency: u64,
-    pub log_level: String,
-    pub emiter_bind_addr: String,
+fn default_forecast_refresh_interval() -> u64 {
+    60
 }
 
-#[derive(Debug)]
-pub(crate) struct CMIConfig {
-    pub host: String,
-    pub our_virtual_can_id: u8,
-    pub rooms: Vec<AssociatedRoomConfig>,
+fn default_forecast_url() -> String {
+    "https://api.open-meteo.com/v1/forecast".to_owned()
+}
+
+/// CT booking status "approved"
+const APPROVED_STATUS_ID: u8 = 2;
+
+/// A building with its own CMIs, as defined in the config
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct SiteConfigData {
+    pub external_temperature_sensor: Option<ExtTempConfigData>,
+    pub ct_status_ids: Option<Vec<u8>>,
+    /// preheat at most ... rooms of this site at the same time
+    pub max_preheating_rooms: Option<usize>,
+    /// the heating season of this site, instead of the global one
+    pub heating_season: Option<Vec<SeasonRange>>,
 }
 
+/// A building with its own CMIs
 #[derive(Debug)]
-pub(crate) struct AssociatedRoomConfig {
+pub(crate) struct SiteConfig {
+    /// the sensor of this site. The default sensor is used if this is not set.
+    pub external_temperature_sensor: Option<ExtTempConfig>,
+    /// only bookings with these CT status ids are pulled for the rooms of this site
+    pub ct_status_ids: Vec<u8>,
+    /// preheat at most ... rooms of this site at the same time
+    pub max_preheating_rooms: Option<usize>,
+    /// the heating season of this site. The global one is used if this is not set.
+    pub heating_season: Option<Vec<SeasonRange>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) struct HttpConfig {
+    /// address and port to listen on
+    pub bind_addr: String,
+    /// bearer token with the operator role
+    pub token: Option<String>,
+    /// users with their own credentials and role
+    #[serde(default)]
+    pub users: Vec<HttpUserConfig>,
+    /// allow requests without credentials to read (default true)
+    #[serde(default = "default_anonymous_read")]
+    pub anonymous_read: bool,
+    /// serve HTTPS instead of HTTP
+    pub tls: Option<HttpTlsConfig>,
+    /// require all requests changing the heating to be signed with HMAC-SHA256 and this secret
+    pub signing_secret: Option<String>,
+    /// address and port to serve the gRPC service on. Needs the grpc feature.
+    pub grpc_bind_addr: Option<String>,
+}
+fn default_anonymous_read() -> bool {
+    true
+}
+impl std::fmt::Debug for HttpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HttpConfig")
+            .field("bind_addr", &self.bind_addr)
+            .field("token", &"[redacated]")
+            .field("users", &self.users)
+            .field("anonymous_read", &self.anonymous_read)
+            .field("tls", &self.tls)
+            .field("signing_secret", &"[redacated]")
+            .field("grpc_bind_addr", &self.grpc_bind_addr)
+            .finish()
+    }
+}
+
+/// Certificate and key of the HTTPS server
+#[derive(Debug, Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) struct HttpTlsConfig {
+    /// PEM file with the certificate chain
+    pub cert_path: PathBuf,
+    /// PEM file with the private key
+    pub key_path: PathBuf,
+    /// Generate a self-signed certificate for these hostnames if cert_path or key_path do not
+    /// exist
+    #[serde(default)]
+    pub self_signed_names: Vec<String>,
+}
+
+/// A user of the HTTP API. Authenticates with a bearer token or basic auth (name and password).
+#[derive(Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) struct HttpUserConfig {
     pub name: String,
-    pub churchtools_id: i64,
-    pub pdo_index: u8,
-    pub preheat_minutes: u8,
-    pub preshutdown_minutes: u8,
+    pub role: HttpRole,
+    pub token: Option<String>,
+    pub password: Option<String>,
 }
-impl AssociatedRoomConfig {
-    /// Calculate the amount of minutes a room should be preheated, depending on the the
-    /// base_preheating time set in the config and the external temperature
-    ///
-    /// external temperature is expected in tenths of a Degree Centigrade
-    /// if external_temp is None, we do not scale the base shutdowns at all.
-    fn preheat_time(&self, external_temp: Option<i32>) -> u8 {
-        if let Some(x) = external_temp {
-            let clamped_external_temp: f64 = x.clamp(-100, 200) as f64;
-            let time_proportion = (clamped_external_temp + 100_f64) / 300_f64;
-            (self.preheat_minutes as f64 * (1_f64 - time_proportion)).round() as u8
-        } else {
-            self.preheat_minutes
-        }
+impl std::fmt::Debug for HttpUserConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("HttpUserConfig")
+            .field("name", &self.name)
+            .field("role", &self.role)
+            .field("token", &"[redacated]")
+            .field("password", &"[redacated]")
+            .finish()
     }
+}
 
-    /// Calculate the amount of minutes a rooms heating may be shut down BEFORE the end of a booking
-    /// base_preshutdown time set in the config and the external temperature
-    ///
-    /// external temperature is expected in tenths of a Degree Centigrade
-    /// if external_temp is None, we do not scale the base shutdowns at all.
-    fn preshutdown_time(&self, external_temp: Option<i32>) -> u8 {
-        if let Some(x) = external_temp {
-            let clamped_external_temp: f64 = x.clamp(-100, 200) as f64;
-            let time_proportion = (clamped_external_temp + 100_f64) / 300_f64;
-            (self.preshutdown_minutes as f64 * time_proportion).round() as u8
-        } else {
-            // if we do not now how warm it is, we are never allowed to prematurely stop heating
-            0
+/// What a user of the HTTP API may do
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HttpRole {
+    /// read status, metrics, maintenance mode and local bookings
+    Viewer,
+    /// also change state: maintenance mode, local bookings and the external temperature
+    Operator,
+}
+
+/// Push notifications when heating control degrades
+#[derive(Debug, Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "alerting"), allow(dead_code))]
+pub(crate) struct AlertingConfig {
+    pub channels: Vec<AlertChannelConfig>,
+    /// alert after pulling from CT failed this many times in a row
+    #[serde(default = "default_alert_ct_failures")]
+    pub ct_failures: u32,
+    /// alert after no external temperature was received for ... minutes
+    #[serde(default = "default_alert_ext_temp_missing")]
+    pub ext_temp_missing: u64,
+    /// send at most ... alerts per hour. Resolve notifications are not limited.
+    #[serde(default = "default_alert_max_per_hour")]
+    pub max_per_hour: usize,
+    /// hold back notifications during these hours
+    pub quiet_hours: Option<QuietHours>,
+    /// send the daily summary (see global.daily_summary_time) to all channels as well
+    #[serde(default)]
+    pub daily_summary: bool,
+}
+
+/// A daily time span in the local time of the host, which may span midnight
+#[derive(Debug, Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "alerting"), allow(dead_code))]
+pub(crate) struct QuietHours {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+fn default_alert_ct_failures() -> u32 {
+    3
+}
+
+fn default_alert_ext_temp_missing() -> u64 {
+    60
+}
+
+fn default_alert_max_per_hour() -> usize {
+    10
+}
+
+/// A single way to send alerts
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[cfg_attr(not(feature = "alerting"), allow(dead_code))]
+pub(crate) enum AlertChannelConfig {
+    /// POST to an ntfy topic
+    Ntfy {
+        /// e.g. https://ntfy.sh/my-topic
+        url: String,
+        /// access token for protected topics
+        token: Option<String>,
+    },
+    /// send a message via a Telegram bot
+    Telegram { bot_token: String, chat_id: String },
+    /// send an email via SMTP with STARTTLS
+    Email {
+        smtp_host: String,
+        #[serde(default = "default_smtp_port")]
+        smtp_port: u16,
+        username: String,
+        password: String,
+        from: String,
+        to: Vec<String>,
+    },
+}
+impl AlertChannelConfig {
+    /// name of this kind of channel, for logging
+    #[cfg(feature = "alerting")]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Ntfy { .. } => "ntfy",
+            Self::Telegram { .. } => "Telegram",
+            Self::Email { .. } => "email",
         }
     }
-
-    /// Apply both prehead and preshutdown times, depending on this rooms configuration.
-    /// Return the real start and real end time (i.e. the times where we have to start heating or
-    /// are allowed to stop heating).
-    ///
-    /// external temperature is expected in tenths of a Degree Centigrade
-    /// if external_temp is None, we do not scale the base shutdowns at all.
-    pub fn apply_preheat_and_preshutdown(
-        &self,
-        start: DateTime<Utc>,
-        end: DateTime<Utc>,
-        external_temp: Option<i32>,
-    ) -> (DateTime<Utc>, DateTime<Utc>) {
-        let new_start = start - TimeDelta::minutes(self.preheat_time(external_temp).into());
-        let new_end = end - TimeDelta::minutes(self.preshutdown_time(external_temp).into());
-        (new_start, new_end)
+}
+impl std::fmt::Debug for AlertChannelConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Ntfy { url, token } => f
+                .debug_struct("Ntfy")
+                .field("url", url)
+                .field("token", &token.as_ref().map(|_| "[redacated]"))
+                .finish(),
+            Self::Telegram { chat_id, .. } => f
+                .debug_struct("Telegram")
+                .field("bot_token", &"[redacated]")
+                .field("chat_id", chat_id)
+                .finish(),
+            Self::Email {
+                smtp_host,
+                smtp_port,
+                username,
+                from,
+                to,
+                ..
+            } => f
+                .debug_struct("Email")
+                .field("smtp_host", smtp_host)
+                .field("smtp_port", smtp_port)
+                .field("username", username)
+                .field("password", &"[redacated]")
+                .field("from", from)
+                .field("to", to)
+                .finish(),
+        }
     }
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct CMIConfigData {
-    pub host: String,
-    pub our_virtual_can_id: u8,
-    pub rooms: Vec<AssociatedRoomConfigData>,
+fn default_smtp_port() -> u16 {
+    587
 }
 
-/// a single room defined in the config
-#[derive(Debug, Deserialize)]
-pub(crate) struct AssociatedRoomConfigData {
-    name: String,
-    pub pdo_index: u8,
+/// Status LEDs on GPIO pins, e.g. of a Raspberry Pi. Needs the gpio feature.
+///
+/// Pins are given as sysfs GPIO numbers.
+#[derive(Debug, Deserialize, JsonSchema)]
+#[cfg_attr(not(feature = "gpio"), allow(dead_code))]
+pub(crate) struct StatusLedsConfig {
+    /// blinks while the sync is running
+    pub heartbeat: Option<u32>,
+    /// on while the last pull from CT succeeded
+    pub ct_reachable: Option<u32>,
+    /// on while the last push to the CMIs succeeded
+    pub cmi_ok: Option<u32>,
+    /// on while the last pull or push failed
+    pub error: Option<u32>,
+    /// the sysfs GPIO directory
+    #[serde(default = "default_gpio_path")]
+    pub gpio_path: std::path::PathBuf,
 }
 
-#[derive(Debug, Deserialize)]
-pub(crate) struct ExtTempConfig {
-    /// IP Address to bind a receiving UDP socket on. Port is 5442
-    pub bind_addr: String,
-    /// Can ID to expect - other ids are ignored
-    pub can_id: u8,
-    /// PDO Index to expect - other ids are ignored
-    pub pdo_index: u8,
-    /// number of minutes to wait for a packet to the correct Can-ID, PDO.
-    /// After this time, the external temperature is not considered anymore
-    pub timeout: u8,
+fn default_gpio_path() -> std::path::PathBuf {
+    std::path::PathBuf::from("/sys/class/gpio")
+}
+
+/// Settings for init systems that do not set them up for us
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct DaemonConfig {
+    /// switch to this user after binding all sockets
+    pub user: Option<String>,
+    /// switch to this group after binding all sockets (default: the primary group of user)
+    pub group: Option<String>,
+    /// write our PID to this file. It is handed over to user when dropping privileges.
+    pub pid_file: Option<std::path::PathBuf>,
+    /// umask, given as octal string like "027"
+    #[serde(default, deserialize_with = "deserialize_umask")]
+    #[schemars(with = "Option<String>", regex(pattern = r"^[0-7]{1,3}$"))]
+    pub umask: Option<u32>,
 }
 
-#[derive(Deserialize)]
+fn deserialize_umask<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    let Some(mask) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    match u32::from_str_radix(&mask, 8) {
+        Ok(x) if x <= 0o777 => Ok(Some(x)),
+        _ => Err(serde::de::Error::custom(format!(
+            "umask {mask} is not an octal number between 000 and 777"
+        ))),
+    }
+}
+
+/// Heat rooms for bookings of their parent or child resources in CT
+#[derive(Debug, Deserialize, JsonSchema)]
+pub(crate) struct ResourceHierarchyConfig {
+    /// a booking of a parent resource heats all its children (default true)
+    #[serde(default = "default_true")]
+    pub parent_implies_children: bool,
+    /// a booking of a child resource heats its parent (default false)
+    #[serde(default)]
+    pub children_imply_parent: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, JsonSchema)]
 pub(crate) struct ChurchToolsConfig {
     pub host: String,
     pub login_token: String,
+    /// bookings whose note contains this keyword (case insensitive) are not heated
+    pub no_heating_keyword: Option<String>,
+    /// bookings with this field set to a true-ish value are not heated
+    pub no_heating_field: Option<String>,
+    /// What to do with bookings without an end (default all_day)
+    #[serde(default)]
+    pub open_ended_bookings: OpenEndedBookingPolicy,
+    /// open-ended bookings last ... hours with the cap policy (default 4)
+    pub open_ended_booking_hours: Option<u8>,
+    /// write the heating window of each booking into this field, so bookers see it in CT
+    pub heating_field: Option<String>,
+    /// bookings starting or ending more than ... days from now are implausible (default 366)
+    pub plausible_days: Option<u16>,
+    /// What to do with implausible bookings (default drop)
+    #[serde(default)]
+    pub implausible_bookings: ImplausibleBookingPolicy,
 }
 impl std::fmt::Debug for ChurchToolsConfig {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("ChurchToolsConfig")
             .field("host", &self.host)
             .field("login_token", &"[redacated]")
+            .field("no_heating_keyword", &self.no_heating_keyword)
+            .field("no_heating_field", &self.no_heating_field)
+            .field("open_ended_bookings", &self.open_ended_bookings)
+            .field("open_ended_booking_hours", &self.open_ended_booking_hours)
+            .field("heating_field", &self.heating_field)
+            .field("plausible_days", &self.plausible_days)
+            .field("implausible_bookings", &self.implausible_bookings)
             .finish()
     }
 }
@@ -255,28 +2310,53 @@ impl std::fmt::Debug for ChurchToolsConfig {
 mod test {
     use super::*;
 
+    use chrono::TimeDelta;
+
     #[test]
     fn preheat_time_below_start() {
         let external_temp = -200;
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preheat_time(Some(external_temp)), 40);
     }
 
+    #[test]
+    fn preheat_weekday_factors() {
+        let room = AssociatedRoomConfig {
+            preheat_minutes: 40,
+            preshutdown_minutes: 13,
+            preheat_weekday_factors: HashMap::from([(Weekday::Sun, 1.5), (Weekday::Tue, 10.0)]),
+            ..test_room("")
+        };
+        // noon, so the day is the same in all timezones
+        let preheat = |day: &str| {
+            let start: DateTime<Utc> = DateTime::parse_from_rfc3339(&format!("{day}T12:00:00Z"))
+                .unwrap()
+                .into();
+            let (heating_start, _) = room.apply_preheat_and_preshutdown(
+                start,
+                start + TimeDelta::hours(1),
+                &Conditions::default(),
+            );
+            (start - heating_start).num_minutes()
+        };
+        assert_eq!(preheat("2024-01-07"), 60);
+        assert_eq!(preheat("2024-01-08"), 40);
+        // saturates instead of overflowing
+        assert_eq!(preheat("2024-01-09"), 255);
+        assert_eq!(room.max_preheat_minutes(), 255);
+    }
+
     #[test]
     fn preheat_time_ext_unknown() {
         let external_temp = None;
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preheat_time(external_temp), 40);
     }
@@ -285,11 +2365,9 @@ mod test {
     fn preheat_time_ext_high() {
         let external_temp = Some(200);
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preheat_time(external_temp), 0);
     }
@@ -298,11 +2376,9 @@ mod test {
     fn preheat_time_ext_middle() {
         let external_temp = Some(50);
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preheat_time(external_temp), 20);
     }
@@ -311,11 +2387,9 @@ mod test {
     fn preshutdown_time_below_start() {
         let external_temp = -200;
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preshutdown_time(Some(external_temp)), 0);
     }
@@ -324,11 +2398,9 @@ mod test {
     fn preshutdown_time_ext_unknown() {
         let external_temp = None;
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preshutdown_time(external_temp), 0);
     }
@@ -337,11 +2409,9 @@ mod test {
     fn preshutdown_time_ext_high() {
         let external_temp = Some(200);
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preshutdown_time(external_temp), 13);
     }
@@ -350,12 +2420,452 @@ mod test {
     fn preshutdown_time_ext_middle() {
         let external_temp = Some(50);
         let room = AssociatedRoomConfig {
-            name: "".to_owned(),
-            churchtools_id: 0,
-            pdo_index: 0,
             preheat_minutes: 40,
             preshutdown_minutes: 13,
+            ..test_room("")
         };
         assert_eq!(room.preshutdown_time(external_temp), 7);
     }
+
+    #[test]
+    fn overrun_after_preshutdown() {
+        let room = AssociatedRoomConfig {
+            preheat_minutes: 40,
+            preshutdown_minutes: 13,
+            overrun_minutes: 20,
+            ..test_room("")
+        };
+        let start = DateTime::parse_from_rfc3339("2021-03-26T15:00:00+00:00")
+            .unwrap()
+            .into();
+        let end = DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+            .unwrap()
+            .into();
+        let warm = Conditions {
+            preheat_temp: Some(200),
+            external_temp: Some(200),
+            preheat_irradiance: None,
+            unused_for: None,
+        };
+        let (_, new_end) = room.apply_preheat_and_preshutdown(start, end, &warm);
+        assert_eq!(new_end, end + TimeDelta::minutes(7));
+        let (_, new_end) = room.apply_preheat_and_preshutdown(start, end, &Conditions::default());
+        assert_eq!(new_end, end + TimeDelta::minutes(20));
+    }
+
+    #[test]
+    fn price_periods_may_span_midnight() {
+        let t = |h, m| chrono::NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let prices = EnergyPricesConfig {
+            default: 30.0,
+            periods: vec![
+                PricePeriod {
+                    start: t(22, 0),
+                    end: t(6, 0),
+                    price: 20.0,
+                },
+                PricePeriod {
+                    start: t(12, 0),
+                    end: t(14, 0),
+                    price: 25.0,
+                },
+            ],
+        };
+        assert_eq!(prices.price_at_local(t(23, 30)), 20.0);
+        assert_eq!(prices.price_at_local(t(5, 59)), 20.0);
+        assert_eq!(prices.price_at_local(t(6, 0)), 30.0);
+        assert_eq!(prices.price_at_local(t(13, 0)), 25.0);
+        assert_eq!(prices.price_at_local(t(14, 0)), 30.0);
+    }
+
+    #[test]
+    fn end_never_before_start() {
+        let room = AssociatedRoomConfig {
+            preshutdown_minutes: 60,
+            ..test_room("")
+        };
+        let start = DateTime::parse_from_rfc3339("2021-03-26T15:00:00+00:00")
+            .unwrap()
+            .into();
+        let end = DateTime::parse_from_rfc3339("2021-03-26T15:10:00+00:00")
+            .unwrap()
+            .into();
+        let warm = Conditions {
+            preheat_temp: Some(200),
+            external_temp: Some(200),
+            preheat_irradiance: None,
+            unused_for: None,
+        };
+        let (new_start, new_end) = room.apply_preheat_and_preshutdown(start, end, &warm);
+        assert_eq!(new_start, start);
+        assert_eq!(new_end, start);
+    }
+
+    #[test]
+    fn umask_is_octal() {
+        let daemon: DaemonConfig = serde_yaml::from_str("umask: \"027\"").unwrap();
+        assert_eq!(daemon.umask, Some(0o27));
+        assert!(serde_yaml::from_str::<DaemonConfig>("umask: \"089\"").is_err());
+        assert!(serde_yaml::from_str::<DaemonConfig>("umask: \"1777\"").is_err());
+        let daemon: DaemonConfig = serde_yaml::from_str("user: heating").unwrap();
+        assert_eq!(daemon.umask, None);
+    }
+
+    #[test]
+    fn schema_covers_example_config() {
+        let schema = serde_json::to_value(schemars::schema_for!(ConfigData)).unwrap();
+        let example: serde_yaml::Mapping =
+            serde_yaml::from_str(include_str!("../config.example.yaml")).unwrap();
+        for key in example.keys() {
+            let key = key.as_str().unwrap();
+            assert!(
+                schema["properties"].get(key).is_some(),
+                "{key} is missing from the schema"
+            );
+        }
+    }
+
+    #[test]
+    fn state_dir_precedence() {
+        let env = Some(std::ffi::OsString::from(
+            "/var/lib/ct-ta-sync:/var/lib/other",
+        ));
+        assert_eq!(
+            state_dir(Some(Path::new("/srv/state")), env.clone()),
+            PathBuf::from("/srv/state")
+        );
+        assert_eq!(state_dir(None, env), PathBuf::from("/var/lib/ct-ta-sync"));
+        assert_eq!(state_dir(None, None), PathBuf::from("."));
+
+        let dir = std::env::temp_dir();
+        assert!(check_writable(&dir).is_ok());
+        assert!(check_writable(&dir.join("does-not-exist")).is_err());
+    }
+
+    /// a config file with the tenants `parish-a` and `parish-b`, whose tokens are `secret-a`
+    /// and `secret-b`
+    fn tenants_yaml(state_dir_b: &str) -> String {
+        let section = |name: &str, extra: &str| {
+            format!(
+                "  {name}:
+    global:
+      ct_pull_frequency: 300
+      ta_push_frequency: 2
+      log_level: info
+      emiter_bind_addr: 0.0.0.0
+{extra}    rooms: {{}}
+    cmis: []
+    ct:
+      host: {name}.church.tools
+      login_token: secret-{}
+    external_temperature_sensor:
+      timeout: 5
+",
+                &name[7..]
+            )
+        };
+        format!(
+            "tenants:\n{}{}",
+            section("parish-a", ""),
+            section("parish-b", state_dir_b)
+        )
+    }
+
+    #[test]
+    fn tenant_sections() {
+        let sections = config_sections(&tenants_yaml("")).unwrap();
+        assert_eq!(
+            sections
+                .iter()
+                .map(|(tenant, x, _)| (tenant.as_deref(), x.ct.host.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (Some("parish-a"), "parish-a.church.tools"),
+                (Some("parish-b"), "parish-b.church.tools")
+            ]
+        );
+        // each tenant only sees its own section
+        assert!(sections[0].2.contains("parish-a.church.tools"));
+        assert!(!sections[0].2.contains("parish-b"));
+        assert!(!sections[1].2.contains("secret-b"));
+
+        let single = "global: {}\n";
+        assert!(matches!(
+            config_sections(single),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            config_sections("tenants: {}\n"),
+            Err(ConfigError::Invalid(CreateConfigError::NoTenants))
+        ));
+        let bad_name = tenants_yaml("").replace("parish-a:", "parish/a:");
+        assert!(matches!(
+            config_sections(&bad_name),
+            Err(ConfigError::Invalid(CreateConfigError::InvalidTenantName(
+                _
+            )))
+        ));
+        // sections of the single-config file are not allowed next to the tenants
+        let mixed = format!("{}database: memory\n", tenants_yaml(""));
+        assert!(matches!(
+            config_sections(&mixed),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn tenants_have_separate_state_dirs() {
+        let env = || Some(std::ffi::OsString::from("/var/lib/ct-ta-sync"));
+        assert_eq!(
+            tenant_state_dir(None, env(), Some("parish-a")),
+            PathBuf::from("/var/lib/ct-ta-sync/parish-a")
+        );
+        assert_eq!(
+            tenant_state_dir(Some(Path::new("/srv/b")), env(), Some("parish-b")),
+            PathBuf::from("/srv/b")
+        );
+        assert_eq!(
+            tenant_state_dir(None, env(), None),
+            PathBuf::from("/var/lib/ct-ta-sync")
+        );
+
+        let check = |yaml: &str| {
+            let sections = config_sections(yaml).unwrap();
+            let state_dirs = sections
+                .iter()
+                .map(|(tenant, x, _)| {
+                    tenant_state_dir(x.global.state_dir.as_deref(), env(), tenant.as_deref())
+                })
+                .collect::<Vec<_>>();
+            check_tenants_separate(&sections, &state_dirs)
+        };
+        assert!(check(&tenants_yaml("")).is_ok());
+        let shared = tenants_yaml("      state_dir: /var/lib/ct-ta-sync/parish-a\n");
+        assert!(matches!(
+            check(&shared),
+            Err(CreateConfigError::SharedStateDir(_, tenants)) if tenants == ["parish-a", "parish-b"]
+        ));
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn memory_db_is_kept_across_queries() {
+        let db = open_db(DatabaseMode::Memory, Path::new("/nonexistent/.bookings.db"))
+            .await
+            .unwrap();
+        crate::migrate::up(&db, Path::new("/nonexistent/backup"))
+            .await
+            .unwrap();
+        let now = Utc::now();
+        crate::db::insert_local_booking(&db, 41, now, now + chrono::TimeDelta::hours(1))
+            .await
+            .unwrap();
+        // a single connection must hold the DB, or the booking would be gone
+        assert_eq!(crate::db::get_local_bookings(&db).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn setpoint_is_clamped() {
+        let data = SetpointConfigData {
+            default: 18.0,
+            min: 16.0,
+            max: 22.5,
+        };
+        let setpoint = Setpoint::from_config_data(0, &data).unwrap();
+        assert_eq!(setpoint.value(None), 180);
+        assert_eq!(setpoint.value(Some(210)), 210);
+        assert_eq!(setpoint.value(Some(300)), 225);
+        assert_eq!(setpoint.value(Some(-50)), 160);
+        let data = SetpointConfigData {
+            default: 25.0,
+            min: 16.0,
+            max: 22.5,
+        };
+        assert!(Setpoint::from_config_data(0, &data).is_none());
+    }
+
+    #[tokio::test]
+    async fn virtual_can_ids_do_not_collide() {
+        let config = |rooms: &str| {
+            let yaml = format!(
+                "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms:
+  hall:
+    churchtools_id: 1
+    setpoint:
+      default: 18
+      min: 16
+      max: 22
+  chapel:
+    churchtools_id: 2
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  timeout: 5
+cmis:
+  - host: cmi.local
+    our_virtual_can_id: 59
+    feedback_can_id: 1
+    rooms:
+{rooms}
+"
+            );
+            let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+            Config::from_config_data(serde_yaml::from_str(&yaml).unwrap(), db)
+        };
+        let cmi = config(
+            "
+    - name: hall
+      pdo_index: 1
+      setpoint_pdo_index: 1
+    - name: chapel
+      pdo_index: 1
+      virtual_can_id: 58
+",
+        )
+        .unwrap();
+        assert_eq!(
+            cmi.cmis[0]
+                .rooms
+                .iter()
+                .map(|room| room.can_id)
+                .collect::<Vec<_>>(),
+            vec![59, 58]
+        );
+        assert!(matches!(
+            config(
+                "
+    - name: hall
+      pdo_index: 1
+    - name: chapel
+      pdo_index: 1
+"
+            ),
+            Err(CreateConfigError::OutputCollision(_, 59, 1))
+        ));
+        assert!(matches!(
+            config(
+                "
+    - name: hall
+      pdo_index: 1
+      virtual_can_id: 1
+"
+            ),
+            Err(CreateConfigError::CanIdIsFeedback(_, 1))
+        ));
+        assert!(matches!(
+            config(
+                "
+    - name: hall
+      pdo_index: 1
+      virtual_can_id: 63
+"
+            ),
+            Err(CreateConfigError::CanIdOutOfBounds(63))
+        ));
+    }
+
+    #[test]
+    fn cooling_above_threshold() {
+        let data = CoolingConfigData {
+            above: 24.5,
+            below: None,
+            pre_run_minutes: Some(30),
+            post_run_minutes: None,
+            max_dew_point: Some(16.0),
+        };
+        let cooling = Cooling::from_config_data(3, &data).unwrap();
+        assert!(!cooling.active(None, false));
+        assert!(!cooling.active(Some(245), false));
+        assert!(cooling.active(Some(246), false));
+        assert!(!cooling.active(Some(245), true));
+        assert!(!cooling.condensation_risk(None));
+        assert!(!cooling.condensation_risk(Some(160)));
+        assert!(cooling.condensation_risk(Some(161)));
+
+        let start = DateTime::parse_from_rfc3339("2025-07-13T09:00:00Z")
+            .unwrap()
+            .into();
+        let end = start + TimeDelta::hours(2);
+        assert_eq!(
+            cooling.window(start, end),
+            (start - TimeDelta::minutes(30), end)
+        );
+    }
+
+    #[test]
+    fn cooling_keeps_its_mode_between_below_and_above() {
+        let mut data = CoolingConfigData {
+            above: 24.5,
+            below: Some(23.0),
+            pre_run_minutes: None,
+            post_run_minutes: None,
+            max_dew_point: None,
+        };
+        let cooling = Cooling::from_config_data(3, &data).unwrap();
+        // entering cooling mode needs above
+        assert!(!cooling.active(Some(240), false));
+        assert!(cooling.active(Some(246), false));
+        // leaving it needs below
+        assert!(cooling.active(Some(240), true));
+        assert!(cooling.active(Some(231), true));
+        assert!(!cooling.active(Some(230), true));
+        assert!(!cooling.active(None, true));
+
+        data.below = Some(25.0);
+        assert!(Cooling::from_config_data(3, &data).is_none());
+    }
+
+    #[test]
+    fn config_hash_ignores_secrets_and_comments() {
+        let a = "ct:\n  host: a\n  login_token: secret1\n";
+        let b = "# a comment\nct:\n  host: a\n  login_token: secret2\n";
+        let c = "ct:\n  host: b\n  login_token: secret1\n";
+        let hash = |x| config_hash(&redacted_yaml(x).unwrap());
+        assert_eq!(hash(a), hash(b));
+        assert_ne!(hash(a), hash(c));
+        assert!(!redacted_yaml(a).unwrap().contains("secret1"));
+    }
+
+    #[test]
+    fn config_diff_is_structural() {
+        let old = "global:\n  ct_pull_frequency: 60\nrooms:\n  room1: {churchtools_id: 1}\n  room2: {churchtools_id: 2}\ncmis:\n  - host: a\n";
+        let new = "global:\n  ct_pull_frequency: 120\nrooms:\n  room1: {churchtools_id: 1}\n  room3: {churchtools_id: 3}\ncmis:\n  - host: a\n  - host: b\n";
+        assert_eq!(
+            config_diff(old, new).unwrap(),
+            vec![
+                "global.ct_pull_frequency: 60 -> 120",
+                "rooms.room2: removed",
+                "rooms.room3: added",
+                "cmis[1]: added",
+            ]
+        );
+        assert!(config_diff(old, old).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fallback_slot_windows() {
+        let slot: FallbackSlot =
+            serde_yaml::from_str("days: [Sun]\nstart: \"08:30\"\nend: \"12:00\"").unwrap();
+        let sunday = NaiveDate::from_ymd_opt(2025, 1, 12).unwrap();
+        let local = |h, min| {
+            sunday
+                .and_hms_opt(h, min, 0)
+                .unwrap()
+                .and_local_timezone(Local)
+                .unwrap()
+                .to_utc()
+        };
+        assert_eq!(slot.window_on(sunday), Some((local(8, 30), local(12, 0))));
+        assert_eq!(slot.window_on(sunday + TimeDelta::days(1)), None);
+        // saturday evening sees sunday's slot
+        let saturday = local(20, 0) - TimeDelta::days(1);
+        assert_eq!(slot.windows_around(saturday).count(), 1);
+    }
 }
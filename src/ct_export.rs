@@ -0,0 +1,288 @@
+//! Write the heating windows back into a field of the CT bookings, so bookers see what will be
+//! done for their events.
+
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use sqlx::{Pool, Sqlite};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    db::DBError,
+    i18n::Language,
+    ics::{heating_events, HeatingEvent},
+    read_ext_temp::ExternalTemperatures,
+    InShutdown,
+};
+
+/// Heating windows are written for the bookings of the next ... days
+const EXPORT_DAYS: i64 = 7;
+
+#[derive(Debug)]
+pub enum CtExportError {
+    Patch(reqwest::Error),
+    Status(i64, reqwest::StatusCode),
+    DB(DBError),
+}
+impl std::fmt::Display for CtExportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Patch(e) => write!(f, "Cannot update the booking in CT. reqwest Error: {e}"),
+            Self::Status(id, status) => {
+                write!(f, "CT returned {status} when updating booking {id}.")
+            }
+            Self::DB(e) => write!(f, "DBError: {e}"),
+        }
+    }
+}
+impl std::error::Error for CtExportError {}
+impl From<DBError> for CtExportError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+
+/// The text written to a booking heating `events`, in local time
+fn export_text(events: &[&HeatingEvent], language: Language) -> String {
+    let scheduled = match language {
+        Language::En => "heating scheduled",
+        Language::De => "Heizung geplant",
+    };
+    let window = |event: &HeatingEvent| {
+        format!(
+            "{scheduled} {}–{}",
+            event.start.with_timezone(&Local).format("%H:%M"),
+            event.end.with_timezone(&Local).format("%H:%M"),
+        )
+    };
+    match events {
+        [event] => window(event),
+        _ => events
+            .iter()
+            .map(|event| format!("{}: {}", event.room, window(event)))
+            .collect::<Vec<_>>()
+            .join("; "),
+    }
+}
+
+/// Set `field` of the CT booking `booking_id` to `text`.
+///
+/// Returns the new modification date of the booking, if CT sent it.
+async fn patch_booking(
+    config: &Config,
+    field: &str,
+    booking_id: i64,
+    text: String,
+) -> Result<Option<DateTime<Utc>>, CtExportError> {
+    let response = reqwest::Client::new()
+        .patch(format!(
+            "https://{}/api/bookings/{booking_id}",
+            config.ct.host
+        ))
+        .header("Authorization", format!("Login {}", config.ct.login_token))
+        .header("Content-Type", "application/json")
+        .body(serde_json::json!({ field: text }).to_string())
+        .send()
+        .await
+        .map_err(CtExportError::Patch)?;
+    if !response.status().is_success() {
+        return Err(CtExportError::Status(booking_id, response.status()));
+    };
+    // the date is only needed to recognize our own change on the next pull
+    Ok(response
+        .text()
+        .await
+        .ok()
+        .and_then(|x| serde_json::from_str::<serde_json::Value>(&x).ok())
+        .as_ref()
+        .and_then(|x| x.pointer("/data/meta/modifiedDate"))
+        .and_then(serde_json::Value::as_str)
+        .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+        .map(Into::into))
+}
+
+/// Write each of `texts` (by booking id) with `patch`, unless it was written before.
+///
+/// A booking that cannot be updated (e.g. it was deleted in CT) does not keep the others from
+/// being updated. It is retried on the next export.
+/// Returns the number of bookings updated.
+async fn patch_changed<F, Fut>(
+    db: &Pool<Sqlite>,
+    texts: BTreeMap<i64, String>,
+    patch: F,
+) -> Result<usize, DBError>
+where
+    F: Fn(i64, String) -> Fut,
+    Fut: Future<Output = Result<Option<DateTime<Utc>>, CtExportError>>,
+{
+    let exported = crate::db::get_ct_exports(db).await?;
+    let mut updated = 0;
+    for (booking_id, text) in texts {
+        // every write bumps the modification date in CT, so unchanged texts are not written
+        if exported.get(&booking_id) == Some(&text) {
+            continue;
+        };
+        match patch(booking_id, text.clone()).await {
+            Ok(modified_at) => {
+                crate::db::set_ct_export(db, booking_id, &text, modified_at).await?;
+                updated += 1;
+            }
+            Err(e) => warn!("Failed to write the heating windows of booking {booking_id} to CT: {e}"),
+        };
+    }
+    Ok(updated)
+}
+
+/// Write the heating windows of upcoming CT bookings whose window changed since the last export.
+///
+/// Returns the number of bookings updated.
+pub async fn export_once(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+) -> Result<usize, CtExportError> {
+    let Some(field) = &config.ct.heating_field else {
+        return Ok(0);
+    };
+    let now = Utc::now();
+    let events = heating_events(config, ext_temps, now, now + TimeDelta::days(EXPORT_DAYS)).await?;
+    let mut by_booking = BTreeMap::<i64, Vec<&HeatingEvent>>::new();
+    // only bookings from CT have positive ids
+    for event in events.iter().filter(|event| event.booking_id > 0) {
+        by_booking.entry(event.booking_id).or_default().push(event);
+    }
+    let texts = by_booking
+        .into_iter()
+        .map(|(booking_id, events)| (booking_id, export_text(&events, config.global.language)))
+        .collect();
+    Ok(patch_changed(&config.db, texts, |booking_id, text| {
+        patch_booking(config, field, booking_id, text)
+    })
+    .await?)
+}
+
+/// Continually write the heating windows back to CT.
+pub async fn keep_ct_export_up_to_date(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    ext_temps: ExternalTemperatures,
+) {
+    if config.ct.heating_field.is_none() {
+        debug!("No heating_field configured, not writing heating windows to CT.");
+        return;
+    };
+    info!("Starting CT export task");
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        config.global.ct_pull_frequency,
+    ));
+    interval.tick().await;
+    loop {
+        match export_once(&config, &ext_temps).await {
+            Ok(x) => debug!("Wrote the heating windows of {x} bookings to CT."),
+            Err(e) => warn!("Failed to write heating windows to CT. Error encountered: {e}"),
+        };
+        // stop on cancellation or continue after the next tick
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down CT export task now.");
+                return;
+            }
+            _ = interval.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::{collections::HashMap, sync::Mutex};
+
+    use chrono::{NaiveDate, TimeZone};
+    use sqlx::SqlitePool;
+
+    use crate::Booking;
+
+    fn at(h: u32, min: u32) -> DateTime<Utc> {
+        Local
+            .from_local_datetime(
+                &NaiveDate::from_ymd_opt(2025, 1, 12)
+                    .unwrap()
+                    .and_hms_opt(h, min, 0)
+                    .unwrap(),
+            )
+            .unwrap()
+            .to_utc()
+    }
+
+    fn event(room: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> HeatingEvent {
+        HeatingEvent {
+            room: room.to_owned(),
+            booking_id: 42,
+            booking_start: at(9, 0),
+            booking_end: at(12, 0),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn heating_window_text() {
+        let hall = event("Hall", at(8, 15), at(11, 50));
+        assert_eq!(
+            export_text(&[&hall], Language::En),
+            "heating scheduled 08:15–11:50"
+        );
+        let stage = event("Stage", at(8, 30), at(12, 0));
+        assert_eq!(
+            export_text(&[&hall, &stage], Language::En),
+            "Hall: heating scheduled 08:15–11:50; Stage: heating scheduled 08:30–12:00"
+        );
+        assert_eq!(
+            export_text(&[&hall], Language::De),
+            "Heizung geplant 08:15–11:50"
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn failed_patches_do_not_stop_the_export(pool: SqlitePool) {
+        for booking_id in [1, 2, 3] {
+            let booking = Booking::new(booking_id, 10, at(9, 0), at(12, 0)).unwrap();
+            crate::db::insert_booking(&pool, &booking).await.unwrap();
+        }
+        crate::db::set_ct_export(&pool, 1, "a", None).await.unwrap();
+        let texts = BTreeMap::from([
+            (1, "a".to_owned()),
+            (2, "b".to_owned()),
+            (3, "c".to_owned()),
+        ]);
+        let patched = Mutex::new(vec![]);
+        let updated = patch_changed(&pool, texts, |booking_id, _| {
+            patched.lock().unwrap().push(booking_id);
+            async move {
+                match booking_id {
+                    2 => Err(CtExportError::Status(2, reqwest::StatusCode::FORBIDDEN)),
+                    _ => Ok(Some(at(8, 0))),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        // 1 is unchanged, 2 failed
+        assert_eq!(updated, 1);
+        assert_eq!(patched.into_inner().unwrap(), vec![2, 3]);
+        assert_eq!(
+            crate::db::get_ct_exports(&pool).await.unwrap(),
+            HashMap::from([(1, "a".to_owned()), (3, "c".to_owned())])
+        );
+        // our own change is not taken for a change in CT
+        let modified = crate::db::get_all_bookings(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.booking_id(), x.modified_at()))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(modified[&2], None);
+        assert_eq!(modified[&3], Some(at(8, 0)));
+    }
+}
@@ -0,0 +1,169 @@
+//! Daemon settings for init systems that do not set them up for us: umask, PID file and privilege
+//! drop.
+//!
+//! umask and privilege drop only exist on unix. Elsewhere, only the PID file is supported.
+
+use std::path::Path;
+
+#[cfg(unix)]
+use nix::{
+    sys::stat::{umask, Mode},
+    unistd::{chown, setgid, setgroups, setuid, Group, User},
+};
+#[cfg(unix)]
+use tracing::info;
+use tracing::{debug, warn};
+
+use crate::config::DaemonConfig;
+
+#[derive(Debug)]
+pub enum DaemonError {
+    UserNotFound(String),
+    GroupNotFound(String),
+    PidFile(std::io::Error),
+    #[cfg(unix)]
+    Nix(nix::Error),
+    /// The setting is not supported on this platform
+    #[cfg(not(unix))]
+    Unsupported(&'static str),
+}
+impl std::fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UserNotFound(x) => write!(f, "User {x} does not exist."),
+            Self::GroupNotFound(x) => write!(f, "Group {x} does not exist."),
+            Self::PidFile(e) => write!(f, "Unable to write the PID file: {e}"),
+            #[cfg(unix)]
+            Self::Nix(e) => write!(f, "Unable to drop privileges: {e}"),
+            #[cfg(not(unix))]
+            Self::Unsupported(x) => write!(f, "{x} is not supported on this platform."),
+        }
+    }
+}
+#[cfg(unix)]
+impl From<nix::Error> for DaemonError {
+    fn from(value: nix::Error) -> Self {
+        Self::Nix(value)
+    }
+}
+impl std::error::Error for DaemonError {}
+
+/// Set the umask and write the PID file, if configured
+pub fn setup(daemon: &DaemonConfig) -> Result<(), DaemonError> {
+    if let Some(mask) = daemon.umask {
+        #[cfg(unix)]
+        {
+            umask(Mode::from_bits_truncate(mask));
+            debug!("Set umask to {mask:04o}.");
+        }
+        #[cfg(not(unix))]
+        warn!("Ignoring umask {mask:04o}: umask is not supported on this platform.");
+    };
+    if let Some(pid_file) = &daemon.pid_file {
+        std::fs::write(pid_file, format!("{}\n", std::process::id()))
+            .map_err(DaemonError::PidFile)?;
+        debug!("Wrote PID file {}.", pid_file.display());
+    };
+    Ok(())
+}
+
+/// Remove the PID file, if configured.
+///
+/// After dropping privileges, its directory may not be writable for us anymore. The file was
+/// handed over to the user then, so it is emptied instead.
+pub fn teardown(daemon: &DaemonConfig) {
+    if let Some(pid_file) = &daemon.pid_file {
+        let removed = std::fs::remove_file(pid_file).or_else(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => {
+                debug!(
+                    "Unable to remove the PID file {}: {e}. Emptying it instead.",
+                    pid_file.display()
+                );
+                std::fs::write(pid_file, "")
+            }
+            _ => Err(e),
+        });
+        if let Err(e) = removed {
+            warn!("Unable to remove the PID file {}: {e}", pid_file.display());
+        };
+    };
+}
+
+/// Switch to the configured user and group.
+///
+/// This has to be called after all privileged sockets are bound. The databases (one per tenant)
+/// are handed over to the user, so new connections can still open them. So are the state
+/// directories we created for tenants, since sqlite creates its journal there, and the PID file,
+/// so it can be removed or emptied on shutdown. `files` (e.g. the GPIO pins of status LEDs) are
+/// written while running and handed over as well.
+#[cfg(unix)]
+pub fn drop_privileges(
+    daemon: &DaemonConfig,
+    db_paths: &[&Path],
+    tenant_dirs: &[&Path],
+    files: &[&Path],
+) -> Result<(), DaemonError> {
+    let user = match &daemon.user {
+        Some(name) => Some(User::from_name(name)?.ok_or(DaemonError::UserNotFound(name.clone()))?),
+        None => None,
+    };
+    // default to the primary group of the user
+    let gid = match &daemon.group {
+        Some(name) => Some(
+            Group::from_name(name)?
+                .ok_or(DaemonError::GroupNotFound(name.clone()))?
+                .gid,
+        ),
+        None => user.as_ref().map(|u| u.gid),
+    };
+    if user.is_none() && gid.is_none() {
+        return Ok(());
+    };
+    // sqlite creates its journal next to the database
+    for db_path in db_paths {
+        for suffix in ["", "-journal", "-wal", "-shm"] {
+            let mut path = db_path.as_os_str().to_owned();
+            path.push(suffix);
+            if Path::new(&path).exists() {
+                chown(path.as_os_str(), user.as_ref().map(|u| u.uid), gid)?;
+            };
+        }
+    }
+    for path in tenant_dirs.iter().chain(files) {
+        chown(*path, user.as_ref().map(|u| u.uid), gid)?;
+    }
+    if let Some(pid_file) = &daemon.pid_file {
+        chown(pid_file, user.as_ref().map(|u| u.uid), gid)?;
+    };
+    if let Some(gid) = gid {
+        setgroups(&[gid])?;
+        setgid(gid)?;
+    };
+    if let Some(user) = &user {
+        setuid(user.uid)?;
+    };
+    info!(
+        "Dropped privileges to user {} and group {}.",
+        daemon.user.as_deref().unwrap_or("(unchanged)"),
+        daemon
+            .group
+            .as_deref()
+            .or(daemon.user.as_deref().map(|_| "(primary group of user)"))
+            .unwrap_or("(unchanged)")
+    );
+    Ok(())
+}
+
+/// Switching users is not supported here; fail if it was configured
+#[cfg(not(unix))]
+pub fn drop_privileges(
+    daemon: &DaemonConfig,
+    _db_paths: &[&Path],
+    _tenant_dirs: &[&Path],
+    _files: &[&Path],
+) -> Result<(), DaemonError> {
+    if daemon.user.is_some() || daemon.group.is_some() {
+        return Err(DaemonError::Unsupported("Dropping privileges"));
+    };
+    Ok(())
+}
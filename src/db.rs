@@ -1,27 +1,169 @@
 //! All the db-related functions
 
-use chrono::{format::StrftimeItems, NaiveDateTime, Timelike};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, NaiveDateTime, NaiveTime, TimeDelta, Utc};
 use sqlx::{Pool, Sqlite};
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use crate::Booking;
 
-/// sqlite does not have tz-aware types, so we can only get NaiveDateTime from it.
-/// We ALWAYS STORE UTC DATETIMES IN SQLITE.
+/// The Unix timestamp a naive (UTC) datetime is stored as.
+///
+/// All datetimes are stored as INTEGER seconds since the epoch, which sqlite compares and indexes
+/// as numbers instead of strings.
+fn timestamp(time: NaiveDateTime) -> i64 {
+    time.and_utc().timestamp()
+}
+
+/// Whether a timestamp out of range was read from the DB already, so it is only warned about once
+static INVALID_TIMESTAMP_READ: AtomicBool = AtomicBool::new(false);
+
+/// Read a datetime stored by [timestamp]
+///
+/// Timestamps out of range (more than 262,000 years from now) cannot have been written by us. They
+/// are read as the epoch.
+fn from_timestamp(timestamp: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(timestamp, 0).unwrap_or_else(|| {
+        if INVALID_TIMESTAMP_READ.swap(true, Ordering::Relaxed) {
+            debug!("Read the invalid timestamp {timestamp} from the DB as 1970-01-01.");
+        } else {
+            warn!("Read the invalid timestamp {timestamp} from the DB as 1970-01-01. Was the DB changed by hand?");
+        };
+        DateTime::default()
+    })
+}
+
+/// Ids of the bookings in the DB already reported as invalid, so they are not reported on every
+/// read
+static INVALID_BOOKINGS: Mutex<BTreeSet<i64>> = Mutex::new(BTreeSet::new());
+
+/// A booking as stored in sqlite, with Unix timestamps
 struct NaiveBooking {
     booking_id: i64,
     resource_id: i64,
-    start_time: chrono::NaiveDateTime,
-    end_time: chrono::NaiveDateTime,
+    start_time: i64,
+    end_time: i64,
+    modified_at: Option<i64>,
+    requested_temperature: Option<i64>,
 }
 impl NaiveBooking {
-    /// Taking a naive booking, interpret all datetimes as UTC datetimes
-    fn interpret_as_utc(self) -> crate::Booking {
-        Booking {
-            booking_id: self.booking_id,
-            resource_id: self.resource_id,
-            start_time: self.start_time.and_utc(),
-            end_time: self.end_time.and_utc(),
+    /// Taking a naive booking, interpret all datetimes as UTC datetimes.
+    ///
+    /// Bookings with an invalid interval are skipped, so they are never heated for.
+    fn interpret_as_utc(self) -> Option<crate::Booking> {
+        match Booking::new(
+            self.booking_id,
+            self.resource_id,
+            from_timestamp(self.start_time),
+            from_timestamp(self.end_time),
+        ) {
+            Ok(booking) => Some(
+                booking
+                    .with_modified_at(self.modified_at.map(from_timestamp))
+                    .with_requested_temperature(self.requested_temperature.map(|x| x as i32)),
+            ),
+            Err(e) => {
+                let new = INVALID_BOOKINGS
+                    .lock()
+                    .map(|mut x| x.insert(self.booking_id))
+                    .unwrap_or(true);
+                if new {
+                    warn!("Ignoring booking {} in the DB: {e}", self.booking_id);
+                } else {
+                    debug!("Ignoring booking {} in the DB: {e}", self.booking_id);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// A single external temperature sample
+#[derive(Debug, PartialEq)]
+pub struct ExternalTemperatureSample {
+    /// ALL DATETIMES ARE UTC.
+    pub recorded_at: DateTime<Utc>,
+    /// in tenths of a Degree Centigrade
+    pub temperature: i32,
+}
+
+/// Statistics of a single run of the pull or push task
+#[derive(Debug, PartialEq)]
+pub struct CycleStat {
+    /// ALL DATETIMES ARE UTC.
+    pub recorded_at: DateTime<Utc>,
+    /// "pull" or "push"
+    pub task: String,
+    pub duration_ms: i64,
+    /// bookings pulled or packets pushed
+    pub items: i64,
+    pub errors: i64,
+}
+
+/// A cycle stat as stored in sqlite, with Unix timestamps
+struct NaiveCycleStat {
+    recorded_at: i64,
+    task: String,
+    duration_ms: i64,
+    items: i64,
+    errors: i64,
+}
+impl NaiveCycleStat {
+    fn interpret_as_utc(self) -> CycleStat {
+        CycleStat {
+            recorded_at: from_timestamp(self.recorded_at),
+            task: self.task,
+            duration_ms: self.duration_ms,
+            items: self.items,
+            errors: self.errors,
+        }
+    }
+}
+
+/// An external temperature sample as stored in sqlite, with Unix timestamps
+struct NaiveExternalTemperatureSample {
+    recorded_at: i64,
+    temperature: i64,
+}
+impl NaiveExternalTemperatureSample {
+    fn interpret_as_utc(self) -> ExternalTemperatureSample {
+        ExternalTemperatureSample {
+            recorded_at: from_timestamp(self.recorded_at),
+            temperature: self.temperature as i32,
+        }
+    }
+}
+
+/// The forecast weather for a single point in time
+#[derive(Debug, PartialEq)]
+pub struct ForecastSample {
+    /// ALL DATETIMES ARE UTC.
+    pub forecast_for: DateTime<Utc>,
+    /// in tenths of a Degree Centigrade
+    pub temperature: i32,
+    /// solar irradiance in W/m², if the provider gave one
+    pub irradiance: Option<i32>,
+}
+
+/// A forecast as stored in sqlite, with Unix timestamps
+struct NaiveForecastSample {
+    forecast_for: i64,
+    temperature: i64,
+    irradiance: Option<i64>,
+}
+impl NaiveForecastSample {
+    fn interpret_as_utc(self) -> ForecastSample {
+        ForecastSample {
+            forecast_for: from_timestamp(self.forecast_for),
+            temperature: self.temperature as i32,
+            irradiance: self.irradiance.map(|x| x as i32),
         }
     }
 }
@@ -31,7 +173,33 @@ pub enum DBError {
     SelectBookings(sqlx::Error),
     InsertBooking(sqlx::Error),
     DeleteBooking(sqlx::Error),
-    UpdateBooking(sqlx::Error),
+    SyncBookings(sqlx::Error),
+    SelectExternalTemperatures(sqlx::Error),
+    InsertExternalTemperature(sqlx::Error),
+    DeleteExternalTemperatures(sqlx::Error),
+    SelectForecasts(sqlx::Error),
+    #[cfg(feature = "weather")]
+    InsertForecast(sqlx::Error),
+    #[cfg(feature = "weather")]
+    DeleteForecasts(sqlx::Error),
+    SelectRoomMaintenance(sqlx::Error),
+    #[cfg(feature = "http")]
+    SetRoomMaintenance(sqlx::Error),
+    SelectResourceParents(sqlx::Error),
+    ReplaceResourceParents(sqlx::Error),
+    SelectCycleStats(sqlx::Error),
+    InsertCycleStat(sqlx::Error),
+    DeleteCycleStats(sqlx::Error),
+    SelectActiveConfig(sqlx::Error),
+    SetActiveConfig(sqlx::Error),
+    InstanceLease(sqlx::Error),
+    ArchiveBookings(sqlx::Error),
+    SelectResourceTypeRooms(sqlx::Error),
+    SetResourceTypeRoom(sqlx::Error),
+    ReplaceExternalBookings(sqlx::Error),
+    SelectCtExports(sqlx::Error),
+    SetCtExport(sqlx::Error),
+    WriteProbe(sqlx::Error),
 }
 impl std::fmt::Display for DBError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -45,174 +213,1009 @@ impl std::fmt::Display for DBError {
             Self::InsertBooking(e) => {
                 write!(f, "Unable to insert booking into the DB. Inner Error: {e}.")
             }
-            Self::UpdateBooking(e) => {
-                write!(f, "Unable to update booking in the DB. Inner Error: {e}.")
+            Self::SyncBookings(e) => {
+                write!(f, "Unable to sync bookings into the DB. Inner Error: {e}.")
             }
             Self::DeleteBooking(e) => {
                 write!(f, "Unable to delete booking from the DB. Inner Error: {e}.")
             }
+            Self::SelectExternalTemperatures(e) => {
+                write!(
+                    f,
+                    "Unable to select external temperatures from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertExternalTemperature(e) => {
+                write!(
+                    f,
+                    "Unable to insert external temperature into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteExternalTemperatures(e) => {
+                write!(
+                    f,
+                    "Unable to delete external temperatures from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectForecasts(e) => {
+                write!(f, "Unable to select forecasts from the DB. Inner Error: {e}.")
+            }
+            #[cfg(feature = "weather")]
+            Self::InsertForecast(e) => {
+                write!(f, "Unable to insert forecast into the DB. Inner Error: {e}.")
+            }
+            #[cfg(feature = "weather")]
+            Self::DeleteForecasts(e) => {
+                write!(f, "Unable to delete forecasts from the DB. Inner Error: {e}.")
+            }
+            Self::SelectRoomMaintenance(e) => {
+                write!(
+                    f,
+                    "Unable to select rooms in maintenance from the DB. Inner Error: {e}."
+                )
+            }
+            #[cfg(feature = "http")]
+            Self::SetRoomMaintenance(e) => {
+                write!(
+                    f,
+                    "Unable to set the maintenance mode of a room in the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectResourceParents(e) => {
+                write!(
+                    f,
+                    "Unable to select resource parents from the DB. Inner Error: {e}."
+                )
+            }
+            Self::ReplaceResourceParents(e) => {
+                write!(
+                    f,
+                    "Unable to replace resource parents in the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectCycleStats(e) => {
+                write!(
+                    f,
+                    "Unable to select cycle stats from the DB. Inner Error: {e}."
+                )
+            }
+            Self::InsertCycleStat(e) => {
+                write!(
+                    f,
+                    "Unable to insert cycle stat into the DB. Inner Error: {e}."
+                )
+            }
+            Self::DeleteCycleStats(e) => {
+                write!(
+                    f,
+                    "Unable to delete cycle stats from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectActiveConfig(e) => {
+                write!(
+                    f,
+                    "Unable to select the active config from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SetActiveConfig(e) => {
+                write!(
+                    f,
+                    "Unable to set the active config in the DB. Inner Error: {e}."
+                )
+            }
+            Self::InstanceLease(e) => {
+                write!(
+                    f,
+                    "Unable to take or renew the instance lease. Inner Error: {e}."
+                )
+            }
+            Self::ArchiveBookings(e) => {
+                write!(
+                    f,
+                    "Unable to insert bookings into the archive. Inner Error: {e}."
+                )
+            }
+            Self::SelectResourceTypeRooms(e) => {
+                write!(
+                    f,
+                    "Unable to select the rooms of resource types from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SetResourceTypeRoom(e) => {
+                write!(
+                    f,
+                    "Unable to store the room of a resource type in the DB. Inner Error: {e}."
+                )
+            }
+            Self::ReplaceExternalBookings(e) => {
+                write!(
+                    f,
+                    "Unable to replace the bookings of an external calendar in the DB. Inner Error: {e}."
+                )
+            }
+            Self::SelectCtExports(e) => {
+                write!(
+                    f,
+                    "Unable to get the texts written back to CT from the DB. Inner Error: {e}."
+                )
+            }
+            Self::SetCtExport(e) => {
+                write!(
+                    f,
+                    "Unable to store a text written back to CT in the DB. Inner Error: {e}."
+                )
+            }
+            Self::WriteProbe(e) => {
+                write!(f, "Unable to write to the DB. Inner Error: {e}.")
+            }
         }
     }
 }
-impl std::error::Error for DBError {}
+impl std::error::Error for DBError {}
+
+/// Get all bookings in the db
+pub async fn get_all_bookings(db: &Pool<Sqlite>) -> Result<Vec<Booking>, DBError> {
+    Ok(sqlx::query_as!(
+        NaiveBooking,
+        "SELECT booking_id, resource_id, start_time, end_time, modified_at, \
+         requested_temperature FROM bookings;"
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectBookings)?
+    .into_iter()
+    .filter_map(NaiveBooking::interpret_as_utc)
+    .collect::<Vec<_>>())
+}
+
+/// Get all bookings in the db which intersect the interval [start, end]
+pub async fn get_bookings_in_timeframe(
+    db: &Pool<Sqlite>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<Booking>, DBError> {
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query_as!(
+        NaiveBooking,
+        "SELECT booking_id, resource_id, start_time, end_time, modified_at, \
+         requested_temperature FROM bookings \
+         WHERE start_time <= ? AND ? <= end_time;",
+        end,
+        start,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectBookings)?
+    .into_iter()
+    .filter_map(NaiveBooking::interpret_as_utc)
+    .collect::<Vec<_>>())
+}
+
+/// Get the bookings pulled from CT which intersect the interval [start, end].
+///
+/// Local bookings are left out, so they are not removed for missing in CT.
+pub async fn get_ct_bookings_in_timeframe(
+    db: &Pool<Sqlite>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<Booking>, DBError> {
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query_as!(
+        NaiveBooking,
+        "SELECT booking_id, resource_id, start_time, end_time, modified_at, \
+         requested_temperature FROM bookings \
+         WHERE start_time <= ? AND ? <= end_time AND source = 'ct';",
+        end,
+        start,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectBookings)?
+    .into_iter()
+    .filter_map(NaiveBooking::interpret_as_utc)
+    .collect::<Vec<_>>())
+}
+
+/// Get all local bookings (those not from CT), ordered by their start
+#[cfg(feature = "http")]
+pub async fn get_local_bookings(db: &Pool<Sqlite>) -> Result<Vec<Booking>, DBError> {
+    Ok(sqlx::query_as!(
+        NaiveBooking,
+        "SELECT booking_id, resource_id, start_time, end_time, modified_at, \
+         requested_temperature FROM bookings WHERE source = 'local' ORDER BY start_time;"
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectBookings)?
+    .into_iter()
+    .filter_map(NaiveBooking::interpret_as_utc)
+    .collect::<Vec<_>>())
+}
+
+/// Insert a local booking of `resource_id` into the DB.
+///
+/// Local bookings get negative ids, so they never collide with those from CT. Ids of archived local
+/// bookings are not reused, so their history is kept. External calendars use the ids below -2^32,
+/// see [external_booking_id].
+/// Returns the id of the new booking.
+#[cfg(feature = "http")]
+pub async fn insert_local_booking(
+    db: &Pool<Sqlite>,
+    resource_id: i64,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<i64, DBError> {
+    let start = start_time.timestamp();
+    let end = end_time.timestamp();
+    sqlx::query_scalar!(
+        "INSERT INTO bookings (booking_id, resource_id, start_time, end_time, source) \
+        VALUES (MIN(0, \
+        IFNULL((SELECT MIN(booking_id) FROM bookings WHERE source = 'local'), 0), \
+        IFNULL((SELECT MIN(booking_id) FROM booking_archive \
+        WHERE booking_id < 0 AND booking_id > -4294967296), 0)) - 1, ?, ?, ?, 'local') \
+        RETURNING booking_id;",
+        resource_id,
+        start,
+        end,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(DBError::InsertBooking)
+}
+
+/// Delete a local booking. Returns false if there is no local booking with this id.
+#[cfg(feature = "http")]
+pub async fn delete_local_booking(db: &Pool<Sqlite>, booking_id: i64) -> Result<bool, DBError> {
+    sqlx::query!(
+        "DELETE FROM bookings WHERE booking_id = ? AND source = 'local';",
+        booking_id,
+    )
+    .execute(db)
+    .await
+    .map(|x| x.rows_affected() != 0)
+    .map_err(DBError::DeleteBooking)
+}
+
+/// Replace all bookings from an external calendar with `bookings`.
+///
+/// `source` is `<kind>:<calendar>`, e.g. `caldav:<url>`.
+pub async fn replace_external_bookings(
+    db: &Pool<Sqlite>,
+    source: &str,
+    bookings: &[Booking],
+) -> Result<(), DBError> {
+    let mut tx = db.begin().await.map_err(DBError::ReplaceExternalBookings)?;
+    sqlx::query!("DELETE FROM bookings WHERE source = ?;", source)
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ReplaceExternalBookings)?;
+    for booking in bookings {
+        let start = booking.start_time().timestamp();
+        let end = booking.end_time().timestamp();
+        let booking_id = booking.booking_id();
+        let resource_id = booking.resource_id();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO bookings (booking_id, resource_id, start_time, end_time, \
+            source) VALUES (?, ?, ?, ?, ?);",
+            booking_id,
+            resource_id,
+            start,
+            end,
+            source,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ReplaceExternalBookings)?;
+    }
+    tx.commit().await.map_err(DBError::ReplaceExternalBookings)
+}
+
+/// Delete the bookings of all external calendars of `kind` (e.g. `caldav`), except `keep`.
+pub async fn prune_external_bookings(
+    db: &Pool<Sqlite>,
+    kind: &str,
+    keep: &[&str],
+) -> Result<u64, DBError> {
+    let prefix = format!("{kind}:");
+    let sources = sqlx::query_scalar!(
+        "SELECT DISTINCT source FROM bookings WHERE substr(source, 1, length(?)) = ?;",
+        prefix,
+        prefix,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::ReplaceExternalBookings)?;
+    let mut deleted = 0;
+    for source in sources
+        .iter()
+        .filter(|x| !keep.iter().any(|k| x.strip_prefix(&prefix) == Some(k)))
+    {
+        deleted += sqlx::query!("DELETE FROM bookings WHERE source = ?;", source)
+            .execute(db)
+            .await
+            .map_err(DBError::ReplaceExternalBookings)?
+            .rows_affected();
+    }
+    Ok(deleted)
+}
+
+/// A stable booking id for an event with `key` from an external calendar.
+///
+/// The ids are below -2^32, so they do not collide with those from CT (positive) or local
+/// bookings (small negative numbers).
+pub fn external_booking_id(source: &str, key: &str) -> i64 {
+    // FNV-1a, which is stable across builds unlike the std hasher
+    let hash = format!("{source}\n{key}")
+        .bytes()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    -(1_i64 << 32) - (hash >> 2) as i64
+}
+
+/// Insert a booking into the DB
+pub async fn insert_booking(db: &Pool<Sqlite>, booking: &Booking) -> Result<(), DBError> {
+    let start = booking.start_time().timestamp();
+    let end = booking.end_time().timestamp();
+    let modified_at = booking.modified_at().map(|x| x.timestamp());
+    let booking_id = booking.booking_id();
+    let resource_id = booking.resource_id();
+    let requested_temperature = booking.requested_temperature();
+    sqlx::query!(
+        "INSERT INTO bookings (booking_id, resource_id, start_time, end_time, modified_at, \
+        requested_temperature) VALUES (?, ?, ?, ?, ?, ?);
+        ",
+        booking_id,
+        resource_id,
+        start,
+        end,
+        modified_at,
+        requested_temperature,
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+    .map_err(DBError::InsertBooking)
+}
+
+pub async fn insert_bookings<'a, I: Iterator<Item = &'a Booking>>(
+    db: &Pool<Sqlite>,
+    bookings: I,
+) -> Result<(), DBError> {
+    for b in bookings {
+        insert_booking(db, b).await?;
+        info!("Inserted new booking: {b:?}");
+    }
+    Ok(())
+}
+
+/// Insert bookings from CT or update them if they exist, and delete `deletes`.
+///
+/// This runs in a single transaction, so an interrupted sync leaves the DB as it was, and
+/// repeating a sync (e.g. after two pulls overlapped) does not fail on existing bookings.
+pub async fn sync_bookings<'a>(
+    db: &Pool<Sqlite>,
+    upserts: impl IntoIterator<Item = &'a Booking>,
+    deletes: impl IntoIterator<Item = i64>,
+) -> Result<(), DBError> {
+    let mut tx = db.begin().await.map_err(DBError::SyncBookings)?;
+    for booking in upserts {
+        let start_time = booking.start_time().timestamp();
+        let end_time = booking.end_time().timestamp();
+        let modified_at = booking.modified_at().map(|x| x.timestamp());
+        let booking_id = booking.booking_id();
+        let resource_id = booking.resource_id();
+        let requested_temperature = booking.requested_temperature();
+        sqlx::query!(
+            "INSERT INTO bookings (booking_id, resource_id, start_time, end_time, modified_at, \
+            requested_temperature) VALUES (?, ?, ?, ?, ?, ?) \
+            ON CONFLICT (booking_id) DO UPDATE SET resource_id = excluded.resource_id, \
+            start_time = excluded.start_time, end_time = excluded.end_time, \
+            modified_at = excluded.modified_at, \
+            requested_temperature = excluded.requested_temperature;",
+            booking_id,
+            resource_id,
+            start_time,
+            end_time,
+            modified_at,
+            requested_temperature,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::SyncBookings)?;
+    }
+    for booking_id in deletes {
+        sqlx::query!("DELETE FROM bookings WHERE booking_id = ?;", booking_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::SyncBookings)?;
+    }
+    tx.commit().await.map_err(DBError::SyncBookings)
+}
+
+/// Delete old bookings from the DB
+///
+/// This removes all bookings which have ended anytime before `todayT00:00:00`.
+/// In other words: bookings that have ended today are kept. This is because the CT Rest-API only
+/// allows granularity down to the day. If we removed bookings from earlier today, the same entries
+/// would constantly get rewritten and repruned.
+/// Bookings that ended less than `overrun` ago are kept as well, since rooms still run for them
+/// (see [crate::config::Config::max_overrun]).
+/// The pruned bookings are moved to the archive, so the past use of each room is known.
+pub async fn prune_old_bookings(db: &Pool<Sqlite>, overrun: TimeDelta) -> Result<u64, DBError> {
+    let now = Utc::now();
+    let time = timestamp(
+        now.date_naive()
+            .and_time(NaiveTime::MIN)
+            .min(now.naive_utc() - overrun),
+    );
+    let mut tx = db.begin().await.map_err(DBError::DeleteBooking)?;
+    sqlx::query!(
+        "INSERT OR REPLACE INTO booking_archive (booking_id, resource_id, start_time, end_time) \
+         SELECT booking_id, resource_id, start_time, end_time FROM bookings WHERE end_time < ?;",
+        time
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::ArchiveBookings)?;
+    let pruned = sqlx::query!("DELETE FROM bookings where end_time < ?;", time)
+        .execute(&mut *tx)
+        .await
+        .map(|x| x.rows_affected())
+        .map_err(DBError::DeleteBooking)?;
+    tx.commit().await.map_err(DBError::DeleteBooking)?;
+    Ok(pruned)
+}
+
+/// Record an external temperature sample
+pub async fn insert_external_temperature(
+    db: &Pool<Sqlite>,
+    sample: &ExternalTemperatureSample,
+) -> Result<(), DBError> {
+    let recorded_at = sample.recorded_at.timestamp();
+    sqlx::query!(
+        "INSERT OR REPLACE INTO external_temperatures (recorded_at, temperature) VALUES (?, ?);",
+        recorded_at,
+        sample.temperature,
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+    .map_err(DBError::InsertExternalTemperature)
+}
+
+/// Get all external temperature samples recorded within [start, end], oldest first
+pub async fn get_external_temperatures_in_timeframe(
+    db: &Pool<Sqlite>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<ExternalTemperatureSample>, DBError> {
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query_as!(
+        NaiveExternalTemperatureSample,
+        "SELECT recorded_at, temperature FROM external_temperatures \
+         WHERE ? <= recorded_at AND recorded_at <= ? ORDER BY recorded_at;",
+        start,
+        end,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectExternalTemperatures)?
+    .into_iter()
+    .map(|x| x.interpret_as_utc())
+    .collect::<Vec<_>>())
+}
+
+/// Delete external temperature samples older than `retention`
+pub async fn prune_old_external_temperatures(
+    db: &Pool<Sqlite>,
+    retention: TimeDelta,
+) -> Result<u64, DBError> {
+    let time = (chrono::Utc::now() - retention).timestamp();
+    sqlx::query!(
+        "DELETE FROM external_temperatures WHERE recorded_at < ?;",
+        time,
+    )
+    .execute(db)
+    .await
+    .map(|x| x.rows_affected())
+    .map_err(DBError::DeleteExternalTemperatures)
+}
+
+/// Insert forecasts into the DB, replacing older forecasts for the same time
+#[cfg(feature = "weather")]
+pub async fn insert_forecasts<'a, I: Iterator<Item = &'a ForecastSample>>(
+    db: &Pool<Sqlite>,
+    forecasts: I,
+) -> Result<(), DBError> {
+    let fetched_at = Utc::now().timestamp();
+    for forecast in forecasts {
+        let forecast_for = forecast.forecast_for.timestamp();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO forecasts (forecast_for, temperature, irradiance, fetched_at) \
+            VALUES (?, ?, ?, ?);",
+            forecast_for,
+            forecast.temperature,
+            forecast.irradiance,
+            fetched_at,
+        )
+        .execute(db)
+        .await
+        .map_err(DBError::InsertForecast)?;
+    }
+    Ok(())
+}
+
+/// Get all forecasts for times within [start, end], oldest first
+pub async fn get_forecasts_in_timeframe(
+    db: &Pool<Sqlite>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<ForecastSample>, DBError> {
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query_as!(
+        NaiveForecastSample,
+        "SELECT forecast_for, temperature, irradiance FROM forecasts \
+         WHERE ? <= forecast_for AND forecast_for <= ? ORDER BY forecast_for;",
+        start,
+        end,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectForecasts)?
+    .into_iter()
+    .map(|x| x.interpret_as_utc())
+    .collect::<Vec<_>>())
+}
+
+/// Delete forecasts for times before `before`
+#[cfg(feature = "weather")]
+pub async fn prune_old_forecasts(db: &Pool<Sqlite>, before: DateTime<Utc>) -> Result<u64, DBError> {
+    let time = before.timestamp();
+    sqlx::query!("DELETE FROM forecasts WHERE forecast_for < ?;", time)
+        .execute(db)
+        .await
+        .map(|x| x.rows_affected())
+        .map_err(DBError::DeleteForecasts)
+}
+
+/// Get the names of all rooms currently in maintenance mode
+pub async fn get_rooms_in_maintenance(db: &Pool<Sqlite>) -> Result<HashSet<String>, DBError> {
+    Ok(sqlx::query!("SELECT room FROM room_maintenance;")
+        .fetch_all(db)
+        .await
+        .map_err(DBError::SelectRoomMaintenance)?
+        .into_iter()
+        .map(|x| x.room)
+        .collect::<HashSet<_>>())
+}
+
+/// Put a room into maintenance mode or take it out of it
+#[cfg(feature = "http")]
+pub async fn set_room_maintenance(
+    db: &Pool<Sqlite>,
+    room: &str,
+    enabled: bool,
+) -> Result<(), DBError> {
+    if enabled {
+        let since = Utc::now().timestamp();
+        sqlx::query!(
+            "INSERT OR IGNORE INTO room_maintenance (room, since) VALUES (?, ?);",
+            room,
+            since,
+        )
+        .execute(db)
+        .await
+    } else {
+        sqlx::query!("DELETE FROM room_maintenance WHERE room = ?;", room)
+            .execute(db)
+            .await
+    }
+    .map(|_| ())
+    .map_err(DBError::SetRoomMaintenance)
+}
+
+/// Get the parent of each resource that has one, as resource_id -> parent_id
+pub async fn get_resource_parents(db: &Pool<Sqlite>) -> Result<HashMap<i64, i64>, DBError> {
+    Ok(
+        sqlx::query!("SELECT resource_id, parent_id FROM resource_parents;")
+            .fetch_all(db)
+            .await
+            .map_err(DBError::SelectResourceParents)?
+            .into_iter()
+            .map(|x| (x.resource_id, x.parent_id))
+            .collect::<HashMap<_, _>>(),
+    )
+}
+
+/// Replace the stored resource hierarchy with `parents` (resource_id, parent_id)
+pub async fn replace_resource_parents(
+    db: &Pool<Sqlite>,
+    parents: &[(i64, i64)],
+) -> Result<(), DBError> {
+    let mut tx = db.begin().await.map_err(DBError::ReplaceResourceParents)?;
+    sqlx::query!("DELETE FROM resource_parents;")
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ReplaceResourceParents)?;
+    for (resource_id, parent_id) in parents {
+        sqlx::query!(
+            "INSERT INTO resource_parents (resource_id, parent_id) VALUES (?, ?);",
+            resource_id,
+            parent_id,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ReplaceResourceParents)?;
+    }
+    tx.commit().await.map_err(DBError::ReplaceResourceParents)
+}
+
+/// Record the statistics of a single run of a task
+pub async fn insert_cycle_stat(db: &Pool<Sqlite>, stat: &CycleStat) -> Result<(), DBError> {
+    let recorded_at = stat.recorded_at.timestamp();
+    sqlx::query!(
+        "INSERT INTO cycle_stats (recorded_at, task, duration_ms, items, errors) \
+         VALUES (?, ?, ?, ?, ?);",
+        recorded_at,
+        stat.task,
+        stat.duration_ms,
+        stat.items,
+        stat.errors,
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+    .map_err(DBError::InsertCycleStat)
+}
+
+/// Get all cycle stats recorded within [start, end], oldest first
+pub async fn get_cycle_stats_in_timeframe(
+    db: &Pool<Sqlite>,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> Result<Vec<CycleStat>, DBError> {
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query_as!(
+        NaiveCycleStat,
+        "SELECT recorded_at, task, duration_ms, items, errors FROM cycle_stats \
+         WHERE ? <= recorded_at AND recorded_at <= ? ORDER BY recorded_at;",
+        start,
+        end,
+    )
+    .fetch_all(db)
+    .await
+    .map_err(DBError::SelectCycleStats)?
+    .into_iter()
+    .map(|x| x.interpret_as_utc())
+    .collect::<Vec<_>>())
+}
+
+/// Get the time of the last successful pull from CT that is still in the cycle stats
+pub async fn get_last_successful_pull(db: &Pool<Sqlite>) -> Result<Option<DateTime<Utc>>, DBError> {
+    let last = sqlx::query_scalar!(
+        r#"SELECT MAX(recorded_at) AS "recorded_at?: i64" FROM cycle_stats
+           WHERE task = 'pull' AND errors = 0;"#
+    )
+    .fetch_one(db)
+    .await
+    .map_err(DBError::SelectCycleStats)?;
+    Ok(last.map(from_timestamp))
+}
+
+/// Delete cycle stats older than `retention`
+pub async fn prune_old_cycle_stats(
+    db: &Pool<Sqlite>,
+    retention: TimeDelta,
+) -> Result<u64, DBError> {
+    let time = (chrono::Utc::now() - retention).timestamp();
+    sqlx::query!("DELETE FROM cycle_stats WHERE recorded_at < ?;", time)
+        .execute(db)
+        .await
+        .map(|x| x.rows_affected())
+        .map_err(DBError::DeleteCycleStats)
+}
+
+/// Get the (redacted) config of the last start, if there was one
+pub async fn get_active_config(db: &Pool<Sqlite>) -> Result<Option<String>, DBError> {
+    Ok(
+        sqlx::query!("SELECT content FROM active_config WHERE id = 0;")
+            .fetch_optional(db)
+            .await
+            .map_err(DBError::SelectActiveConfig)?
+            .map(|x| x.content),
+    )
+}
+
+/// Remember the (redacted) config of this start
+pub async fn set_active_config(db: &Pool<Sqlite>, content: &str) -> Result<(), DBError> {
+    let loaded_at = Utc::now().timestamp();
+    sqlx::query!(
+        "INSERT OR REPLACE INTO active_config (id, content, loaded_at) VALUES (0, ?, ?);",
+        content,
+        loaded_at,
+    )
+    .execute(db)
+    .await
+    .map(|_| ())
+    .map_err(DBError::SetActiveConfig)
+}
 
-#[allow(dead_code)]
-async fn get_all_bookings(db: &Pool<Sqlite>) -> Result<Vec<Booking>, DBError> {
-    Ok(sqlx::query_as!(
-        NaiveBooking,
-        "SELECT booking_id, resource_id, start_time, end_time FROM bookings;"
+/// Take or renew the instance lease for `owner`.
+///
+/// This succeeds if the lease is free, already ours, older than `lease`, or `force` is set.
+/// Otherwise, the current owner is returned.
+pub async fn take_instance_lease(
+    db: &Pool<Sqlite>,
+    owner: &str,
+    lease: TimeDelta,
+    force: bool,
+) -> Result<Result<(), String>, DBError> {
+    let now = Utc::now();
+    let heartbeat = now.timestamp();
+    let stale_before = (now - lease).timestamp();
+    // a single statement, so two instances starting at once cannot both get it
+    let taken = sqlx::query!(
+        "INSERT INTO instance_lease (id, owner, heartbeat) VALUES (0, ?, ?) \
+         ON CONFLICT (id) DO UPDATE SET owner = excluded.owner, heartbeat = excluded.heartbeat \
+         WHERE instance_lease.owner = excluded.owner OR instance_lease.heartbeat < ? OR ?;",
+        owner,
+        heartbeat,
+        stale_before,
+        force,
     )
-    .fetch_all(db)
+    .execute(db)
     .await
-    .map_err(DBError::SelectBookings)?
-    .into_iter()
-    .map(|x| x.interpret_as_utc())
-    .collect::<Vec<_>>())
+    .map_err(DBError::InstanceLease)?
+    .rows_affected()
+        == 1;
+    if taken {
+        return Ok(Ok(()));
+    };
+    let holder = sqlx::query!("SELECT owner FROM instance_lease WHERE id = 0;")
+        .fetch_optional(db)
+        .await
+        .map_err(DBError::InstanceLease)?
+        .map(|x| x.owner)
+        .unwrap_or_default();
+    Ok(Err(holder))
 }
 
-/// Get all bookings in the db which intersect the interval [start, end]
-pub async fn get_bookings_in_timeframe(
+/// The owner of the instance lease, if it was renewed at or after `fresh_since`.
+///
+/// Runs before migrating, so the table may not exist yet or still store heartbeats as text.
+pub async fn get_instance_lease_holder(
+    db: &Pool<Sqlite>,
+    fresh_since: DateTime<Utc>,
+) -> Result<Option<String>, DBError> {
+    // not checked against the schema at compile time, since the schema may be older
+    let exists = sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'instance_lease';",
+    )
+    .fetch_one(db)
+    .await
+    .map_err(DBError::InstanceLease)?
+        != 0;
+    if !exists {
+        return Ok(None);
+    };
+    sqlx::query_scalar::<_, String>(
+        "SELECT owner FROM instance_lease WHERE id = 0 AND CASE typeof(heartbeat) \
+         WHEN 'integer' THEN heartbeat ELSE CAST(strftime('%s', heartbeat) AS INTEGER) END >= ?;",
+    )
+    .bind(fresh_since.timestamp())
+    .fetch_optional(db)
+    .await
+    .map_err(DBError::InstanceLease)
+}
+
+/// Give up the instance lease, if `owner` still holds it
+pub async fn release_instance_lease(db: &Pool<Sqlite>, owner: &str) -> Result<(), DBError> {
+    sqlx::query!("DELETE FROM instance_lease WHERE owner = ?;", owner)
+        .execute(db)
+        .await
+        .map(|_| ())
+        .map_err(DBError::InstanceLease)
+}
+
+/// Insert past bookings into the archive, replacing those archived before.
+///
+/// The archive is used for reports and to extend the preheat of rooms that were not used for a
+/// while, never to decide whether to heat.
+/// Returns the number of bookings archived.
+pub async fn archive_bookings(db: &Pool<Sqlite>, bookings: &[Booking]) -> Result<u64, DBError> {
+    let mut tx = db.begin().await.map_err(DBError::ArchiveBookings)?;
+    for booking in bookings {
+        let start = booking.start_time().timestamp();
+        let end = booking.end_time().timestamp();
+        let booking_id = booking.booking_id();
+        let resource_id = booking.resource_id();
+        sqlx::query!(
+            "INSERT OR REPLACE INTO booking_archive (booking_id, resource_id, start_time, end_time) \
+             VALUES (?, ?, ?, ?);",
+            booking_id,
+            resource_id,
+            start,
+            end,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::ArchiveBookings)?;
+    }
+    tx.commit().await.map_err(DBError::ArchiveBookings)?;
+    Ok(bookings.len() as u64)
+}
+
+/// Get all bookings, current and archived, that start within [start, end], oldest first.
+///
+/// Only the interval and resource are archived, so the other fields are empty.
+pub async fn get_booking_history_in_timeframe(
     db: &Pool<Sqlite>,
     start: NaiveDateTime,
     end: NaiveDateTime,
 ) -> Result<Vec<Booking>, DBError> {
-    let fmt = StrftimeItems::new("%Y-%m-%dT%H:%M:%S");
-    let start_str = start.format_with_items(fmt.clone()).to_string();
-    let end_str = end.format_with_items(fmt.clone()).to_string();
-    Ok(sqlx::query_as!(
-        NaiveBooking,
+    let start = timestamp(start);
+    let end = timestamp(end);
+    Ok(sqlx::query!(
         "SELECT booking_id, resource_id, start_time, end_time FROM bookings \
-         WHERE start_time <= ? AND ? <= end_time;",
-        end_str,
-        start_str,
+         WHERE ? <= start_time AND start_time <= ? \
+         UNION SELECT booking_id, resource_id, start_time, end_time FROM booking_archive \
+         WHERE ? <= start_time AND start_time <= ? \
+         ORDER BY start_time;",
+        start,
+        end,
+        start,
+        end,
     )
     .fetch_all(db)
     .await
     .map_err(DBError::SelectBookings)?
     .into_iter()
-    .map(|x| x.interpret_as_utc())
+    .filter_map(|x| {
+        Booking::new(
+            x.booking_id,
+            x.resource_id,
+            from_timestamp(x.start_time),
+            from_timestamp(x.end_time),
+        )
+        .ok()
+    })
     .collect::<Vec<_>>())
 }
 
-/// Insert a booking into the DB
-pub async fn insert_booking(db: &Pool<Sqlite>, booking: &Booking) -> Result<(), DBError> {
-    let fmt = StrftimeItems::new("%Y-%m-%dT%H:%M:%S");
-    let start_str = booking
-        .start_time
-        .format_with_items(fmt.clone())
-        .to_string();
-    let end_str = booking.end_time.format_with_items(fmt.clone()).to_string();
-    sqlx::query!(
-        "INSERT INTO bookings (booking_id, resource_id, start_time, end_time) VALUES \
-        (?, ?, ?, ?);
-        ",
-        booking.booking_id,
-        booking.resource_id,
-        start_str,
-        end_str,
+/// A room added for a CT resource type on a CMI
+#[derive(Debug, PartialEq)]
+pub struct ResourceTypeRoom {
+    pub resource_id: i64,
+    pub type_id: i64,
+    pub name: String,
+    /// already shifted to 0-63
+    pub pdo_index: u8,
+}
+
+/// Get the rooms added for resource types on the CMI at `cmi_host`
+pub async fn get_resource_type_rooms(
+    db: &Pool<Sqlite>,
+    cmi_host: &str,
+) -> Result<Vec<ResourceTypeRoom>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT resource_id, type_id, name, pdo_index FROM resource_type_rooms WHERE cmi_host = ? \
+         ORDER BY resource_id;",
+        cmi_host,
     )
-    .execute(db)
+    .fetch_all(db)
     .await
-    .map(|_| ())
-    .map_err(DBError::InsertBooking)
+    .map_err(DBError::SelectResourceTypeRooms)?
+    .into_iter()
+    .map(|x| ResourceTypeRoom {
+        resource_id: x.resource_id,
+        type_id: x.type_id,
+        name: x.name,
+        pdo_index: x.pdo_index as u8,
+    })
+    .collect())
 }
 
-pub async fn insert_bookings<'a, I: Iterator<Item = &'a Booking>>(
+/// Remember the room added for a resource type on the CMI at `cmi_host`
+pub async fn set_resource_type_room(
     db: &Pool<Sqlite>,
-    bookings: I,
+    cmi_host: &str,
+    room: &ResourceTypeRoom,
 ) -> Result<(), DBError> {
-    for b in bookings {
-        insert_booking(db, b).await?;
-        info!("Inserted new booking: {b:?}");
-    }
-    Ok(())
-}
-
-pub async fn delete_booking(db: &Pool<Sqlite>, booking_id: i64) -> Result<(), DBError> {
     sqlx::query!(
-        "DELETE FROM bookings \
-        WHERE booking_id = ?;
-        ",
-        booking_id,
+        "INSERT OR REPLACE INTO resource_type_rooms \
+         (cmi_host, resource_id, type_id, name, pdo_index) VALUES (?, ?, ?, ?, ?);",
+        cmi_host,
+        room.resource_id,
+        room.type_id,
+        room.name,
+        room.pdo_index,
     )
     .execute(db)
     .await
     .map(|_| ())
-    .map_err(DBError::DeleteBooking)
+    .map_err(DBError::SetResourceTypeRoom)
 }
 
-pub async fn delete_bookings<'a, I: Iterator<Item = i64>>(
-    db: &Pool<Sqlite>,
-    bookings: I,
-) -> Result<(), DBError> {
-    for b in bookings {
-        delete_booking(db, b).await?;
-    }
-    Ok(())
+/// Check that the DB can be written to, without changing it.
+///
+/// A table is created and dropped again by rolling back, which needs the DB file and its journal
+/// to be writable.
+pub async fn check_writable(db: &Pool<Sqlite>) -> Result<(), DBError> {
+    let mut tx = db.begin().await.map_err(DBError::WriteProbe)?;
+    sqlx::query("CREATE TABLE write_probe (x INTEGER);")
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::WriteProbe)?;
+    tx.rollback().await.map_err(DBError::WriteProbe)
 }
 
-pub async fn update_booking(db: &Pool<Sqlite>, booking: &Booking) -> Result<(), DBError> {
-    let fmt = StrftimeItems::new("%Y-%m-%dT%H:%M:%S");
-    let start_time = booking
-        .start_time
-        .format_with_items(fmt.clone())
-        .to_string();
-    let end_time = booking.end_time.format_with_items(fmt).to_string();
+/// Get the texts last written back to CT bookings, by booking id.
+///
+/// Texts of bookings no longer in the db are deleted first.
+pub async fn get_ct_exports(db: &Pool<Sqlite>) -> Result<HashMap<i64, String>, DBError> {
     sqlx::query!(
-        "UPDATE bookings SET resource_id = ?, start_time = ?, end_time = ? \
-        WHERE booking_id = ?;
-        ",
-        booking.resource_id,
-        start_time,
-        end_time,
-        booking.booking_id,
+        "DELETE FROM ct_exports WHERE booking_id NOT IN (SELECT booking_id FROM bookings);"
     )
     .execute(db)
     .await
-    .map(|_| ())
-    .map_err(DBError::UpdateBooking)
+    .map_err(DBError::SelectCtExports)?;
+    Ok(sqlx::query!("SELECT booking_id, text FROM ct_exports;")
+        .fetch_all(db)
+        .await
+        .map_err(DBError::SelectCtExports)?
+        .into_iter()
+        .map(|x| (x.booking_id, x.text))
+        .collect())
 }
 
-pub async fn update_bookings<'a, I: Iterator<Item = &'a Booking>>(
+/// Remember the text written back to a CT booking.
+///
+/// Writing bumps the modification date of the booking in CT. With `modified_at`, that is stored
+/// too, so the next pull does not take the booking for changed.
+pub async fn set_ct_export(
     db: &Pool<Sqlite>,
-    bookings: I,
+    booking_id: i64,
+    text: &str,
+    modified_at: Option<DateTime<Utc>>,
 ) -> Result<(), DBError> {
-    for b in bookings {
-        update_booking(db, b).await?;
-        info!("Updated Booking {}. Is now: {:?}", b.booking_id, b)
-    }
-    Ok(())
-}
-
-/// Delete old bookings from the DB
-///
-/// This removes all bookings which have ended anytime before `todayT00:00:00`.
-/// In other words: bookings that have ended today are kept. This is because the CT Rest-API only
-/// allows granularity down to the day. If we removed bookings from earlier today, the same entries
-/// would constantly get rewritten and repruned.
-pub async fn prune_old_bookings(db: &Pool<Sqlite>) -> Result<u64, DBError> {
-    let time = chrono::Utc::now()
-        .naive_utc()
-        .with_hour(0)
-        .expect("zeroeth hour always exstis")
-        .with_minute(0)
-        .expect("zeroeth minute always exstis")
-        .with_second(0)
-        .expect("zeroeth second always exstis");
-    let fmt = StrftimeItems::new("%Y-%m-%dT%H:%M:%S");
-    let time_str = time.format_with_items(fmt).to_string();
-    sqlx::query!("DELETE FROM bookings where end_time < ?;", time_str,)
-        .execute(db)
+    let mut tx = db.begin().await.map_err(DBError::SetCtExport)?;
+    sqlx::query!(
+        "INSERT OR REPLACE INTO ct_exports (booking_id, text) VALUES (?, ?);",
+        booking_id,
+        text,
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::SetCtExport)?;
+    if let Some(modified_at) = modified_at.map(|x| x.timestamp()) {
+        sqlx::query!(
+            "UPDATE bookings SET modified_at = ? WHERE booking_id = ? AND source = 'ct';",
+            modified_at,
+            booking_id,
+        )
+        .execute(&mut *tx)
         .await
-        .map(|x| x.rows_affected())
-        .map_err(DBError::DeleteBooking)
+        .map_err(DBError::SetCtExport)?;
+    };
+    tx.commit().await.map_err(DBError::SetCtExport)
 }
 
+#[cfg(test)]
+#[path = "fixtures/archive.rs"]
+pub mod archive;
+
 #[cfg(test)]
 mod tests {
+    use super::archive::archive;
     use super::*;
 
-    use chrono::{DateTime, NaiveDate, TimeDelta};
+    use chrono::{DateTime, NaiveDate, TimeDelta, Timelike};
     use sqlx::SqlitePool;
 
     #[sqlx::test(fixtures("001_good_data"))]
@@ -221,29 +1224,31 @@ mod tests {
         assert_eq!(bookings.len(), 2);
         assert_eq!(
             bookings[0],
-            Booking {
-                booking_id: 123,
-                resource_id: 10,
-                start_time: DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
+            Booking::new(
+                123,
+                10,
+                DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
                     .unwrap()
                     .into(),
-                end_time: DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+                DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
                     .unwrap()
                     .into(),
-            }
+            )
+            .unwrap()
         );
         assert_eq!(
             bookings[1],
-            Booking {
-                booking_id: 125,
-                resource_id: 11,
-                start_time: DateTime::parse_from_rfc3339("2021-03-28T15:30:00+00:00")
+            Booking::new(
+                125,
+                11,
+                DateTime::parse_from_rfc3339("2021-03-28T15:30:00+00:00")
                     .unwrap()
                     .into(),
-                end_time: DateTime::parse_from_rfc3339("2021-03-28T17:00:00+00:00")
+                DateTime::parse_from_rfc3339("2021-03-28T17:00:00+00:00")
                     .unwrap()
                     .into(),
-            }
+            )
+            .unwrap()
         );
     }
 
@@ -261,22 +1266,23 @@ mod tests {
         assert_eq!(bookings.len(), 1);
         assert_eq!(
             bookings[0],
-            Booking {
-                booking_id: 123,
-                resource_id: 10,
-                start_time: DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
+            Booking::new(
+                123,
+                10,
+                DateTime::parse_from_rfc3339("2021-03-26T15:30:00+00:00")
                     .unwrap()
                     .into(),
-                end_time: DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
+                DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00")
                     .unwrap()
                     .into(),
-            }
+            )
+            .unwrap()
         );
     }
 
     #[sqlx::test(fixtures("001_good_data"))]
     async fn delete_single_booking(pool: SqlitePool) {
-        delete_booking(&pool, 123).await.unwrap();
+        sync_bookings(&pool, [], [123]).await.unwrap();
 
         let start = NaiveDate::from_ymd_opt(2021, 3, 26)
             .unwrap()
@@ -293,7 +1299,7 @@ mod tests {
     #[sqlx::test(fixtures("001_good_data"))]
     async fn delete_multiple_bookings(pool: SqlitePool) {
         let to_delete = vec![123, 125];
-        delete_bookings(&pool, to_delete.into_iter()).await.unwrap();
+        sync_bookings(&pool, [], to_delete).await.unwrap();
 
         let bookings = get_all_bookings(&pool).await.unwrap();
         assert_eq!(bookings.len(), 0);
@@ -301,17 +1307,20 @@ mod tests {
 
     #[sqlx::test(fixtures("001_good_data"))]
     async fn test_update_booking(pool: SqlitePool) {
-        let new_booking = Booking {
-            booking_id: 123,
-            resource_id: 10,
-            start_time: DateTime::parse_from_rfc3339("2021-04-26T15:30:00+00:00")
+        let new_booking = Booking::new(
+            123,
+            10,
+            DateTime::parse_from_rfc3339("2021-04-26T15:30:00+00:00")
                 .unwrap()
                 .into(),
-            end_time: DateTime::parse_from_rfc3339("2021-04-26T17:00:00+00:00")
+            DateTime::parse_from_rfc3339("2021-04-26T17:00:00+00:00")
                 .unwrap()
                 .into(),
-        };
-        update_booking(&pool, &new_booking).await.unwrap();
+        )
+        .unwrap();
+        sync_bookings(&pool, [&new_booking], []).await.unwrap();
+        // syncing again is a no-op
+        sync_bookings(&pool, [&new_booking], []).await.unwrap();
         let start = NaiveDate::from_ymd_opt(2021, 4, 20)
             .unwrap()
             .and_hms_opt(0, 0, 0)
@@ -327,16 +1336,17 @@ mod tests {
 
     #[sqlx::test(fixtures("001_good_data"))]
     async fn test_insert_booking(pool: SqlitePool) {
-        let new_booking = Booking {
-            booking_id: 12341234,
-            resource_id: 21,
-            start_time: DateTime::parse_from_rfc3339("2019-04-26T14:28:00+00:00")
+        let new_booking = Booking::new(
+            12341234,
+            21,
+            DateTime::parse_from_rfc3339("2019-04-26T14:28:00+00:00")
                 .unwrap()
                 .into(),
-            end_time: DateTime::parse_from_rfc3339("2019-04-26T18:00:00+00:00")
+            DateTime::parse_from_rfc3339("2019-04-26T18:00:00+00:00")
                 .unwrap()
                 .into(),
-        };
+        )
+        .unwrap();
         insert_booking(&pool, &new_booking).await.unwrap();
         let start = NaiveDate::from_ymd_opt(2019, 1, 1)
             .unwrap()
@@ -356,29 +1366,452 @@ mod tests {
         // insert booking for today and tomorrow
         let now = chrono::Utc::now().with_nanosecond(0).unwrap();
         let in_an_hour = now + TimeDelta::hours(1);
-        let booking_today = Booking {
-            resource_id: 31,
-            booking_id: 9999,
-            start_time: now,
-            end_time: in_an_hour,
-        };
+        let booking_today = Booking::new(9999, 31, now, in_an_hour).unwrap();
         let yesterday = now - TimeDelta::days(1);
         let yesterday_plus_one_hour = yesterday + TimeDelta::hours(1);
-        let booking_yesterday = Booking {
-            resource_id: 31,
-            booking_id: 8888,
-            start_time: yesterday,
-            end_time: yesterday_plus_one_hour,
-        };
+        let booking_yesterday = Booking::new(8888, 31, yesterday, yesterday_plus_one_hour).unwrap();
         insert_bookings(&pool, vec![&booking_yesterday, &booking_today].into_iter())
             .await
             .unwrap();
+        // the room may still run for yesterday's booking
+        assert_eq!(prune_old_bookings(&pool, TimeDelta::days(2)).await.unwrap(), 0);
         // prune
-        let rows_changed = prune_old_bookings(&pool).await.unwrap();
+        let rows_changed = prune_old_bookings(&pool, TimeDelta::zero()).await.unwrap();
         assert_eq!(rows_changed, 1);
         // check that only the one from tomorrow survives
         let bookings = get_all_bookings(&pool).await.unwrap();
         assert_eq!(bookings.len(), 1);
         assert_eq!(bookings[0], booking_today);
+        // the pruned one is kept in the history
+        let history = get_booking_history_in_timeframe(
+            &pool,
+            (yesterday - TimeDelta::hours(1)).naive_utc(),
+            (now + TimeDelta::hours(2)).naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(history, vec![booking_yesterday, booking_today]);
+    }
+
+    #[cfg(feature = "http")]
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_local_bookings(pool: SqlitePool) {
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let from_ct = Booking::new(9999, 31, now, now + TimeDelta::hours(1)).unwrap();
+        insert_booking(&pool, &from_ct).await.unwrap();
+        let first = insert_local_booking(&pool, 31, now, now + TimeDelta::hours(2))
+            .await
+            .unwrap();
+        let second = insert_local_booking(
+            &pool,
+            32,
+            now + TimeDelta::minutes(5),
+            now + TimeDelta::hours(3),
+        )
+        .await
+        .unwrap();
+        // negative ids do not collide with CT
+        assert_eq!((first, second), (-1, -2));
+
+        let start = (now - TimeDelta::hours(1)).naive_utc();
+        let end = (now + TimeDelta::hours(1)).naive_utc();
+        assert_eq!(
+            get_bookings_in_timeframe(&pool, start, end)
+                .await
+                .unwrap()
+                .len(),
+            3
+        );
+        // pulling from CT only compares against bookings from CT
+        assert_eq!(
+            get_ct_bookings_in_timeframe(&pool, start, end)
+                .await
+                .unwrap(),
+            vec![from_ct]
+        );
+        let local = get_local_bookings(&pool).await.unwrap();
+        assert_eq!(
+            local.iter().map(|b| b.resource_id()).collect::<Vec<_>>(),
+            vec![31, 32]
+        );
+
+        // bookings from CT cannot be deleted as local bookings
+        assert!(!delete_local_booking(&pool, 9999).await.unwrap());
+        assert!(delete_local_booking(&pool, first).await.unwrap());
+        assert!(!delete_local_booking(&pool, first).await.unwrap());
+        assert_eq!(get_all_bookings(&pool).await.unwrap().len(), 2);
+
+        // ids of pruned local bookings are not reused, those of external calendars are ignored
+        let archived = |booking_id| {
+            Booking::new(
+                booking_id,
+                31,
+                now - TimeDelta::days(3),
+                now - TimeDelta::days(2),
+            )
+            .unwrap()
+        };
+        archive_bookings(&pool, &[archived(-7), archived(-(1 << 40))])
+            .await
+            .unwrap();
+        assert_eq!(
+            insert_local_booking(&pool, 31, now, now + TimeDelta::hours(1))
+                .await
+                .unwrap(),
+            -8
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_external_bookings(pool: SqlitePool) {
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let booking =
+            |booking_id| Booking::new(booking_id, 31, now, now + TimeDelta::hours(1)).unwrap();
+        replace_external_bookings(&pool, "caldav:https://a", &[booking(-(1 << 40))])
+            .await
+            .unwrap();
+        replace_external_bookings(&pool, "caldav:https://b", &[booking(-(1 << 41))])
+            .await
+            .unwrap();
+        // replacing only touches the bookings of the same calendar
+        replace_external_bookings(&pool, "caldav:https://a", &[booking(-(1 << 42))])
+            .await
+            .unwrap();
+        let mut ids = get_all_bookings(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|b| b.booking_id())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, vec![-(1 << 42), -(1 << 41)]);
+        // external bookings do not move the ids of local bookings
+        #[cfg(feature = "http")]
+        assert_eq!(
+            insert_local_booking(&pool, 31, now, now + TimeDelta::hours(1))
+                .await
+                .unwrap(),
+            -1
+        );
+
+        assert_eq!(
+            prune_external_bookings(&pool, "caldav", &["https://a"])
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(get_all_bookings(&pool).await.unwrap().len(), 2);
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_external_temperature_history(pool: SqlitePool) {
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let old_sample = ExternalTemperatureSample {
+            recorded_at: now - TimeDelta::days(100),
+            temperature: -52,
+        };
+        let new_sample = ExternalTemperatureSample {
+            recorded_at: now,
+            temperature: 105,
+        };
+        insert_external_temperature(&pool, &old_sample).await.unwrap();
+        insert_external_temperature(&pool, &new_sample).await.unwrap();
+        let samples = get_external_temperatures_in_timeframe(
+            &pool,
+            (now - TimeDelta::days(365)).naive_utc(),
+            now.naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(samples, vec![old_sample, new_sample]);
+
+        let rows_changed = prune_old_external_temperatures(&pool, TimeDelta::days(90))
+            .await
+            .unwrap();
+        assert_eq!(rows_changed, 1);
+        let samples = get_external_temperatures_in_timeframe(
+            &pool,
+            (now - TimeDelta::days(365)).naive_utc(),
+            now.naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(samples.len(), 1);
+    }
+
+    #[cfg(feature = "weather")]
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_forecasts(pool: SqlitePool) {
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let forecasts = [
+            ForecastSample {
+                forecast_for: now - TimeDelta::days(2),
+                temperature: -10,
+                irradiance: None,
+            },
+            ForecastSample {
+                forecast_for: now,
+                temperature: 20,
+                irradiance: Some(100),
+            },
+        ];
+        insert_forecasts(&pool, forecasts.iter()).await.unwrap();
+        // newer forecasts replace older ones
+        let newer = ForecastSample {
+            forecast_for: now,
+            temperature: 30,
+            irradiance: Some(250),
+        };
+        insert_forecasts(&pool, std::iter::once(&newer)).await.unwrap();
+        let rows_changed = prune_old_forecasts(&pool, now - TimeDelta::days(1))
+            .await
+            .unwrap();
+        assert_eq!(rows_changed, 1);
+        let from_db = get_forecasts_in_timeframe(
+            &pool,
+            (now - TimeDelta::days(3)).naive_utc(),
+            now.naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(from_db, vec![newer]);
+    }
+
+    #[cfg(feature = "http")]
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_room_maintenance(pool: SqlitePool) {
+        assert!(get_rooms_in_maintenance(&pool).await.unwrap().is_empty());
+        set_room_maintenance(&pool, "room1", true).await.unwrap();
+        set_room_maintenance(&pool, "room2", true).await.unwrap();
+        // enabling twice is fine
+        set_room_maintenance(&pool, "room1", true).await.unwrap();
+        set_room_maintenance(&pool, "room2", false).await.unwrap();
+        assert_eq!(
+            get_rooms_in_maintenance(&pool).await.unwrap(),
+            HashSet::from(["room1".to_owned()])
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_resource_parents(pool: SqlitePool) {
+        replace_resource_parents(&pool, &[(2, 1), (3, 1)])
+            .await
+            .unwrap();
+        replace_resource_parents(&pool, &[(2, 1)]).await.unwrap();
+        assert_eq!(
+            get_resource_parents(&pool).await.unwrap(),
+            HashMap::from([(2, 1)])
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_cycle_stats(pool: SqlitePool) {
+        let now = chrono::Utc::now().with_nanosecond(0).unwrap();
+        let old_stat = CycleStat {
+            recorded_at: now - TimeDelta::days(100),
+            task: "pull".to_owned(),
+            duration_ms: 250,
+            items: 3,
+            errors: 0,
+        };
+        let new_stat = CycleStat {
+            recorded_at: now,
+            task: "push".to_owned(),
+            duration_ms: 4,
+            items: 2,
+            errors: 1,
+        };
+        insert_cycle_stat(&pool, &old_stat).await.unwrap();
+        insert_cycle_stat(&pool, &new_stat).await.unwrap();
+        assert_eq!(
+            prune_old_cycle_stats(&pool, TimeDelta::days(28))
+                .await
+                .unwrap(),
+            1
+        );
+        let stats = get_cycle_stats_in_timeframe(
+            &pool,
+            (now - TimeDelta::days(1)).naive_utc(),
+            now.naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stats, vec![new_stat]);
+        // the only successful pull was pruned
+        assert_eq!(get_last_successful_pull(&pool).await.unwrap(), None);
+        insert_cycle_stat(&pool, &old_stat).await.unwrap();
+        assert_eq!(
+            get_last_successful_pull(&pool).await.unwrap(),
+            Some(old_stat.recorded_at)
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_active_config(pool: SqlitePool) {
+        assert_eq!(get_active_config(&pool).await.unwrap(), None);
+        set_active_config(&pool, "a: 1\n").await.unwrap();
+        set_active_config(&pool, "a: 2\n").await.unwrap();
+        assert_eq!(
+            get_active_config(&pool).await.unwrap().as_deref(),
+            Some("a: 2\n")
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_instance_lease(pool: SqlitePool) {
+        let lease = TimeDelta::minutes(2);
+        take_instance_lease(&pool, "a", lease, false)
+            .await
+            .unwrap()
+            .unwrap();
+        // renewing our own lease works, taking someone else's does not
+        take_instance_lease(&pool, "a", lease, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            take_instance_lease(&pool, "b", lease, false).await.unwrap(),
+            Err("a".to_owned())
+        );
+        // unless forced, or the lease is stale
+        take_instance_lease(&pool, "b", lease, true)
+            .await
+            .unwrap()
+            .unwrap();
+        take_instance_lease(&pool, "a", TimeDelta::minutes(-1), false)
+            .await
+            .unwrap()
+            .unwrap();
+        release_instance_lease(&pool, "b").await.unwrap();
+        assert!(take_instance_lease(&pool, "b", lease, false)
+            .await
+            .unwrap()
+            .is_err());
+        release_instance_lease(&pool, "a").await.unwrap();
+        take_instance_lease(&pool, "b", lease, false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            get_instance_lease_holder(&pool, chrono::Utc::now() - lease)
+                .await
+                .unwrap(),
+            Some("b".to_owned())
+        );
+    }
+
+    #[sqlx::test(migrations = false)]
+    fn test_instance_lease_holder_before_migrating(pool: SqlitePool) {
+        let now = chrono::Utc::now();
+        assert_eq!(
+            get_instance_lease_holder(&pool, now - TimeDelta::minutes(2))
+                .await
+                .unwrap(),
+            None
+        );
+        // the table as created by migration 011, with the heartbeat as text
+        sqlx::query(
+            "CREATE TABLE instance_lease (id INTEGER PRIMARY KEY NOT NULL CHECK (id = 0), \
+             owner TEXT NOT NULL, heartbeat DATETIME NOT NULL);",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO instance_lease VALUES (0, 'a', ?);")
+            .bind(now.format("%Y-%m-%d %H:%M:%S").to_string())
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_instance_lease_holder(&pool, now - TimeDelta::minutes(2))
+                .await
+                .unwrap(),
+            Some("a".to_owned())
+        );
+        assert_eq!(
+            get_instance_lease_holder(&pool, now + TimeDelta::minutes(1))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_booking_archive(pool: SqlitePool) {
+        let start = chrono::Utc::now().with_nanosecond(0).unwrap() - TimeDelta::days(400);
+        let booking = |booking_id, hours| {
+            Booking::new(booking_id, 1, start, start + TimeDelta::hours(hours)).unwrap()
+        };
+        assert_eq!(
+            archive_bookings(&pool, &[booking(1, 1), booking(2, 2)])
+                .await
+                .unwrap(),
+            2
+        );
+        // archiving again replaces the booking
+        archive_bookings(&pool, &[booking(2, 3)]).await.unwrap();
+        let archived: Vec<(i64, i64)> =
+            sqlx::query_as("SELECT booking_id, end_time FROM booking_archive ORDER BY booking_id;")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(archived.len(), 2);
+        assert_eq!(archived[1].1, (start + TimeDelta::hours(3)).timestamp());
+        // the archive is not used for control
+        assert!(get_bookings_in_timeframe(
+            &pool,
+            (start - TimeDelta::days(1)).naive_utc(),
+            (start + TimeDelta::days(1)).naive_utc()
+        )
+        .await
+        .unwrap()
+        .is_empty());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn test_resource_type_rooms(pool: SqlitePool) {
+        let room = |resource_id, name: &str, pdo_index| ResourceTypeRoom {
+            resource_id,
+            type_id: 7,
+            name: name.to_owned(),
+            pdo_index,
+        };
+        set_resource_type_room(&pool, "cmi1", &room(2, "Hall", 5))
+            .await
+            .unwrap();
+        set_resource_type_room(&pool, "cmi1", &room(1, "Chapel", 4))
+            .await
+            .unwrap();
+        set_resource_type_room(&pool, "cmi2", &room(1, "Chapel", 0))
+            .await
+            .unwrap();
+        // renamed in CT
+        set_resource_type_room(&pool, "cmi1", &room(2, "Great Hall", 5))
+            .await
+            .unwrap();
+        assert_eq!(
+            get_resource_type_rooms(&pool, "cmi1").await.unwrap(),
+            vec![room(1, "Chapel", 4), room(2, "Great Hall", 5)]
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn timeframe_query_uses_index(pool: SqlitePool) {
+        sync_bookings(&pool, &archive(1000), []).await.unwrap();
+        sqlx::query("ANALYZE;").execute(&pool).await.unwrap();
+        let plan = sqlx::query_as::<_, (i64, i64, i64, String)>(
+            "EXPLAIN QUERY PLAN SELECT booking_id FROM bookings \
+             WHERE start_time <= ? AND ? <= end_time;",
+        )
+        .bind(1749340800_i64)
+        .bind(1748736000_i64)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert!(
+            plan.iter()
+                .any(|(_, _, _, detail)| detail.contains("bookings_start_end")),
+            "{plan:?}"
+        );
     }
 }
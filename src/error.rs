@@ -0,0 +1,224 @@
+//! The errors that stop ct-ta-sync, and the exit codes supervisors see for them.
+//!
+//! Exit codes follow sysexits.h, so supervisors can tell whether a restart may help (e.g. a port
+//! that is still in use) or someone has to fix something first (e.g. the config).
+
+use std::process::ExitCode;
+
+use crate::{
+    config::ConfigError, daemon::DaemonError, db::DBError,
+    instance_lease::LeaseError, migrate::MigrateError,
+};
+
+/// Something went wrong that should not happen (e.g. a task panicked)
+pub const EXIT_SOFTWARE: u8 = 70;
+/// The config is missing, malformed or inconsistent
+pub const EXIT_CONFIG: u8 = 78;
+/// The DB cannot be opened, read, written or migrated
+pub const EXIT_DB: u8 = 74;
+/// A socket cannot be bound (e.g. the port is in use)
+pub const EXIT_BIND: u8 = 69;
+/// Another instance is syncing with the DB. Retrying later may help.
+pub const EXIT_LEASE_HELD: u8 = 75;
+/// The OS refused something else (e.g. dropping privileges, GPIO pins)
+pub const EXIT_OS: u8 = 71;
+/// The command line does not fit the config (e.g. an unknown --tenant)
+pub const EXIT_USAGE: u8 = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    Config(ConfigError),
+    LogLevel(tracing_subscriber::filter::LevelParseError),
+    /// --tenant names no tenant of the config
+    UnknownTenant(String),
+    /// a one-off command or --once without --tenant, but several tenants are configured
+    TenantRequired,
+    Db(DBError),
+    Migrate(MigrateError),
+    Lease(LeaseError),
+    Daemon(DaemonError),
+    #[cfg(feature = "http")]
+    Http(crate::http::HttpError),
+    #[cfg(feature = "grpc")]
+    Grpc(crate::grpc::GrpcError),
+    /// binding the CoE sockets failed
+    Bind(std::io::Error),
+    /// an OS error in a task or while setting up GPIO pins
+    Io(std::io::Error),
+    /// a one-off command failed
+    Command(Box<dyn std::error::Error>),
+    /// a task panicked or was cancelled
+    Task(tokio::task::JoinError),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "{e}"),
+            Self::LogLevel(e) => write!(f, "global.log_level is invalid: {e}"),
+            Self::UnknownTenant(x) => write!(f, "The tenant {x} is not configured."),
+            Self::TenantRequired => write!(
+                f,
+                "Several tenants are configured. Select one of them with --tenant."
+            ),
+            Self::Db(e) => write!(f, "{e}"),
+            Self::Migrate(e) => write!(f, "{e}"),
+            Self::Lease(e) => write!(f, "{e}"),
+            Self::Daemon(e) => write!(f, "{e}"),
+            #[cfg(feature = "http")]
+            Self::Http(e) => write!(f, "HTTP server failed: {e}"),
+            #[cfg(feature = "grpc")]
+            Self::Grpc(e) => write!(f, "gRPC server failed: {e}"),
+            Self::Bind(e) => write!(f, "Unable to bind the CoE sockets: {e}"),
+            Self::Io(e) => write!(f, "IO Error: {e}"),
+            Self::Command(e) => write!(f, "{e}"),
+            Self::Task(e) => write!(f, "A task failed: {e}"),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl Error {
+    /// The exit code of the process when it stops with this error
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Config(e) if matches!(e.root(), ConfigError::OpenDb(_)) => EXIT_DB,
+            Self::Config(_) | Self::LogLevel(_) => EXIT_CONFIG,
+            Self::UnknownTenant(_) | Self::TenantRequired => EXIT_USAGE,
+            Self::Db(_)
+            | Self::Migrate(
+                MigrateError::Backup(_)
+                | MigrateError::Migrate(_)
+                | MigrateError::UnparsableDatetime(..),
+            ) => EXIT_DB,
+            // pending migrations with auto_migrate off
+            Self::Migrate(_) => EXIT_CONFIG,
+            Self::Lease(LeaseError::Held(_)) => EXIT_LEASE_HELD,
+            Self::Lease(LeaseError::Db(_)) => EXIT_DB,
+            Self::Daemon(DaemonError::UserNotFound(_) | DaemonError::GroupNotFound(_)) => {
+                EXIT_CONFIG
+            }
+            Self::Daemon(_) | Self::Io(_) => EXIT_OS,
+            #[cfg(feature = "http")]
+            Self::Http(crate::http::HttpError::Bind(..)) => EXIT_BIND,
+            Self::Bind(_) => EXIT_BIND,
+            #[cfg(feature = "http")]
+            Self::Http(crate::http::HttpError::Io(_)) => EXIT_OS,
+            // certificates and keys
+            #[cfg(feature = "http")]
+            Self::Http(_) => EXIT_CONFIG,
+            #[cfg(feature = "grpc")]
+            Self::Grpc(crate::grpc::GrpcError::Bind(..)) => EXIT_BIND,
+            #[cfg(feature = "grpc")]
+            Self::Grpc(crate::grpc::GrpcError::Io(_)) => EXIT_OS,
+            #[cfg(feature = "grpc")]
+            Self::Grpc(_) => EXIT_CONFIG,
+            Self::Command(_) => 1,
+            Self::Task(_) => EXIT_SOFTWARE,
+        })
+    }
+}
+impl From<ConfigError> for Error {
+    fn from(value: ConfigError) -> Self {
+        Self::Config(value)
+    }
+}
+impl From<tracing_subscriber::filter::LevelParseError> for Error {
+    fn from(value: tracing_subscriber::filter::LevelParseError) -> Self {
+        Self::LogLevel(value)
+    }
+}
+impl From<DBError> for Error {
+    fn from(value: DBError) -> Self {
+        Self::Db(value)
+    }
+}
+impl From<MigrateError> for Error {
+    fn from(value: MigrateError) -> Self {
+        Self::Migrate(value)
+    }
+}
+impl From<LeaseError> for Error {
+    fn from(value: LeaseError) -> Self {
+        Self::Lease(value)
+    }
+}
+impl From<DaemonError> for Error {
+    fn from(value: DaemonError) -> Self {
+        Self::Daemon(value)
+    }
+}
+#[cfg(feature = "http")]
+impl From<crate::http::HttpError> for Error {
+    fn from(value: crate::http::HttpError) -> Self {
+        Self::Http(value)
+    }
+}
+#[cfg(feature = "grpc")]
+impl From<crate::grpc::GrpcError> for Error {
+    fn from(value: crate::grpc::GrpcError) -> Self {
+        Self::Grpc(value)
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<Box<dyn std::error::Error>> for Error {
+    fn from(value: Box<dyn std::error::Error>) -> Self {
+        Self::Command(value)
+    }
+}
+impl From<tokio::task::JoinError> for Error {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Self::Task(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exit_codes_by_class() {
+        let io = || std::io::Error::from(std::io::ErrorKind::AddrInUse);
+        let code = |e: Error| e.exit_code();
+        assert_eq!(
+            code(ConfigError::Read(io()).into()),
+            ExitCode::from(EXIT_CONFIG)
+        );
+        assert_eq!(
+            code(MigrateError::Pending(vec![15]).into()),
+            ExitCode::from(EXIT_CONFIG)
+        );
+        assert_eq!(
+            code(DBError::SelectBookings(sqlx::Error::PoolClosed).into()),
+            ExitCode::from(EXIT_DB)
+        );
+        assert_eq!(
+            code(
+                ConfigError::Tenant(
+                    "parish-a".to_owned(),
+                    Box::new(ConfigError::OpenDb(sqlx::Error::PoolClosed))
+                )
+                .into()
+            ),
+            ExitCode::from(EXIT_DB)
+        );
+        #[cfg(feature = "http")]
+        assert_eq!(
+            code(crate::http::HttpError::Bind("0.0.0.0:443".to_owned(), io()).into()),
+            ExitCode::from(EXIT_BIND)
+        );
+        // e.g. writing the self-signed certificate
+        #[cfg(feature = "http")]
+        assert_eq!(
+            code(crate::http::HttpError::Io(io()).into()),
+            ExitCode::from(EXIT_OS)
+        );
+        assert_eq!(code(Error::Bind(io())), ExitCode::from(EXIT_BIND));
+        assert_eq!(
+            code(LeaseError::Held("host:1".to_owned()).into()),
+            ExitCode::from(EXIT_LEASE_HELD)
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Compare the state commanded for each room with the state its CMI reports back.
+//!
+//! CMIs may mirror the actual state of a room (e.g. the pump or valve output) to a CoE output.
+//! If that feedback does not match the commanded state for too long, the wiring or the function
+//! chart is probably wrong.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use tracing::{info, warn};
+
+/// Identifies a room on a CMI: (cmi host, room name)
+pub type RoomKey = (String, String);
+
+/// What we know about a single room
+#[derive(Debug, Default)]
+struct RoomFeedback {
+    commanded: Option<bool>,
+    actual: Option<bool>,
+    /// when commanded and actual state started to differ
+    mismatch_since: Option<DateTime<Utc>>,
+    /// whether we already warned about the current mismatch
+    warned: bool,
+}
+impl RoomFeedback {
+    /// Returns true if a mismatch we warned about was resolved
+    fn update(&mut self, key: &RoomKey, now: DateTime<Utc>) -> bool {
+        match self.commanded {
+            Some(commanded) if self.actual != Some(commanded) => {
+                self.mismatch_since.get_or_insert(now);
+                false
+            }
+            _ => {
+                let resolved = self.warned;
+                if resolved {
+                    info!(
+                        "The feedback of room {} on CMI {} matches the commanded state again.",
+                        key.1, key.0
+                    );
+                };
+                self.mismatch_since = None;
+                self.warned = false;
+                resolved
+            }
+        }
+    }
+}
+
+/// Tracks commanded and actual state of all rooms with feedback
+#[derive(Debug)]
+pub struct FeedbackTracker {
+    timeout: TimeDelta,
+    rooms: HashMap<RoomKey, RoomFeedback>,
+    /// rooms whose mismatch was resolved since the last call to take_resolved
+    resolved: Vec<RoomKey>,
+}
+impl FeedbackTracker {
+    pub fn new(timeout: TimeDelta) -> Self {
+        Self {
+            timeout,
+            rooms: HashMap::new(),
+            resolved: vec![],
+        }
+    }
+
+    /// Record the state sent to a room
+    pub fn set_commanded(&mut self, key: RoomKey, state: bool, now: DateTime<Utc>) {
+        let room = self.rooms.entry(key.clone()).or_default();
+        if room.commanded != Some(state) {
+            // the CMI needs some time to follow a new command
+            room.mismatch_since = None;
+        };
+        room.commanded = Some(state);
+        if room.update(&key, now) {
+            self.resolved.push(key);
+        };
+    }
+
+    /// Record the state a CMI reported for a room
+    pub fn set_actual(&mut self, key: RoomKey, state: bool, now: DateTime<Utc>) {
+        let room = self.rooms.entry(key.clone()).or_default();
+        room.actual = Some(state);
+        if room.update(&key, now) {
+            self.resolved.push(key);
+        };
+    }
+
+    /// Warn about all rooms whose feedback has not matched the commanded state within the timeout.
+    ///
+    /// Each mismatch is only warned about once. Returns the rooms warned about in this call.
+    pub fn check(&mut self, now: DateTime<Utc>) -> Vec<RoomKey> {
+        let mut newly_warned = vec![];
+        for (key, room) in self.rooms.iter_mut() {
+            let Some(since) = room.mismatch_since else {
+                continue;
+            };
+            if room.warned || now - since < self.timeout {
+                continue;
+            };
+            match room.actual {
+                Some(actual) => warn!(
+                    "Room {} on CMI {} reports {}, but was commanded {} for {} minutes. Check the wiring and function chart.",
+                    key.1,
+                    key.0,
+                    actual,
+                    !actual,
+                    (now - since).num_minutes()
+                ),
+                None => warn!(
+                    "Room {} on CMI {} has not reported its state for {} minutes. Check the CoE outputs of the CMI.",
+                    key.1,
+                    key.0,
+                    (now - since).num_minutes()
+                ),
+            };
+            room.warned = true;
+            newly_warned.push(key.clone());
+        }
+        newly_warned
+    }
+
+    /// Get all rooms whose mismatch was resolved since the last call
+    pub fn take_resolved(&mut self) -> Vec<RoomKey> {
+        std::mem::take(&mut self.resolved)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> RoomKey {
+        ("cmi".to_owned(), "room1".to_owned())
+    }
+
+    #[test]
+    fn matching_feedback_is_fine() {
+        let now = Utc::now();
+        let mut tracker = FeedbackTracker::new(TimeDelta::minutes(10));
+        tracker.set_commanded(key(), true, now);
+        tracker.set_actual(key(), true, now + TimeDelta::minutes(1));
+        assert!(tracker.check(now + TimeDelta::minutes(30)).is_empty());
+    }
+
+    #[test]
+    fn mismatch_is_warned_once_after_timeout() {
+        let now = Utc::now();
+        let mut tracker = FeedbackTracker::new(TimeDelta::minutes(10));
+        tracker.set_actual(key(), false, now);
+        tracker.set_commanded(key(), true, now);
+        assert!(tracker.check(now + TimeDelta::minutes(9)).is_empty());
+        // commanding the same state again does not restart the timeout
+        tracker.set_commanded(key(), true, now + TimeDelta::minutes(9));
+        assert_eq!(tracker.check(now + TimeDelta::minutes(10)), vec![key()]);
+        assert!(tracker.check(now + TimeDelta::minutes(11)).is_empty());
+        // resolved, then mismatched again
+        tracker.set_actual(key(), true, now + TimeDelta::minutes(12));
+        assert_eq!(tracker.take_resolved(), vec![key()]);
+        assert!(tracker.take_resolved().is_empty());
+        tracker.set_actual(key(), false, now + TimeDelta::minutes(13));
+        assert!(tracker.check(now + TimeDelta::minutes(20)).is_empty());
+        assert_eq!(tracker.check(now + TimeDelta::minutes(23)), vec![key()]);
+    }
+
+    #[test]
+    fn missing_feedback_is_a_mismatch() {
+        let now = Utc::now();
+        let mut tracker = FeedbackTracker::new(TimeDelta::minutes(10));
+        tracker.set_commanded(key(), false, now);
+        assert_eq!(tracker.check(now + TimeDelta::minutes(10)), vec![key()]);
+    }
+}
@@ -1,3 +1,4 @@
+-- 2021-03-26T15:30:00Z - 17:00:00Z and 2021-03-28T15:30:00Z - 17:00:00Z
 INSERT INTO bookings (booking_id, resource_id, start_time, end_time) VALUES
-(123, 10, '2021-03-26T15:30:00+00:00', '2021-03-26T17:00:00+00:00'),
-(125, 11, '2021-03-28T15:30:00+00:00', '2021-03-28T17:00:00+00:00');
+(123, 10, 1616772600, 1616778000),
+(125, 11, 1616945400, 1616950800);
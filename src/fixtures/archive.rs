@@ -0,0 +1,24 @@
+//! A large set of bookings, shared by the db tests and `benches/bookings_in_timeframe.rs`
+
+use chrono::{DateTime, TimeDelta};
+
+use crate::Booking;
+
+/// `count` bookings of an hour each, spread over three years from 2024
+pub fn archive(count: i64) -> Vec<Booking> {
+    let first = DateTime::parse_from_rfc3339("2024-01-01T08:00:00+00:00")
+        .unwrap()
+        .to_utc();
+    let step = TimeDelta::days(3 * 365) / count as i32;
+    (0..count)
+        .map(|i| {
+            Booking::new(
+                i + 1,
+                i % 40,
+                first + step * i as i32,
+                first + step * i as i32 + TimeDelta::hours(1),
+            )
+            .unwrap()
+        })
+        .collect()
+}
@@ -0,0 +1,248 @@
+//! Get hourly weather forecasts, so preheating can use the temperature expected at preheat time
+//!
+//! Getting the forecasts needs the `weather` feature. Without it, only the forecasts already in
+//! the DB are used.
+
+#[cfg(feature = "weather")]
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+#[cfg(feature = "weather")]
+use chrono::{NaiveDateTime, TimeDelta};
+#[cfg(feature = "weather")]
+use serde::Deserialize;
+#[cfg(feature = "weather")]
+use tracing::{debug, info, warn};
+
+use crate::db::ForecastSample;
+#[cfg(feature = "weather")]
+use crate::{
+    config::{Config, ForecastConfig},
+    db::DBError,
+    InShutdown,
+};
+
+/// The relevant parts of an open-meteo forecast response
+#[cfg(feature = "weather")]
+#[derive(Debug, Deserialize)]
+struct ForecastResponse {
+    hourly: HourlyForecast,
+}
+
+#[cfg(feature = "weather")]
+#[derive(Debug, Deserialize)]
+struct HourlyForecast {
+    /// UTC times formatted as %Y-%m-%dT%H:%M
+    time: Vec<String>,
+    /// in Degree Centigrade
+    temperature_2m: Vec<Option<f64>>,
+    /// global horizontal irradiance in W/m², averaged over the preceding hour
+    shortwave_radiation: Option<Vec<Option<f64>>>,
+}
+
+#[cfg(feature = "weather")]
+#[derive(Debug)]
+pub enum ForecastError {
+    Get(reqwest::Error),
+    Utf8Decode(reqwest::Error),
+    Deserialize(serde_json::Error),
+    ParseTime(chrono::ParseError),
+    DB(DBError),
+}
+#[cfg(feature = "weather")]
+impl std::fmt::Display for ForecastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Get(e) => write!(f, "Cannot get the forecast. reqwest Error: {e}"),
+            Self::Utf8Decode(e) => {
+                write!(f, "Cannot decode the forecast as utf-8. reqwest Error: {e}")
+            }
+            Self::Deserialize(e) => {
+                write!(f, "Cannot deserialize the forecast. serde Error: {e}")
+            }
+            Self::ParseTime(e) => {
+                write!(f, "Cannot parse a time in the forecast. chrono Error: {e}")
+            }
+            Self::DB(e) => write!(f, "DBError: {e}"),
+        }
+    }
+}
+#[cfg(feature = "weather")]
+impl std::error::Error for ForecastError {}
+#[cfg(feature = "weather")]
+impl From<DBError> for ForecastError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+
+/// Get the hourly temperature forecast for today and tomorrow
+#[cfg(feature = "weather")]
+async fn get_forecast(
+    forecast_config: &ForecastConfig,
+) -> Result<Vec<ForecastSample>, ForecastError> {
+    let text = reqwest::Client::new()
+        .get(&forecast_config.url)
+        .query(&[
+            ("latitude", forecast_config.latitude.to_string()),
+            ("longitude", forecast_config.longitude.to_string()),
+            ("hourly", "temperature_2m,shortwave_radiation".to_owned()),
+            ("timezone", "UTC".to_owned()),
+            ("forecast_days", "2".to_owned()),
+        ])
+        .send()
+        .await
+        .map_err(ForecastError::Get)?
+        .text()
+        .await
+        .map_err(ForecastError::Utf8Decode)?;
+    let response: ForecastResponse =
+        serde_json::from_str(&text).map_err(ForecastError::Deserialize)?;
+    let irradiances = response.hourly.shortwave_radiation.unwrap_or_default();
+    response
+        .hourly
+        .time
+        .iter()
+        .zip(response.hourly.temperature_2m)
+        .enumerate()
+        // the API returns null for hours it has no forecast for
+        .filter_map(|(idx, (time, temp))| temp.map(|t| (idx, time, t)))
+        .map(|(idx, time, temp)| {
+            Ok(ForecastSample {
+                forecast_for: NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")
+                    .map_err(ForecastError::ParseTime)?
+                    .and_utc(),
+                temperature: (temp * 10_f64).round() as i32,
+                irradiance: irradiances
+                    .get(idx)
+                    .copied()
+                    .flatten()
+                    .map(|x| x.round() as i32),
+            })
+        })
+        .collect()
+}
+
+/// Get a new forecast into the db and remove forecasts for the past
+#[cfg(feature = "weather")]
+async fn get_forecast_into_db(
+    config: &Config,
+    forecast_config: &ForecastConfig,
+) -> Result<usize, ForecastError> {
+    let forecasts = get_forecast(forecast_config).await?;
+    crate::db::insert_forecasts(&config.db, forecasts.iter()).await?;
+    crate::db::prune_old_forecasts(&config.db, Utc::now() - TimeDelta::days(1)).await?;
+    Ok(forecasts.len())
+}
+
+/// A forecast value at `time`, linearly interpolated between the forecasts around it.
+///
+/// forecasts need to be sorted by time.
+/// Returns None if there is no forecast with this value before and after `time`.
+fn interpolate_at<F: Fn(&ForecastSample) -> Option<i32>>(
+    forecasts: &[ForecastSample],
+    time: DateTime<Utc>,
+    value: F,
+) -> Option<i32> {
+    let after_idx = forecasts.iter().position(|f| f.forecast_for >= time)?;
+    let after = &forecasts[after_idx];
+    if after.forecast_for == time {
+        return value(after);
+    };
+    let before = &forecasts[after_idx.checked_sub(1)?];
+    let (before_value, after_value) = (value(before)?, value(after)?);
+    let proportion = (time - before.forecast_for).num_seconds() as f64
+        / (after.forecast_for - before.forecast_for).num_seconds() as f64;
+    Some((before_value as f64 + proportion * (after_value - before_value) as f64).round() as i32)
+}
+
+/// The forecast temperature at `time`, see [interpolate_at]
+pub fn forecast_at(forecasts: &[ForecastSample], time: DateTime<Utc>) -> Option<i32> {
+    interpolate_at(forecasts, time, |f| Some(f.temperature))
+}
+
+/// The forecast solar irradiance at `time`, see [interpolate_at]
+pub fn irradiance_at(forecasts: &[ForecastSample], time: DateTime<Utc>) -> Option<i32> {
+    interpolate_at(forecasts, time, |f| f.irradiance)
+}
+
+/// Continually get new forecasts into the db.
+#[cfg(feature = "weather")]
+pub async fn keep_forecast_up_to_date(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(forecast_config) = &config.forecast else {
+        debug!("No forecast configured.");
+        return;
+    };
+    info!("Starting forecast task");
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
+        forecast_config.refresh_interval * 60,
+    ));
+    interval.tick().await;
+    loop {
+        match get_forecast_into_db(&config, forecast_config).await {
+            Ok(x) => debug!("Successfully got {x} hourly forecasts."),
+            Err(e) => warn!("Failed to get a new forecast. Error encountered: {e}"),
+        };
+        // stop on cancellation or continue after the next tick
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down forecast task now.");
+                return;
+            }
+            _ = interval.tick() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(time: &str, temperature: i32, irradiance: Option<i32>) -> ForecastSample {
+        ForecastSample {
+            forecast_for: DateTime::parse_from_rfc3339(time).unwrap().into(),
+            temperature,
+            irradiance,
+        }
+    }
+
+    #[test]
+    fn interpolate_forecast() {
+        let forecasts = vec![
+            sample("2021-03-26T06:00:00+00:00", -50, None),
+            sample("2021-03-26T07:00:00+00:00", -30, Some(100)),
+        ];
+        let at = |time: &str| {
+            forecast_at(
+                &forecasts,
+                DateTime::parse_from_rfc3339(time).unwrap().into(),
+            )
+        };
+        assert_eq!(at("2021-03-26T06:00:00+00:00"), Some(-50));
+        assert_eq!(at("2021-03-26T06:30:00+00:00"), Some(-40));
+        assert_eq!(at("2021-03-26T07:00:00+00:00"), Some(-30));
+        assert_eq!(at("2021-03-26T05:59:00+00:00"), None);
+        assert_eq!(at("2021-03-26T07:01:00+00:00"), None);
+    }
+
+    #[test]
+    fn interpolate_irradiance() {
+        let forecasts = vec![
+            sample("2021-03-26T06:00:00+00:00", -50, None),
+            sample("2021-03-26T07:00:00+00:00", -30, Some(100)),
+            sample("2021-03-26T08:00:00+00:00", -30, Some(300)),
+        ];
+        let at = |time: &str| {
+            irradiance_at(
+                &forecasts,
+                DateTime::parse_from_rfc3339(time).unwrap().into(),
+            )
+        };
+        assert_eq!(at("2021-03-26T06:30:00+00:00"), None);
+        assert_eq!(at("2021-03-26T07:00:00+00:00"), Some(100));
+        assert_eq!(at("2021-03-26T07:30:00+00:00"), Some(200));
+    }
+}
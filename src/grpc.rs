@@ -0,0 +1,409 @@
+//! A gRPC service for building-management integrations, see proto/ct_ta_sync.proto.
+//!
+//! Instead of polling the HTTP API, integrators get the state of each room as a stream and can
+//! change the maintenance mode and local bookings. The credentials and roles of the HTTP API apply.
+
+// every call returns a Status on errors, boxing it in the helpers would not help
+#![allow(clippy::result_large_err)]
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    transport::{server::TcpIncoming, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    booking::check_interval,
+    config::{AssociatedRoomConfig, Config, HttpRole},
+    metrics::Metrics,
+    InShutdown,
+};
+
+/// The code generated from proto/ct_ta_sync.proto
+#[allow(clippy::all)]
+mod proto {
+    tonic::include_proto!("ct_ta_sync.v1");
+}
+use proto::heating_server::{Heating, HeatingServer};
+
+/// Room states not yet sent to a slow client. Further changes wait until it catches up.
+const WATCH_BUFFER: usize = 64;
+
+#[derive(Debug)]
+pub enum GrpcError {
+    /// Listening on the address failed
+    Bind(String, std::io::Error),
+    Io(std::io::Error),
+    /// Reading the certificate or key failed
+    Read(PathBuf, std::io::Error),
+    Transport(tonic::transport::Error),
+    /// http.signing_secret is set, but gRPC calls cannot be signed
+    Unsigned,
+}
+impl std::fmt::Display for GrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Bind(addr, x) => write!(f, "Unable to listen on {addr}: {x}"),
+            Self::Io(x) => write!(f, "IO Error: {x}"),
+            Self::Read(path, x) => write!(f, "Unable to read {}: {x}", path.display()),
+            Self::Transport(x) => write!(f, "gRPC transport error: {x}"),
+            Self::Unsigned => write!(
+                f,
+                "http.signing_secret is set, but gRPC calls cannot be signed. Remove http.grpc_bind_addr or http.signing_secret."
+            ),
+        }
+    }
+}
+impl From<std::io::Error> for GrpcError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<tonic::transport::Error> for GrpcError {
+    fn from(value: tonic::transport::Error) -> Self {
+        Self::Transport(value)
+    }
+}
+impl std::error::Error for GrpcError {}
+
+/// The states in `new` that differ from `old`, in the order of `new`
+fn changes(
+    old: &BTreeMap<(String, String), (u8, bool)>,
+    new: &BTreeMap<(String, String), (u8, bool)>,
+) -> Vec<proto::RoomState> {
+    new.iter()
+        .filter(|(key, state)| old.get(*key) != Some(*state))
+        .map(|((cmi, room), (output, heating))| proto::RoomState {
+            cmi: cmi.clone(),
+            room: room.clone(),
+            output: u32::from(*output),
+            heating: *heating,
+        })
+        .collect()
+}
+
+fn timestamp(time: DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: time.timestamp(),
+        nanos: time.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// Parse the timestamp `name` of a request
+fn parse_timestamp(
+    timestamp: Option<prost_types::Timestamp>,
+    name: &str,
+) -> Result<DateTime<Utc>, Status> {
+    timestamp
+        .and_then(|x| DateTime::from_timestamp(x.seconds, u32::try_from(x.nanos).ok()?))
+        .ok_or_else(|| Status::invalid_argument(format!("{name} is missing or invalid")))
+}
+
+struct HeatingService {
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    watcher: watch::Receiver<InShutdown>,
+}
+impl HeatingService {
+    /// Check that the sender of `request` has at least the `required` role
+    fn authorize<T>(&self, request: &Request<T>, required: HttpRole) -> Result<(), Status> {
+        let headers = request.metadata().clone().into_headers();
+        crate::http::authorize(self.config.http.as_ref(), &headers, required).map_err(|status| {
+            if status == StatusCode::FORBIDDEN {
+                Status::permission_denied("The credentials do not allow this.")
+            } else {
+                Status::unauthenticated("Missing or invalid credentials.")
+            }
+        })
+    }
+
+    fn room(&self, name: &str) -> Result<&AssociatedRoomConfig, Status> {
+        self.config
+            .cmis
+            .iter()
+            .flat_map(|cmi| &cmi.rooms)
+            .find(|room| room.name == name)
+            .ok_or_else(|| Status::not_found(format!("There is no room {name}.")))
+    }
+}
+
+#[tonic::async_trait]
+impl Heating for HeatingService {
+    async fn list_rooms(
+        &self,
+        request: Request<proto::ListRoomsRequest>,
+    ) -> Result<Response<proto::ListRoomsResponse>, Status> {
+        self.authorize(&request, HttpRole::Viewer)?;
+        let in_maintenance = crate::db::get_rooms_in_maintenance(&self.config.db)
+            .await
+            .map_err(|e| {
+                warn!("Unable to get the rooms in maintenance mode: {e}");
+                Status::internal("Unable to read the DB.")
+            })?;
+        let heating = self.metrics.room_heating();
+        let rooms = self
+            .config
+            .cmis
+            .iter()
+            .flat_map(|cmi| cmi.rooms.iter().map(move |room| (cmi, room)))
+            .map(|(cmi, room)| proto::Room {
+                name: room.name.clone(),
+                cmi: cmi.host.clone(),
+                output: u32::from(room.pdo_index) + 1,
+                heating: heating
+                    .get(&(cmi.host.clone(), room.name.clone()))
+                    .map(|(_, heating)| *heating),
+                maintenance: in_maintenance.contains(&room.name),
+            })
+            .collect();
+        Ok(Response::new(proto::ListRoomsResponse { rooms }))
+    }
+
+    type WatchRoomsStream = ReceiverStream<Result<proto::RoomState, Status>>;
+
+    async fn watch_rooms(
+        &self,
+        request: Request<proto::WatchRoomsRequest>,
+    ) -> Result<Response<Self::WatchRoomsStream>, Status> {
+        self.authorize(&request, HttpRole::Viewer)?;
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        let metrics = self.metrics.clone();
+        let mut watcher = self.watcher.clone();
+        tokio::spawn(
+            async move {
+                let mut sent = BTreeMap::new();
+                loop {
+                    // enabled before reading the state, so no change in between is missed
+                    let changed = metrics.room_heating_changed();
+                    tokio::pin!(changed);
+                    changed.as_mut().enable();
+                    let state = metrics.room_heating();
+                    for room_state in changes(&sent, &state) {
+                        if tx.send(Ok(room_state)).await.is_err() {
+                            return;
+                        };
+                    }
+                    sent = state;
+                    tokio::select! {
+                        _ = changed => {}
+                        _ = tx.closed() => return,
+                        _ = watcher.changed() => return,
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn set_maintenance(
+        &self,
+        request: Request<proto::SetMaintenanceRequest>,
+    ) -> Result<Response<proto::SetMaintenanceResponse>, Status> {
+        if let Err(status) = self.authorize(&request, HttpRole::Operator) {
+            warn!(
+                "Rejected unauthorized gRPC request to change the maintenance mode of room {}.",
+                request.get_ref().room
+            );
+            return Err(status);
+        };
+        let proto::SetMaintenanceRequest { room, enabled } = request.into_inner();
+        self.room(&room)?;
+        match crate::db::set_room_maintenance(&self.config.db, &room, enabled).await {
+            Ok(()) => {
+                self.config.bookings_changed.notify_one();
+                info!(
+                    "Room {room} is {} maintenance mode now.",
+                    if enabled { "in" } else { "no longer in" }
+                );
+                Ok(Response::new(proto::SetMaintenanceResponse {}))
+            }
+            Err(e) => {
+                warn!("Unable to change the maintenance mode of room {room}: {e}");
+                Err(Status::internal("Unable to write the DB."))
+            }
+        }
+    }
+
+    async fn add_booking(
+        &self,
+        request: Request<proto::AddBookingRequest>,
+    ) -> Result<Response<proto::Booking>, Status> {
+        if let Err(status) = self.authorize(&request, HttpRole::Operator) {
+            warn!(
+                "Rejected unauthorized gRPC request to book room {}.",
+                request.get_ref().room
+            );
+            return Err(status);
+        };
+        let request = request.into_inner();
+        let room = self.room(&request.room)?;
+        let start = parse_timestamp(request.start, "start")?;
+        let end = parse_timestamp(request.end, "end")?;
+        if let Err(e) = check_interval(start, end) {
+            info!("Rejected a local booking of room {}: {e}", room.name);
+            return Err(Status::invalid_argument(e.to_string()));
+        };
+        if end <= Utc::now() {
+            return Err(Status::invalid_argument("The booking is over already."));
+        };
+        match crate::db::insert_local_booking(&self.config.db, room.churchtools_id, start, end)
+            .await
+        {
+            Ok(booking_id) => {
+                self.config.bookings_changed.notify_one();
+                info!(
+                    "Booked room {} from {start} to {end} as local booking {booking_id}.",
+                    room.name
+                );
+                Ok(Response::new(proto::Booking {
+                    id: booking_id,
+                    room: room.name.clone(),
+                    start: Some(timestamp(start)),
+                    end: Some(timestamp(end)),
+                }))
+            }
+            Err(e) => {
+                warn!("Unable to store a local booking of room {}: {e}", room.name);
+                Err(Status::internal("Unable to write the DB."))
+            }
+        }
+    }
+
+    async fn delete_booking(
+        &self,
+        request: Request<proto::DeleteBookingRequest>,
+    ) -> Result<Response<proto::DeleteBookingResponse>, Status> {
+        let booking_id = request.get_ref().id;
+        if let Err(status) = self.authorize(&request, HttpRole::Operator) {
+            warn!("Rejected unauthorized gRPC request to delete local booking {booking_id}.");
+            return Err(status);
+        };
+        match crate::db::delete_local_booking(&self.config.db, booking_id).await {
+            Ok(true) => {
+                self.config.bookings_changed.notify_one();
+                info!("Deleted local booking {booking_id}.");
+                Ok(Response::new(proto::DeleteBookingResponse {}))
+            }
+            Ok(false) => Err(Status::not_found(format!(
+                "There is no local booking {booking_id}."
+            ))),
+            Err(e) => {
+                warn!("Unable to delete local booking {booking_id}: {e}");
+                Err(Status::internal("Unable to write the DB."))
+            }
+        }
+    }
+}
+
+/// The bound listener of the gRPC server, and its TLS config if it serves TLS
+pub(crate) struct GrpcListener {
+    listener: TcpListener,
+    tls: Option<ServerTlsConfig>,
+}
+
+/// Bind the gRPC listener, if `http.grpc_bind_addr` is configured.
+///
+/// With `http.tls`, the certificate of the HTTP server is used. Like [crate::http::bind], this
+/// has to happen before privileges are dropped, and after it, so a self-signed certificate exists.
+/// With `http.signing_secret`, the server is refused: its mutating calls would not be signed.
+pub(crate) async fn bind(config: &Config) -> Result<Option<GrpcListener>, GrpcError> {
+    let Some(http_config) = &config.http else {
+        return Ok(None);
+    };
+    let Some(bind_addr) = &http_config.grpc_bind_addr else {
+        debug!("No gRPC server configured.");
+        return Ok(None);
+    };
+    if http_config.signing_secret.is_some() {
+        return Err(GrpcError::Unsigned);
+    };
+    let tls = match &http_config.tls {
+        Some(tls) => {
+            let cert = std::fs::read(&tls.cert_path)
+                .map_err(|e| GrpcError::Read(tls.cert_path.clone(), e))?;
+            let key = std::fs::read(&tls.key_path)
+                .map_err(|e| GrpcError::Read(tls.key_path.clone(), e))?;
+            Some(ServerTlsConfig::new().identity(Identity::from_pem(cert, key)))
+        }
+        None => None,
+    };
+    match TcpListener::bind(bind_addr).await {
+        Ok(listener) => Ok(Some(GrpcListener { listener, tls })),
+        Err(e) => {
+            error!("Unable to listen for gRPC on {bind_addr}.");
+            Err(GrpcError::Bind(bind_addr.clone(), e))
+        }
+    }
+}
+
+/// Serve the gRPC service on listener until shutdown
+pub(crate) async fn serve(
+    config: Arc<Config>,
+    listener: Option<GrpcListener>,
+    metrics: Arc<Metrics>,
+    mut watcher: watch::Receiver<InShutdown>,
+) -> Result<(), GrpcError> {
+    let Some(GrpcListener { listener, tls }) = listener else {
+        return Ok(());
+    };
+    info!(
+        "Starting gRPC server{} on {}",
+        if tls.is_some() { " with TLS" } else { "" },
+        listener.local_addr()?
+    );
+    let service = HeatingService {
+        config,
+        metrics,
+        watcher: watcher.clone(),
+    };
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+        server = server.tls_config(tls)?;
+    };
+    server
+        .add_service(HeatingServer::new(service))
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), async move {
+            let _ = watcher.changed().await;
+            debug!("Shutting down the gRPC server now.");
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changes_are_new_or_different_states() {
+        let key = |room: &str| ("cmi.local".to_owned(), room.to_owned());
+        let old = BTreeMap::from([(key("hall"), (1, false)), (key("office"), (2, true))]);
+        let new = BTreeMap::from([
+            (key("hall"), (1, true)),
+            (key("office"), (2, true)),
+            (key("kitchen"), (3, false)),
+        ]);
+        let changed = changes(&old, &new);
+        assert_eq!(
+            changed
+                .iter()
+                .map(|x| (x.room.as_str(), x.heating))
+                .collect::<Vec<_>>(),
+            [("hall", true), ("kitchen", false)]
+        );
+        assert_eq!(changed[1].output, 3);
+        // a new client gets everything
+        assert_eq!(changes(&BTreeMap::new(), &new).len(), 3);
+        assert!(changes(&new, &new).is_empty());
+    }
+}
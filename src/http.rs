@@ -0,0 +1,1019 @@
+//! The embedded HTTP server
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::{ws::WebSocketUpgrade, Path, Query, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::mpsc};
+use tracing::{debug, error, info, warn, Instrument};
+
+use crate::{
+    api::{ApiBooking, ApiRoom, ApiState, DEFAULT_BOOKING_DAYS, MAX_BOOKING_DAYS},
+    booking::{check_interval, Booking},
+    build_info::BuildInfo,
+    config::{Config, HttpConfig, HttpRole, HttpTlsConfig},
+    metrics::{CoeSource, MalformedCoe, Metrics},
+    read_ext_temp::ExternalTemperatures,
+    InShutdown,
+};
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// Listening on the address failed
+    Bind(String, std::io::Error),
+    Io(std::io::Error),
+    /// Reading the certificate or key failed
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    /// The certificate and key do not fit together
+    Tls(rustls::Error),
+    /// Generating the self-signed certificate failed
+    SelfSigned(rcgen::Error),
+}
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Bind(addr, x) => write!(f, "Unable to listen on {addr}: {x}"),
+            Self::Io(x) => write!(f, "IO Error: {x}"),
+            Self::Pem(path, x) => write!(f, "Unable to read {}: {x}", path.display()),
+            Self::Tls(x) => write!(f, "TLS Error: {x}"),
+            Self::SelfSigned(x) => {
+                write!(f, "Unable to generate a self-signed certificate: {x}")
+            }
+        }
+    }
+}
+impl From<std::io::Error> for HttpError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+impl From<rustls::Error> for HttpError {
+    fn from(value: rustls::Error) -> Self {
+        Self::Tls(value)
+    }
+}
+impl From<rcgen::Error> for HttpError {
+    fn from(value: rcgen::Error) -> Self {
+        Self::SelfSigned(value)
+    }
+}
+impl std::error::Error for HttpError {}
+
+/// State shared by all handlers
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    ext_temp_tx: mpsc::Sender<i32>,
+    ext_temps: ExternalTemperatures,
+    metrics: Arc<Metrics>,
+    seen_signatures: Arc<SeenSignatures>,
+}
+
+/// Body of POST /ext-temp
+#[derive(Debug, Deserialize)]
+struct ExtTempRequest {
+    /// in Degree Centigrade
+    temperature: f64,
+}
+
+/// Body of POST /bookings
+#[derive(Debug, Deserialize)]
+struct LocalBookingRequest {
+    /// name of the room, as in the config
+    room: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+}
+
+/// Compare two tokens in constant time
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0_u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// The role of the sender of a request, if it sent valid credentials (bearer token or basic auth)
+fn role(http_config: &HttpConfig, headers: &HeaderMap) -> Option<HttpRole> {
+    let authorization = headers.get("Authorization")?.to_str().ok()?;
+    if let Some(token) = authorization.strip_prefix("Bearer ") {
+        if http_config
+            .token
+            .as_ref()
+            .is_some_and(|x| token_matches(token, x))
+        {
+            return Some(HttpRole::Operator);
+        };
+        return http_config
+            .users
+            .iter()
+            .find(|user| user.token.as_ref().is_some_and(|x| token_matches(token, x)))
+            .map(|user| user.role);
+    };
+    let basic = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(basic).ok()?).ok()?;
+    let (name, password) = decoded.split_once(':')?;
+    http_config
+        .users
+        .iter()
+        .find(|user| {
+            user.name == name
+                && user
+                    .password
+                    .as_ref()
+                    .is_some_and(|x| token_matches(password, x))
+        })
+        .map(|user| user.role)
+}
+
+/// Check that the sender of a request has at least the `required` role.
+///
+/// Returns the status to respond with otherwise.
+pub(crate) fn authorize(
+    http_config: Option<&HttpConfig>,
+    headers: &HeaderMap,
+    required: HttpRole,
+) -> Result<(), StatusCode> {
+    let Some(http_config) = http_config else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    if required == HttpRole::Viewer
+        && http_config.anonymous_read
+        && !headers.contains_key("Authorization")
+    {
+        return Ok(());
+    };
+    match role(http_config, headers) {
+        None => Err(StatusCode::UNAUTHORIZED),
+        Some(role) if role < required => Err(StatusCode::FORBIDDEN),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Signed requests older or newer than this are rejected, so they cannot be replayed later
+const SIGNATURE_MAX_AGE_SECONDS: u64 = 300;
+/// Longest body of a signed request
+const SIGNED_BODY_LIMIT: usize = 64 * 1024;
+
+/// The HMAC-SHA256 of a request, hex encoded.
+///
+/// Signed are the timestamp (unix seconds), the method, the path and the body, each followed by a
+/// newline except the body.
+fn signature(secret: &str, timestamp: i64, method: &Method, path: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let mut context = ring::hmac::Context::with_key(&key);
+    context.update(format!("{timestamp}\n{method}\n{path}\n").as_bytes());
+    context.update(body);
+    context
+        .sign()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The signatures accepted within the last SIGNATURE_MAX_AGE_SECONDS, so a signed request is
+/// not accepted twice
+#[derive(Debug, Default)]
+pub(crate) struct SeenSignatures(Mutex<HashMap<String, i64>>);
+impl SeenSignatures {
+    /// Remember the signature of a request signed at `timestamp`. False if it was seen before.
+    fn first_use(&self, signature: &str, timestamp: i64, now: DateTime<Utc>) -> bool {
+        let mut seen = self.0.lock().expect("no thread panics while holding the lock");
+        // older ones are rejected by their timestamp anyway
+        seen.retain(|_, t| now.timestamp().abs_diff(*t) <= SIGNATURE_MAX_AGE_SECONDS);
+        seen.insert(signature.to_lowercase(), timestamp).is_none()
+    }
+}
+
+/// Check the X-Signature and X-Signature-Timestamp headers of a request, returning why they do
+/// not fit
+pub(crate) fn check_signature(
+    secret: &str,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: DateTime<Utc>,
+    seen: &SeenSignatures,
+) -> Result<(), &'static str> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let timestamp = header("X-Signature-Timestamp")
+        .ok_or("no X-Signature-Timestamp")?
+        .parse::<i64>()
+        .map_err(|_| "X-Signature-Timestamp is not a number")?;
+    if now.timestamp().abs_diff(timestamp) > SIGNATURE_MAX_AGE_SECONDS {
+        return Err("X-Signature-Timestamp is too far off");
+    };
+    let given = header("X-Signature").ok_or("no X-Signature")?;
+    let expected = signature(secret, timestamp, method, path, body);
+    // compare in constant time, so the signature cannot be guessed byte by byte
+    let differences = given
+        .to_lowercase()
+        .bytes()
+        .zip(expected.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    if given.len() != expected.len() || differences != 0 {
+        return Err("X-Signature is wrong");
+    };
+    if !seen.first_use(given, timestamp, now) {
+        return Err("the request was replayed");
+    };
+    Ok(())
+}
+
+/// Reject requests changing the heating unless they are signed, if a signing secret is set
+async fn require_signature(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let secret = state
+        .config
+        .http
+        .as_ref()
+        .and_then(|http| http.signing_secret.as_deref());
+    let Some(secret) = secret else {
+        return next.run(request).await;
+    };
+    if [Method::GET, Method::HEAD, Method::OPTIONS].contains(request.method()) {
+        return next.run(request).await;
+    };
+    let (parts, body) = request.into_parts();
+    let Ok(body) = axum::body::to_bytes(body, SIGNED_BODY_LIMIT).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let path = parts.uri.path();
+    if let Err(reason) = check_signature(
+        secret,
+        &parts.method,
+        path,
+        &parts.headers,
+        &body,
+        Utc::now(),
+        &state.seen_signatures,
+    ) {
+        warn!(
+            "Rejected the unsigned request {} {path}: {reason}.",
+            parts.method
+        );
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Ask browsers for basic auth credentials (e.g. on a screen in the foyer)
+async fn add_auth_challenge(mut response: Response) -> Response {
+    if response.status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            "WWW-Authenticate",
+            HeaderValue::from_static("Basic realm=\"ct-ta-sync\""),
+        );
+    };
+    response
+}
+
+/// Inject the external temperature.
+///
+/// The value is handled exactly like one received via CoE or MQTT.
+async fn post_ext_temp(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ExtTempRequest>,
+) -> StatusCode {
+    if let Err(status) = authorize(state.config.http.as_ref(), &headers, HttpRole::Operator) {
+        warn!("Rejected unauthorized request to inject the external temperature.");
+        return status;
+    };
+    if !request.temperature.is_finite() {
+        return StatusCode::BAD_REQUEST;
+    };
+    let temp = (request.temperature * 10_f64).round() as i32;
+    debug!("Got the external temperature via HTTP: {} °C", temp as f32 / 10_f32);
+    match state.ext_temp_tx.send(temp).await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// List all rooms currently in maintenance mode
+async fn get_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    match crate::db::get_rooms_in_maintenance(&state.config.db).await {
+        Ok(rooms) => {
+            let mut rooms = rooms.into_iter().collect::<Vec<_>>();
+            rooms.sort();
+            Ok(Json(rooms))
+        }
+        Err(e) => {
+            warn!("Unable to get the rooms in maintenance mode: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Put a room into maintenance mode or take it out of it
+async fn set_maintenance(
+    state: AppState,
+    headers: HeaderMap,
+    room: String,
+    enabled: bool,
+) -> StatusCode {
+    if let Err(status) = authorize(state.config.http.as_ref(), &headers, HttpRole::Operator) {
+        warn!("Rejected unauthorized request to change the maintenance mode of room {room}.");
+        return status;
+    };
+    let room_exists = state
+        .config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        .any(|r| r.name == room);
+    if !room_exists {
+        return StatusCode::NOT_FOUND;
+    };
+    match crate::db::set_room_maintenance(&state.config.db, &room, enabled).await {
+        Ok(()) => {
+            state.config.bookings_changed.notify_one();
+            info!(
+                "Room {room} is {} maintenance mode now.",
+                if enabled { "in" } else { "no longer in" }
+            );
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            warn!("Unable to change the maintenance mode of room {room}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn put_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+) -> StatusCode {
+    set_maintenance(state, headers, room, true).await
+}
+
+async fn delete_maintenance(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(room): Path<String>,
+) -> StatusCode {
+    set_maintenance(state, headers, room, false).await
+}
+
+/// List all local bookings
+async fn get_bookings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Booking>>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    match crate::db::get_local_bookings(&state.config.db).await {
+        Ok(bookings) => Ok(Json(bookings)),
+        Err(e) => {
+            warn!("Unable to get the local bookings: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Book a room without CT, e.g. for an event phoned in to the caretaker.
+///
+/// Local bookings are heated for like those from CT, but are never changed by pulling from CT.
+async fn post_booking(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<LocalBookingRequest>,
+) -> Result<(StatusCode, Json<Booking>), StatusCode> {
+    if let Err(status) = authorize(state.config.http.as_ref(), &headers, HttpRole::Operator) {
+        warn!(
+            "Rejected unauthorized request to book room {}.",
+            request.room
+        );
+        return Err(status);
+    };
+    let Some(room) = state
+        .config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        .find(|r| r.name == request.room)
+    else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    if let Err(e) = check_interval(request.start, request.end) {
+        info!("Rejected a local booking of room {}: {e}", room.name);
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if request.end <= Utc::now() {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    match crate::db::insert_local_booking(
+        &state.config.db,
+        room.churchtools_id,
+        request.start,
+        request.end,
+    )
+    .await
+    {
+        Ok(booking_id) => {
+            state.config.bookings_changed.notify_one();
+            info!(
+                "Booked room {} from {} to {} as local booking {booking_id}.",
+                room.name, request.start, request.end
+            );
+            // the interval was checked above
+            let booking = Booking::new(booking_id, room.churchtools_id, request.start, request.end)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            Ok((StatusCode::CREATED, Json(booking)))
+        }
+        Err(e) => {
+            warn!("Unable to store a local booking of room {}: {e}", room.name);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Delete a local booking. Bookings from CT cannot be deleted.
+async fn delete_booking(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(booking_id): Path<i64>,
+) -> StatusCode {
+    if let Err(status) = authorize(state.config.http.as_ref(), &headers, HttpRole::Operator) {
+        warn!("Rejected unauthorized request to delete local booking {booking_id}.");
+        return status;
+    };
+    match crate::db::delete_local_booking(&state.config.db, booking_id).await {
+        Ok(true) => {
+            state.config.bookings_changed.notify_one();
+            info!("Deleted local booking {booking_id}.");
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            warn!("Unable to delete local booking {booking_id}: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// The heating windows of all rooms as an ICS feed, see [crate::ics]
+async fn get_heating_ics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<([(HeaderName, &'static str); 1], String), StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    match crate::ics::heating_calendar(&state.config, &state.ext_temps).await {
+        Ok(ics) => Ok(([(CONTENT_TYPE, "text/calendar; charset=utf-8")], ics)),
+        Err(e) => {
+            warn!("Unable to compute the heating windows: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Today's heating plan as page for a wall-mounted tablet, see [crate::kiosk]
+async fn get_today(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Html<String>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    match crate::kiosk::today(&state.config, &state.ext_temps, &state.metrics).await {
+        Ok(html) => Ok(Html(html)),
+        Err(e) => {
+            warn!("Unable to compute today's heating plan: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Room states and outdoor temperatures as they change, over a WebSocket, see [crate::live]
+async fn get_live(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    Ok(ws.on_upgrade(move |socket| crate::live::stream(socket, state.ext_temps, state.metrics)))
+}
+
+/// Let websites of any origin read the JSON API, e.g. for badges
+///
+/// Browsers ask with an OPTIONS request (preflight) before sending the Authorization header to
+/// another origin. That is answered here, without authorization.
+async fn allow_any_origin(request: Request, next: Next) -> Response {
+    let mut response = if request.method() == Method::OPTIONS {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            "Access-Control-Allow-Methods",
+            HeaderValue::from_static("GET, HEAD"),
+        );
+        headers.insert(
+            "Access-Control-Allow-Headers",
+            HeaderValue::from_static("Authorization"),
+        );
+        headers.insert("Access-Control-Max-Age", HeaderValue::from_static("86400"));
+        response
+    } else {
+        next.run(request).await
+    };
+    response
+        .headers_mut()
+        .insert("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
+    response
+}
+
+/// All rooms with their state and next heating window, see [crate::api]
+async fn get_api_rooms(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ApiRoom>>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    match crate::api::rooms(&state.config, &state.ext_temps, &state.metrics).await {
+        Ok(rooms) => Ok(Json(rooms)),
+        Err(e) => {
+            warn!("Unable to compute the heating windows: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BookingsQuery {
+    days: Option<i64>,
+}
+
+/// The upcoming bookings with their heating windows, see [crate::api]
+async fn get_api_bookings(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<BookingsQuery>,
+) -> Result<Json<Vec<ApiBooking>>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    let days = query.days.unwrap_or(DEFAULT_BOOKING_DAYS);
+    if !(1..=MAX_BOOKING_DAYS).contains(&days) {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    match crate::api::bookings(&state.config, &state.ext_temps, days).await {
+        Ok(bookings) => Ok(Json(bookings)),
+        Err(e) => {
+            warn!("Unable to compute the heating windows: {e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The state of the whole installation, see [crate::api]
+async fn get_api_state(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ApiState>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    Ok(Json(
+        crate::api::state(&state.config, &state.ext_temps, &state.metrics).await,
+    ))
+}
+
+/// The OpenAPI document of the JSON API. It is public, like the source code it is generated from.
+async fn get_openapi() -> Json<serde_json::Value> {
+    Json(crate::api::openapi())
+}
+
+/// The response to GET /status
+#[derive(Serialize)]
+struct Status {
+    #[serde(flatten)]
+    build: BuildInfo,
+    /// sensor -> external dew point in Degree Centigrade, for sensors receiving the humidity
+    dew_points: BTreeMap<String, Option<f64>>,
+    /// sender -> datagrams received since startup that were not parsable as CoE
+    malformed_coe: BTreeMap<CoeSource, MalformedCoe>,
+    /// room listed under several CMIs -> those of them the last push did not reach, for rooms it
+    /// reached on others
+    partially_reached_rooms: BTreeMap<String, Vec<String>>,
+    /// CMI host -> the address the last push was sent to (its host or a backup host)
+    cmi_send_paths: BTreeMap<String, String>,
+}
+
+/// Version and build of this binary, the hash of the active config, the external dew points, the
+/// senders of malformed CoE, the rooms only some of their CMIs were reached for and the address
+/// each CMI was sent to
+async fn get_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Status>, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    let dew_points = state
+        .ext_temps
+        .dew_points(&state.config)
+        .await
+        .into_iter()
+        .map(|(sensor, x)| (sensor, x.map(|x| x as f64 / 10_f64)))
+        .collect();
+    Ok(Json(Status {
+        build: BuildInfo::new(&state.config.hash),
+        dew_points,
+        malformed_coe: state.metrics.malformed_coe(),
+        partially_reached_rooms: state.metrics.partially_reached_rooms(),
+        cmi_send_paths: state.metrics.cmi_send_paths(),
+    }))
+}
+
+/// Our own statistics in the Prometheus text format
+async fn get_metrics(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<String, StatusCode> {
+    authorize(state.config.http.as_ref(), &headers, HttpRole::Viewer)?;
+    Ok(state.metrics.render())
+}
+
+fn router(state: AppState) -> Router {
+    let api = Router::new()
+        .route("/api/rooms", get(get_api_rooms))
+        .route("/api/bookings", get(get_api_bookings))
+        .route("/api/state", get(get_api_state))
+        .route("/api/openapi.json", get(get_openapi))
+        .layer(middleware::from_fn(allow_any_origin));
+    Router::new()
+        .merge(api)
+        .route("/ext-temp", post(post_ext_temp))
+        .route("/metrics", get(get_metrics))
+        .route("/status", get(get_status))
+        .route("/maintenance", get(get_maintenance))
+        .route(
+            "/rooms/{room}/maintenance",
+            put(put_maintenance).delete(delete_maintenance),
+        )
+        .route("/bookings", get(get_bookings).post(post_booking))
+        .route("/bookings/{booking_id}", delete(delete_booking))
+        .route("/heating.ics", get(get_heating_ics))
+        .route("/today", get(get_today))
+        .route("/live", get(get_live))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_signature,
+        ))
+        .layer(middleware::map_response(add_auth_challenge))
+        .with_state(state)
+}
+
+/// The bound listener of the HTTP API, with the TLS config if it serves HTTPS
+pub struct HttpListener {
+    listener: TcpListener,
+    tls: Option<RustlsConfig>,
+}
+
+/// Write a self-signed certificate for `names` and its key, unless both files exist
+fn ensure_self_signed(tls: &HttpTlsConfig) -> Result<(), HttpError> {
+    if tls.self_signed_names.is_empty() || (tls.cert_path.exists() && tls.key_path.exists()) {
+        return Ok(());
+    };
+    let certified = rcgen::generate_simple_self_signed(tls.self_signed_names.clone())?;
+    std::fs::write(&tls.cert_path, certified.cert.pem())?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // the key must not be readable by others
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&tls.key_path)?
+        .write_all(certified.key_pair.serialize_pem().as_bytes())?;
+    info!(
+        "Generated a self-signed certificate for {} at {}.",
+        tls.self_signed_names.join(", "),
+        tls.cert_path.display()
+    );
+    Ok(())
+}
+
+/// Read certificate and key and build the config of the HTTPS server
+fn tls_config(tls: &HttpTlsConfig) -> Result<RustlsConfig, HttpError> {
+    ensure_self_signed(tls)?;
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| HttpError::Pem(tls.cert_path.clone(), e))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+        .map_err(|e| HttpError::Pem(tls.key_path.clone(), e))?;
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Bind the HTTP listener, if the HTTP API is configured.
+///
+/// This happens before privileges are dropped, so privileged ports can be used and the key may
+/// only be readable by root.
+pub async fn bind(config: &Config) -> Result<Option<HttpListener>, HttpError> {
+    let Some(http_config) = &config.http else {
+        debug!("No HTTP server configured.");
+        return Ok(None);
+    };
+    let tls = http_config.tls.as_ref().map(tls_config).transpose()?;
+    match TcpListener::bind(&http_config.bind_addr).await {
+        Ok(listener) => Ok(Some(HttpListener { listener, tls })),
+        Err(e) => {
+            error!("Unable to listen for HTTP on {}.", http_config.bind_addr);
+            Err(HttpError::Bind(http_config.bind_addr.clone(), e))
+        }
+    }
+}
+
+/// Handle a request in `span`, which is the one the server was started in (e.g. that of a tenant)
+async fn in_span(State(span): State<tracing::Span>, request: Request, next: Next) -> Response {
+    next.run(request).instrument(span).await
+}
+
+/// Serve the HTTP API on listener until shutdown
+pub async fn serve(
+    config: Arc<Config>,
+    listener: Option<HttpListener>,
+    ext_temp_tx: mpsc::Sender<i32>,
+    ext_temps: ExternalTemperatures,
+    metrics: Arc<Metrics>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) -> Result<(), HttpError> {
+    let Some(HttpListener { listener, tls }) = listener else {
+        return Ok(());
+    };
+    let app = router(AppState {
+        config: config.clone(),
+        ext_temp_tx,
+        ext_temps,
+        metrics,
+        seen_signatures: Arc::default(),
+    })
+    .layer(middleware::from_fn_with_state(
+        tracing::Span::current(),
+        in_span,
+    ));
+    let Some(tls) = tls else {
+        info!("Starting HTTP server on {}", listener.local_addr()?);
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = watcher.changed().await;
+                debug!("Shutting down the HTTP server now.");
+            })
+            .await?;
+        return Ok(());
+    };
+    info!("Starting HTTPS server on {}", listener.local_addr()?);
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(
+        async move {
+            let _ = watcher.changed().await;
+            debug!("Shutting down the HTTPS server now.");
+            shutdown_handle.graceful_shutdown(Some(std::time::Duration::from_secs(5)));
+        }
+        .in_current_span(),
+    );
+    axum_server::from_tcp_rustls(listener.into_std()?, tls)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::HttpUserConfig;
+
+    fn http_config() -> HttpConfig {
+        HttpConfig {
+            bind_addr: "127.0.0.1:0".to_owned(),
+            token: Some("secret".to_owned()),
+            users: vec![HttpUserConfig {
+                name: "foyer".to_owned(),
+                role: HttpRole::Viewer,
+                token: Some("screen".to_owned()),
+                password: Some("pa:ss".to_owned()),
+            }],
+            anonymous_read: false,
+            tls: None,
+            signing_secret: None,
+            grpc_bind_addr: None,
+        }
+    }
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn authorized_with_correct_token() {
+        let config = http_config();
+        let operator = headers("Bearer secret");
+        assert_eq!(
+            authorize(Some(&config), &operator, HttpRole::Operator),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(Some(&config), &operator, HttpRole::Viewer),
+            Ok(())
+        );
+        let viewer = headers("Bearer screen");
+        assert_eq!(authorize(Some(&config), &viewer, HttpRole::Viewer), Ok(()));
+        assert_eq!(
+            authorize(Some(&config), &viewer, HttpRole::Operator),
+            Err(StatusCode::FORBIDDEN)
+        );
+        // foyer:pa:ss
+        let basic = headers("Basic Zm95ZXI6cGE6c3M=");
+        assert_eq!(authorize(Some(&config), &basic, HttpRole::Viewer), Ok(()));
+    }
+
+    #[test]
+    fn unauthorized_with_wrong_token() {
+        let mut config = http_config();
+        for authorization in ["Bearer secreT", "secret", "Basic Zm95ZXI6cGFzcw=="] {
+            assert_eq!(
+                authorize(Some(&config), &headers(authorization), HttpRole::Viewer),
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+        assert_eq!(
+            authorize(Some(&config), &HeaderMap::new(), HttpRole::Viewer),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authorize(None, &headers("Bearer secret"), HttpRole::Viewer),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        // anonymous requests may only read
+        config.anonymous_read = true;
+        assert_eq!(
+            authorize(Some(&config), &HeaderMap::new(), HttpRole::Viewer),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(Some(&config), &HeaderMap::new(), HttpRole::Operator),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn self_signed_certificate_is_generated() {
+        let dir = std::env::temp_dir().join(format!("ct-ta-sync-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tls = HttpTlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            self_signed_names: vec!["ct-ta-sync.local".to_owned()],
+        };
+        assert!(tls_config(&tls).is_ok());
+        let cert = std::fs::read(&tls.cert_path).unwrap();
+        // an existing certificate is kept
+        assert!(tls_config(&tls).is_ok());
+        assert_eq!(std::fs::read(&tls.cert_path).unwrap(), cert);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&tls.key_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // without names, missing files are an error
+        std::fs::remove_file(&tls.key_path).unwrap();
+        let tls = HttpTlsConfig {
+            self_signed_names: vec![],
+            ..tls
+        };
+        assert!(matches!(tls_config(&tls), Err(HttpError::Pem(..))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn signed_requests() {
+        let body = br#"{"temperature": -5.2}"#;
+        let path = "/ext-temp";
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        // the same as openssl dgst -sha256 -hmac secret
+        let expected = "7c9a1ec2e5d52e6a8d12d5fe8820c0282d2db0908dc3d6b36ca332ae09c81f5a";
+        assert_eq!(
+            signature("secret", now.timestamp(), &Method::POST, path, body),
+            expected
+        );
+        let signed = |timestamp: i64, signature: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Signature-Timestamp", timestamp.into());
+            headers.insert("X-Signature", signature.parse().unwrap());
+            headers
+        };
+        let check = |headers: &HeaderMap, body: &[u8]| {
+            let seen = SeenSignatures::default();
+            check_signature("secret", &Method::POST, path, headers, body, now, &seen)
+        };
+        assert_eq!(check(&signed(now.timestamp(), expected), body), Ok(()));
+        assert_eq!(
+            check(&signed(now.timestamp(), &expected.to_uppercase()), body),
+            Ok(())
+        );
+        // replayed within the time window
+        let seen = SeenSignatures::default();
+        let headers = signed(now.timestamp(), expected);
+        let check_twice = |headers: &HeaderMap| {
+            check_signature("secret", &Method::POST, path, headers, body, now, &seen)
+        };
+        assert_eq!(check_twice(&headers), Ok(()));
+        assert!(check_twice(&headers).is_err());
+        assert!(check_twice(&signed(now.timestamp(), &expected.to_uppercase())).is_err());
+        // an absurd timestamp does not overflow
+        assert!(check(&signed(i64::MIN, expected), body).is_err());
+        // a changed temperature
+        assert!(check(
+            &signed(now.timestamp(), expected),
+            br#"{"temperature": 30}"#
+        )
+        .is_err());
+        // replayed later
+        assert!(check(&signed(now.timestamp() - 301, expected), body).is_err());
+        assert!(check(&signed(now.timestamp(), &expected[..10]), body).is_err());
+        assert!(check(&HeaderMap::new(), body).is_err());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn api_answers_cors_preflight(pool: sqlx::SqlitePool) {
+        let config = Arc::new(crate::config::test_empty_config(
+            "
+http:
+  bind_addr: 127.0.0.1:0
+  token: secret
+  signing_secret: also_secret
+",
+            pool,
+        ));
+        let app = router(AppState {
+            ext_temp_tx: mpsc::channel(1).0,
+            ext_temps: ExternalTemperatures::new(&config),
+            metrics: Arc::default(),
+            seen_signatures: Arc::default(),
+            config,
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api/rooms", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        // without authorization and signature
+        let preflight = client
+            .request(reqwest::Method::OPTIONS, &url)
+            .header("Origin", "https://example.org")
+            .header("Access-Control-Request-Method", "GET")
+            .header("Access-Control-Request-Headers", "authorization")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        let allowed = |name| preflight.headers()[name].to_str().unwrap();
+        assert_eq!(allowed("access-control-allow-origin"), "*");
+        assert_eq!(allowed("access-control-allow-headers"), "Authorization");
+
+        let response = client
+            .get(&url)
+            .header("Authorization", "Bearer secret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["access-control-allow-origin"], "*");
+        server.abort();
+    }
+}
@@ -0,0 +1,16 @@
+//! The language of texts shown to people: notifications, the heating calendar and the heating
+//! windows written to CT.
+//!
+//! Logs stay in English. Each translated text matches on the [Language], so a new locale is a new
+//! variant and the compiler points at every text still missing for it.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Language {
+    #[default]
+    En,
+    De,
+}
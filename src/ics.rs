@@ -0,0 +1,256 @@
+//! ICS feed of the heating windows of all rooms, to overlay on the shared calendar of the church.
+//!
+//! It explains why rooms are heated outside of their bookings (preheat, preshutdown, overrun).
+
+use std::collections::HashSet;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+#[cfg(feature = "http")]
+use crate::i18n::Language;
+use crate::{
+    config::Config,
+    db::DBError,
+    push_to_ta::{get_booking_history, heating_window, unused_for},
+    read_ext_temp::ExternalTemperatures,
+    resource_hierarchy::implying_resources,
+};
+
+/// The feed covers the bookings of the next ... days
+#[cfg(feature = "http")]
+const ICS_DAYS: i64 = 7;
+
+/// A room heating for a booking
+#[derive(Debug, PartialEq)]
+pub(crate) struct HeatingEvent {
+    pub room: String,
+    pub booking_id: i64,
+    pub booking_start: DateTime<Utc>,
+    pub booking_end: DateTime<Utc>,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+/// Escape text for a property value (RFC 5545, 3.3.11)
+#[cfg(feature = "http")]
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Fold a content line after at most 75 octets (RFC 5545, 3.1)
+#[cfg(feature = "http")]
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            // the leading space counts
+            octets = 1;
+        };
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded
+}
+
+#[cfg(feature = "http")]
+fn ics_time(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Render `events` as an iCalendar
+#[cfg(feature = "http")]
+fn render(events: &[HeatingEvent], now: DateTime<Utc>, language: Language) -> String {
+    let heating = match language {
+        Language::En => "Heating",
+        Language::De => "Heizung",
+    };
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//ct-ta-sync//heating windows//EN".to_owned(),
+        format!("X-WR-CALNAME:{heating}"),
+    ];
+    for event in events {
+        let overrun = (event.end - event.booking_end).num_minutes();
+        let preheat = (event.booking_start - event.start).num_minutes();
+        let (from, to) = (
+            event.booking_start.to_rfc3339(),
+            event.booking_end.to_rfc3339(),
+        );
+        let description = match (language, overrun < 0) {
+            (Language::En, true) => format!(
+                "Booked from {from} to {to}. Preheat {preheat} min, heating stops {} min before the end.",
+                -overrun
+            ),
+            (Language::En, false) => format!(
+                "Booked from {from} to {to}. Preheat {preheat} min, heating continues {overrun} min after the end."
+            ),
+            (Language::De, true) => format!(
+                "Gebucht von {from} bis {to}. Vorheizen {preheat} min, Heizung endet {} min vor dem Ende.",
+                -overrun
+            ),
+            (Language::De, false) => format!(
+                "Gebucht von {from} bis {to}. Vorheizen {preheat} min, Heizung läuft {overrun} min nach dem Ende weiter."
+            ),
+        };
+        lines.extend([
+            "BEGIN:VEVENT".to_owned(),
+            format!(
+                "UID:{}-{}@ct-ta-sync",
+                event.booking_id,
+                escape(&event.room.replace(' ', "-"))
+            ),
+            format!("DTSTAMP:{}", ics_time(now)),
+            format!("DTSTART:{}", ics_time(event.start)),
+            format!("DTEND:{}", ics_time(event.end)),
+            format!("SUMMARY:{}", escape(&format!("{heating} {}", event.room))),
+            format!("DESCRIPTION:{}", escape(&description)),
+            "TRANSP:TRANSPARENT".to_owned(),
+            "END:VEVENT".to_owned(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_owned());
+    lines
+        .iter()
+        .map(|line| fold(line) + "\r\n")
+        .collect::<String>()
+}
+
+/// The heating windows of all rooms for the bookings starting before `until` that are not over.
+///
+/// Windows are computed with the current external temperature and forecast. Rooms deferred for
+/// others on the same circuit or by max_preheating_rooms may start later. Windows starting outside
+/// of the heating season are left out.
+pub(crate) async fn heating_events(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    now: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<Vec<HeatingEvent>, DBError> {
+    let bookings = crate::db::get_bookings_in_timeframe(
+        &config.db,
+        (now - TimeDelta::days(1)).naive_utc(),
+        until.naive_utc(),
+    )
+    .await?;
+    let history = get_booking_history(config, now - TimeDelta::days(1), until).await?;
+    let parents = if config.resource_hierarchy.is_some() {
+        crate::db::get_resource_parents(&config.db).await?
+    } else {
+        Default::default()
+    };
+    let forecasts = if config.forecast.is_some() {
+        crate::db::get_forecasts_in_timeframe(
+            &config.db,
+            (now - TimeDelta::hours(1)).naive_utc(),
+            until.naive_utc(),
+        )
+        .await?
+    } else {
+        vec![]
+    };
+
+    let mut events = vec![];
+    // rooms may be on multiple CMIs, but are configured the same on all of them
+    let mut seen = HashSet::new();
+    for cmi in &config.cmis {
+        let ext_temp = *ext_temps.for_site(cmi.site.as_deref()).read().await;
+        for room in cmi.rooms.iter().filter(|room| seen.insert(&room.name)) {
+            let implying = implying_resources(
+                config.resource_hierarchy.as_ref(),
+                &parents,
+                room.churchtools_id,
+            );
+            for booking in bookings
+                .iter()
+                .filter(|b| implying.contains(&b.resource_id()))
+            {
+                let unused_for = unused_for(room, &history, &implying, booking);
+                let (start, end) =
+                    heating_window(config, room, booking, &forecasts, ext_temp, unused_for);
+                if end < now || !config.in_heating_season(cmi.site.as_deref(), start) {
+                    continue;
+                };
+                events.push(HeatingEvent {
+                    room: room.name.clone(),
+                    booking_id: booking.booking_id(),
+                    booking_start: booking.start_time(),
+                    booking_end: booking.end_time(),
+                    start,
+                    end,
+                });
+            }
+        }
+    }
+    events.sort_by_key(|event| event.start);
+    Ok(events)
+}
+
+/// The heating windows of all rooms for the bookings of the next days, as an iCalendar.
+///
+/// See [heating_events].
+#[cfg(feature = "http")]
+pub async fn heating_calendar(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+) -> Result<String, DBError> {
+    let now = Utc::now();
+    let events = heating_events(config, ext_temps, now, now + TimeDelta::days(ICS_DAYS)).await?;
+    Ok(render(&events, now, config.global.language))
+}
+
+#[cfg(all(test, feature = "http"))]
+mod test {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn at(h: u32, min: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2025, 1, 12)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn render_heating_events() {
+        let events = [HeatingEvent {
+            room: "Hall, Stage".to_owned(),
+            booking_id: 42,
+            booking_start: at(10, 0),
+            booking_end: at(12, 0),
+            start: at(9, 15),
+            end: at(11, 50),
+        }];
+        let ics = render(&events, at(8, 0), Language::En);
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        assert!(ics.contains("\r\nUID:42-Hall\\,-Stage@ct-ta-sync\r\n"));
+        assert!(ics.contains("\r\nDTSTART:20250112T091500Z\r\nDTEND:20250112T115000Z\r\n"));
+        assert!(ics.contains("\r\nSUMMARY:Heating Hall\\, Stage\r\n"));
+        // long lines are folded
+        assert!(ics.lines().all(|line| line.len() <= 75));
+        assert!(ics
+            .replace("\r\n ", "")
+            .contains("Preheat 45 min\\, heating stops 10 min before the end."));
+        let ics = render(&events, at(8, 0), Language::De);
+        assert!(ics.contains("\r\nSUMMARY:Heizung Hall\\, Stage\r\n"));
+        assert!(ics
+            .replace("\r\n ", "")
+            .contains("Vorheizen 45 min\\, Heizung endet 10 min vor dem Ende."));
+    }
+
+    #[test]
+    fn fold_multibyte() {
+        let line = "SUMMARY:".to_owned() + &"ä".repeat(40);
+        let folded = fold(&line);
+        assert!(folded.split("\r\n").all(|x| x.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+}
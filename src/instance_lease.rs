@@ -0,0 +1,124 @@
+//! Make sure only one instance syncs with a DB at a time.
+//!
+//! Two instances using the same DB and CMIs would fight over the heating. The running instance
+//! holds a lease in the DB and renews it regularly. A lease that was not renewed for
+//! [`LEASE_DURATION`] is considered stale (e.g. after a crash) and may be taken over.
+
+use std::sync::Arc;
+
+use chrono::{TimeDelta, Utc};
+use sqlx::{Pool, Sqlite};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::Config,
+    db::{get_instance_lease_holder, release_instance_lease, take_instance_lease, DBError},
+    InShutdown,
+};
+
+/// A lease not renewed for this long is stale
+const LEASE_DURATION: TimeDelta = TimeDelta::minutes(2);
+/// Renew the lease every ...
+const RENEW_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum LeaseError {
+    Db(DBError),
+    /// another instance holds the lease
+    Held(String),
+}
+impl std::fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Db(e) => write!(f, "{e}"),
+            Self::Held(x) => write!(
+                f,
+                "Another instance ({x}) is already syncing with this DB. Stop it first, or start with --force if it is gone."
+            ),
+        }
+    }
+}
+impl From<DBError> for LeaseError {
+    fn from(value: DBError) -> Self {
+        Self::Db(value)
+    }
+}
+impl std::error::Error for LeaseError {}
+
+/// The lease held by this instance
+#[derive(Debug, Clone)]
+pub struct InstanceLease {
+    owner: String,
+}
+impl InstanceLease {
+    /// Take the lease, failing if another instance holds a current one, unless `force` is set
+    pub async fn acquire(db: &Pool<Sqlite>, force: bool) -> Result<Self, LeaseError> {
+        let owner = format!(
+            "pid {}, started {}",
+            std::process::id(),
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ")
+        );
+        if let Err(holder) = take_instance_lease(db, &owner, LEASE_DURATION, force).await? {
+            return Err(LeaseError::Held(holder));
+        };
+        if force {
+            warn!("Took the instance lease by force.");
+        };
+        debug!("Took the instance lease as {owner}.");
+        Ok(Self { owner })
+    }
+
+    /// Fail if another instance holds a current lease, unless `force` is set. The lease is not
+    /// taken, so this works on a DB that is not migrated yet.
+    pub async fn ensure_not_held(db: &Pool<Sqlite>, force: bool) -> Result<(), LeaseError> {
+        if force {
+            return Ok(());
+        };
+        match get_instance_lease_holder(db, Utc::now() - LEASE_DURATION).await? {
+            Some(holder) => Err(LeaseError::Held(holder)),
+            None => Ok(()),
+        }
+    }
+
+    /// Renew the lease until shutdown, then release it.
+    ///
+    /// Shuts down if another instance took the lease over in the meantime.
+    pub async fn keep(
+        self,
+        config: Arc<Config>,
+        mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+        shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
+    ) {
+        let mut interval = tokio::time::interval(RENEW_INTERVAL);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = watcher.changed() => {
+                    break;
+                }
+                _ = interval.tick() => {}
+            };
+            match take_instance_lease(&config.db, &self.owner, LEASE_DURATION, false).await {
+                Ok(Ok(())) => {}
+                Ok(Err(holder)) => {
+                    error!(
+                        "Another instance ({holder}) took over the instance lease. Shutting down."
+                    );
+                    shutdown_tx.send_replace(InShutdown::Yes);
+                    return;
+                }
+                // keep going, the lease only becomes stale after a few failures
+                Err(e) => warn!("{e}"),
+            };
+        }
+        self.release(&config.db).await;
+    }
+
+    /// Give up the lease, so the next instance can start right away
+    pub async fn release(self, db: &Pool<Sqlite>) {
+        match release_instance_lease(db, &self.owner).await {
+            Ok(()) => info!("Released the instance lease."),
+            Err(e) => warn!("{e}"),
+        };
+    }
+}
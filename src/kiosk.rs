@@ -0,0 +1,197 @@
+//! A read-only page with today's heating plan, for a tablet on the wall of the foyer.
+//!
+//! It shows each room with its next heating window and the state last sent to it, and the outdoor
+//! temperature. The page reloads itself and has no controls.
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+
+use crate::{
+    config::Config, db::DBError, i18n::Language, ics::heating_events, metrics::Metrics,
+    read_ext_temp::ExternalTemperatures,
+};
+
+/// The page reloads itself every ... seconds
+const REFRESH_SECONDS: u32 = 60;
+
+/// A room on the page
+#[derive(Debug, PartialEq)]
+struct Row {
+    room: String,
+    /// the next heating window today that is not over
+    window: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// whether HEATING was sent last, None before the first push
+    heating: Option<bool>,
+}
+
+/// Escape text for HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 215 -> 21.5 °C
+fn temperature(temp: Option<i32>) -> String {
+    match temp {
+        Some(temp) => format!("{:.1} °C", temp as f64 / 10_f64),
+        None => "–".to_owned(),
+    }
+}
+
+/// Render the page. `outdoor` has the temperature of each sensor, the default one as None.
+fn render(
+    rows: &[Row],
+    outdoor: &[(Option<String>, Option<i32>)],
+    now: DateTime<Utc>,
+    language: Language,
+) -> String {
+    let (title, outdoor_label, room_label, window_label, state_label) = match language {
+        Language::En => ("Heating today", "Outdoor", "Room", "Heating", "State"),
+        Language::De => ("Heizung heute", "Außen", "Raum", "Heizzeit", "Zustand"),
+    };
+    let hm = |time: DateTime<Utc>| time.with_timezone(&Local).format("%H:%M").to_string();
+    let mut html = format!(
+        "<!DOCTYPE html>
+<html lang=\"{lang}\">
+<head>
+<meta charset=\"utf-8\">
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">
+<meta http-equiv=\"refresh\" content=\"{REFRESH_SECONDS}\">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; font-size: 1.6em; margin: 1em; background: #111; color: #eee; }}
+table {{ width: 100%; border-collapse: collapse; }}
+th, td {{ text-align: left; padding: 0.4em; border-bottom: 1px solid #444; }}
+.heating {{ color: #f84; }}
+.off {{ color: #8af; }}
+</style>
+</head>
+<body>
+<h1>{title} <small>{date} {time}</small></h1>
+",
+        lang = match language {
+            Language::En => "en",
+            Language::De => "de",
+        },
+        date = now.with_timezone(&Local).format("%d.%m.%Y"),
+        time = hm(now),
+    );
+    for (site, temp) in outdoor {
+        let label = match site {
+            Some(site) => format!("{outdoor_label} ({})", escape(site)),
+            None => outdoor_label.to_owned(),
+        };
+        html.push_str(&format!("<p>{label}: {}</p>\n", temperature(*temp)));
+    }
+    html.push_str(&format!(
+        "<table>\n<tr><th>{room_label}</th><th>{window_label}</th><th>{state_label}</th></tr>\n"
+    ));
+    for row in rows {
+        let window = match row.window {
+            Some((start, end)) if start <= now => match language {
+                Language::En => format!("until {}", hm(end)),
+                Language::De => format!("bis {}", hm(end)),
+            },
+            Some((start, end)) => format!("{}–{}", hm(start), hm(end)),
+            None => "–".to_owned(),
+        };
+        let state = match (language, row.heating) {
+            (Language::En, Some(true)) => "<span class=\"heating\">heating</span>",
+            (Language::En, Some(false)) => "<span class=\"off\">off</span>",
+            (Language::En, None) => "unknown",
+            (Language::De, Some(true)) => "<span class=\"heating\">heizt</span>",
+            (Language::De, Some(false)) => "<span class=\"off\">aus</span>",
+            (Language::De, None) => "unbekannt",
+        };
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{window}</td><td>{state}</td></tr>\n",
+            escape(&row.room)
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// The page for now: every room of every CMI with its first heating window until midnight
+pub(crate) async fn today(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    metrics: &Metrics,
+) -> Result<String, DBError> {
+    let now = Utc::now();
+    let midnight = (now.with_timezone(&Local).date_naive() + TimeDelta::days(1))
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight exists")
+        .and_local_timezone(Local)
+        .earliest()
+        .map_or(now + TimeDelta::days(1), |midnight| midnight.to_utc());
+    let events = heating_events(config, ext_temps, now, midnight).await?;
+    let heating = metrics.room_heating();
+    let rows = config
+        .cmis
+        .iter()
+        .flat_map(|cmi| cmi.rooms.iter().map(move |room| (cmi, room)))
+        .map(|(cmi, room)| Row {
+            room: room.name.clone(),
+            // events are sorted by start
+            window: events
+                .iter()
+                .find(|event| event.room == room.name && event.end > now)
+                .map(|event| (event.start, event.end)),
+            heating: heating
+                .get(&(cmi.host.clone(), room.name.clone()))
+                .map(|(_, heating)| *heating),
+        })
+        .collect::<Vec<_>>();
+
+    let mut outdoor = vec![(None, *ext_temps.for_site(None).read().await)];
+    let mut sites = config
+        .sites
+        .iter()
+        .filter(|(_, site)| site.external_temperature_sensor.is_some())
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    sites.sort();
+    for site in sites {
+        let temp = *ext_temps.for_site(Some(site)).read().await;
+        outdoor.push((Some(site.clone()), temp));
+    }
+    Ok(render(&rows, &outdoor, now, config.global.language))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_rooms() {
+        let now = Utc::now();
+        let rows = [
+            Row {
+                room: "Hall <Stage>".to_owned(),
+                window: Some((now - TimeDelta::minutes(5), now + TimeDelta::hours(1))),
+                heating: Some(true),
+            },
+            Row {
+                room: "Office".to_owned(),
+                window: None,
+                heating: None,
+            },
+        ];
+        let outdoor = [(None, Some(-52)), (Some("Annex".to_owned()), None)];
+        let html = render(&rows, &outdoor, now, Language::En);
+        assert!(html.contains("<meta http-equiv=\"refresh\" content=\"60\">"));
+        assert!(html.contains("<p>Outdoor: -5.2 °C</p>"));
+        assert!(html.contains("<p>Outdoor (Annex): –</p>"));
+        assert!(html.contains("<td>Hall &lt;Stage&gt;</td><td>until "));
+        assert!(html.contains("<span class=\"heating\">heating</span>"));
+        assert!(html.contains("<tr><td>Office</td><td>–</td><td>unknown</td></tr>"));
+        // nothing to click
+        assert!(!html.contains("<form") && !html.contains("<button") && !html.contains("<a "));
+
+        let html = render(&rows, &outdoor, now, Language::De);
+        assert!(html.contains("<title>Heizung heute</title>"));
+        assert!(html.contains("<p>Außen: -5.2 °C</p>"));
+    }
+}
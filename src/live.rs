@@ -0,0 +1,146 @@
+//! Live state changes over a WebSocket (GET /live), so dashboards do not have to poll.
+//!
+//! A client first gets the state of every room and sensor, then each change of them as it
+//! happens. Every message is a JSON object with a `type` of `room` or `temperature`.
+
+use std::collections::BTreeMap;
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::Serialize;
+
+use crate::{metrics::Metrics, read_ext_temp::ExternalTemperatures};
+
+/// A change sent to clients
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LiveEvent {
+    /// the state sent to a room changed
+    Room {
+        cmi: String,
+        room: String,
+        /// the output on the CMI (1-64)
+        output: u8,
+        heating: bool,
+    },
+    /// the outdoor temperature of a sensor changed
+    Temperature {
+        /// `default` or the site of the sensor
+        sensor: String,
+        /// in Degree Centigrade, null if unknown
+        temperature: Option<f64>,
+    },
+}
+
+/// Everything clients are told about
+#[derive(Debug, Default)]
+struct LiveState {
+    /// (CMI host, room name) -> (CMI output, heating)
+    rooms: BTreeMap<(String, String), (u8, bool)>,
+    /// sensor -> temperature in tenths of a Degree Centigrade
+    temperatures: BTreeMap<String, Option<i32>>,
+}
+impl LiveState {
+    async fn now(ext_temps: &ExternalTemperatures, metrics: &Metrics) -> Self {
+        Self {
+            rooms: metrics.room_heating(),
+            temperatures: ext_temps.by_sensor().await,
+        }
+    }
+
+    /// The events leading from `self` to `new`
+    fn changes(&self, new: &Self) -> Vec<LiveEvent> {
+        let rooms = new
+            .rooms
+            .iter()
+            .filter(|(key, state)| self.rooms.get(*key) != Some(*state))
+            .map(|((cmi, room), (output, heating))| LiveEvent::Room {
+                cmi: cmi.clone(),
+                room: room.clone(),
+                output: *output,
+                heating: *heating,
+            });
+        let temperatures = new
+            .temperatures
+            .iter()
+            .filter(|(sensor, temp)| self.temperatures.get(*sensor) != Some(*temp))
+            .map(|(sensor, temp)| LiveEvent::Temperature {
+                sensor: sensor.clone(),
+                temperature: temp.map(|x| x as f64 / 10_f64),
+            });
+        rooms.chain(temperatures).collect()
+    }
+}
+
+/// Send the state and then each change to `socket` until the client disconnects
+pub(crate) async fn stream(
+    mut socket: WebSocket,
+    ext_temps: ExternalTemperatures,
+    metrics: std::sync::Arc<Metrics>,
+) {
+    let mut sent = LiveState::default();
+    loop {
+        // enabled before reading the state, so no change in between is missed
+        let rooms_changed = metrics.room_heating_changed();
+        let temperatures_changed = ext_temps.changed();
+        tokio::pin!(rooms_changed, temperatures_changed);
+        rooms_changed.as_mut().enable();
+        temperatures_changed.as_mut().enable();
+        let state = LiveState::now(&ext_temps, &metrics).await;
+        for event in sent.changes(&state) {
+            let text = serde_json::to_string(&event).expect("events are serializable");
+            if socket.send(Message::Text(text.into())).await.is_err() {
+                return;
+            };
+        }
+        sent = state;
+        tokio::select! {
+            _ = rooms_changed => {}
+            _ = temperatures_changed => {}
+            // messages from the client are ignored, pings are answered by axum
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn changes_since_last_sent() {
+        let key = |room: &str| ("cmi.local".to_owned(), room.to_owned());
+        let old = LiveState {
+            rooms: BTreeMap::from([(key("hall"), (1, false)), (key("office"), (2, true))]),
+            temperatures: BTreeMap::from([("default".to_owned(), Some(-52))]),
+        };
+        let new = LiveState {
+            rooms: BTreeMap::from([(key("hall"), (1, true)), (key("office"), (2, true))]),
+            temperatures: BTreeMap::from([("default".to_owned(), None)]),
+        };
+        assert_eq!(
+            old.changes(&new),
+            [
+                LiveEvent::Room {
+                    cmi: "cmi.local".to_owned(),
+                    room: "hall".to_owned(),
+                    output: 1,
+                    heating: true,
+                },
+                LiveEvent::Temperature {
+                    sensor: "default".to_owned(),
+                    temperature: None,
+                },
+            ]
+        );
+        assert!(new.changes(&new).is_empty());
+        // new clients get everything
+        assert_eq!(LiveState::default().changes(&old).len(), 3);
+        assert_eq!(
+            serde_json::to_string(&old.changes(&new)[0]).unwrap(),
+            r#"{"type":"room","cmi":"cmi.local","room":"hall","output":1,"heating":true}"#
+        );
+    }
+}
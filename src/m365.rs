@@ -0,0 +1,280 @@
+//! Pull bookings from room mailboxes in Microsoft 365 (Exchange Online) via the Graph API.
+//!
+//! Uses the client credentials flow of an app registration with the `Calendars.Read`
+//! application permission. Each room mailbox is a calendar, see [crate::booking_source] for how
+//! its events are stored.
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    booking_source::BookingSource,
+    config::{M365Config, M365RoomConfig},
+    db::DBError,
+    Booking,
+};
+
+#[derive(Debug)]
+pub enum M365Error {
+    Request(reqwest::Error),
+    Status(String, reqwest::StatusCode),
+    Deserialize(serde_json::Error),
+    ParseTime(chrono::ParseError),
+    DB(DBError),
+}
+impl std::fmt::Display for M365Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "Cannot query the Graph API. reqwest Error: {e}"),
+            Self::Status(url, status) => {
+                write!(f, "Microsoft 365 returned {status} for {url}.")
+            }
+            Self::Deserialize(e) => {
+                write!(
+                    f,
+                    "Cannot deserialize the Graph API response. serde Error: {e}"
+                )
+            }
+            Self::ParseTime(e) => {
+                write!(f, "Cannot parse a time of an event. chrono Error: {e}")
+            }
+            Self::DB(e) => write!(f, "DBError: {e}"),
+        }
+    }
+}
+impl std::error::Error for M365Error {}
+impl From<DBError> for M365Error {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<reqwest::Error> for M365Error {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Request(value)
+    }
+}
+
+/// The relevant parts of a token response
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// A page of a calendarView response
+#[derive(Debug, Deserialize)]
+struct CalendarViewPage {
+    value: Vec<GraphEvent>,
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+/// An event, or an instance of a recurring event
+#[derive(Debug, Deserialize)]
+struct GraphEvent {
+    /// unique per instance
+    id: String,
+    #[serde(rename = "isCancelled", default)]
+    is_cancelled: bool,
+    start: GraphTime,
+    end: GraphTime,
+}
+
+/// A time in the zone requested with the `Prefer: outlook.timezone` header, which is UTC
+#[derive(Debug, Deserialize)]
+struct GraphTime {
+    /// e.g. 2025-01-12T10:00:00.0000000
+    #[serde(rename = "dateTime")]
+    date_time: String,
+}
+
+/// Send a request and deserialize the response
+async fn get_json<T: for<'de> Deserialize<'de>>(
+    request: reqwest::RequestBuilder,
+    url: &str,
+) -> Result<T, M365Error> {
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        return Err(M365Error::Status(url.to_owned(), response.status()));
+    };
+    serde_json::from_str(&response.text().await?).map_err(M365Error::Deserialize)
+}
+
+/// Get an access token for the Graph API
+async fn get_token(
+    client: &reqwest::Client,
+    m365_config: &M365Config,
+) -> Result<String, M365Error> {
+    let url = format!(
+        "{}/{}/oauth2/v2.0/token",
+        m365_config.login_url, m365_config.tenant_id
+    );
+    let request = client.post(&url).form(&[
+        ("client_id", m365_config.client_id.as_str()),
+        ("client_secret", m365_config.client_secret.as_str()),
+        ("scope", "https://graph.microsoft.com/.default"),
+        ("grant_type", "client_credentials"),
+    ]);
+    Ok(get_json::<TokenResponse>(request, &url).await?.access_token)
+}
+
+/// The bookings of `room` for the events of a calendarView page. Cancelled events are skipped.
+fn page_to_bookings(
+    page: &CalendarViewPage,
+    room: &M365RoomConfig,
+) -> Result<Vec<Booking>, M365Error> {
+    let parse = |time: &GraphTime| {
+        NaiveDateTime::parse_from_str(&time.date_time, "%Y-%m-%dT%H:%M:%S%.f")
+            .map(|x| x.and_utc())
+            .map_err(M365Error::ParseTime)
+    };
+    let source = format!("{}:{}", M365Config::KIND, room.mailbox);
+    page.value
+        .iter()
+        .filter(|event| !event.is_cancelled)
+        .map(|event| {
+            Ok(Booking::new(
+                crate::db::external_booking_id(&source, &event.id),
+                room.resource_id,
+                parse(&event.start)?,
+                parse(&event.end)?,
+            )
+            .inspect_err(|e| debug!("Skipping an event of {}: {e}", room.mailbox))
+            .ok())
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
+/// Get the bookings of a room mailbox for the next `days` days
+async fn get_bookings(
+    client: &reqwest::Client,
+    m365_config: &M365Config,
+    token: &str,
+    room: &M365RoomConfig,
+) -> Result<Vec<Booking>, M365Error> {
+    let start = Utc::now() - TimeDelta::days(1);
+    let end = Utc::now() + TimeDelta::days(m365_config.days.into());
+    let mut url = format!(
+        "{}/users/{}/calendarView",
+        m365_config.graph_url, room.mailbox
+    );
+    let mut request = client.get(&url).query(&[
+        (
+            "startDateTime",
+            start.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        ),
+        ("endDateTime", end.format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        ("$select", "id,isCancelled,start,end".to_owned()),
+        ("$top", "100".to_owned()),
+    ]);
+    let mut bookings = vec![];
+    // recurring events are expanded into their instances, across pages
+    loop {
+        let page: CalendarViewPage = get_json(
+            request
+                .bearer_auth(token)
+                .header("Prefer", "outlook.timezone=\"UTC\""),
+            &url,
+        )
+        .await?;
+        bookings.extend(page_to_bookings(&page, room)?);
+        match page.next_link {
+            Some(next) => {
+                url = next;
+                request = client.get(&url);
+            }
+            None => return Ok(bookings),
+        };
+    }
+}
+
+impl BookingSource for M365Config {
+    type Calendar = M365RoomConfig;
+    /// the client and an access token
+    type Session = (reqwest::Client, String);
+    type Error = M365Error;
+    const KIND: &'static str = "m365";
+    const NAME: &'static str = "Microsoft 365";
+
+    fn calendars(&self) -> &[M365RoomConfig] {
+        &self.rooms
+    }
+
+    fn key(room: &M365RoomConfig) -> &str {
+        &room.mailbox
+    }
+
+    fn room(room: &M365RoomConfig) -> &str {
+        &room.room
+    }
+
+    fn pull_frequency(&self) -> u64 {
+        self.pull_frequency
+    }
+
+    async fn connect(&self) -> Result<(reqwest::Client, String), M365Error> {
+        let client = reqwest::Client::new();
+        let token = get_token(&client, self).await?;
+        Ok((client, token))
+    }
+
+    async fn get_bookings(
+        &self,
+        (client, token): &(reqwest::Client, String),
+        room: &M365RoomConfig,
+    ) -> Result<Vec<Booking>, M365Error> {
+        get_bookings(client, self, token, room).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn calendar_view_to_bookings() {
+        let page: CalendarViewPage = serde_json::from_str(
+            r#"{
+                "@odata.context": "https://graph.microsoft.com/v1.0/$metadata#users('hall%40example.com')/calendarView",
+                "@odata.nextLink": "https://graph.microsoft.com/v1.0/users/hall@example.com/calendarView?$skip=100",
+                "value": [
+                    {
+                        "@odata.etag": "W/\"abc\"",
+                        "id": "AAMkAGI1AAA=",
+                        "isCancelled": false,
+                        "start": {"dateTime": "2025-01-12T10:00:00.0000000", "timeZone": "UTC"},
+                        "end": {"dateTime": "2025-01-12T12:30:00.0000000", "timeZone": "UTC"}
+                    },
+                    {
+                        "id": "AAMkAGI1AAB=",
+                        "isCancelled": true,
+                        "start": {"dateTime": "2025-01-13T10:00:00.0000000", "timeZone": "UTC"},
+                        "end": {"dateTime": "2025-01-13T12:30:00.0000000", "timeZone": "UTC"}
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert!(page.next_link.is_some());
+        let room = M365RoomConfig {
+            mailbox: "hall@example.com".to_owned(),
+            room: "hall".to_owned(),
+            resource_id: 41,
+        };
+        let bookings = page_to_bookings(&page, &room).unwrap();
+        assert_eq!(bookings.len(), 1);
+        assert_eq!(bookings[0].resource_id(), 41);
+        assert!(bookings[0].booking_id() < -(1 << 32));
+        assert_eq!(
+            bookings[0].start_time(),
+            "2025-01-12T10:00:00Z"
+                .parse::<chrono::DateTime<Utc>>()
+                .unwrap()
+        );
+        assert_eq!(
+            bookings[0].end_time() - bookings[0].start_time(),
+            TimeDelta::minutes(150)
+        );
+    }
+}
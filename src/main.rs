@@ -1,42 +1,80 @@
+use std::process::ExitCode;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use chrono::Utc;
-use tokio::sync::RwLock;
+use chrono::TimeDelta;
+use clap::Parser;
+use tokio::sync::Mutex;
 
-use tracing::{error, info};
+use tracing::{error, info, warn, Instrument};
 use tracing_subscriber::{filter, fmt::format::FmtSpan};
 use tracing_subscriber::{prelude::*, EnvFilter};
 
+use booking::Booking;
+
+mod alert;
+#[cfg(feature = "http")]
+mod api;
+mod booking;
+mod booking_source;
+mod build_info;
+mod caldav;
+mod cli;
+mod coe_hub;
+mod coe_monitor;
+mod coe_sender;
 mod config;
+mod ct_export;
+mod daemon;
 mod db;
+mod error;
+mod feedback;
+mod forecast;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http")]
+mod http;
+mod i18n;
+mod ics;
+mod instance_lease;
+#[cfg(feature = "http")]
+mod kiosk;
+#[cfg(feature = "http")]
+mod live;
+mod m365;
+mod map_pdo;
+mod metrics;
+mod migrate;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(feature = "alerting")]
+mod notifier;
+mod panic_hook;
+mod preheat;
 mod pull_from_ct;
 mod push_to_ta;
 mod read_ext_temp;
+mod resource_hierarchy;
+mod resource_types;
+mod schedule;
+mod season;
+mod selftest;
+mod simulate;
+#[cfg(feature = "gpio")]
+mod status_leds;
+mod summary;
 
 const BOOKING_DATABASE_NAME: &str = ".bookings.db";
-
-/// A single booking for a room
-#[derive(Debug, PartialEq)]
-struct Booking {
-    /// the ID of the resource for this booking.
-    /// NOTE: this is NOT the ID of the booking, but of the resource in CT.
-    /// This ID is used for matching ressources against rooms defined in the config.
-    resource_id: i64,
-    /// The ID of this booking. This is used to update bookings when they are updated in CT.
-    booking_id: i64,
-    /// The booking starts at...
-    /// ALL DATETIMES ARE UTC.
-    start_time: chrono::DateTime<Utc>,
-    /// The booking ends at...
-    end_time: chrono::DateTime<Utc>,
-}
+/// used with --simulate, so the real bookings are untouched
+const SIMULATION_DATABASE_NAME: &str = ".simulation.db";
 
 enum InShutdown {
     Yes,
     No,
 }
 
+/// Wait for SIGTERM, SIGHUP, SIGINT or Ctrl-c and signal a shutdown
+#[cfg(unix)]
 async fn signal_handler(
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
     shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
@@ -81,31 +119,134 @@ async fn signal_handler(
             info!("Got SIGINT. Shuting down.");
             shutdown_tx.send_replace(InShutdown::Yes);
         }
-        x = tokio::signal::ctrl_c() =>  {
-            match x {
-                Ok(()) => {
-                    info!("Received Ctrl-c. Shutting down.");
-                    shutdown_tx.send_replace(InShutdown::Yes);
-                }
-                Err(err) => {
-                    error!("Unable to listen for shutdown signal: {}", err);
-                    // we also shut down in case of error
-                    shutdown_tx.send_replace(InShutdown::Yes);
-                }
-            }
+        x = tokio::signal::ctrl_c() => ctrl_c_received(x, &shutdown_tx),
+    };
+
+    Ok(())
+}
+
+/// Wait for Ctrl-c or the console being closed and signal a shutdown
+///
+/// Unix signals do not exist here. Closing the console is only noticed on Windows.
+#[cfg(not(unix))]
+async fn signal_handler(
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
+) -> Result<(), std::io::Error> {
+    #[cfg(windows)]
+    let mut ctrl_close = match tokio::signal::windows::ctrl_close() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to install CTRL_CLOSE listener: {e} Aborting.");
+            shutdown_tx.send_replace(InShutdown::Yes);
+            return Err(e);
+        }
+    };
+    #[cfg(windows)]
+    let console_closed = ctrl_close.recv();
+    #[cfg(not(windows))]
+    let console_closed = std::future::pending::<Option<()>>();
+    tokio::select! {
+        // shutdown the signal handler when some other process signals a shutdown
+        _ = watcher.changed() => {}
+        _ = console_closed => {
+            info!("The console was closed. Shuting down.");
+            shutdown_tx.send_replace(InShutdown::Yes);
         }
+        x = tokio::signal::ctrl_c() => ctrl_c_received(x, &shutdown_tx),
     };
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config = Arc::new(config::Config::create().await?);
+fn ctrl_c_received(
+    result: Result<(), std::io::Error>,
+    shutdown_tx: &tokio::sync::watch::Sender<InShutdown>,
+) {
+    match result {
+        Ok(()) => {
+            info!("Received Ctrl-c. Shutting down.");
+        }
+        Err(err) => {
+            error!("Unable to listen for shutdown signal: {}", err);
+            // we also shut down in case of error
+        }
+    };
+    shutdown_tx.send_replace(InShutdown::Yes);
+}
+
+/// Build the tokio runtime with this many worker threads.
+///
+/// Without a number, a single core (e.g. a Pi Zero) gets a current-thread runtime and anything
+/// else one worker per core. A single worker also means a current-thread runtime, which saves the
+/// RAM of the scheduler threads.
+fn runtime(worker_threads: Option<usize>) -> std::io::Result<tokio::runtime::Runtime> {
+    let worker_threads = worker_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(std::num::NonZeroUsize::get)
+            .unwrap_or(1)
+    });
+    let mut builder = if worker_threads <= 1 {
+        tokio::runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(worker_threads);
+        builder
+    };
+    builder.enable_all().build()
+}
+
+fn main() -> ExitCode {
+    let cli = cli::Cli::parse();
+    let runtime = match runtime(cli.worker_threads) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error: Unable to start the async runtime: {e}");
+            return ExitCode::from(error::EXIT_OS);
+        }
+    };
+    match runtime.block_on(run(cli)) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            // logging is not set up yet if the config could not be read
+            eprintln!("Error: {e}");
+            e.exit_code()
+        }
+    }
+}
+
+async fn run(cli: cli::Cli) -> Result<ExitCode, error::Error> {
+    // the schema is needed to write a config in the first place
+    if let Some(cli::Command::PrintConfigSchema) = cli.command {
+        return Ok(cli::print_config_schema().map(|()| ExitCode::SUCCESS)?);
+    };
+    let db_path = if cli.simulate {
+        SIMULATION_DATABASE_NAME
+    } else {
+        BOOKING_DATABASE_NAME
+    };
+    let mut configs = config::Config::create(db_path).await?;
+    if let Some(tenant) = &cli.tenant {
+        configs.retain(|x| x.tenant.as_ref() == Some(tenant));
+        if configs.is_empty() {
+            return Err(error::Error::UnknownTenant(tenant.clone()));
+        };
+    };
+    // one-off commands and --once are for a single tenant
+    if (cli.command.is_some() || cli.once) && configs.len() > 1 {
+        return Err(error::Error::TenantRequired);
+    };
     // Setup tracing
 
     let my_crate_filter = EnvFilter::new("ct_ta_sync");
-    let level_filter = filter::LevelFilter::from_str(&config.global.log_level)?;
+    // all tenants log through one subscriber, which is as verbose as the most verbose of them
+    let level_filter = configs
+        .iter()
+        .map(|x| filter::LevelFilter::from_str(&x.global.log_level))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .max()
+        .unwrap_or(filter::LevelFilter::INFO);
     let subscriber = tracing_subscriber::registry().with(my_crate_filter).with(
         tracing_subscriber::fmt::layer()
             .compact()
@@ -114,48 +255,568 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .with_filter(level_filter),
     );
     tracing::subscriber::set_global_default(subscriber).expect("static tracing config");
+    panic_hook::install();
 
-    // migrate the database
-    sqlx::migrate!().run(&config.db).await?;
+    let mut tenants = vec![];
+    for mut config in configs {
+        let span = tenant_span(&config);
+        start_tenant(&mut config, &cli)
+            .instrument(span.clone())
+            .await?;
+        tenants.push((Arc::new(config), span));
+    }
 
-    // the external temperature
-    let external_temperature = Arc::new(RwLock::new(None));
+    // run one-off commands instead of the sync
+    if let Some(command) = cli.command {
+        let (config, span) = &tenants[0];
+        return Ok(cli::run_command(config, command)
+            .instrument(span.clone())
+            .await
+            .map(|()| ExitCode::SUCCESS)?);
+    }
+    // leave the schedule to cron or a systemd timer
+    if cli.once {
+        let (config, span) = &tenants[0];
+        return once(config.clone(), cli.force)
+            .instrument(span.clone())
+            .await;
+    };
 
-    // cancellation channel
-    let (tx, rx) = tokio::sync::watch::channel(InShutdown::No);
+    // refuse to fight over the heating with another instance, and confirm that edits to the
+    // config were picked up
+    let mut leases = vec![];
+    for (config, span) in &tenants {
+        let lease = async {
+            let lease = instance_lease::InstanceLease::acquire(&config.db, cli.force).await?;
+            // only informational, the sync works without it
+            if let Err(e) = config.log_changes_since_last_start().await {
+                warn!("Unable to compare the config with the one of the last start: {e}");
+            };
+            Ok::<_, error::Error>(lease)
+        }
+        .instrument(span.clone())
+        .await?;
+        leases.push(lease);
+    }
 
-    // start the data-gatherer
-    let gatherer_handle = tokio::spawn(pull_from_ct::keep_db_up_to_date(config.clone(), rx));
+    // umask and PID file. Only one tenant may configure them.
+    let daemon_config = tenants
+        .iter()
+        .find_map(|(config, _)| config.daemon.as_ref());
+    if let Some(daemon) = daemon_config {
+        daemon::setup(daemon)?;
+    };
 
-    // start the data-sender
-    let emitter_handle = tokio::spawn(push_to_ta::push_coe(
-        config.clone(),
-        tx.subscribe(),
-        external_temperature.clone(),
-    ));
+    // bind privileged sockets and set up GPIO pins, then drop privileges
+    let mut bound = vec![];
+    for ((config, span), lease) in tenants.iter().zip(leases) {
+        bound.push(bind(config, lease).instrument(span.clone()).await?);
+    }
+    if let Some(daemon) = daemon_config {
+        let db_paths = tenants
+            .iter()
+            .map(|(config, _)| config.db_path.as_path())
+            .collect::<Vec<_>>();
+        let tenant_dirs = tenants
+            .iter()
+            .map(|(config, _)| config)
+            .filter(|config| {
+                config.tenant.is_some()
+                    && config.global.state_dir.is_none()
+                    && config.database == config::DatabaseMode::File
+            })
+            .filter_map(|config| config.db_path.parent())
+            .collect::<Vec<_>>();
+        // the pins of the status LEDs
+        #[cfg(feature = "gpio")]
+        let files = bound
+            .iter()
+            .filter_map(|bound| bound.status_leds.as_ref())
+            .flat_map(status_leds::StatusLeds::value_paths)
+            .collect::<Vec<_>>();
+        #[cfg(not(feature = "gpio"))]
+        let files = Vec::<std::path::PathBuf>::new();
+        let files = files.iter().map(|x| x.as_path()).collect::<Vec<_>>();
+        daemon::drop_privileges(daemon, &db_paths, &tenant_dirs, &files)?;
+    };
 
-    // start the temperature-receiver
-    let receiver_handle = tokio::spawn(read_ext_temp::read_ext_temp(
-        config.clone(),
-        external_temperature,
-        tx.subscribe(),
-        tx.clone(),
-    ));
+    // cancellation channel, shared by all tenants. Each tenant also has its own.
+    let (tx, _) = tokio::sync::watch::channel(InShutdown::No);
 
     // start the Signal handler
     let signal_handle = tokio::spawn(signal_handler(tx.subscribe(), tx.clone()));
 
+    // run all tenants until the shutdown, and report the first error
+    let tenant_results =
+        futures_util::future::join_all(tenants.iter().zip(bound).map(|((config, span), bound)| {
+            sync(
+                config.clone(),
+                bound,
+                cli.simulate,
+                cli.simulate_density,
+                cli.fake_cmi,
+                tx.subscribe(),
+            )
+            .instrument(span.clone())
+        }))
+        .await;
+    // all tenants may have stopped on their own
+    tx.send_replace(InShutdown::Yes);
+    signal_handle.await??;
+    if let Some(daemon) = daemon_config {
+        daemon::teardown(daemon);
+    };
+    tenant_results
+        .into_iter()
+        .collect::<Result<Vec<()>, _>>()
+        .map(|_| ExitCode::SUCCESS)
+}
+
+/// The span of all logs of a tenant, so they can be told apart. There is none without tenants.
+fn tenant_span(config: &config::Config) -> tracing::Span {
+    match &config.tenant {
+        Some(tenant) => tracing::info_span!("tenant", tenant = tenant.as_str()),
+        None => tracing::Span::none(),
+    }
+}
+
+/// Migrate the DB of a tenant and complete its config
+async fn start_tenant(config: &mut config::Config, cli: &cli::Cli) -> Result<(), error::Error> {
+    info!("Starting {}", build_info::BuildInfo::new(&config.hash));
+    if config.database == config::DatabaseMode::Memory {
+        info!("Keeping all state in memory. It is lost on shutdown.");
+    } else {
+        info!("Using the DB {}.", config.db_path.display());
+    };
+
+    // migrate the database, unless that is what the command is for
+    if !matches!(cli.command, Some(cli::Command::Migrate { .. })) {
+        // never change the schema underneath another instance still using the DB
+        if !migrate::pending(&config.db).await?.is_empty() {
+            instance_lease::InstanceLease::ensure_not_held(&config.db, cli.force).await?;
+        };
+        migrate::on_startup(
+            &config.db,
+            &config.db_path,
+            config.global.auto_migrate.unwrap_or(true),
+        )
+        .await?;
+        // synthetic bookings do not need the resources from CT. The selftest reports on CT
+        // itself instead of failing here, and map-pdo maps the rooms of the config file.
+        if !matches!(
+            cli.command,
+            Some(
+                cli::Command::Selftest { .. }
+                    | cli::Command::MapPdo { .. }
+                    | cli::Command::CoeMonitor { .. }
+            )
+        ) {
+            resource_types::add_resource_type_rooms(config, cli.simulate).await?;
+        };
+    };
+    if cli.fake_cmi {
+        simulate::use_fake_cmi(config);
+    };
+    Ok(())
+}
+
+/// Pull and push once for a single tenant, see [cli::Cli::once]
+async fn once(config: Arc<config::Config>, force: bool) -> Result<ExitCode, error::Error> {
+    // refuse to fight over the heating with another instance
+    let lease = instance_lease::InstanceLease::acquire(&config.db, force).await?;
+    let exit_code = cli::run_once(config.clone()).await;
+    lease.release(&config.db).await;
+    Ok(exit_code)
+}
+
+/// What a tenant binds or sets up before privileges are dropped
+struct Bound {
+    lease: instance_lease::InstanceLease,
+    #[cfg(feature = "http")]
+    http_listener: Option<http::HttpListener>,
+    #[cfg(feature = "grpc")]
+    grpc_listener: Option<grpc::GrpcListener>,
+    #[cfg(feature = "gpio")]
+    status_leds: Option<status_leds::StatusLeds>,
+}
+
+/// Bind the privileged sockets and set up the GPIO pins of a tenant
+async fn bind(
+    config: &config::Config,
+    lease: instance_lease::InstanceLease,
+) -> Result<Bound, error::Error> {
+    #[cfg(feature = "http")]
+    let http_listener = http::bind(config).await?;
+    #[cfg(not(feature = "http"))]
+    if config.http.is_some() {
+        tracing::warn!("http is configured, but this build does not have the http feature.");
+    };
+    #[cfg(feature = "grpc")]
+    let grpc_listener = grpc::bind(config).await?;
+    #[cfg(not(feature = "grpc"))]
+    if config
+        .http
+        .as_ref()
+        .is_some_and(|x| x.grpc_bind_addr.is_some())
+    {
+        tracing::warn!(
+            "http.grpc_bind_addr is configured, but this build does not have the grpc feature."
+        );
+    };
+    #[cfg(feature = "gpio")]
+    let status_leds = config
+        .status_leds
+        .as_ref()
+        .map(status_leds::StatusLeds::setup)
+        .transpose()?;
+    #[cfg(not(feature = "gpio"))]
+    if config.status_leds.is_some() {
+        tracing::warn!(
+            "status_leds are configured, but this build does not have the gpio feature."
+        );
+    };
+    Ok(Bound {
+        lease,
+        #[cfg(feature = "http")]
+        http_listener,
+        #[cfg(feature = "grpc")]
+        grpc_listener,
+        #[cfg(feature = "gpio")]
+        status_leds,
+    })
+}
+
+/// Run all tasks of a tenant until `shutdown` signals a shutdown of all tenants, or the tenant
+/// shuts itself down
+async fn sync(
+    config: Arc<config::Config>,
+    bound: Bound,
+    simulate: bool,
+    simulate_density: f64,
+    fake_cmi: bool,
+    mut shutdown: tokio::sync::watch::Receiver<InShutdown>,
+) -> Result<(), error::Error> {
+    // cancellation channel of this tenant, also cancelled by the global one
+    let (tx, mut tenant_shutdown) = tokio::sync::watch::channel(InShutdown::No);
+    let forward_handle = {
+        let tx = tx.clone();
+        tokio::spawn(
+            async move {
+                tokio::select! {
+                    _ = shutdown.wait_for(|x| matches!(x, InShutdown::Yes)) => {}
+                    _ = tenant_shutdown.wait_for(|x| matches!(x, InShutdown::Yes)) => {}
+                };
+                tx.send_replace(InShutdown::Yes);
+            }
+            .in_current_span(),
+        )
+    };
+    // never keep running with a dead task
+    let on_panic = config
+        .global
+        .shutdown_on_panic
+        .unwrap_or(true)
+        .then_some(&tx);
+
+    // the external temperature of each sensor
+    let external_temperatures = read_ext_temp::ExternalTemperatures::new(&config);
+    // raw external temperatures from all sources
+    let (ext_temp_tx, ext_temp_rx) = tokio::sync::mpsc::channel(16);
+    // commanded and actual state of all rooms with feedback
+    let feedback = Arc::new(Mutex::new(feedback::FeedbackTracker::new(
+        TimeDelta::minutes(config.global.feedback_timeout.unwrap_or(10) as i64),
+    )));
+
+    // all CoE we receive, by CAN id and PDO
+    let mut coe_hub = coe_hub::CoeHub::default();
+    coe_hub.register_sensor(
+        &config.external_temperature_sensor,
+        ext_temp_tx.clone(),
+        external_temperatures.humidity(None),
+    );
+    coe_hub.register_feedback(&config, feedback.clone());
+    // raw external temperatures of sites with their own sensor
+    let site_channels = config
+        .sites
+        .iter()
+        .filter_map(|(name, site)| Some((name, site.external_temperature_sensor.as_ref()?)))
+        .map(|(name, sensor)| {
+            let (site_tx, site_rx) = tokio::sync::mpsc::channel(16);
+            coe_hub.register_sensor(
+                sensor,
+                site_tx.clone(),
+                external_temperatures.humidity(Some(name)),
+            );
+            (name.clone(), site_tx, site_rx)
+        })
+        .collect::<Vec<_>>();
+    // send from the CoE port as well
+    let share_coe_port = config.global.emiter_bind_port == Some(coe_sender::COE_PORT);
+    if share_coe_port {
+        coe_hub.share(&config.global.emiter_bind_addr);
+    };
+    let coe_hub = coe_hub.bind().await.map_err(error::Error::Bind)?;
+    let shared_socket = share_coe_port
+        .then(|| coe_hub.socket(&config.global.emiter_bind_addr))
+        .flatten();
+
+    // statistics of all tasks
+    let metrics = Arc::new(metrics::Metrics::for_tenant(config.tenant.clone()));
+
+    // alerts raised by all tasks
+    let (alert_tx, alert_rx) = tokio::sync::mpsc::channel(64);
+
+    // start the notifier
+    #[cfg(feature = "alerting")]
+    let notifier_handle = panic_hook::spawn(
+        on_panic,
+        notifier::notify(config.clone(), alert_rx, tx.subscribe()),
+    );
+    // raised alerts are dropped
+    #[cfg(not(feature = "alerting"))]
+    {
+        drop(alert_rx);
+        if config.alerting.is_some() {
+            tracing::warn!(
+                "alerting is configured, but this build does not have the alerting feature."
+            );
+        };
+    };
+
+    // set after the first successful pull
+    let (ready_tx, ready_rx) = tokio::sync::watch::channel(false);
+
+    // start the data-gatherer, or generate synthetic data instead
+    let gatherer_handle = if simulate {
+        panic_hook::spawn(
+            on_panic,
+            simulate::keep_db_simulated(
+                config.clone(),
+                tx.subscribe(),
+                metrics.clone(),
+                simulate_density,
+                ready_tx,
+            ),
+        )
+    } else {
+        panic_hook::spawn(
+            on_panic,
+            pull_from_ct::keep_db_up_to_date(
+                config.clone(),
+                tx.subscribe(),
+                alert_tx.clone(),
+                metrics.clone(),
+                ready_tx,
+            ),
+        )
+    };
+    let simulation_handle = simulate.then(|| {
+        panic_hook::spawn(
+            on_panic,
+            simulate::simulate_ext_temp(ext_temp_tx.clone(), tx.subscribe()),
+        )
+    });
+    let fake_cmi_handle = fake_cmi
+        .then(|| panic_hook::spawn(on_panic, simulate::fake_cmi(tx.subscribe(), tx.clone())));
+
+    // start the forecast-gatherer
+    #[cfg(feature = "weather")]
+    let forecast_handle = panic_hook::spawn(
+        on_panic,
+        forecast::keep_forecast_up_to_date(config.clone(), tx.subscribe()),
+    );
+    #[cfg(not(feature = "weather"))]
+    if config.forecast.is_some() {
+        tracing::warn!("forecast is configured, but this build does not have the weather feature.");
+    };
+
+    // start the CalDAV- and Microsoft 365-gatherers. Simulations do not pull real bookings.
+    let caldav_handle = (!simulate).then(|| {
+        panic_hook::spawn(
+            on_panic,
+            booking_source::keep_up_to_date(
+                config.clone(),
+                |config| config.caldav.as_ref(),
+                tx.subscribe(),
+            ),
+        )
+    });
+    let m365_handle = (!simulate).then(|| {
+        panic_hook::spawn(
+            on_panic,
+            booking_source::keep_up_to_date(
+                config.clone(),
+                |config| config.m365.as_ref(),
+                tx.subscribe(),
+            ),
+        )
+    });
+
+    // write the heating windows back to CT. Simulated bookings do not exist in CT.
+    let ct_export_handle = (!simulate).then(|| {
+        panic_hook::spawn(
+            on_panic,
+            ct_export::keep_ct_export_up_to_date(
+                config.clone(),
+                tx.subscribe(),
+                external_temperatures.clone(),
+            ),
+        )
+    });
+
+    // summarize each day for the wardens
+    let summary_handle = config.global.daily_summary_time.is_some().then(|| {
+        panic_hook::spawn(
+            on_panic,
+            summary::send_daily_summaries(config.clone(), tx.subscribe()),
+        )
+    });
+
+    // start the data-sender
+    let emitter_handle = panic_hook::spawn(
+        on_panic,
+        push_to_ta::push_coe(
+            config.clone(),
+            tx.subscribe(),
+            external_temperatures.clone(),
+            feedback.clone(),
+            alert_tx.clone(),
+            metrics.clone(),
+            shared_socket,
+            ready_rx,
+        ),
+    );
+
+    // start the temperature-receiver
+    let receiver_handle = panic_hook::spawn(
+        on_panic,
+        read_ext_temp::read_ext_temp(
+            config.clone(),
+            None,
+            external_temperatures.clone(),
+            alert_tx.clone(),
+            ext_temp_tx.clone(),
+            ext_temp_rx,
+            tx.subscribe(),
+        ),
+    );
+
+    // start the temperature-receivers of sites with their own sensor
+    let site_receiver_handles = site_channels
+        .into_iter()
+        .map(|(name, site_tx, site_rx)| {
+            panic_hook::spawn(
+                on_panic,
+                read_ext_temp::read_ext_temp(
+                    config.clone(),
+                    Some(name),
+                    external_temperatures.clone(),
+                    alert_tx.clone(),
+                    site_tx,
+                    site_rx,
+                    tx.subscribe(),
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    drop(alert_tx);
+
+    // start the CoE receiver
+    let coe_hub_handle = panic_hook::spawn(on_panic, coe_hub.run(metrics.clone(), tx.subscribe()));
+
+    // drive the status LEDs
+    #[cfg(feature = "gpio")]
+    let status_leds_handle = bound
+        .status_leds
+        .map(|leds| panic_hook::spawn(on_panic, leds.run(metrics.clone(), tx.subscribe())));
+
+    // start the gRPC server, shutting down if it fails
+    #[cfg(feature = "grpc")]
+    let grpc_handle = {
+        let serve = grpc::serve(
+            config.clone(),
+            bound.grpc_listener,
+            metrics.clone(),
+            tx.subscribe(),
+        );
+        let tx = tx.clone();
+        panic_hook::spawn(on_panic, async move {
+            let res = serve.await;
+            if let Err(e) = &res {
+                error!("The gRPC server failed: {e}. Shutting down.");
+                tx.send_replace(InShutdown::Yes);
+            };
+            res
+        })
+    };
+
+    // start the HTTP server
+    #[cfg(feature = "http")]
+    let http_handle = panic_hook::spawn(
+        on_panic,
+        http::serve(
+            config.clone(),
+            bound.http_listener,
+            ext_temp_tx,
+            external_temperatures.clone(),
+            metrics,
+            tx.subscribe(),
+        ),
+    );
+
+    // keep the instance lease
+    let lease_handle = panic_hook::spawn(
+        on_panic,
+        bound.lease.keep(config.clone(), tx.subscribe(), tx.clone()),
+    );
+
     // Join both tasks
-    let (gather_res, emit_res, receive_res, signal_res) = tokio::join!(
+    let (gather_res, emit_res, receive_res, coe_hub_res, lease_res) = tokio::join!(
         gatherer_handle,
         emitter_handle,
         receiver_handle,
-        signal_handle
+        coe_hub_handle,
+        lease_handle
     );
     gather_res?;
     emit_res?;
-    receive_res??;
-    signal_res??;
+    receive_res?;
+    coe_hub_res?;
+    lease_res?;
+    #[cfg(feature = "alerting")]
+    notifier_handle.await?;
+    #[cfg(feature = "weather")]
+    forecast_handle.await?;
+    #[cfg(feature = "http")]
+    http_handle.await??;
+    for handle in site_receiver_handles {
+        handle.await?;
+    }
+    #[cfg(feature = "gpio")]
+    if let Some(handle) = status_leds_handle {
+        handle.await?;
+    };
+    #[cfg(feature = "grpc")]
+    grpc_handle.await??;
+    if let Some(handle) = caldav_handle {
+        handle.await?;
+    };
+    if let Some(handle) = m365_handle {
+        handle.await?;
+    };
+    if let Some(handle) = ct_export_handle {
+        handle.await?;
+    };
+    if let Some(handle) = summary_handle {
+        handle.await?;
+    };
+    if let Some(handle) = simulation_handle {
+        handle.await?;
+    };
+    if let Some(handle) = fake_cmi_handle {
+        handle.await??;
+    };
+    forward_handle.await?;
 
     Ok(())
 }
@@ -0,0 +1,398 @@
+//! Interactive wizard to confirm which PDO each room is sent on.
+//!
+//! A distinctive on/off pattern is sent to one room at a time while the installer watches the CAN
+//! monitor of the CMI. Confirmed rooms are written to a config snippet for the `cmis:` section,
+//! with all other settings of the CMIs and rooms taken from the config.
+
+use std::{fmt::Write, path::Path, time::Duration};
+
+use coe::{COEValue, DigitalCOEValue, Payload};
+
+use crate::{
+    coe_sender::{packets, CoeSender},
+    config::{AssociatedRoomConfig, CMIConfig, Config, IpPreference},
+};
+
+/// The pattern sent to a room, one step per second. It is unlike a room switching on or off.
+const PATTERN: [bool; 4] = [true, false, true, true];
+
+/// An answer of the installer
+#[derive(Debug, PartialEq)]
+enum Answer {
+    /// the pattern showed up where the room is expected
+    Yes,
+    /// the pattern did not show up, keep the room unconfirmed
+    No,
+    /// try this PDO index (1-64) instead
+    Pdo(u8),
+    /// stop mapping
+    Quit,
+}
+
+/// Parse a line typed by the installer
+fn parse_answer(line: &str) -> Option<Answer> {
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(Answer::Yes),
+        "n" | "no" => Some(Answer::No),
+        "q" | "quit" => Some(Answer::Quit),
+        other => match other.parse::<u8>() {
+            Ok(pdo_index @ 1..=64) => Some(Answer::Pdo(pdo_index)),
+            _ => None,
+        },
+    }
+}
+
+/// A room and the PDO index (1-64) it was last sent on
+#[derive(Debug)]
+struct MappedRoom {
+    name: String,
+    can_id: u8,
+    pdo_index: u8,
+    confirmed: bool,
+    /// the other PDO indices (1-64) from the config
+    feedback_pdo_index: Option<u8>,
+    setpoint_pdo_index: Option<u8>,
+    cooling_pdo_index: Option<u8>,
+}
+impl MappedRoom {
+    fn new(room: &AssociatedRoomConfig) -> Self {
+        Self {
+            name: room.name.clone(),
+            can_id: room.can_id,
+            pdo_index: room.pdo_index + 1,
+            confirmed: false,
+            feedback_pdo_index: room.feedback_pdo_index.map(|x| x + 1),
+            setpoint_pdo_index: room.setpoint.map(|x| x.pdo_index + 1),
+            cooling_pdo_index: room.cooling.map(|x| x.pdo_index + 1),
+        }
+    }
+}
+
+/// The rooms of one CMI, and its other settings from the config
+#[derive(Debug)]
+struct MappedCmi {
+    host: String,
+    backup_hosts: Vec<String>,
+    ip_version: IpPreference,
+    feedback_can_id: Option<u8>,
+    site: Option<String>,
+    our_virtual_can_id: u8,
+    rooms: Vec<MappedRoom>,
+    /// whether the CMI has resource_types, which are not part of the snippet
+    has_resource_types: bool,
+}
+impl MappedCmi {
+    fn new(cmi: &CMIConfig) -> Self {
+        Self {
+            host: cmi.host.clone(),
+            backup_hosts: cmi.backup_hosts.clone(),
+            ip_version: cmi.ip_version,
+            feedback_can_id: cmi.feedback_can_id,
+            site: cmi.site.clone(),
+            our_virtual_can_id: cmi.our_virtual_can_id,
+            rooms: Vec::new(),
+            has_resource_types: !cmi.resource_types.is_empty(),
+        }
+    }
+}
+
+/// Format the mapping as snippet of the `cmis:` section. Unconfirmed rooms are commented out.
+///
+/// Everything but the resource_types of the CMIs is included, so the `cmis:` section can be
+/// replaced with it.
+fn snippet(cmis: &[MappedCmi]) -> String {
+    let mut out = String::from("cmis:\n");
+    for cmi in cmis {
+        let _ = writeln!(out, "- host: {}", cmi.host);
+        if !cmi.backup_hosts.is_empty() {
+            let _ = writeln!(out, "  backup_hosts:");
+            for host in &cmi.backup_hosts {
+                let _ = writeln!(out, "  - {host}");
+            }
+        };
+        match cmi.ip_version {
+            IpPreference::Any => {}
+            IpPreference::V4 => {
+                let _ = writeln!(out, "  ip_version: v4");
+            }
+            IpPreference::V6 => {
+                let _ = writeln!(out, "  ip_version: v6");
+            }
+        };
+        if let Some(can_id) = cmi.feedback_can_id {
+            let _ = writeln!(out, "  feedback_can_id: {can_id}");
+        };
+        if let Some(site) = &cmi.site {
+            let _ = writeln!(out, "  site: {site}");
+        };
+        let _ = writeln!(out, "  our_virtual_can_id: {}", cmi.our_virtual_can_id);
+        if cmi.has_resource_types {
+            let _ = writeln!(
+                out,
+                "  # resource_types are not included, copy them from the config"
+            );
+        };
+        let _ = writeln!(out, "  rooms:");
+        for room in &cmi.rooms {
+            let comment = if room.confirmed {
+                ""
+            } else {
+                let _ = writeln!(out, "  # not confirmed:");
+                "# "
+            };
+            let _ = writeln!(out, "  {comment}- name: {}", room.name);
+            let _ = writeln!(out, "  {comment}  pdo_index: {}", room.pdo_index);
+            let others = [
+                ("feedback_pdo_index", room.feedback_pdo_index),
+                ("setpoint_pdo_index", room.setpoint_pdo_index),
+                ("cooling_pdo_index", room.cooling_pdo_index),
+            ];
+            for (key, pdo_index) in others {
+                if let Some(pdo_index) = pdo_index {
+                    let _ = writeln!(out, "  {comment}  {key}: {pdo_index}");
+                };
+            }
+            if room.can_id != cmi.our_virtual_can_id {
+                let _ = writeln!(out, "  {comment}  virtual_can_id: {}", room.can_id);
+            };
+        }
+    }
+    out
+}
+
+/// Read the next line from stdin. None if stdin is closed.
+async fn read_line() -> Option<String> {
+    tokio::task::spawn_blocking(|| {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// Send the pattern to `pdo_index` (1-64) of `cmi` until the installer answers
+async fn send_pattern_until_answer(
+    sender: &mut CoeSender,
+    cmi: &CMIConfig,
+    can_id: u8,
+    pdo_index: u8,
+) -> Option<String> {
+    let answer = read_line();
+    tokio::pin!(answer);
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut steps = PATTERN.iter().cycle();
+    let line = loop {
+        tokio::select! {
+            line = &mut answer => break line,
+            _ = interval.tick() => {
+                let value = *steps.next().expect("cycle never ends");
+                send(sender, cmi, can_id, pdo_index, value).await;
+            }
+        }
+    };
+    // leave the room off, the sync sets it again once it runs
+    send(sender, cmi, can_id, pdo_index, false).await;
+    line
+}
+
+/// Send `value` to `pdo_index` (1-64) of `cmi`, printing errors instead of stopping the wizard
+async fn send(sender: &mut CoeSender, cmi: &CMIConfig, can_id: u8, pdo_index: u8, value: bool) {
+    let payload = Payload::new(
+        can_id,
+        pdo_index - 1,
+        COEValue::Digital(DigitalCOEValue::OnOff(value)),
+    );
+    for packet in packets(&[payload]) {
+        if let Err(e) = sender.send_to(packet, &cmi.host, cmi.ip_version).await {
+            eprintln!("Unable to send to {}: {e}", cmi.host);
+        };
+    }
+}
+
+/// Walk through the rooms of all CMIs (or only those of `host`), then print the confirmed
+/// mapping or write it to `output`
+pub(crate) async fn run(
+    config: &Config,
+    host: Option<&str>,
+    output: Option<&Path>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sender = CoeSender::new(
+        config.global.emiter_bind_addr.clone(),
+        config.global.emiter_bind_port.unwrap_or(0),
+        Duration::from_secs(config.global.cmi_dns_refresh.unwrap_or(10) * 60),
+    );
+    let cmis = config
+        .cmis
+        .iter()
+        .filter(|cmi| host.is_none_or(|host| cmi.host == host))
+        .collect::<Vec<_>>();
+    if cmis.is_empty() {
+        return Err(format!("No CMI {} in the config.", host.unwrap_or_default()).into());
+    };
+
+    let mut mapped = Vec::new();
+    'cmis: for cmi in cmis {
+        println!("Open the CAN monitor of {}.", cmi.host);
+        let mut mapped_cmi = MappedCmi::new(cmi);
+        for room in &cmi.rooms {
+            let mut mapped_room = MappedRoom::new(room);
+            loop {
+                println!(
+                    "{}: blinking on CAN id {} PDO {}. Seen there? [y]es, [n]o, a PDO index \
+                     (1-64) to try instead or [q]uit",
+                    room.name, mapped_room.can_id, mapped_room.pdo_index
+                );
+                let line = send_pattern_until_answer(
+                    &mut sender,
+                    cmi,
+                    mapped_room.can_id,
+                    mapped_room.pdo_index,
+                )
+                .await;
+                match line.as_deref().map(parse_answer) {
+                    Some(Some(Answer::Yes)) => {
+                        mapped_room.confirmed = true;
+                        break;
+                    }
+                    Some(Some(Answer::No)) => break,
+                    Some(Some(Answer::Pdo(pdo_index))) => mapped_room.pdo_index = pdo_index,
+                    Some(Some(Answer::Quit)) | None => {
+                        mapped_cmi.rooms.push(mapped_room);
+                        mapped.push(mapped_cmi);
+                        break 'cmis;
+                    }
+                    Some(None) => println!("Please answer y, n, q or a number from 1 to 64."),
+                };
+            }
+            mapped_cmi.rooms.push(mapped_room);
+        }
+        mapped.push(mapped_cmi);
+    }
+
+    let snippet = snippet(&mapped);
+    match output {
+        Some(path) => {
+            std::fs::write(path, snippet)?;
+            println!("Wrote the mapping to {}.", path.display());
+        }
+        None => print!("{snippet}"),
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::config::CMIConfigData;
+
+    #[test]
+    fn answers() {
+        assert_eq!(parse_answer("y\n"), Some(Answer::Yes));
+        assert_eq!(parse_answer(" No "), Some(Answer::No));
+        assert_eq!(parse_answer("q"), Some(Answer::Quit));
+        assert_eq!(parse_answer("12"), Some(Answer::Pdo(12)));
+        assert_eq!(parse_answer("0"), None);
+        assert_eq!(parse_answer("65"), None);
+        assert_eq!(parse_answer("maybe"), None);
+    }
+
+    #[test]
+    fn snippet_comments_out_unconfirmed_rooms() {
+        let cmis = [MappedCmi {
+            host: "cmi.example.com".to_owned(),
+            backup_hosts: vec![],
+            ip_version: IpPreference::Any,
+            feedback_can_id: None,
+            site: None,
+            our_virtual_can_id: 59,
+            rooms: vec![
+                MappedRoom {
+                    name: "hall".to_owned(),
+                    can_id: 59,
+                    pdo_index: 3,
+                    confirmed: true,
+                    feedback_pdo_index: None,
+                    setpoint_pdo_index: None,
+                    cooling_pdo_index: None,
+                },
+                MappedRoom {
+                    name: "office".to_owned(),
+                    can_id: 60,
+                    pdo_index: 1,
+                    confirmed: false,
+                    feedback_pdo_index: None,
+                    setpoint_pdo_index: None,
+                    cooling_pdo_index: None,
+                },
+            ],
+            has_resource_types: false,
+        }];
+        assert_eq!(
+            snippet(&cmis),
+            "cmis:
+- host: cmi.example.com
+  our_virtual_can_id: 59
+  rooms:
+  - name: hall
+    pdo_index: 3
+  # not confirmed:
+  # - name: office
+  #   pdo_index: 1
+  #   virtual_can_id: 60
+"
+        );
+        // the snippet is valid YAML
+        serde_yaml::from_str::<serde_yaml::Value>(&snippet(&cmis)).unwrap();
+    }
+
+    #[test]
+    fn snippet_keeps_the_other_settings() {
+        let cmis = [MappedCmi {
+            host: "cmi.example.com".to_owned(),
+            backup_hosts: vec!["10.8.0.2".to_owned()],
+            ip_version: IpPreference::V6,
+            feedback_can_id: Some(13),
+            site: Some("church".to_owned()),
+            our_virtual_can_id: 59,
+            rooms: vec![MappedRoom {
+                name: "hall".to_owned(),
+                can_id: 59,
+                pdo_index: 3,
+                confirmed: true,
+                feedback_pdo_index: Some(4),
+                setpoint_pdo_index: Some(5),
+                cooling_pdo_index: Some(6),
+            }],
+            has_resource_types: true,
+        }];
+        let snippet = snippet(&cmis);
+        assert_eq!(
+            snippet,
+            "cmis:
+- host: cmi.example.com
+  backup_hosts:
+  - 10.8.0.2
+  ip_version: v6
+  feedback_can_id: 13
+  site: church
+  our_virtual_can_id: 59
+  # resource_types are not included, copy them from the config
+  rooms:
+  - name: hall
+    pdo_index: 3
+    feedback_pdo_index: 4
+    setpoint_pdo_index: 5
+    cooling_pdo_index: 6
+"
+        );
+        let cmis = serde_yaml::from_str::<HashMap<String, Vec<CMIConfigData>>>(&snippet).unwrap();
+        assert_eq!(cmis["cmis"][0].ip_version, Some(IpPreference::V6));
+    }
+}
@@ -0,0 +1,609 @@
+//! Statistics about our own operation.
+//!
+//! Counters are served live on GET /metrics in the Prometheus text format. Each run of the pull
+//! and push tasks is also recorded in the DB, so sites without a Prometheus server can review the
+//! last weeks with `ct-ta-sync export-metrics`.
+
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{config::Config, db::CycleStat};
+
+/// The tasks whose runs are recorded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Task {
+    Pull,
+    Push,
+}
+impl Task {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Pull => "pull",
+            Self::Push => "push",
+        }
+    }
+}
+
+/// Senders of ignored or malformed CoE counted on their own. Any further ones are counted as
+/// [CoeSource::Other], so nobody can grow the metrics without bound by sending from many addresses.
+const MAX_COE_SOURCES: usize = 32;
+
+/// The sender ignored or malformed CoE is counted for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CoeSource {
+    Addr(IpAddr),
+    /// all senders beyond the first [MAX_COE_SOURCES]
+    Other,
+}
+impl CoeSource {
+    /// The entry of `source` in `counted`: its own, unless there are too many senders already
+    fn of<T>(counted: &BTreeMap<CoeSource, T>, source: IpAddr) -> Self {
+        let source = Self::Addr(source);
+        let addrs = counted.len() - usize::from(counted.contains_key(&Self::Other));
+        if counted.contains_key(&source) || addrs < MAX_COE_SOURCES {
+            source
+        } else {
+            Self::Other
+        }
+    }
+}
+impl std::fmt::Display for CoeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Addr(x) => write!(f, "{x}"),
+            Self::Other => write!(f, "other"),
+        }
+    }
+}
+impl Serialize for CoeSource {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Malformed CoE received from one sender
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MalformedCoe {
+    /// datagrams since startup
+    pub count: u64,
+    /// why the last one could not be parsed
+    pub last_error: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Live counters since startup
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// added as `tenant` label to all samples
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    tenant: Option<String>,
+    pulls: AtomicU64,
+    pull_errors: AtomicU64,
+    last_pull_duration_ms: AtomicU64,
+    /// bookings in CT at the last successful pull
+    bookings: AtomicU64,
+    /// bookings with implausible dates at the last successful pull
+    implausible_bookings: AtomicU64,
+    pushes: AtomicU64,
+    push_errors: AtomicU64,
+    last_push_duration_ms: AtomicU64,
+    packets_sent: AtomicU64,
+    last_pull_ok: AtomicBool,
+    last_push_ok: AtomicBool,
+    /// CMI host -> CoE sends that failed since the last successful one
+    send_failures: Mutex<BTreeMap<String, u64>>,
+    /// (CMI host, room name) -> (CMI output, whether HEATING was sent last)
+    room_heating: Mutex<BTreeMap<(String, String), (u8, bool)>>,
+    /// woken whenever a room gets a different state than before
+    room_heating_changed: Notify,
+    /// sensor -> external dew point in tenths of a Degree Centigrade, if known
+    dew_points: Mutex<BTreeMap<String, Option<i32>>>,
+    /// CMI host -> the address the last push was sent to without a local error
+    cmi_send_paths: Mutex<BTreeMap<String, String>>,
+    /// room listed under several CMIs -> those of them the last push did not reach, if it reached
+    /// others
+    partially_reached_rooms: Mutex<BTreeMap<String, Vec<String>>>,
+    /// sender -> received datagrams that were not CoE or for nobody
+    coe_ignored: Mutex<BTreeMap<CoeSource, u64>>,
+    /// sender -> received datagrams that were not parsable as CoE
+    coe_malformed: Mutex<BTreeMap<CoeSource, MalformedCoe>>,
+}
+impl Metrics {
+    /// Metrics whose samples are labelled with `tenant`, if there is one
+    pub(crate) fn for_tenant(tenant: Option<String>) -> Self {
+        Self {
+            tenant,
+            ..Self::default()
+        }
+    }
+
+    /// `{tenant="...",key="value",...}`, or nothing without tenant and labels
+    #[cfg(feature = "http")]
+    fn labels(&self, labels: &[(&str, &str)]) -> String {
+        let labels = self
+            .tenant
+            .as_deref()
+            .map(|tenant| ("tenant", tenant))
+            .iter()
+            .chain(labels)
+            .map(|(key, value)| format!("{key}=\"{value}\""))
+            .collect::<Vec<_>>();
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+
+    /// Count a single run of `task` that handled `items` bookings or packets
+    pub(crate) fn count(&self, task: Task, duration_ms: u64, items: u64, ok: bool) {
+        match task {
+            Task::Pull => {
+                self.pulls.fetch_add(1, Ordering::Relaxed);
+                self.last_pull_ok.store(ok, Ordering::Relaxed);
+                self.last_pull_duration_ms
+                    .store(duration_ms, Ordering::Relaxed);
+                if ok {
+                    self.bookings.store(items, Ordering::Relaxed);
+                } else {
+                    self.pull_errors.fetch_add(1, Ordering::Relaxed);
+                };
+            }
+            Task::Push => {
+                self.pushes.fetch_add(1, Ordering::Relaxed);
+                self.last_push_ok.store(ok, Ordering::Relaxed);
+                self.last_push_duration_ms
+                    .store(duration_ms, Ordering::Relaxed);
+                self.packets_sent.fetch_add(items, Ordering::Relaxed);
+                if !ok {
+                    self.push_errors.fetch_add(1, Ordering::Relaxed);
+                };
+            }
+        };
+    }
+
+    /// Set the bookings with implausible dates found by the last successful pull
+    pub(crate) fn set_implausible_bookings(&self, count: usize) {
+        self.implausible_bookings
+            .store(count as u64, Ordering::Relaxed);
+    }
+
+    /// Set the consecutive failed CoE sends per CMI host
+    pub(crate) fn set_send_failures<'a>(&self, failures: impl IntoIterator<Item = (&'a str, u64)>) {
+        let mut send_failures = self
+            .send_failures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (host, count) in failures {
+            send_failures.insert(host.to_owned(), count);
+        }
+    }
+
+    /// Set the state last sent to a room, `output` being its pdo index as shown on the CMI (1-64)
+    pub(crate) fn set_room_heating(&self, cmi: &str, room: &str, output: u8, heating: bool) {
+        let previous = self
+            .room_heating
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert((cmi.to_owned(), room.to_owned()), (output, heating));
+        if previous != Some((output, heating)) {
+            self.room_heating_changed.notify_waiters();
+        };
+    }
+
+    /// Set the address `cmi` was last sent to: its host or one of its backup_hosts
+    pub(crate) fn set_cmi_send_path(&self, cmi: &str, host: &str) {
+        self.cmi_send_paths
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(cmi.to_owned(), host.to_owned());
+    }
+
+    /// The address each CMI was last sent to: CMI host -> host or backup host
+    #[cfg(feature = "http")]
+    pub(crate) fn cmi_send_paths(&self) -> BTreeMap<String, String> {
+        self.cmi_send_paths
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Set the rooms listed under several CMIs that the last push only reached on some of them,
+    /// with the CMIs it did not reach
+    pub(crate) fn set_partially_reached_rooms(&self, rooms: BTreeMap<String, Vec<String>>) {
+        *self
+            .partially_reached_rooms
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = rooms;
+    }
+
+    /// Rooms listed under several CMIs that the last push only reached on some of them -> the
+    /// CMIs it did not reach
+    #[cfg(feature = "http")]
+    pub(crate) fn partially_reached_rooms(&self) -> BTreeMap<String, Vec<String>> {
+        self.partially_reached_rooms
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Wakes up once any room gets a different state than before.
+    ///
+    /// Changes are only seen by futures that were polled or enabled before the change.
+    #[cfg(feature = "http")]
+    pub(crate) fn room_heating_changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.room_heating_changed.notified()
+    }
+
+    /// The state last sent to each room: (CMI host, room name) -> (CMI output, heating)
+    #[cfg(feature = "http")]
+    pub(crate) fn room_heating(&self) -> BTreeMap<(String, String), (u8, bool)> {
+        self.room_heating
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Set the external dew point of each sensor receiving the humidity
+    pub(crate) fn set_dew_points(&self, dew_points: BTreeMap<String, Option<i32>>) {
+        *self
+            .dew_points
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = dew_points;
+    }
+
+    /// Count a received datagram from `source` that was not CoE or for nobody
+    pub(crate) fn count_ignored_coe(&self, source: IpAddr) {
+        let mut coe_ignored = self
+            .coe_ignored
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let source = CoeSource::of(&coe_ignored, source);
+        *coe_ignored.entry(source).or_default() += 1;
+    }
+
+    /// Count a received datagram from `source` that was not parsable as CoE
+    pub(crate) fn count_malformed_coe(&self, source: IpAddr, error: String) {
+        let mut coe_malformed = self
+            .coe_malformed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Utc::now();
+        let source = CoeSource::of(&coe_malformed, source);
+        let malformed = coe_malformed.entry(source).or_insert(MalformedCoe {
+            count: 0,
+            last_error: String::new(),
+            last_seen: now,
+        });
+        malformed.count += 1;
+        malformed.last_error = error;
+        malformed.last_seen = now;
+    }
+
+    /// The malformed CoE received since startup, by sender
+    #[cfg(feature = "http")]
+    pub(crate) fn malformed_coe(&self) -> BTreeMap<CoeSource, MalformedCoe> {
+        self.coe_malformed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Whether the last run of `task` succeeded, None before the first run
+    #[cfg(any(feature = "http", feature = "gpio"))]
+    pub fn last_run_ok(&self, task: Task) -> Option<bool> {
+        let (runs, ok) = match task {
+            Task::Pull => (&self.pulls, &self.last_pull_ok),
+            Task::Push => (&self.pushes, &self.last_push_ok),
+        };
+        (runs.load(Ordering::Relaxed) != 0).then(|| ok.load(Ordering::Relaxed))
+    }
+
+    /// Count a run of `task` and record it in the DB.
+    ///
+    /// Failing to record it is only logged, statistics are not worth failing a run for.
+    pub async fn record(
+        &self,
+        config: &Config,
+        task: Task,
+        duration: std::time::Duration,
+        items: usize,
+        ok: bool,
+    ) {
+        let duration_ms = duration.as_millis() as u64;
+        self.count(task, duration_ms, items as u64, ok);
+        let stat = CycleStat {
+            recorded_at: Utc::now(),
+            task: task.name().to_owned(),
+            duration_ms: duration_ms as i64,
+            items: items as i64,
+            errors: if ok { 0 } else { 1 },
+        };
+        if let Err(e) = crate::db::insert_cycle_stat(&config.db, &stat).await {
+            warn!(
+                "Unable to record the statistics of this {} run: {e}",
+                task.name()
+            );
+        };
+    }
+
+    /// Render all counters in the Prometheus text format
+    #[cfg(feature = "http")]
+    pub fn render(&self) -> String {
+        let metrics: [(&str, &str, &str, &AtomicU64); 9] = [
+            (
+                "ct_ta_sync_pulls_total",
+                "counter",
+                "Runs of the CT pull",
+                &self.pulls,
+            ),
+            (
+                "ct_ta_sync_pull_errors_total",
+                "counter",
+                "Failed runs of the CT pull",
+                &self.pull_errors,
+            ),
+            (
+                "ct_ta_sync_last_pull_duration_ms",
+                "gauge",
+                "Duration of the last CT pull",
+                &self.last_pull_duration_ms,
+            ),
+            (
+                "ct_ta_sync_bookings",
+                "gauge",
+                "Bookings in CT at the last successful pull",
+                &self.bookings,
+            ),
+            (
+                "ct_ta_sync_implausible_bookings",
+                "gauge",
+                "Bookings with implausible dates at the last successful pull",
+                &self.implausible_bookings,
+            ),
+            (
+                "ct_ta_sync_pushes_total",
+                "counter",
+                "Runs of the CoE push",
+                &self.pushes,
+            ),
+            (
+                "ct_ta_sync_push_errors_total",
+                "counter",
+                "Failed runs of the CoE push",
+                &self.push_errors,
+            ),
+            (
+                "ct_ta_sync_last_push_duration_ms",
+                "gauge",
+                "Duration of the last CoE push",
+                &self.last_push_duration_ms,
+            ),
+            (
+                "ct_ta_sync_packets_sent_total",
+                "counter",
+                "CoE packets sent to CMIs",
+                &self.packets_sent,
+            ),
+        ];
+        let mut rendered = metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{} {}\n",
+                    self.labels(&[]),
+                    value.load(Ordering::Relaxed)
+                )
+            })
+            .collect::<String>();
+        let send_failures = self
+            .send_failures
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !send_failures.is_empty() {
+            let name = "ct_ta_sync_consecutive_send_failures";
+            rendered.push_str(&format!(
+                "# HELP {name} CoE sends to a CMI that failed since the last successful one\n# TYPE {name} gauge\n"
+            ));
+            for (host, count) in send_failures.iter() {
+                rendered.push_str(&format!(
+                    "{name}{} {count}\n",
+                    self.labels(&[("cmi", host)])
+                ));
+            }
+        };
+        let cmi_send_paths = self.cmi_send_paths();
+        if !cmi_send_paths.is_empty() {
+            let name = "ct_ta_sync_cmi_send_path";
+            rendered.push_str(&format!(
+                "# HELP {name} The address a CMI was last sent to without a local error (its host or a backup host)\n# TYPE {name} gauge\n"
+            ));
+            for (cmi, host) in cmi_send_paths.iter() {
+                rendered.push_str(&format!(
+                    "{name}{} 1\n",
+                    self.labels(&[("cmi", cmi), ("host", host)])
+                ));
+            }
+        };
+        let room_heating = self
+            .room_heating
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !room_heating.is_empty() {
+            let name = "ct_ta_sync_room_heating";
+            rendered.push_str(&format!(
+                "# HELP {name} Whether HEATING (1) or NOT HEATING (0) was last sent to a room\n# TYPE {name} gauge\n"
+            ));
+            for ((host, room), (output, heating)) in room_heating.iter() {
+                rendered.push_str(&format!(
+                    "{name}{} {}\n",
+                    self.labels(&[
+                        ("cmi", host),
+                        ("room", room),
+                        ("output", &output.to_string())
+                    ]),
+                    u8::from(*heating)
+                ));
+            }
+        };
+        let dew_points = self
+            .dew_points
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if dew_points.values().any(Option::is_some) {
+            let name = "ct_ta_sync_dew_point_celsius";
+            rendered.push_str(&format!(
+                "# HELP {name} External dew point, from the temperature and humidity of a sensor\n# TYPE {name} gauge\n"
+            ));
+            for (sensor, dew_point) in dew_points.iter() {
+                if let Some(dew_point) = dew_point {
+                    rendered.push_str(&format!(
+                        "{name}{} {}\n",
+                        self.labels(&[("sensor", sensor)]),
+                        *dew_point as f64 / 10_f64
+                    ));
+                };
+            }
+        };
+        let coe_ignored = self
+            .coe_ignored
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !coe_ignored.is_empty() {
+            let name = "ct_ta_sync_coe_ignored_packets_total";
+            rendered.push_str(&format!(
+                "# HELP {name} Received datagrams that were not CoE or for nobody\n# TYPE {name} counter\n"
+            ));
+            for (source, count) in coe_ignored.iter() {
+                rendered.push_str(&format!(
+                    "{name}{} {count}\n",
+                    self.labels(&[("source", &source.to_string())])
+                ));
+            }
+        };
+        let coe_malformed = self
+            .coe_malformed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !coe_malformed.is_empty() {
+            let name = "ct_ta_sync_coe_malformed_packets_total";
+            rendered.push_str(&format!(
+                "# HELP {name} Received datagrams that were not parsable as CoE\n# TYPE {name} counter\n"
+            ));
+            for (source, malformed) in coe_malformed.iter() {
+                rendered.push_str(&format!(
+                    "{name}{} {}\n",
+                    self.labels(&[("source", &source.to_string())]),
+                    malformed.count
+                ));
+            }
+        };
+        rendered
+    }
+}
+
+#[cfg(all(test, feature = "http"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_counts() {
+        let metrics = Metrics::default();
+        metrics.count(Task::Pull, 120, 5, true);
+        metrics.count(Task::Pull, 80, 0, false);
+        metrics.count(Task::Push, 3, 2, true);
+        let rendered = metrics.render();
+        assert!(rendered.contains("ct_ta_sync_pulls_total 2\n"));
+        assert!(rendered.contains("ct_ta_sync_pull_errors_total 1\n"));
+        assert!(rendered.contains("ct_ta_sync_last_pull_duration_ms 80\n"));
+        // a failed pull does not reset the bookings
+        assert!(rendered.contains("ct_ta_sync_bookings 5\n"));
+        assert!(rendered.contains("ct_ta_sync_packets_sent_total 2\n"));
+        assert!(rendered.contains("# TYPE ct_ta_sync_bookings gauge\n"));
+        assert_eq!(metrics.last_run_ok(Task::Pull), Some(false));
+        assert_eq!(metrics.last_run_ok(Task::Push), Some(true));
+        assert_eq!(Metrics::default().last_run_ok(Task::Push), None);
+        assert!(!rendered.contains("send_failures"));
+    }
+
+    #[test]
+    fn coe_senders_are_capped() {
+        let metrics = Metrics::default();
+        for i in 0..=MAX_COE_SOURCES as u32 + 5 {
+            metrics.count_ignored_coe(IpAddr::from(i.to_be_bytes()));
+            metrics.count_malformed_coe(IpAddr::from(i.to_be_bytes()), "short".to_owned());
+        }
+        // senders counted before are still counted on their own
+        metrics.count_ignored_coe(IpAddr::from([0, 0, 0, 0]));
+        let rendered = metrics.render();
+        assert!(rendered.contains("ct_ta_sync_coe_ignored_packets_total{source=\"0.0.0.0\"} 2\n"));
+        assert!(rendered.contains("ct_ta_sync_coe_ignored_packets_total{source=\"other\"} 6\n"));
+        assert!(rendered.contains("ct_ta_sync_coe_malformed_packets_total{source=\"other\"} 6\n"));
+        let malformed = metrics.malformed_coe();
+        assert_eq!(malformed.len(), MAX_COE_SOURCES + 1);
+        assert_eq!(malformed[&CoeSource::Other].count, 6);
+    }
+
+    #[test]
+    fn render_send_failures() {
+        let metrics = Metrics::default();
+        metrics.set_send_failures([("cmi-a.local", 4), ("cmi-b.local", 0)]);
+        metrics.set_send_failures([("cmi-a.local", 0)]);
+        let rendered = metrics.render();
+        assert!(rendered.contains("ct_ta_sync_consecutive_send_failures{cmi=\"cmi-a.local\"} 0\n"));
+        assert!(rendered.contains("ct_ta_sync_consecutive_send_failures{cmi=\"cmi-b.local\"} 0\n"));
+    }
+
+    #[test]
+    fn render_room_heating() {
+        let metrics = Metrics::default();
+        assert!(!metrics.render().contains("ct_ta_sync_room_heating"));
+        metrics.set_room_heating("cmi-a.local", "room1", 1, true);
+        metrics.set_room_heating("cmi-a.local", "room2", 2, true);
+        metrics.set_room_heating("cmi-a.local", "room2", 2, false);
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "ct_ta_sync_room_heating{cmi=\"cmi-a.local\",room=\"room1\",output=\"1\"} 1\n"
+        ));
+        assert!(rendered.contains(
+            "ct_ta_sync_room_heating{cmi=\"cmi-a.local\",room=\"room2\",output=\"2\"} 0\n"
+        ));
+    }
+
+    #[test]
+    fn render_tenant_label() {
+        let metrics = Metrics::for_tenant(Some("parish-a".to_owned()));
+        metrics.count(Task::Pull, 120, 5, true);
+        metrics.set_room_heating("cmi-a.local", "room1", 1, true);
+        let rendered = metrics.render();
+        assert!(rendered.contains("ct_ta_sync_bookings{tenant=\"parish-a\"} 5\n"));
+        assert!(rendered.contains(
+            "ct_ta_sync_room_heating{tenant=\"parish-a\",cmi=\"cmi-a.local\",room=\"room1\",output=\"1\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn render_dew_points() {
+        let metrics = Metrics::default();
+        metrics.set_dew_points(BTreeMap::from([("default".to_owned(), None)]));
+        assert!(!metrics.render().contains("ct_ta_sync_dew_point"));
+        metrics.set_dew_points(BTreeMap::from([
+            ("default".to_owned(), Some(125)),
+            ("hall".to_owned(), None),
+        ]));
+        let rendered = metrics.render();
+        assert!(rendered.contains("ct_ta_sync_dew_point_celsius{sensor=\"default\"} 12.5\n"));
+        assert!(!rendered.contains("sensor=\"hall\""));
+    }
+}
@@ -0,0 +1,297 @@
+//! Schema migrations of the database.
+//!
+//! Migrations run at startup by default. Operators who want to control when the schema changes
+//! can disable that and run `ct-ta-sync migrate` instead. A backup of the database is taken before
+//! any migration touches it.
+
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    Pool, Sqlite,
+};
+use tracing::info;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The migration converting formatted datetimes into Unix timestamps
+const INTEGER_TIMESTAMPS: i64 = 17;
+
+/// (table, column) of the datetimes converted by [INTEGER_TIMESTAMPS]
+const LEGACY_DATETIMES: [(&str, &str); 12] = [
+    ("bookings", "start_time"),
+    ("bookings", "end_time"),
+    ("bookings", "modified_at"),
+    ("external_temperatures", "recorded_at"),
+    ("forecasts", "forecast_for"),
+    ("forecasts", "fetched_at"),
+    ("room_maintenance", "since"),
+    ("cycle_stats", "recorded_at"),
+    ("active_config", "loaded_at"),
+    ("instance_lease", "heartbeat"),
+    ("booking_archive", "start_time"),
+    ("booking_archive", "end_time"),
+];
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Migrate(sqlx::migrate::MigrateError),
+    Backup(sqlx::Error),
+    /// migrations exist that are not applied yet and auto_migrate is off
+    Pending(Vec<i64>),
+    NothingToRedo,
+    /// (table, column, value) of a datetime the migration to Unix timestamps cannot convert
+    UnparsableDatetime(&'static str, &'static str, String),
+}
+impl std::fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Migrate(e) => write!(f, "Unable to migrate the DB. Inner Error: {e}."),
+            Self::Backup(e) => write!(f, "Unable to back up the DB. Inner Error: {e}."),
+            Self::Pending(x) => write!(
+                f,
+                "The DB is missing the migrations {x:?}. Run `ct-ta-sync migrate up` or set global.auto_migrate."
+            ),
+            Self::NothingToRedo => write!(f, "No migration was applied yet, nothing to redo."),
+            Self::UnparsableDatetime(table, column, value) => write!(
+                f,
+                "The DB cannot be migrated: {table}.{column} contains {value:?}, which is not a datetime. Fix or delete that row."
+            ),
+        }
+    }
+}
+impl From<sqlx::migrate::MigrateError> for MigrateError {
+    fn from(value: sqlx::migrate::MigrateError) -> Self {
+        Self::Migrate(value)
+    }
+}
+impl std::error::Error for MigrateError {}
+
+/// A migration known to this binary
+#[derive(Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+/// Get all migrations known to this binary and whether they are applied to the DB
+pub async fn status(db: &Pool<Sqlite>) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = db
+        .acquire()
+        .await
+        .map_err(sqlx::migrate::MigrateError::from)?;
+    conn.ensure_migrations_table().await?;
+    let applied = conn.list_applied_migrations().await?;
+    Ok(MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+        .map(|m| MigrationStatus {
+            version: m.version,
+            description: m.description.to_string(),
+            applied: applied.iter().any(|a| a.version == m.version),
+        })
+        .collect())
+}
+
+/// Where to put a backup taken now
+pub fn backup_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(format!(".backup-{}", Utc::now().format("%Y%m%dT%H%M%S")));
+    PathBuf::from(path)
+}
+
+/// Write a consistent copy of the DB to `path`
+async fn backup(db: &Pool<Sqlite>, path: &Path) -> Result<(), MigrateError> {
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy())
+        .execute(db)
+        .await
+        .map_err(MigrateError::Backup)?;
+    info!("Backed up the DB to {}.", path.display());
+    Ok(())
+}
+
+/// Apply all pending migrations, backing up the DB to `backup_to` first.
+///
+/// No backup is taken if nothing is pending or the DB is new.
+pub async fn up(db: &Pool<Sqlite>, backup_to: &Path) -> Result<(), MigrateError> {
+    let migrations = status(db).await?;
+    let pending = migrations.iter().filter(|m| !m.applied).count();
+    if pending == 0 {
+        return Ok(());
+    };
+    if migrations.iter().any(|m| m.applied) {
+        let converts_datetimes = migrations
+            .iter()
+            .any(|m| m.version == INTEGER_TIMESTAMPS && !m.applied);
+        if converts_datetimes {
+            check_legacy_datetimes(db).await?;
+        };
+        backup(db, backup_to).await?;
+    };
+    MIGRATOR.run(db).await?;
+    info!("Applied {pending} migrations.");
+    Ok(())
+}
+
+/// Make sure [INTEGER_TIMESTAMPS] can convert all datetimes, before it fails halfway through
+async fn check_legacy_datetimes(db: &Pool<Sqlite>) -> Result<(), MigrateError> {
+    for (table, column) in LEGACY_DATETIMES {
+        // tables of migrations not applied yet are still empty
+        let exists =
+            sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?;")
+                .bind(table)
+                .fetch_optional(db)
+                .await
+                .map_err(sqlx::migrate::MigrateError::from)?
+                .is_some();
+        if !exists {
+            continue;
+        };
+        let unparsable: Option<String> = sqlx::query_scalar(&format!(
+            "SELECT CAST({column} AS TEXT) FROM {table} \
+             WHERE {column} IS NOT NULL AND strftime('%s', {column}) IS NULL LIMIT 1;"
+        ))
+        .fetch_optional(db)
+        .await
+        .map_err(sqlx::migrate::MigrateError::from)?;
+        if let Some(value) = unparsable {
+            return Err(MigrateError::UnparsableDatetime(table, column, value));
+        };
+    }
+    Ok(())
+}
+
+/// Revert the last applied migration and apply it again, backing up the DB to `backup_to` first.
+///
+/// Returns the version of the migration.
+pub async fn redo(db: &Pool<Sqlite>, backup_to: &Path) -> Result<i64, MigrateError> {
+    let migrations = status(db).await?;
+    let mut applied = migrations.iter().filter(|m| m.applied).map(|m| m.version);
+    let Some(last) = applied.next_back() else {
+        return Err(MigrateError::NothingToRedo);
+    };
+    // undo reverts everything newer than the target
+    let target = applied.next_back().unwrap_or(0);
+    backup(db, backup_to).await?;
+    MIGRATOR.undo(db, target).await?;
+    MIGRATOR.run(db).await?;
+    info!("Redid migration {last}.");
+    Ok(last)
+}
+
+/// The versions of the migrations not applied yet
+pub async fn pending(db: &Pool<Sqlite>) -> Result<Vec<i64>, MigrateError> {
+    Ok(status(db)
+        .await?
+        .into_iter()
+        .filter(|m| !m.applied)
+        .map(|m| m.version)
+        .collect())
+}
+
+/// Bring the DB up to date at startup, or make sure it already is if `auto_migrate` is off
+pub async fn on_startup(
+    db: &Pool<Sqlite>,
+    db_path: &Path,
+    auto_migrate: bool,
+) -> Result<(), MigrateError> {
+    if auto_migrate {
+        return up(db, &backup_path(db_path)).await;
+    };
+    let pending = pending(db).await?;
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrateError::Pending(pending))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn temp_backup(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ct-ta-sync-{name}-{}.db", std::process::id()))
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn up_and_redo(pool: Pool<Sqlite>) {
+        let backup_to = temp_backup("up-and-redo");
+        assert!(on_startup(&pool, Path::new("unused"), false).await.is_err());
+        // a new DB is not backed up
+        up(&pool, &backup_to).await.unwrap();
+        assert!(!backup_to.exists());
+        assert!(status(&pool).await.unwrap().iter().all(|m| m.applied));
+        on_startup(&pool, Path::new("unused"), false).await.unwrap();
+
+        let last = redo(&pool, &backup_to).await.unwrap();
+        assert_eq!(last, MIGRATOR.iter().map(|m| m.version).max().unwrap());
+        assert!(backup_to.exists());
+        assert!(status(&pool).await.unwrap().iter().all(|m| m.applied));
+        std::fs::remove_file(backup_to).unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn datetimes_become_timestamps(pool: Pool<Sqlite>) {
+        let backup_to = temp_backup("datetimes-become-timestamps");
+        MIGRATOR.run(&pool).await.unwrap();
+        // the schema before migration 017, with datetimes stored as text like the db functions
+        // wrote them
+        MIGRATOR.undo(&pool, INTEGER_TIMESTAMPS - 1).await.unwrap();
+        sqlx::query(
+            "INSERT INTO bookings (booking_id, resource_id, start_time, end_time, modified_at) \
+             VALUES (1, 2, '2025-01-12T09:00:00', '2025-01-12T12:30:00', '2025-01-10T17:45:10');",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO external_temperatures VALUES ('2025-01-12T08:00:00', -15);")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO room_maintenance VALUES ('hall', 'yesterday');")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // nothing is touched
+        assert!(matches!(
+            up(&pool, &backup_to).await,
+            Err(MigrateError::UnparsableDatetime("room_maintenance", "since", x)) if x == "yesterday"
+        ));
+        assert!(!backup_to.exists());
+        assert_eq!(pending(&pool).await.unwrap(), vec![INTEGER_TIMESTAMPS]);
+        sqlx::query("DELETE FROM room_maintenance;")
+            .execute(&pool)
+            .await
+            .unwrap();
+        up(&pool, &backup_to).await.unwrap();
+        std::fs::remove_file(backup_to).unwrap();
+
+        let at = |d, h, m, s| Utc.with_ymd_and_hms(2025, 1, d, h, m, s).unwrap();
+        let bookings = crate::db::get_all_bookings(&pool).await.unwrap();
+        assert_eq!(
+            bookings,
+            vec![
+                crate::Booking::new(1, 2, at(12, 9, 0, 0), at(12, 12, 30, 0))
+                    .unwrap()
+                    .with_modified_at(Some(at(10, 17, 45, 10)))
+            ]
+        );
+        let samples = crate::db::get_external_temperatures_in_timeframe(
+            &pool,
+            at(12, 0, 0, 0).naive_utc(),
+            at(13, 0, 0, 0).naive_utc(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].recorded_at, at(12, 8, 0, 0));
+        assert_eq!(samples[0].temperature, -15);
+    }
+}
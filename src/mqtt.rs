@@ -0,0 +1,143 @@
+//! Receive the external temperature via MQTT
+
+use std::{sync::Arc, time::Duration};
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn};
+
+use crate::config::{Config, MqttExtTempConfig};
+
+/// Wait this long before polling the broker again after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Parse an MQTT payload into tenths of a Degree Centigrade
+fn parse_payload(payload: &[u8], json_field: Option<&str>) -> Option<i32> {
+    let text = std::str::from_utf8(payload).ok()?;
+    let temp = match json_field {
+        Some(field) => serde_json::from_str::<serde_json::Value>(text)
+            .ok()?
+            .get(field)?
+            .as_f64()?,
+        None => text.trim().parse::<f64>().ok()?,
+    };
+    if temp.is_finite() {
+        Some((temp * 10_f64).round() as i32)
+    } else {
+        None
+    }
+}
+
+/// Forward all external temperatures received via MQTT for the sensor of `site`
+///
+/// Connection errors are logged and the connection is retried.
+pub async fn mqtt_source(config: Arc<Config>, site: Option<String>, tx: mpsc::Sender<i32>) {
+    let Some(mqtt_config) = &config.ext_temp_sensor(site.as_deref()).mqtt else {
+        return;
+    };
+    let options = mqtt_options(mqtt_config, config.tenant.as_deref(), site.as_deref());
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    info!(
+        "Receiving the external temperature from MQTT topic {} on {}",
+        mqtt_config.topic, mqtt_config.host
+    );
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                debug!("Connected to the MQTT broker.");
+                // subscriptions do not survive reconnects
+                if let Err(e) = client
+                    .subscribe(mqtt_config.topic.clone(), QoS::AtMostOnce)
+                    .await
+                {
+                    warn!(
+                        "Failed to subscribe to MQTT topic {}: {e}",
+                        mqtt_config.topic
+                    );
+                };
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                match parse_payload(&publish.payload, mqtt_config.json_field.as_deref()) {
+                    Some(temp) => {
+                        debug!(
+                            "Got the external temperature via MQTT: {} °C",
+                            temp as f32 / 10_f32
+                        );
+                        if tx.send(temp).await.is_err() {
+                            return;
+                        };
+                    }
+                    None => {
+                        trace!("Got an MQTT message without a parsable temperature.");
+                    }
+                };
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!(
+                    "MQTT connection error: {e}. Retrying in {}s.",
+                    RECONNECT_DELAY.as_secs()
+                );
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The client id, unique per tenant and site, since the broker disconnects clients with the same id
+fn client_id(tenant: Option<&str>, site: Option<&str>) -> String {
+    ["ct-ta-sync"]
+        .into_iter()
+        .chain(tenant)
+        .chain(site)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn mqtt_options(
+    mqtt_config: &MqttExtTempConfig,
+    tenant: Option<&str>,
+    site: Option<&str>,
+) -> MqttOptions {
+    let client_id = client_id(tenant, site);
+    let mut options = MqttOptions::new(client_id, mqtt_config.host.clone(), mqtt_config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let (Some(username), Some(password)) = (&mqtt_config.username, &mqtt_config.password) {
+        options.set_credentials(username.clone(), password.clone());
+    };
+    options
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client_ids_per_tenant_and_site() {
+        assert_eq!(client_id(None, None), "ct-ta-sync");
+        assert_eq!(client_id(None, Some("chapel")), "ct-ta-sync-chapel");
+        assert_eq!(
+            client_id(Some("parish-a"), Some("chapel")),
+            "ct-ta-sync-parish-a-chapel"
+        );
+        assert_ne!(
+            client_id(Some("parish-a"), None),
+            client_id(Some("parish-b"), None)
+        );
+    }
+
+    #[test]
+    fn parse_plain_payload() {
+        assert_eq!(parse_payload(b"-5.24", None), Some(-52));
+        assert_eq!(parse_payload(b" 12\n", None), Some(120));
+        assert_eq!(parse_payload(b"warm", None), None);
+    }
+
+    #[test]
+    fn parse_json_payload() {
+        let payload = br#"{"battery": 100, "temperature": -3.1}"#;
+        assert_eq!(parse_payload(payload, Some("temperature")), Some(-31));
+        assert_eq!(parse_payload(payload, Some("humidity")), None);
+        assert_eq!(parse_payload(b"-3.1", Some("temperature")), None);
+    }
+}
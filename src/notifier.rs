@@ -0,0 +1,577 @@
+//! Push notifications when heating control degrades
+//!
+//! The notifier forwards the alerts raised by all tasks to all configured channels (ntfy,
+//! Telegram, email).
+//!
+//! Alerts are deduplicated by the condition they are about. Notifications are rate limited and
+//! held back during quiet hours, so a flapping link at night does not produce hundreds of messages.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Local, NaiveTime, TimeDelta};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    alert::{Alert, AlertEvent, AlertKey},
+    config::{AlertChannelConfig, AlertingConfig, Config},
+    i18n::Language,
+    InShutdown,
+};
+
+impl Alert {
+    /// A short title for the notification
+    fn title(&self, language: Language) -> &'static str {
+        match (language, self) {
+            (Language::En, Self::CtPullFailing { .. }) => "ChurchTools unreachable",
+            (Language::En, Self::Db { .. }) => "Database error",
+            (Language::En, Self::ExtTempMissing { .. }) => "External temperature missing",
+            (Language::En, Self::FeedbackMismatch { .. }) => "Room does not follow heating command",
+            (Language::De, Self::CtPullFailing { .. }) => "ChurchTools nicht erreichbar",
+            (Language::De, Self::Db { .. }) => "Datenbankfehler",
+            (Language::De, Self::ExtTempMissing { .. }) => "Außentemperatur fehlt",
+            (Language::De, Self::FeedbackMismatch { .. }) => "Raum folgt dem Heizbefehl nicht",
+        }
+    }
+
+    /// The text of the notification. Logs use the English [Display](std::fmt::Display) instead.
+    fn message(&self, language: Language) -> String {
+        match (language, self) {
+            (Language::En, _) => self.to_string(),
+            (Language::De, Self::CtPullFailing { failures, error }) => format!(
+                "Das Abrufen der Buchungen aus ChurchTools ist {failures}-mal in Folge fehlgeschlagen. Die Buchungen werden nicht aktualisiert. Letzter Fehler: {error}"
+            ),
+            (Language::De, Self::Db { task, error }) => {
+                format!(
+                    "Der Zugriff auf die Datenbank ist fehlgeschlagen ({}): {error}",
+                    task.name()
+                )
+            }
+            (Language::De, Self::ExtTempMissing { site, minutes }) => format!(
+                "Vom Sensor {} wurde seit {minutes} Minuten keine Außentemperatur empfangen. Die Vorheizzeiten werden nicht angepasst.",
+                site.as_deref().unwrap_or("Standard")
+            ),
+            (Language::De, Self::FeedbackMismatch { cmi, room }) => format!(
+                "Die Rückmeldung von Raum {room} an der CMI {cmi} folgt nicht dem gesendeten Zustand. Bitte Verkabelung und Funktionsdaten prüfen."
+            ),
+        }
+    }
+}
+
+/// A message sent to all channels
+#[derive(Debug, PartialEq)]
+enum Notification {
+    Raised(Alert),
+    Resolved(Alert),
+}
+impl Notification {
+    fn title(&self, language: Language) -> String {
+        match (language, self) {
+            (_, Self::Raised(alert)) => alert.title(language).to_owned(),
+            (Language::En, Self::Resolved(alert)) => format!("Resolved: {}", alert.title(language)),
+            (Language::De, Self::Resolved(alert)) => format!("Behoben: {}", alert.title(language)),
+        }
+    }
+
+    fn message(&self, language: Language) -> String {
+        match (language, self) {
+            (_, Self::Raised(alert)) => alert.message(language),
+            (Language::En, Self::Resolved(alert)) => {
+                format!("This is resolved: {}", alert.message(language))
+            }
+            (Language::De, Self::Resolved(alert)) => {
+                format!("Das ist behoben: {}", alert.message(language))
+            }
+        }
+    }
+}
+
+/// What happened to an active alert
+#[derive(Debug, PartialEq)]
+enum AlertState {
+    /// raised during quiet hours, not sent yet
+    Pending,
+    /// sent
+    Notified,
+    /// sent, then resolved during quiet hours
+    ResolvePending,
+    /// dropped by the rate limit
+    Suppressed,
+}
+
+/// Is time within the quiet hours from start to end (which may span midnight)?
+fn is_quiet_time(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    if start <= end {
+        start <= time && time < end
+    } else {
+        start <= time || time < end
+    }
+}
+
+/// Decides which alert events become notifications
+#[derive(Debug)]
+struct Throttle<'a> {
+    config: &'a AlertingConfig,
+    /// all raised alerts that have not been resolved
+    active: HashMap<AlertKey, (Alert, AlertState)>,
+    /// times at which raised alerts were sent within the last hour, oldest first
+    sent: VecDeque<DateTime<Local>>,
+}
+impl<'a> Throttle<'a> {
+    fn new(config: &'a AlertingConfig) -> Self {
+        Self {
+            config,
+            active: HashMap::new(),
+            sent: VecDeque::new(),
+        }
+    }
+
+    fn is_quiet(&self, now: DateTime<Local>) -> bool {
+        self.config
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|q| is_quiet_time(q.start, q.end, now.time()))
+    }
+
+    /// Record a sent notification, or return false if the rate limit is reached
+    fn take_rate_limit(&mut self, now: DateTime<Local>) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|&t| now - t >= TimeDelta::hours(1))
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.config.max_per_hour {
+            return false;
+        };
+        self.sent.push_back(now);
+        true
+    }
+
+    /// Send an alert now, unless the rate limit is reached
+    fn notify(&mut self, alert: Alert, now: DateTime<Local>) -> Option<Notification> {
+        let key = alert.key();
+        if self.take_rate_limit(now) {
+            self.active
+                .insert(key, (alert.clone(), AlertState::Notified));
+            Some(Notification::Raised(alert))
+        } else {
+            warn!(
+                "Reached the limit of {} alerts per hour. Not sending alert: {alert}",
+                self.config.max_per_hour
+            );
+            self.active.insert(key, (alert, AlertState::Suppressed));
+            None
+        }
+    }
+
+    fn raised(&mut self, alert: Alert, now: DateTime<Local>) -> Option<Notification> {
+        let quiet = self.is_quiet(now);
+        match self.active.get_mut(&alert.key()) {
+            // flapped back during quiet hours, the resolve was never sent
+            Some((_, state @ AlertState::ResolvePending)) => {
+                *state = AlertState::Notified;
+                None
+            }
+            Some(_) => {
+                trace!("Alert is already active: {alert}");
+                None
+            }
+            None if quiet => {
+                debug!("Holding back alert during quiet hours: {alert}");
+                self.active
+                    .insert(alert.key(), (alert, AlertState::Pending));
+                None
+            }
+            None => self.notify(alert, now),
+        }
+    }
+
+    fn resolved(&mut self, key: AlertKey, now: DateTime<Local>) -> Option<Notification> {
+        let quiet = self.is_quiet(now);
+        match self.active.remove(&key)? {
+            (alert, AlertState::Notified) if quiet => {
+                self.active.insert(key, (alert, AlertState::ResolvePending));
+                None
+            }
+            (alert, AlertState::Notified | AlertState::ResolvePending) => {
+                Some(Notification::Resolved(alert))
+            }
+            // nobody was notified about these
+            (_, AlertState::Pending | AlertState::Suppressed) => None,
+        }
+    }
+
+    /// Send everything held back during quiet hours, once they are over
+    fn flush(&mut self, now: DateTime<Local>) -> Vec<Notification> {
+        if self.is_quiet(now) {
+            return vec![];
+        };
+        let held = self
+            .active
+            .iter()
+            .filter(|(_, (_, state))| {
+                matches!(state, AlertState::Pending | AlertState::ResolvePending)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        let mut notifications = vec![];
+        for key in held {
+            let Some((alert, state)) = self.active.remove(&key) else {
+                continue;
+            };
+            match state {
+                AlertState::Pending => notifications.extend(self.notify(alert, now)),
+                _ => notifications.push(Notification::Resolved(alert)),
+            };
+        }
+        notifications
+    }
+}
+
+#[derive(Debug)]
+pub enum AlertError {
+    Http(reqwest::Error),
+    HttpStatus(reqwest::StatusCode),
+    InvalidAddress(lettre::address::AddressError),
+    BuildEmail(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+}
+impl std::fmt::Display for AlertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Http(e) => write!(f, "Cannot send the alert. reqwest Error: {e}"),
+            Self::HttpStatus(x) => write!(f, "Sending the alert was answered with status {x}."),
+            Self::InvalidAddress(e) => write!(f, "Invalid email address: {e}"),
+            Self::BuildEmail(e) => write!(f, "Cannot build the alert email: {e}"),
+            Self::Smtp(e) => write!(f, "Cannot send the alert email: {e}"),
+        }
+    }
+}
+impl From<reqwest::Error> for AlertError {
+    fn from(value: reqwest::Error) -> Self {
+        // the URL of a Telegram request contains the bot token
+        Self::Http(value.without_url())
+    }
+}
+impl From<lettre::address::AddressError> for AlertError {
+    fn from(value: lettre::address::AddressError) -> Self {
+        Self::InvalidAddress(value)
+    }
+}
+impl From<lettre::error::Error> for AlertError {
+    fn from(value: lettre::error::Error) -> Self {
+        Self::BuildEmail(value)
+    }
+}
+impl From<lettre::transport::smtp::Error> for AlertError {
+    fn from(value: lettre::transport::smtp::Error) -> Self {
+        Self::Smtp(value)
+    }
+}
+impl std::error::Error for AlertError {}
+
+/// Fail on non-2xx responses
+fn check_status(response: reqwest::Response) -> Result<(), AlertError> {
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(AlertError::HttpStatus(response.status()))
+    }
+}
+
+/// Send a message to a single channel
+async fn send_to_channel(
+    client: &reqwest::Client,
+    channel: &AlertChannelConfig,
+    title: &str,
+    message: &str,
+) -> Result<(), AlertError> {
+    match channel {
+        AlertChannelConfig::Ntfy { url, token } => {
+            let mut request = client
+                .post(url)
+                .header("Title", title)
+                .body(message.to_owned());
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {token}"));
+            };
+            check_status(request.send().await?)
+        }
+        AlertChannelConfig::Telegram { bot_token, chat_id } => {
+            let text = format!("{title}\n{message}");
+            let response = client
+                .post(format!(
+                    "https://api.telegram.org/bot{bot_token}/sendMessage"
+                ))
+                .query(&[("chat_id", chat_id.as_str()), ("text", text.as_str())])
+                .send()
+                .await?;
+            check_status(response)
+        }
+        AlertChannelConfig::Email {
+            smtp_host,
+            smtp_port,
+            username,
+            password,
+            from,
+            to,
+        } => {
+            let mut builder = Message::builder()
+                .from(from.parse::<Mailbox>()?)
+                .subject(format!("ct-ta-sync: {title}"));
+            for recipient in to {
+                builder = builder.to(recipient.parse::<Mailbox>()?);
+            }
+            let message = builder.body(message.to_owned())?;
+            let transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?
+                .port(*smtp_port)
+                .credentials(Credentials::new(username.clone(), password.clone()))
+                .build();
+            transport.send(message).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Send a notification to all channels
+async fn send_notification(
+    client: &reqwest::Client,
+    alerting: &AlertingConfig,
+    notification: &Notification,
+    language: Language,
+) {
+    info!(
+        "Sending notification: {}",
+        notification.message(Language::En)
+    );
+    send_to_all(
+        client,
+        alerting,
+        &notification.title(language),
+        &notification.message(language),
+    )
+    .await;
+}
+
+/// Send a message to all channels, regardless of quiet hours and rate limits
+pub async fn send_to_all(
+    client: &reqwest::Client,
+    alerting: &AlertingConfig,
+    title: &str,
+    message: &str,
+) {
+    for channel in &alerting.channels {
+        if let Err(e) = send_to_channel(client, channel, title, message).await {
+            warn!(
+                "Failed to send an alert via {}. Error encountered: {e}",
+                channel.kind()
+            );
+        };
+    }
+}
+
+/// Forward all raised and resolved alerts to the configured channels until shutdown
+pub async fn notify(
+    config: Arc<Config>,
+    mut rx: mpsc::Receiver<AlertEvent>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(alerting) = &config.alerting else {
+        debug!("No alerting configured.");
+        return;
+    };
+    info!(
+        "Starting notifier with {} alert channels",
+        alerting.channels.len()
+    );
+    let client = reqwest::Client::new();
+    let mut throttle = Throttle::new(alerting);
+    // check whether quiet hours are over
+    let mut flush_interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+    loop {
+        let notifications = tokio::select! {
+            Some(event) = rx.recv() => {
+                match event {
+                    AlertEvent::Raised(alert) => throttle.raised(alert, Local::now()),
+                    AlertEvent::Resolved(key) => throttle.resolved(key, Local::now()),
+                }
+                .into_iter()
+                .collect::<Vec<_>>()
+            }
+            _ = flush_interval.tick() => throttle.flush(Local::now()),
+            _ = watcher.changed() => {
+                debug!("Shutting down the notifier now.");
+                return;
+            }
+        };
+        for notification in notifications {
+            send_notification(&client, alerting, &notification, config.global.language).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{alert::DbTask, config::QuietHours};
+
+    fn alerting_config(max_per_hour: usize, quiet_hours: Option<QuietHours>) -> AlertingConfig {
+        AlertingConfig {
+            channels: vec![],
+            ct_failures: 3,
+            ext_temp_missing: 60,
+            max_per_hour,
+            quiet_hours,
+            daily_summary: false,
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        chrono::NaiveDate::from_ymd_opt(2024, 1, 15)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+            .and_local_timezone(Local)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_do_not_contain_the_url() {
+        let error = reqwest::Client::new()
+            .post("http://127.0.0.1:1/bot123:secret-token/sendMessage")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("secret-token"));
+        let error = AlertError::from(error);
+        assert!(!error.to_string().contains("secret-token"));
+    }
+
+    #[test]
+    fn quiet_time_spans_midnight() {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert!(is_quiet_time(t(22, 0), t(6, 0), t(23, 0)));
+        assert!(is_quiet_time(t(22, 0), t(6, 0), t(2, 0)));
+        assert!(!is_quiet_time(t(22, 0), t(6, 0), t(6, 0)));
+        assert!(is_quiet_time(t(12, 0), t(14, 0), t(12, 0)));
+        assert!(!is_quiet_time(t(12, 0), t(14, 0), t(15, 0)));
+    }
+
+    #[test]
+    fn alerts_are_deduplicated_and_resolved() {
+        let config = alerting_config(10, None);
+        let mut throttle = Throttle::new(&config);
+        let alert = Alert::Db {
+            task: DbTask::Push,
+            error: "broken".to_owned(),
+        };
+        assert_eq!(
+            throttle.raised(alert.clone(), at(12, 0)),
+            Some(Notification::Raised(alert.clone()))
+        );
+        assert_eq!(throttle.raised(alert.clone(), at(12, 1)), None);
+        assert_eq!(
+            throttle.resolved(AlertKey::Db(DbTask::Push), at(12, 2)),
+            Some(Notification::Resolved(alert))
+        );
+        // nothing left to resolve
+        assert_eq!(throttle.resolved(AlertKey::Db(DbTask::Push), at(12, 3)), None);
+    }
+
+    #[test]
+    fn db_alerts_are_resolved_per_task() {
+        let config = alerting_config(10, None);
+        let mut throttle = Throttle::new(&config);
+        let pull = Alert::Db {
+            task: DbTask::Pull,
+            error: "disk full".to_owned(),
+        };
+        assert!(throttle.raised(pull.clone(), at(12, 0)).is_some());
+        // a successful push does not mean the pull can write again
+        assert_eq!(throttle.resolved(AlertKey::Db(DbTask::Push), at(12, 1)), None);
+        assert_eq!(
+            throttle.resolved(AlertKey::Db(DbTask::Pull), at(12, 2)),
+            Some(Notification::Resolved(pull))
+        );
+    }
+
+    #[test]
+    fn notifications_in_german() {
+        let resolved = Notification::Resolved(Alert::ExtTempMissing {
+            site: None,
+            minutes: 30,
+        });
+        assert_eq!(
+            resolved.title(Language::De),
+            "Behoben: Außentemperatur fehlt"
+        );
+        assert_eq!(
+            resolved.message(Language::De),
+            "Das ist behoben: Vom Sensor Standard wurde seit 30 Minuten keine Außentemperatur empfangen. Die Vorheizzeiten werden nicht angepasst."
+        );
+        assert_eq!(
+            resolved.title(Language::En),
+            "Resolved: External temperature missing"
+        );
+    }
+
+    #[test]
+    fn alerts_are_rate_limited() {
+        let config = alerting_config(1, None);
+        let mut throttle = Throttle::new(&config);
+        let first = Alert::Db {
+            task: DbTask::Push,
+            error: "broken".to_owned(),
+        };
+        let second = Alert::ExtTempMissing {
+            site: None,
+            minutes: 60,
+        };
+        assert!(throttle.raised(first, at(12, 0)).is_some());
+        assert_eq!(throttle.raised(second.clone(), at(12, 30)), None);
+        // suppressed alerts are not resolved
+        assert_eq!(
+            throttle.resolved(AlertKey::ExtTempMissing(None), at(12, 40)),
+            None
+        );
+        assert!(throttle.raised(second, at(13, 0)).is_some());
+    }
+
+    #[test]
+    fn flapping_during_quiet_hours_is_silent() {
+        let config = alerting_config(
+            10,
+            Some(QuietHours {
+                start: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            }),
+        );
+        let mut throttle = Throttle::new(&config);
+        let ct = Alert::CtPullFailing {
+            failures: 3,
+            error: "timeout".to_owned(),
+        };
+        for minute in 0..30 {
+            assert_eq!(throttle.raised(ct.clone(), at(23, minute)), None);
+            assert_eq!(throttle.resolved(AlertKey::CtPull, at(23, minute)), None);
+        }
+        let db = Alert::Db {
+            task: DbTask::Push,
+            error: "broken".to_owned(),
+        };
+        assert_eq!(throttle.raised(db.clone(), at(23, 45)), None);
+        assert!(throttle.flush(at(3, 0)).is_empty());
+        // only the alert still active after quiet hours is sent
+        assert_eq!(throttle.flush(at(6, 0)), vec![Notification::Raised(db)]);
+        assert!(throttle.flush(at(6, 1)).is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+//! Log panics through tracing, and shut down instead of running on without a task.
+//!
+//! Without this, a panicking task (e.g. the emitter) would die silently while the others keep
+//! running, and no CoE would be sent until someone notices.
+
+use std::{backtrace::Backtrace, future::Future, panic::AssertUnwindSafe};
+
+use futures_util::FutureExt;
+use tokio::{sync::watch::Sender, task::JoinHandle};
+use tracing::{error, Instrument};
+
+use crate::InShutdown;
+
+/// Log all panics with a backtrace.
+pub fn install() {
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        error!(
+            "Thread {} {info}\n{}",
+            thread.name().unwrap_or("<unnamed>"),
+            Backtrace::force_capture()
+        );
+    }));
+}
+
+/// Spawn a task of a tenant in the current span. With `shutdown_tx`, a panic of the task shuts
+/// down the tenant it belongs to.
+pub fn spawn<F>(shutdown_tx: Option<&Sender<InShutdown>>, task: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let shutdown_tx = shutdown_tx.cloned();
+    tokio::spawn(
+        async move {
+            match AssertUnwindSafe(task).catch_unwind().await {
+                Ok(x) => x,
+                Err(panic) => {
+                    if let Some(tx) = shutdown_tx {
+                        error!("Shutting down because of the panic.");
+                        tx.send_replace(InShutdown::Yes);
+                    };
+                    std::panic::resume_unwind(panic)
+                }
+            }
+        }
+        .in_current_span(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn task_panic_shuts_down() {
+        let (tx, rx) = tokio::sync::watch::channel(InShutdown::No);
+        let res = spawn(Some(&tx), async { panic!("emitter died") }).await;
+        assert!(res.unwrap_err().is_panic());
+        assert!(matches!(*rx.borrow(), InShutdown::Yes));
+    }
+
+    #[tokio::test]
+    async fn task_panic_without_shutdown() {
+        let (tx, rx) = tokio::sync::watch::channel(InShutdown::No);
+        let res = spawn(None, async { panic!("emitter died") }).await;
+        assert!(res.unwrap_err().is_panic());
+        assert!(matches!(*rx.borrow(), InShutdown::No));
+        drop(tx);
+    }
+}
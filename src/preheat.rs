@@ -0,0 +1,600 @@
+//! Pure calculations for preheat, preshutdown and overrun times, and which rooms may heat when
+//! they compete for the heat source
+//!
+//! All external temperatures are expected in tenths of a Degree Centigrade.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// The proportion of preheat time to apply, depending on the external temperature.
+///
+/// Between the points, the factor is interpolated linearly. Below the first and above the last
+/// point, the factor of that point is used.
+/// Preshutdown is scaled inversely, with a factor of `1 - preheat factor`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PreheatCurve {
+    /// (external temperature in tenths of a Degree Centigrade, factor), sorted by temperature
+    points: Vec<(i32, f64)>,
+}
+impl Default for PreheatCurve {
+    /// Full preheat at -10 °C and below, no preheat at 20 °C and above
+    fn default() -> Self {
+        PreheatCurve {
+            points: vec![(-100, 1.0), (200, 0.0)],
+        }
+    }
+}
+impl PreheatCurve {
+    /// Create a curve from (external temperature in Degree Centigrade, factor) points
+    pub fn from_points(points: &[(f64, f64)]) -> Result<Self, &'static str> {
+        if points.is_empty() {
+            return Err("it needs at least one point");
+        };
+        if points.iter().any(|p| !(0.0..=1.0).contains(&p.1)) {
+            return Err("all factors need to be within 0.0 - 1.0");
+        };
+        let points = points
+            .iter()
+            .map(|p| ((p.0 * 10_f64).round() as i32, p.1))
+            .collect::<Vec<_>>();
+        if points.windows(2).any(|w| w[0].0 >= w[1].0) {
+            return Err("temperatures need to be strictly increasing");
+        };
+        Ok(PreheatCurve { points })
+    }
+
+    /// The preheat factor at this external temperature
+    pub fn factor(&self, external_temp: i32) -> f64 {
+        let first = self.points.first().expect("curve has at least one point");
+        let last = self.points.last().expect("curve has at least one point");
+        if external_temp <= first.0 {
+            return first.1;
+        };
+        if external_temp >= last.0 {
+            return last.1;
+        };
+        let (lower, upper) = self
+            .points
+            .windows(2)
+            .map(|w| (w[0], w[1]))
+            .find(|(_, upper)| external_temp <= upper.0)
+            .expect("temperature is within the curve");
+        let proportion = (external_temp - lower.0) as f64 / (upper.0 - lower.0) as f64;
+        lower.1 + proportion * (upper.1 - lower.1)
+    }
+}
+
+/// Calculate the amount of minutes a room should be preheated, depending on the the
+/// base_preheating time, the preheat curve and the external temperature
+///
+/// if external_temp is None, we do not scale the base preheat time at all.
+pub(crate) fn preheat_time(
+    preheat_minutes: u8,
+    curve: &PreheatCurve,
+    external_temp: Option<i32>,
+) -> u8 {
+    if let Some(x) = external_temp {
+        (preheat_minutes as f64 * curve.factor(x)).round() as u8
+    } else {
+        preheat_minutes
+    }
+}
+
+/// Calculate the amount of minutes a rooms heating may be shut down BEFORE the end of a booking,
+/// depending on the base_preshutdown time, the preheat curve and the external temperature
+///
+/// if external_temp is None, we never shut down prematurely.
+pub(crate) fn preshutdown_time(
+    preshutdown_minutes: u8,
+    curve: &PreheatCurve,
+    external_temp: Option<i32>,
+) -> u8 {
+    if let Some(x) = external_temp {
+        (preshutdown_minutes as f64 * (1_f64 - curve.factor(x))).round() as u8
+    } else {
+        // if we do not now how warm it is, we are never allowed to prematurely stop heating
+        0
+    }
+}
+
+/// Multiply a number of minutes by `factor`, saturating at u8::MAX
+pub(crate) fn scale_minutes(minutes: u8, factor: f64) -> u8 {
+    (minutes as f64 * factor)
+        .round()
+        .clamp(0_f64, u8::MAX as f64) as u8
+}
+
+/// The factor to extend preheat by, after a room was not booked for `unused_for`.
+///
+/// Preheat grows by `extra_per_day` for each day, up to `max_extra`.
+pub(crate) fn cold_start_factor(extra_per_day: f64, max_extra: f64, unused_for: TimeDelta) -> f64 {
+    let days = unused_for.num_minutes().max(0) as f64 / (24_f64 * 60_f64);
+    1_f64 + (extra_per_day * days).min(max_extra)
+}
+
+/// Irradiance at which the full solar gain of a room is expected, in W/m²
+const FULL_SOLAR_GAIN_IRRADIANCE: f64 = 800_f64;
+
+/// The external conditions a booking is evaluated under
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Conditions {
+    /// external temperature expected while preheating (e.g. from a forecast)
+    pub preheat_temp: Option<i32>,
+    /// current external temperature, used for preshutdown
+    pub external_temp: Option<i32>,
+    /// solar irradiance expected while preheating, in W/m²
+    pub preheat_irradiance: Option<i32>,
+    /// how long the room was not booked before (see cold_start in the config)
+    pub unused_for: Option<TimeDelta>,
+}
+
+/// Reduce the preheat time by the expected solar gain.
+///
+/// sun_exposure is the proportion of preheat time the sun can replace in this room (0.0 - 1.0),
+/// reached at FULL_SOLAR_GAIN_IRRADIANCE. Without an irradiance, preheat is not reduced.
+pub(crate) fn apply_solar_gain(preheat: u8, sun_exposure: f64, irradiance: Option<i32>) -> u8 {
+    let Some(irradiance) = irradiance else {
+        return preheat;
+    };
+    let solar_proportion = (irradiance as f64 / FULL_SOLAR_GAIN_IRRADIANCE).clamp(0_f64, 1_f64);
+    (preheat as f64 * (1_f64 - sun_exposure.clamp(0_f64, 1_f64) * solar_proportion)).round() as u8
+}
+
+/// Return the real start and real end time of a booking (i.e. the times where we have to start
+/// heating or are allowed to stop heating), given the already scaled times in minutes.
+///
+/// The overrun time is added after preshutdown was applied, so an overrun of the same length
+/// as the preshutdown cancels it out. The real end is never before the real start.
+pub(crate) fn adjust_booking_times(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    preheat: u8,
+    preshutdown: u8,
+    overrun: u8,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let new_start = start - TimeDelta::minutes(preheat.into());
+    let new_end = end - TimeDelta::minutes(preshutdown.into()) + TimeDelta::minutes(overrun.into());
+    (new_start, new_end.max(new_start))
+}
+
+/// Granularity in which an earlier preheat start is searched
+const PRICE_SHIFT_STEP: TimeDelta = TimeDelta::minutes(5);
+/// The share of the heating power needed to keep a preheated room at its temperature
+const HOLD_POWER: f64 = 0.5;
+
+/// Move the start of preheating up to `flexibility` earlier, into the cheapest energy prices.
+///
+/// Preheating takes `preheat` from `start` on. Each earlier start is rated by the price over the
+/// whole time until the booking starts: the time it preheats, then the time it keeps the room
+/// warm at [HOLD_POWER]. Later starts win ties, so the room is not heated earlier for nothing.
+pub(crate) fn cheapest_start(
+    start: DateTime<Utc>,
+    preheat: TimeDelta,
+    flexibility: TimeDelta,
+    price_at: impl Fn(DateTime<Utc>) -> f64,
+) -> DateTime<Utc> {
+    if preheat <= TimeDelta::zero() {
+        return start;
+    };
+    let booking_start = start + preheat;
+    let cost = |candidate: DateTime<Utc>| {
+        (0..(booking_start - candidate).num_minutes())
+            .map(|m| {
+                let power = if m < preheat.num_minutes() {
+                    1_f64
+                } else {
+                    HOLD_POWER
+                };
+                power * price_at(candidate + TimeDelta::minutes(m))
+            })
+            .sum::<f64>()
+    };
+    let mut best = (start, cost(start));
+    let mut candidate = start - PRICE_SHIFT_STEP;
+    while candidate >= start - flexibility {
+        let candidate_cost = cost(candidate);
+        if candidate_cost < best.1 {
+            best = (candidate, candidate_cost);
+        };
+        candidate -= PRICE_SHIFT_STEP;
+    }
+    best.0
+}
+
+/// A room that wants to heat now
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HeatingCandidate<'a> {
+    pub room: &'a str,
+    /// the site of the CMI wanting to heat the room
+    pub site: Option<&'a str>,
+    /// the heating circuit the room shares with others
+    pub circuit: Option<&'a str>,
+    pub priority: i32,
+    /// the start of the first booking the room is heated for
+    pub first_start: DateTime<Utc>,
+    /// whether the room was heated at the last push, so it keeps its place
+    pub heating: bool,
+}
+
+/// The order in which rooms get to heat: higher priority first, then the earlier booking.
+///
+/// The room name breaks ties, so the order is stable between pushes.
+pub(crate) fn priority_order(
+    a: (i32, DateTime<Utc>, &str),
+    b: (i32, DateTime<Utc>, &str),
+) -> std::cmp::Ordering {
+    b.0.cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(b.2))
+}
+
+/// Get the rooms that may not heat, because a room with a shared circuit goes first.
+///
+/// Only one room per circuit is heated at a time. The room heating it keeps it until its bookings
+/// end, so outputs do not flap. Otherwise, it is chosen by [`priority_order`].
+pub(crate) fn circuit_conflicts<'a>(candidates: &[HeatingCandidate<'a>]) -> HashSet<&'a str> {
+    let mut winners: HashMap<&str, &HeatingCandidate> = HashMap::new();
+    for candidate in candidates {
+        let Some(circuit) = candidate.circuit else {
+            continue;
+        };
+        let winner = winners.entry(circuit).or_insert(candidate);
+        let key = |c: &HeatingCandidate<'a>| (c.priority, c.first_start, c.room);
+        let order = winner
+            .heating
+            .cmp(&candidate.heating)
+            .then(priority_order(key(candidate), key(winner)));
+        if order.is_lt() {
+            *winner = candidate;
+        };
+    }
+    candidates
+        .iter()
+        .filter(|c| {
+            c.circuit
+                .is_some_and(|circuit| winners[circuit].room != c.room)
+        })
+        .map(|c| c.room)
+        .collect()
+}
+
+/// Get the rooms that have to wait for preheating, because too many rooms want to preheat.
+///
+/// Rooms already preheating keep their place, so outputs do not flap when a room of higher
+/// priority wants to preheat later. The others preheat in [`priority_order`]. A room on multiple
+/// CMIs counts once, but against the limits of all their sites.
+pub(crate) fn limit_preheating<'a>(
+    candidates: &[HeatingCandidate<'a>],
+    global_limit: Option<usize>,
+    site_limits: &HashMap<String, usize>,
+) -> HashSet<&'a str> {
+    /// A room on all its CMIs
+    struct Room<'a> {
+        priority: i32,
+        first_start: DateTime<Utc>,
+        sites: HashSet<&'a str>,
+        heating: bool,
+    }
+    let mut rooms: HashMap<&str, Room> = HashMap::new();
+    for candidate in candidates {
+        let room = rooms.entry(candidate.room).or_insert(Room {
+            priority: candidate.priority,
+            first_start: candidate.first_start,
+            sites: HashSet::new(),
+            heating: false,
+        });
+        room.first_start = room.first_start.min(candidate.first_start);
+        room.sites.extend(candidate.site);
+        room.heating |= candidate.heating;
+    }
+    let mut ordered = rooms.into_iter().collect::<Vec<_>>();
+    ordered.sort_by(|(a_name, a), (b_name, b)| {
+        b.heating.cmp(&a.heating).then(priority_order(
+            (a.priority, a.first_start, a_name),
+            (b.priority, b.first_start, b_name),
+        ))
+    });
+
+    let mut preheating = 0;
+    let mut preheating_per_site: HashMap<&str, usize> = HashMap::new();
+    let mut deferred = HashSet::new();
+    for (room, Room { sites, .. }) in ordered {
+        let site_full = sites.iter().any(|site| {
+            site_limits
+                .get(*site)
+                .is_some_and(|limit| preheating_per_site.get(site).copied().unwrap_or(0) >= *limit)
+        });
+        if site_full || global_limit.is_some_and(|limit| preheating >= limit) {
+            deferred.insert(room);
+            continue;
+        };
+        preheating += 1;
+        for site in sites {
+            *preheating_per_site.entry(site).or_default() += 1;
+        }
+    }
+    deferred
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn cold_start_grows_per_day_up_to_max() {
+        assert_eq!(cold_start_factor(0.25, 1.0, TimeDelta::zero()), 1.0);
+        assert_eq!(cold_start_factor(0.25, 1.0, TimeDelta::hours(48)), 1.5);
+        assert_eq!(cold_start_factor(0.25, 1.0, TimeDelta::days(30)), 2.0);
+    }
+
+    #[test]
+    fn one_room_per_circuit() {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-07T10:00:00+00:00")
+            .unwrap()
+            .into();
+        let candidate = |room, circuit, priority| HeatingCandidate {
+            room,
+            site: None,
+            circuit,
+            priority,
+            first_start: start,
+            heating: false,
+        };
+        let candidates = [
+            candidate("hall", Some("north"), 0),
+            candidate("stage", Some("north"), 1),
+            candidate("nave", Some("south"), 0),
+            candidate("office", None, 0),
+        ];
+        assert_eq!(circuit_conflicts(&candidates), HashSet::from(["hall"]));
+        // equal priorities are decided by the earlier booking
+        let mut early_hall = candidates[0].clone();
+        early_hall.priority = 1;
+        early_hall.first_start = start - TimeDelta::minutes(30);
+        assert_eq!(
+            circuit_conflicts(&[early_hall, candidates[1].clone()]),
+            HashSet::from(["stage"])
+        );
+        // the room heating the circuit keeps it
+        let mut heating_hall = candidates[0].clone();
+        heating_hall.heating = true;
+        assert_eq!(
+            circuit_conflicts(&[heating_hall, candidates[1].clone()]),
+            HashSet::from(["stage"])
+        );
+    }
+
+    #[test]
+    fn preheating_is_limited_by_priority() {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-07T10:00:00+00:00")
+            .unwrap()
+            .into();
+        let candidate = |room, site, priority, minutes| HeatingCandidate {
+            room,
+            site,
+            circuit: None,
+            priority,
+            first_start: start + TimeDelta::minutes(minutes),
+            heating: false,
+        };
+        let candidates = [
+            candidate("hall", Some("church"), 0, 30),
+            candidate("nave", Some("church"), 5, 60),
+            candidate("office", None, 0, 0),
+            // the same room on a second CMI
+            candidate("office", Some("church"), 0, 15),
+            candidate("kitchen", None, 0, 45),
+        ];
+        assert!(limit_preheating(&candidates, None, &HashMap::new()).is_empty());
+        // the nave has the highest priority, the office the earliest booking
+        assert_eq!(
+            limit_preheating(&candidates, Some(2), &HashMap::new()),
+            HashSet::from(["hall", "kitchen"])
+        );
+        assert_eq!(
+            limit_preheating(
+                &candidates,
+                None,
+                &HashMap::from([("church".to_owned(), 2)])
+            ),
+            HashSet::from(["hall"])
+        );
+        // rooms already preheating are not switched off for a room of higher priority
+        let mut candidates = candidates;
+        candidates[0].heating = true;
+        candidates[4].heating = true;
+        assert_eq!(
+            limit_preheating(&candidates, Some(2), &HashMap::new()),
+            HashSet::from(["nave", "office"])
+        );
+    }
+
+    #[test]
+    fn preheat_moves_into_cheap_hours() {
+        let start = DateTime::parse_from_rfc3339("2024-01-07T06:00:00+00:00")
+            .unwrap()
+            .into();
+        let cheap_until = DateTime::parse_from_rfc3339("2024-01-07T06:00:00+00:00").unwrap();
+        let price_at = |t: DateTime<Utc>| if t < cheap_until { 10.0 } else { 30.0 };
+        // the preheat of 60 minutes fits into the cheap hours if it starts an hour earlier, not more
+        assert_eq!(
+            cheapest_start(
+                start,
+                TimeDelta::minutes(60),
+                TimeDelta::minutes(90),
+                price_at
+            ),
+            start - TimeDelta::minutes(60)
+        );
+        // only partly
+        assert_eq!(
+            cheapest_start(
+                start,
+                TimeDelta::minutes(60),
+                TimeDelta::minutes(30),
+                price_at
+            ),
+            start - TimeDelta::minutes(30)
+        );
+        // a flat price does not move the start
+        assert_eq!(
+            cheapest_start(
+                start,
+                TimeDelta::minutes(60),
+                TimeDelta::minutes(90),
+                |_| 25.0
+            ),
+            start
+        );
+    }
+
+    #[test]
+    fn keeping_the_room_warm_is_costed() {
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-07T06:00:00+00:00")
+            .unwrap()
+            .into();
+        // cheap from 04:00 to 05:00 only
+        let price_at = |t: DateTime<Utc>| {
+            if start - TimeDelta::hours(2) <= t && t < start - TimeDelta::hours(1) {
+                10.0
+            } else {
+                30.0
+            }
+        };
+        // preheating in the cheap hour would keep the room warm through two expensive hours
+        assert_eq!(
+            cheapest_start(
+                start,
+                TimeDelta::minutes(60),
+                TimeDelta::minutes(120),
+                price_at
+            ),
+            start
+        );
+    }
+
+    #[test]
+    fn custom_preheat_curve() {
+        let curve = PreheatCurve::from_points(&[(0.0, 1.0), (10.0, 0.5), (30.0, 0.0)]).unwrap();
+        assert_eq!(curve.factor(-50), 1.0);
+        assert_eq!(curve.factor(50), 0.75);
+        assert_eq!(curve.factor(100), 0.5);
+        assert_eq!(curve.factor(200), 0.25);
+        assert_eq!(curve.factor(400), 0.0);
+    }
+
+    #[test]
+    fn invalid_preheat_curves() {
+        assert!(PreheatCurve::from_points(&[]).is_err());
+        assert!(PreheatCurve::from_points(&[(0.0, 1.5)]).is_err());
+        assert!(PreheatCurve::from_points(&[(10.0, 1.0), (0.0, 0.0)]).is_err());
+    }
+
+    /// Valid curves with non-increasing factors
+    fn decreasing_curve() -> impl Strategy<Value = PreheatCurve> {
+        prop::collection::vec((1_u8..50, 0.0_f64..=1.0), 1..6).prop_map(|steps| {
+            let mut temperature = -30_f64;
+            let mut factor = 1_f64;
+            let points = steps
+                .into_iter()
+                .map(|(temp_step, factor_step)| {
+                    temperature += temp_step as f64 / 2_f64;
+                    factor *= factor_step;
+                    (temperature, factor)
+                })
+                .collect::<Vec<_>>();
+            PreheatCurve::from_points(&points).expect("generated curves are valid")
+        })
+    }
+
+    #[test]
+    fn solar_gain() {
+        assert_eq!(apply_solar_gain(40, 0.5, None), 40);
+        assert_eq!(apply_solar_gain(40, 0.0, Some(800)), 40);
+        assert_eq!(apply_solar_gain(40, 0.5, Some(400)), 30);
+        assert_eq!(apply_solar_gain(40, 0.5, Some(1200)), 20);
+    }
+
+    /// Any valid curves
+    fn any_curve() -> impl Strategy<Value = PreheatCurve> {
+        prop::collection::vec((1_u8..50, 0.0_f64..=1.0), 1..6).prop_map(|steps| {
+            let mut temperature = -30_f64;
+            let points = steps
+                .into_iter()
+                .map(|(temp_step, factor)| {
+                    temperature += temp_step as f64 / 2_f64;
+                    (temperature, factor)
+                })
+                .collect::<Vec<_>>();
+            PreheatCurve::from_points(&points).expect("generated curves are valid")
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn preheat_monotonic_in_temperature(
+            curve in decreasing_curve(),
+            minutes: u8,
+            temp in -500_i32..500,
+            delta in 0_i32..500,
+        ) {
+            prop_assert!(
+                preheat_time(minutes, &curve, Some(temp)) >= preheat_time(minutes, &curve, Some(temp + delta))
+            );
+            prop_assert!(
+                preshutdown_time(minutes, &curve, Some(temp)) <= preshutdown_time(minutes, &curve, Some(temp + delta))
+            );
+        }
+
+        #[test]
+        fn default_curve_monotonic_in_temperature(
+            minutes: u8,
+            temp in -500_i32..500,
+            delta in 0_i32..500,
+        ) {
+            let curve = PreheatCurve::default();
+            prop_assert!(
+                preheat_time(minutes, &curve, Some(temp)) >= preheat_time(minutes, &curve, Some(temp + delta))
+            );
+            prop_assert!(
+                preshutdown_time(minutes, &curve, Some(temp)) <= preshutdown_time(minutes, &curve, Some(temp + delta))
+            );
+        }
+
+        #[test]
+        fn times_within_configured_minutes(
+            curve in any_curve(),
+            minutes: u8,
+            temp in proptest::option::of(any::<i32>()),
+        ) {
+            prop_assert!(preheat_time(minutes, &curve, temp) <= minutes);
+            prop_assert!(preshutdown_time(minutes, &curve, temp) <= minutes);
+        }
+
+        #[test]
+        fn solar_gain_never_increases_preheat(
+            preheat: u8,
+            sun_exposure in 0.0_f64..=1.0,
+            irradiance in proptest::option::of(-100_i32..2000),
+        ) {
+            prop_assert!(apply_solar_gain(preheat, sun_exposure, irradiance) <= preheat);
+        }
+
+        #[test]
+        fn adjusted_start_not_after_start(
+            start in 0_i64..4_000_000_000,
+            duration in 0_i64..100_000,
+            preheat: u8,
+            preshutdown: u8,
+            overrun: u8,
+        ) {
+            let start = DateTime::from_timestamp(start, 0).unwrap();
+            let end = start + TimeDelta::seconds(duration);
+            let (new_start, new_end) = adjust_booking_times(start, end, preheat, preshutdown, overrun);
+            prop_assert!(new_start <= start);
+            prop_assert!(new_start <= new_end);
+            prop_assert!(new_end <= end + TimeDelta::minutes(overrun.into()));
+        }
+    }
+}
@@ -1,26 +1,36 @@
 //! Get data from Churchtools
 
-use std::sync::Arc;
+use std::{collections::HashMap, str::FromStr, sync::Arc};
 
-use chrono::Utc;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
 use itertools::Itertools;
 use serde::Deserialize;
 use tracing::{debug, info, trace, warn};
 
 use crate::{
-    config::Config,
+    alert::{raise, resolve, Alert, AlertEvent, AlertKey, DbTask},
+    booking::InvalidBooking,
+    config::{
+        ChurchToolsConfig, Config, DeletedBookingPolicy, ImplausibleBookingPolicy,
+        OpenEndedBookingPolicy,
+    },
     db::DBError,
+    metrics::{Metrics, Task},
+    resource_hierarchy::implying_resources,
+    schedule::Schedule,
     Booking, InShutdown,
 };
 
 #[derive(Debug, Deserialize)]
 struct CTBookingsResponse {
-    data: Vec<BookingsData>,
+    /// each [BookingsData] is parsed on its own, so one broken booking does not fail the others
+    data: Vec<serde_json::Value>,
 }
 #[derive(Debug, Deserialize)]
 struct BookingsData {
     base: BookingsDataBase,
     calculated: BookingsDataCalculated,
+    meta: Option<BookingsDataMeta>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +38,11 @@ struct BookingsDataBase {
     /// this is the bookings ID
     id: i64,
     resource: ResourceData,
+    /// free text entered by the booker
+    note: Option<String>,
+    /// all other fields, including custom fields
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,16 +55,102 @@ struct ResourceData {
 struct BookingsDataCalculated {
     #[serde(rename = "startDate")]
     start_date: String,
+    /// CT omits this (or sends null) for some all-day blocks
     #[serde(rename = "endDate")]
-    end_date: String,
+    end_date: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BookingsDataMeta {
+    #[serde(rename = "modifiedDate")]
+    modified_date: Option<String>,
+}
+
+/// The response of CTs /api/resources endpoint. We only care about the hierarchy and types.
+#[derive(Debug, Deserialize)]
+struct CTResourcesResponse {
+    data: Vec<CTResource>,
+}
+#[derive(Debug, Deserialize)]
+struct CTResource {
+    id: i64,
+    #[serde(rename = "parentId")]
+    parent_id: Option<i64>,
+    #[serde(rename = "resourceTypeId")]
+    resource_type_id: Option<i64>,
+    name: Option<String>,
+}
+
+/// The response of CTs /api/info endpoint. We only care about the version.
+#[derive(Debug, Deserialize)]
+struct CTInfoResponse {
+    version: String,
+    build: Option<String>,
+}
+
+/// The version of a CT instance, as reported by /api/info
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CTVersion {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+impl CTVersion {
+    /// The oldest CT version whose bookings API is known to be compatible with our parser.
+    const MIN_COMPATIBLE: CTVersion = CTVersion {
+        major: 3,
+        minor: 0,
+        patch: 0,
+    };
+    /// The first major version we have not seen yet. Field renames usually come with a new major.
+    ///
+    /// All versions in between are parsed the same way; the version only decides whether parse
+    /// errors come with a hint that the API may have changed.
+    const FIRST_UNKNOWN_MAJOR: u32 = 4;
+
+    /// Is the bookings API of this version known to be compatible with our parser?
+    fn bookings_api_compatible(&self) -> bool {
+        *self >= Self::MIN_COMPATIBLE && self.major < Self::FIRST_UNKNOWN_MAJOR
+    }
+}
+impl FromStr for CTVersion {
+    type Err = CTApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split('.');
+        let mut next_part = || {
+            parts
+                .next()
+                .map(|p| p.parse::<u32>())
+                .transpose()
+                .map_err(|_| CTApiError::ParseVersion(s.to_owned()))
+        };
+        let major = next_part()?.ok_or(CTApiError::ParseVersion(s.to_owned()))?;
+        let minor = next_part()?.unwrap_or(0);
+        let patch = next_part()?.unwrap_or(0);
+        Ok(CTVersion {
+            major,
+            minor,
+            patch,
+        })
+    }
+}
+impl std::fmt::Display for CTVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
 }
 
 #[derive(Debug)]
 pub enum CTApiError {
     GetBookings(reqwest::Error),
-    Deserialize,
+    GetInfo(reqwest::Error),
+    GetResources(reqwest::Error),
+    ParseVersion(String),
+    Deserialize(serde_path_to_error::Error<serde_json::Error>),
     Utf8Decode,
     ParseTime(chrono::ParseError),
+    InvalidBooking(InvalidBooking),
 }
 impl std::fmt::Display for CTApiError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -57,8 +158,22 @@ impl std::fmt::Display for CTApiError {
             Self::GetBookings(e) => {
                 write!(f, "Cannot get bookings. reqwest Error: {e}")
             }
-            Self::Deserialize => {
-                write!(f, "Cannot deserialize the response.")
+            Self::GetInfo(e) => {
+                write!(f, "Cannot get CT info. reqwest Error: {e}")
+            }
+            Self::GetResources(e) => {
+                write!(f, "Cannot get resources. reqwest Error: {e}")
+            }
+            Self::ParseVersion(x) => {
+                write!(f, "Cannot parse {x} as a CT version.")
+            }
+            Self::Deserialize(e) => {
+                write!(
+                    f,
+                    "Cannot deserialize the response at `{}`. serde Error: {}",
+                    e.path(),
+                    e.inner()
+                )
             }
             Self::Utf8Decode=> {
                 write!(f, "Cannot decode the message bytes as utf-8.")
@@ -69,10 +184,18 @@ impl std::fmt::Display for CTApiError {
                     "Cannot parse a time contained in CTs response. chrono Error: {e}"
                 )
             }
+            Self::InvalidBooking(e) => {
+                write!(f, "CT returned an invalid booking. {e}")
+            }
         }
     }
 }
 impl std::error::Error for CTApiError {}
+impl From<InvalidBooking> for CTApiError {
+    fn from(value: InvalidBooking) -> Self {
+        Self::InvalidBooking(value)
+    }
+}
 
 /// Something went wrong while gathering Information from CT into the DB
 #[derive(Debug)]
@@ -100,83 +223,442 @@ impl From<CTApiError> for GatherError {
     }
 }
 
+/// The maximum number of bytes of a response body we keep for logging
+const MAX_LOGGED_BODY_LEN: usize = 1024;
+
+/// Truncate a response body to at most MAX_LOGGED_BODY_LEN bytes, respecting char boundaries
+fn truncate_body(text: &str) -> &str {
+    if text.len() <= MAX_LOGGED_BODY_LEN {
+        return text;
+    };
+    let mut end = MAX_LOGGED_BODY_LEN;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Deserialize a response from CT, keeping the path to the offending field on failure.
+///
+/// On failure, the (truncated) body is logged, since it is otherwise lost.
+fn deserialize_ct_response<'a, T: Deserialize<'a>>(text: &'a str) -> Result<T, CTApiError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(text);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        if text.trim_start().starts_with('<') {
+            warn!("CT returned something that looks like HTML. Is a reverse proxy returning an error page?");
+        };
+        debug!(
+            "The text received was ({} bytes, truncated): {}",
+            text.len(),
+            truncate_body(text)
+        );
+        CTApiError::Deserialize(e)
+    })
+}
+
+/// Get the version of the CT instance from /api/info
+async fn get_ct_version(config: &Config) -> Result<CTVersion, CTApiError> {
+    let response = reqwest::Client::new()
+        .get(format!("https://{}/api/info", config.ct.host))
+        .header("accept", "application/json")
+        .header("Authorization", format!("Login {}", config.ct.login_token))
+        .send()
+        .await
+        .map_err(CTApiError::GetInfo)?;
+    let text = response.text().await.map_err(|e| {
+        warn!("There was an error reading the response from CT as utf-8: {e}");
+        CTApiError::Utf8Decode
+    })?;
+    let info: CTInfoResponse = deserialize_ct_response(&text)?;
+    debug!("CT reports build {:?}", info.build);
+    info.version.parse()
+}
+
+/// Get the CT version and log it, warning if the bookings API may have changed.
+async fn check_ct_version(config: &Config) -> Result<CTVersion, CTApiError> {
+    let version = get_ct_version(config).await?;
+    if version.bookings_api_compatible() {
+        info!("Connected to ChurchTools version {version}.");
+    } else {
+        warn!(
+            "Connected to ChurchTools version {version}, which is not known to be compatible. Parsing bookings may fail."
+        );
+    };
+    Ok(version)
+}
+
+/// Check that CT is reachable and the login token is accepted.
+///
+/// Returns the CT version and the number of resources visible to the CT user.
+pub async fn check_reachable(config: &Config) -> Result<(CTVersion, usize), CTApiError> {
+    let version = get_ct_version(config).await?;
+    let resources = get_resources(config).await?;
+    Ok((version, resources.len()))
+}
+
+/// Get all CT resources
+async fn get_resources(config: &Config) -> Result<Vec<CTResource>, CTApiError> {
+    let response = reqwest::Client::new()
+        .get(format!("https://{}/api/resources", config.ct.host))
+        .header("accept", "application/json")
+        .header("Authorization", format!("Login {}", config.ct.login_token))
+        .send()
+        .await
+        .map_err(CTApiError::GetResources)?;
+    let text = response.text().await.map_err(|e| {
+        warn!("There was an error reading the response from CT as utf-8: {e}");
+        CTApiError::Utf8Decode
+    })?;
+    let resources: CTResourcesResponse = deserialize_ct_response(&text)?;
+    Ok(resources.data)
+}
+
+/// Get the parent of each CT resource that has one, as (resource_id, parent_id)
+async fn get_resource_parents(config: &Config) -> Result<Vec<(i64, i64)>, CTApiError> {
+    Ok(get_resources(config)
+        .await?
+        .into_iter()
+        .filter_map(|r| r.parent_id.map(|p| (r.id, p)))
+        .collect())
+}
+
+/// Get the CT resources of type `type_id`, as (resource_id, name)
+pub async fn get_resources_of_type(
+    config: &Config,
+    type_id: i64,
+) -> Result<Vec<(i64, String)>, CTApiError> {
+    Ok(get_resources(config)
+        .await?
+        .into_iter()
+        .filter(|r| r.resource_type_id == Some(type_id))
+        .map(|r| (r.id, r.name.unwrap_or_else(|| format!("resource {}", r.id))))
+        .collect())
+}
+
+/// Pull the resource hierarchy from CT into the DB
+async fn refresh_resource_hierarchy(config: &Config) -> Result<usize, GatherError> {
+    let parents = get_resource_parents(config).await?;
+    crate::db::replace_resource_parents(&config.db, &parents).await?;
+    Ok(parents.len())
+}
+
+/// The resource ids to pull bookings for, grouped by the status ids relevant for them
+///
+/// This includes the parents and children of rooms, if their bookings imply heating the room.
+fn resource_filters(config: &Config, parents: &HashMap<i64, i64>) -> Vec<(Vec<u8>, Vec<i64>)> {
+    config
+        .cmis
+        .iter()
+        .flat_map(|cmi| {
+            let status_ids = config.ct_status_ids(cmi.site.as_deref()).to_vec();
+            cmi.rooms.iter().flat_map(move |room| {
+                let status_ids = status_ids.clone();
+                implying_resources(
+                    config.resource_hierarchy.as_ref(),
+                    parents,
+                    room.churchtools_id,
+                )
+                .into_iter()
+                .map(move |id| (status_ids.clone(), id))
+            })
+        })
+        .into_group_map()
+        .into_iter()
+        .map(|(status_ids, ids)| (status_ids, ids.into_iter().unique().collect()))
+        .collect()
+}
+
+/// The last response of CT to a bookings query, to only download the bookings again if they
+/// changed
+#[derive(Debug)]
+struct CachedBookings {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    data: Vec<serde_json::Value>,
+}
+impl CachedBookings {
+    /// Cache `data` if the response has an ETag or Last-Modified header
+    fn from_response(
+        headers: &reqwest::header::HeaderMap,
+        data: &[serde_json::Value],
+    ) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        let etag = header(reqwest::header::ETAG);
+        let last_modified = header(reqwest::header::LAST_MODIFIED);
+        if etag.is_none() && last_modified.is_none() {
+            return None;
+        };
+        Some(Self {
+            etag,
+            last_modified,
+            data: data.to_vec(),
+        })
+    }
+
+    /// The headers asking CT to only send the bookings if they changed
+    fn conditional_headers(&self) -> Vec<(reqwest::header::HeaderName, &str)> {
+        let mut headers = vec![];
+        if let Some(etag) = &self.etag {
+            headers.push((reqwest::header::IF_NONE_MATCH, etag.as_str()));
+        };
+        if let Some(last_modified) = &self.last_modified {
+            headers.push((reqwest::header::IF_MODIFIED_SINCE, last_modified.as_str()));
+        };
+        headers
+    }
+}
+
+/// The last responses of CT by query, if CT supports conditional requests
+type BookingsCache = HashMap<Vec<(&'static str, String)>, CachedBookings>;
+
 async fn get_relevant_bookings(
     config: &Config,
+    resource_ids: &[i64],
+    status_ids: &[u8],
     start_date: chrono::NaiveDate,
     end_date: chrono::NaiveDate,
-) -> Result<Vec<Booking>, CTApiError> {
-    let mut query_strings = config
-        .cmis
+    ct_version: Option<CTVersion>,
+    cache: &mut BookingsCache,
+) -> Result<RelevantBookings, CTApiError> {
+    let mut query_strings = resource_ids
         .iter()
-        .flat_map(|cmi| &cmi.rooms)
-        .map(|room_config| room_config.churchtools_id)
-        .unique()
-        // we now have the resource ids we care about
-        // convert them to the query parameters we need
         .map(|id| ("resource_ids[]", format!("{id}")))
         .collect::<Vec<_>>();
     query_strings.push(("from", start_date.to_string()));
     query_strings.push(("to", end_date.to_string()));
-    query_strings.push(("status_ids[]", "2".to_owned()));
-    // TODO: add login token to request
-    let response = match reqwest::Client::new()
+    query_strings.extend(
+        status_ids
+            .iter()
+            .map(|id| ("status_ids[]", format!("{id}"))),
+    );
+    let mut request = reqwest::Client::new()
         .get(format!("https://{}/api/bookings", config.ct.host))
         .query(&query_strings)
         .header("accept", "application/json")
-        .header("Authorization", format!("Login {}", config.ct.login_token))
-        .send()
-        .await {
-            Ok(x) => {
-                let text_res = x.text().await;
-                match text_res {
-                    Ok(text) => {
-                        let deser_res: Result<CTBookingsResponse, _> = serde_json::from_str(&text);
-                        if let Ok(y) = deser_res {
-                            y
-                        } else {
-                            warn!("There was an error parsing the return value from CT.");
-                            warn!("The complete text received was: {text}");
-                            return Err(CTApiError::Deserialize);
-                        }
-                    }
-                    Err(e) => {
-                        warn!("There was an error reading the response from CT as utf-8: {e}");
-                        return Err(CTApiError::Utf8Decode);
-                    }
-                }
-            }
+        .header("Authorization", format!("Login {}", config.ct.login_token));
+    if let Some(cached) = cache.get(&query_strings) {
+        for (name, value) in cached.conditional_headers() {
+            request = request.header(name, value);
+        }
+    };
+    let response = match request.send().await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("There was a problem getting a response from CT");
+            return Err(CTApiError::GetBookings(e));
+        }
+    };
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        if let Some(cached) = cache.get(&query_strings) {
+            trace!("The bookings in CT did not change since the last pull.");
+            return Ok(parse_bookings(&config.ct, cached.data.clone()));
+        };
+    };
+    let headers = response.headers().clone();
+    let text = match response.text().await {
+        Ok(text) => text,
+        Err(e) => {
+            warn!("There was an error reading the response from CT as utf-8: {e}");
+            return Err(CTApiError::Utf8Decode);
+        }
+    };
+    let response: CTBookingsResponse = match deserialize_ct_response(&text) {
+        Ok(y) => y,
+        Err(e) => {
+            warn!("There was an error parsing the return value from CT.");
+            if let Some(v) = ct_version.filter(|v| !v.bookings_api_compatible()) {
+                warn!("CT version {v} is not known to be compatible. Did the bookings API change?");
+            };
+            return Err(e);
+        }
+    };
+    // CT does not support conditional requests for every query, so fall back to full pulls
+    match CachedBookings::from_response(&headers, &response.data) {
+        Some(cached) => {
+            cache.insert(query_strings, cached);
+        }
+        None => {
+            cache.remove(&query_strings);
+        }
+    };
+    Ok(parse_bookings(&config.ct, response.data))
+}
+
+/// The bookings CT returned
+#[derive(Debug, Default)]
+struct RelevantBookings {
+    bookings: Vec<Booking>,
+    /// IDs of the bookings that could not be parsed. They must not be deleted from the DB.
+    broken: Vec<i64>,
+}
+
+/// Parse each booking on its own, skipping those that are broken or do not require heating
+fn parse_bookings(ct: &ChurchToolsConfig, data: Vec<serde_json::Value>) -> RelevantBookings {
+    let mut res = RelevantBookings::default();
+    for value in data {
+        let id = value.pointer("/base/id").and_then(serde_json::Value::as_i64);
+        let parsed = serde_path_to_error::deserialize::<_, BookingsData>(&value)
+            .map_err(CTApiError::Deserialize)
+            .and_then(|x| {
+                if opts_out_of_heating(ct, &x.base) {
+                    debug!("Booking {} does not require heating. Skipping it.", x.base.id);
+                    return Ok(None);
+                };
+                booking_from_ct(ct, x)
+            });
+        match parsed {
+            Ok(Some(booking)) => res.bookings.push(booking),
+            Ok(None) => (),
             Err(e) => {
-                warn!("There was a problem getting a response from CT");
-                return Err(CTApiError::GetBookings(e));
+                match id {
+                    Some(id) => warn!("Skipping booking {id}, which cannot be parsed: {e}"),
+                    None => warn!("Skipping a booking without an ID, which cannot be parsed: {e}"),
+                };
+                res.broken.extend(id);
             }
         };
-    response
-        .data
-        .into_iter()
-        .map(|x: BookingsData| {
-            Ok::<Booking, CTApiError>(Booking {
-                booking_id: x.base.id,
-                resource_id: x.base.resource.id,
-                start_time: chrono::DateTime::parse_from_rfc3339(&x.calculated.start_date)
-                    .map_err(CTApiError::ParseTime)?
-                    // we get the date from CT with an unknown offset, and need to cast to UTC
-                    // (actually, CT seems to always return UTC, but this is not part of a stably documented API)
-                    .into(),
-                end_time: chrono::DateTime::parse_from_rfc3339(&x.calculated.end_date)
-                    .map_err(CTApiError::ParseTime)?
-                    .into(),
+    }
+    res
+}
+
+/// Convert a booking from CT. Returns None for open-ended bookings we ignore.
+fn booking_from_ct(
+    ct: &ChurchToolsConfig,
+    x: BookingsData,
+) -> Result<Option<Booking>, CTApiError> {
+    let requested_temperature = match x.base.note.as_deref().and_then(parse_requested_temperature) {
+        Some(Ok(t)) => Some(t),
+        Some(Err(e)) => {
+            warn!("Ignoring the temperature requested in booking {}: {e}", x.base.id);
+            None
+        }
+        None => None,
+    };
+    let start_time: DateTime<Utc> = chrono::DateTime::parse_from_rfc3339(&x.calculated.start_date)
+        .map_err(CTApiError::ParseTime)?
+        // we get the date from CT with an unknown offset, and need to cast to UTC
+        // (actually, CT seems to always return UTC, but this is not part of a stably documented API)
+        .into();
+    let end_time = match x.calculated.end_date {
+        Some(end) => chrono::DateTime::parse_from_rfc3339(&end)
+            .map_err(CTApiError::ParseTime)?
+            .into(),
+        None => {
+            let local_start = start_time.with_timezone(&chrono::Local);
+            match end_of_open_ended_booking(ct, local_start) {
+                Some(end) => {
+                    debug!("Booking {} has no end. Assuming it ends at {end}.", x.base.id);
+                    end
+                }
+                None => {
+                    debug!("Booking {} has no end. Ignoring it.", x.base.id);
+                    return Ok(None);
+                }
+            }
+        }
+    };
+    // only used to detect changes, so a broken one must not drop the booking
+    let modified_at = x.meta.and_then(|m| m.modified_date).and_then(|d| {
+        chrono::DateTime::parse_from_rfc3339(&d)
+            .inspect_err(|e| {
+                warn!(
+                    "Ignoring the modification date {d} of booking {}: {e}",
+                    x.base.id
+                );
             })
-        })
-        .collect::<Result<Vec<_>, _>>()
+            .ok()
+    });
+    Ok(Some(
+        Booking::new(x.base.id, x.base.resource.id, start_time, end_time)?
+            .with_modified_at(modified_at.map(Into::into))
+            .with_requested_temperature(requested_temperature),
+    ))
 }
 
-async fn get_bookings_into_db(config: Arc<Config>) -> Result<(), GatherError> {
-    let start = Utc::now().naive_utc().into();
+/// The end assumed for a booking starting at `start` without an end, according to the policy
+fn end_of_open_ended_booking<Tz: chrono::TimeZone>(
+    ct: &ChurchToolsConfig,
+    start: DateTime<Tz>,
+) -> Option<DateTime<Utc>> {
+    match ct.open_ended_bookings {
+        OpenEndedBookingPolicy::AllDay => start
+            .date_naive()
+            .succ_opt()?
+            .and_time(chrono::NaiveTime::MIN)
+            .and_local_timezone(start.timezone())
+            .earliest()
+            .map(|x| x.to_utc()),
+        OpenEndedBookingPolicy::Ignore => None,
+        OpenEndedBookingPolicy::Cap => Some(
+            start.to_utc() + TimeDelta::hours(ct.open_ended_booking_hours.unwrap_or(4) as i64),
+        ),
+    }
+}
+
+/// Sync the bookings of today and tomorrow from CT into the DB.
+///
+/// Returns the number of bookings in CT.
+async fn get_bookings_into_db(
+    config: Arc<Config>,
+    ct_version: Option<CTVersion>,
+    cache: &mut BookingsCache,
+) -> Result<Pulled, GatherError> {
+    let start: chrono::NaiveDate = Utc::now().naive_utc().into();
     let end = start + chrono::TimeDelta::days(1);
+    // the responses of past days are of no use anymore
+    cache.retain(|query, _| query.contains(&("from", start.to_string())));
     // get bookings from CT
-    let bookings_from_ct = get_relevant_bookings(&config, start, end).await?;
+    // sites may use different status ids, so query each filter on its own
+    let parents = if config.resource_hierarchy.is_some() {
+        crate::db::get_resource_parents(&config.db).await?
+    } else {
+        HashMap::new()
+    };
+    let mut bookings_from_ct = vec![];
+    let mut broken = vec![];
+    for (status_ids, resource_ids) in resource_filters(&config, &parents) {
+        let relevant = get_relevant_bookings(
+            &config,
+            &resource_ids,
+            &status_ids,
+            start,
+            end,
+            ct_version,
+            cache,
+        )
+        .await?;
+        bookings_from_ct.extend(relevant.bookings);
+        broken.extend(relevant.broken);
+    }
+    // a resource may be in multiple filters
+    let (implausible, mut bookings_from_ct): (Vec<_>, Vec<_>) = bookings_from_ct
+        .into_iter()
+        .unique_by(|b| b.booking_id())
+        .partition(|b| is_implausible(&config.ct, b, Utc::now()));
+    for b in &implausible {
+        warn!(
+            "Booking {} of {} from {} to {} has implausible dates. Is there a typo in CT?{}",
+            b.booking_id(),
+            config.resource_label(b.resource_id()),
+            b.start_time(),
+            b.end_time(),
+            match config.ct.implausible_bookings {
+                ImplausibleBookingPolicy::Drop => " Not heating for it.",
+                ImplausibleBookingPolicy::Warn => "",
+            }
+        );
+    }
+    let implausible_count = implausible.len();
+    if config.ct.implausible_bookings == ImplausibleBookingPolicy::Warn {
+        bookings_from_ct.extend(implausible);
+    };
     // get bookings from db
-    let bookings_from_db = crate::db::get_bookings_in_timeframe(
+    let bookings_from_db = crate::db::get_ct_bookings_in_timeframe(
         &config.db,
         start.and_time(chrono::NaiveTime::from_hms_opt(0, 0, 0).expect("statically good time")),
         end.and_time(chrono::NaiveTime::from_hms_opt(23, 59, 59).expect("statically good time")),
@@ -190,52 +672,419 @@ async fn get_bookings_into_db(config: Arc<Config>) -> Result<(), GatherError> {
     let new_bookings = bookings_from_ct.iter().filter(|b| {
         !bookings_from_db
             .iter()
-            .any(|x| x.booking_id == b.booking_id)
+            .any(|x| x.booking_id() == b.booking_id())
     });
     trace!(
         "Adding these bookings: {:?}",
         new_bookings.clone().collect::<Vec<_>>()
     );
-    crate::db::insert_bookings(&config.db, new_bookings).await?;
+    for b in new_bookings.clone() {
+        info!(
+            "Inserting new booking of {}: {b:?}",
+            config.resource_label(b.resource_id())
+        );
+    }
 
     // remove bookings no longer present in ct
-    let deprecated_bookings = bookings_from_db
-        .iter()
-        .map(|b| b.booking_id)
-        .filter(|&id| !bookings_from_ct.iter().any(|x| x.booking_id == id));
-    crate::db::delete_bookings(&config.db, deprecated_bookings).await?;
+    // bookings in progress may be kept for a while, depending on the config
+    // bookings we could not parse are still in CT, so they are kept as they are
+    let now = Utc::now();
+    let mut deprecated_bookings = vec![];
+    let mut shortened_bookings = vec![];
+    for b in bookings_from_db.iter().filter(|b| {
+        !bookings_from_ct
+            .iter()
+            .any(|x| x.booking_id() == b.booking_id())
+            && !broken.contains(&b.booking_id())
+    }) {
+        match end_of_deleted_booking(config.global.deleted_booking_in_progress, b, now) {
+            None => deprecated_bookings.push(b.booking_id()),
+            Some(end) if end == b.end_time() => {
+                debug!(
+                    "Booking {} of {} was deleted in CT while in progress. Keeping it until {end}.",
+                    b.booking_id(),
+                    config.resource_label(b.resource_id())
+                );
+            }
+            Some(end) => {
+                info!(
+                    "Booking {} of {} was deleted in CT while in progress. Keeping it until {end}.",
+                    b.booking_id(),
+                    config.resource_label(b.resource_id())
+                );
+                // the booking is in progress, so it still ends after its start
+                match Booking::new(b.booking_id(), b.resource_id(), b.start_time(), end) {
+                    Ok(x) => shortened_bookings.push(
+                        x.with_modified_at(b.modified_at())
+                            .with_requested_temperature(b.requested_temperature()),
+                    ),
+                    Err(_) => deprecated_bookings.push(b.booking_id()),
+                };
+            }
+        };
+    }
 
-    // Update bookings that have changed times in CT
+    // Update bookings that have changed in CT
     let changed_bookings = bookings_from_ct.iter().filter(|b| {
         bookings_from_db
             .iter()
-            .any(|x| x.booking_id == b.booking_id && x != *b)
+            .any(|x| x.booking_id() == b.booking_id() && booking_changed(x, b))
     });
-    crate::db::update_bookings(&config.db, changed_bookings).await?;
-    Ok(())
+    for b in changed_bookings.clone() {
+        info!(
+            "Updating booking {} of {}. Is now: {b:?}",
+            b.booking_id(),
+            config.resource_label(b.resource_id())
+        );
+    }
+    for b in new_bookings.clone().chain(changed_bookings.clone()) {
+        let end = crate::push_to_ta::capped_end(&config, b);
+        if end < b.end_time() {
+            warn!(
+                "Booking {} of {} lasts until {}. Heating for it stops at {end} (max_heating_hours). Is there a typo in CT?",
+                b.booking_id(),
+                config.resource_label(b.resource_id()),
+                b.end_time()
+            );
+        };
+    }
+    let upserts = new_bookings
+        .chain(&shortened_bookings)
+        .chain(changed_bookings)
+        .collect::<Vec<_>>();
+    // all at once, so an interrupted or overlapping pull cannot leave the DB half-synced
+    crate::db::sync_bookings(&config.db, upserts, deprecated_bookings).await?;
+    Ok(Pulled {
+        bookings: bookings_from_ct.len(),
+        implausible: implausible_count,
+    })
+}
+
+/// What a pull found in CT
+#[derive(Debug, Clone, Copy)]
+struct Pulled {
+    /// bookings to heat for
+    bookings: usize,
+    /// bookings with implausible dates, whether they are heated for or not
+    implausible: usize,
+}
+
+/// Whether `booking` starts or ends so far from `now` that its dates are probably a typo
+fn is_implausible(ct: &ChurchToolsConfig, booking: &Booking, now: DateTime<Utc>) -> bool {
+    let plausible = TimeDelta::days(ct.plausible_days.unwrap_or(366).into());
+    let range = now - plausible..=now + plausible;
+    !range.contains(&booking.start_time()) || !range.contains(&booking.end_time())
+}
+
+/// Did the booker mark this booking as not requiring heating?
+fn opts_out_of_heating(ct: &ChurchToolsConfig, base: &BookingsDataBase) -> bool {
+    let keyword_in_note = ct
+        .no_heating_keyword
+        .as_ref()
+        .zip(base.note.as_ref())
+        .is_some_and(|(keyword, note)| note.to_lowercase().contains(&keyword.to_lowercase()));
+    let field_set = ct
+        .no_heating_field
+        .as_ref()
+        .and_then(|field| base.other.get(field))
+        .is_some_and(|value| match value {
+            serde_json::Value::Bool(x) => *x,
+            serde_json::Value::Number(x) => x.as_f64().is_some_and(|x| x != 0.0),
+            serde_json::Value::String(x) => {
+                ["1", "true", "yes", "ja"].contains(&x.trim().to_lowercase().as_str())
+            }
+            _ => false,
+        });
+    keyword_in_note || field_set
+}
+
+/// Marks a setpoint request in a booking note, e.g. `#temp:21`
+const TEMPERATURE_REQUEST_TAG: &str = "#temp:";
+
+/// Parse a setpoint request from a booking note.
+///
+/// Returns None if the note contains no request, and the temperature in tenths of a Degree
+/// Centigrade otherwise. Both `20.5` and `20,5` are accepted.
+fn parse_requested_temperature(note: &str) -> Option<Result<i32, String>> {
+    let (start, _) = note.match_indices('#').find(|(i, _)| {
+        note.get(*i..*i + TEMPERATURE_REQUEST_TAG.len())
+            .is_some_and(|x| x.eq_ignore_ascii_case(TEMPERATURE_REQUEST_TAG))
+    })?;
+    let value = note[start + TEMPERATURE_REQUEST_TAG.len()..]
+        .split_whitespace()
+        .next()
+        .unwrap_or("");
+    let parsed = value
+        .trim_end_matches([',', ';', '.'])
+        .replace(',', ".")
+        .parse::<f64>()
+        .ok()
+        // rooms clamp the value later, this only catches typos
+        .filter(|x| (-50_f64..=100_f64).contains(x));
+    Some(match parsed {
+        Some(x) => Ok((x * 10_f64).round() as i32),
+        None => Err(format!("{value:?} is not a temperature in Degree Centigrade")),
+    })
+}
+
+/// Until when to keep a booking that was deleted in CT.
+///
+/// Returns None if the booking should be deleted now.
+fn end_of_deleted_booking(
+    policy: DeletedBookingPolicy,
+    booking: &Booking,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    if now < booking.start_time() || booking.end_time() <= now {
+        return None;
+    };
+    match policy {
+        DeletedBookingPolicy::TurnOff => None,
+        DeletedBookingPolicy::FinishHour => {
+            let next_hour = now.duration_trunc(TimeDelta::hours(1)).ok()? + TimeDelta::hours(1);
+            Some(next_hour.min(booking.end_time()))
+        }
+        DeletedBookingPolicy::KeepUntilEnd => Some(booking.end_time()),
+    }
+}
+
+/// Has the booking changed in CT since we stored it?
+///
+/// If both sides know when the booking was last modified, that is compared instead of resource and
+/// times. Fields we derive from the booking are always compared: rows stored before we derived them
+/// do not have them, although CT did not modify the booking since.
+fn booking_changed(in_db: &Booking, in_ct: &Booking) -> bool {
+    let derived_changed = in_db.requested_temperature() != in_ct.requested_temperature();
+    derived_changed
+        || match (in_db.modified_at(), in_ct.modified_at()) {
+            (Some(db_modified), Some(ct_modified)) => db_modified != ct_modified,
+            _ => {
+                in_db.resource_id() != in_ct.resource_id()
+                    || in_db.start_time() != in_ct.start_time()
+                    || in_db.end_time() != in_ct.end_time()
+            }
+        }
+}
+
+/// Find all pairs of bookings for the same resource which overlap in time
+fn overlapping_bookings(bookings: &[Booking]) -> Vec<(&Booking, &Booking)> {
+    bookings
+        .iter()
+        .tuple_combinations()
+        .filter(|(a, b)| {
+            a.resource_id() == b.resource_id()
+                && a.start_time() < b.end_time()
+                && b.start_time() < a.end_time()
+        })
+        .collect()
+}
+
+/// Log all overlapping bookings currently in the db.
+///
+/// Overlapping bookings for the same resource usually mean that a booking was approved by mistake
+/// in CT. Heating cannot distinguish them, so we only report them.
+async fn report_overlapping_bookings(config: &Config) -> Result<usize, DBError> {
+    let bookings = crate::db::get_all_bookings(&config.db).await?;
+    let overlaps = overlapping_bookings(&bookings);
+    for (a, b) in &overlaps {
+        warn!(
+            "Bookings {} ({} - {}) and {} ({} - {}) of {} overlap. Is one of them a mistake?",
+            a.booking_id(),
+            a.start_time(),
+            a.end_time(),
+            b.booking_id(),
+            b.start_time(),
+            b.end_time(),
+            config.resource_label(a.resource_id())
+        );
+    }
+    Ok(overlaps.len())
+}
+
+/// Bookings are backfilled in chunks of this many days, to keep the responses of CT small
+const BACKFILL_CHUNK_DAYS: i64 = 28;
+
+/// Import the past bookings from `from` to `until` (inclusive) into the archive.
+///
+/// Archived bookings are only used for reports. Returns the number of bookings archived.
+pub async fn backfill(
+    config: &Config,
+    from: chrono::NaiveDate,
+    until: chrono::NaiveDate,
+) -> Result<usize, GatherError> {
+    let ct_version = check_ct_version(config).await.ok();
+    let parents = if config.resource_hierarchy.is_some() {
+        refresh_resource_hierarchy(config).await?;
+        crate::db::get_resource_parents(&config.db).await?
+    } else {
+        HashMap::new()
+    };
+    let now = Utc::now();
+    let mut archived = std::collections::HashSet::new();
+    let mut chunk_start = from;
+    while chunk_start <= until {
+        let chunk_end = (chunk_start + TimeDelta::days(BACKFILL_CHUNK_DAYS - 1)).min(until);
+        let mut bookings = vec![];
+        for (status_ids, resource_ids) in resource_filters(config, &parents) {
+            bookings.extend(
+                get_relevant_bookings(
+                    config,
+                    &resource_ids,
+                    &status_ids,
+                    chunk_start,
+                    chunk_end,
+                    ct_version,
+                    &mut BookingsCache::new(),
+                )
+                .await?
+                .bookings,
+            );
+        }
+        // only bookings that are over are history
+        let bookings = bookings
+            .into_iter()
+            .unique_by(|b| b.booking_id())
+            .filter(|b| b.end_time() <= now)
+            .collect::<Vec<_>>();
+        crate::db::archive_bookings(&config.db, &bookings).await?;
+        info!("Archived {} bookings from {chunk_start} to {chunk_end}.", bookings.len());
+        archived.extend(bookings.iter().map(|b| b.booking_id()));
+        chunk_start = chunk_end + TimeDelta::days(1);
+    }
+    Ok(archived.len())
+}
+
+/// Pull the bookings from CT once and prune old ones, for `--once`.
+///
+/// Returns the number of bookings pulled.
+pub async fn pull_once(config: Arc<Config>) -> Result<usize, GatherError> {
+    let ct_version = check_ct_version(&config)
+        .await
+        .inspect_err(|e| warn!("Failed to get the CT version. Error encountered: {e}"))
+        .ok();
+    if config.resource_hierarchy.is_some() {
+        let parents = refresh_resource_hierarchy(&config).await?;
+        debug!("Got {parents} resources with a parent from CT.");
+    };
+    let pulled = get_bookings_into_db(config.clone(), ct_version, &mut BookingsCache::new())
+        .await?
+        .bookings;
+    let pruned = crate::db::prune_old_bookings(&config.db, config.max_overrun()).await?;
+    debug!("Successfully pruned db. Removed {pruned} old bookings.");
+    Ok(pulled)
 }
 
 pub async fn keep_db_up_to_date(
     config: Arc<Config>,
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    alerts: tokio::sync::mpsc::Sender<AlertEvent>,
+    metrics: Arc<Metrics>,
+    ready: tokio::sync::watch::Sender<bool>,
 ) {
     info!("Starting CT -> DB Sync task");
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
-        config.global.ct_pull_frequency,
-    ));
-    interval.tick().await;
+    let mut schedule = Schedule::new(
+        tokio::time::Duration::from_secs(config.global.ct_pull_frequency),
+        config.global.ct_pull_schedule.as_deref(),
+        None,
+    );
+    schedule.tick().await;
+    // the CT version is checked at startup and then once a day
+    let mut ct_version = None;
+    let mut last_version_check: Option<DateTime<Utc>> = None;
+    let mut last_overlap_report: Option<DateTime<Utc>> = None;
+    let mut failures_in_a_row = 0_u32;
+    let mut db_alert_raised = false;
+    // to only download bookings again if they changed
+    let mut cache = BookingsCache::new();
     loop {
         debug!("Gatherer starting new run.");
+        let version_check_due = match last_version_check {
+            Some(t) => Utc::now() - t >= chrono::TimeDelta::days(1),
+            None => true,
+        };
+        if version_check_due {
+            match check_ct_version(&config).await {
+                Ok(v) => {
+                    ct_version = Some(v);
+                    last_version_check = Some(Utc::now());
+                }
+                Err(e) => {
+                    warn!("Failed to get the CT version. Error encountered: {e}");
+                }
+            };
+            // the hierarchy changes about as rarely as the version
+            if config.resource_hierarchy.is_some() {
+                match refresh_resource_hierarchy(&config).await {
+                    Ok(x) => debug!("Got {x} resources with a parent from CT."),
+                    Err(e) => {
+                        warn!("Failed to get the resource hierarchy from CT. Error encountered: {e}")
+                    }
+                };
+            };
+        };
         // get new data
-        let ct_to_db_res = get_bookings_into_db(config.clone()).await;
+        let pull_start = std::time::Instant::now();
+        let ct_to_db_res = get_bookings_into_db(config.clone(), ct_version, &mut cache).await;
+        let mut db_failed = false;
+        if let Ok(pulled) = &ct_to_db_res {
+            metrics.set_implausible_bookings(pulled.implausible);
+        };
+        metrics
+            .record(
+                &config,
+                Task::Pull,
+                pull_start.elapsed(),
+                ct_to_db_res.as_ref().map(|x| x.bookings).unwrap_or(0),
+                ct_to_db_res.is_ok(),
+            )
+            .await;
         match ct_to_db_res {
-            Ok(()) => debug!("Successfully updated db."),
+            Ok(_) => {
+                debug!("Successfully updated db.");
+                // the emitter may now trust the bookings in the db
+                ready.send_replace(true);
+                if failures_in_a_row != 0 {
+                    resolve(&alerts, AlertKey::CtPull);
+                };
+                failures_in_a_row = 0;
+            }
+            Err(GatherError::DB(e)) => {
+                warn!("Failed to update db from CT. Error encountered: {e}");
+                raise(
+                    &alerts,
+                    Alert::Db {
+                        task: DbTask::Pull,
+                        error: e.to_string(),
+                    },
+                );
+                db_failed = true;
+            }
             Err(e) => {
                 warn!("Failed to update db from CT. Error encountered: {e}");
+                failures_in_a_row += 1;
+                if config
+                    .alerting
+                    .as_ref()
+                    .is_some_and(|x| x.ct_failures == failures_in_a_row)
+                {
+                    raise(
+                        &alerts,
+                        Alert::CtPullFailing {
+                            failures: failures_in_a_row,
+                            error: e.to_string(),
+                        },
+                    );
+                };
             }
         };
         // prune old entries in db
-        let db_prune_res = crate::db::prune_old_bookings(&config.db).await;
+        let retention =
+            chrono::TimeDelta::days(config.global.metrics_retention.unwrap_or(28) as i64);
+        match crate::db::prune_old_cycle_stats(&config.db, retention).await {
+            Ok(x) => debug!("Pruned {x} old cycle stats."),
+            Err(e) => warn!("Failed to prune old cycle stats. Error encountered: {e}"),
+        };
+        let db_prune_res = crate::db::prune_old_bookings(&config.db, config.max_overrun()).await;
         match db_prune_res {
             Ok(x) => match x {
                 0 => debug!("Successfully pruned db. Removed {x} old bookings."),
@@ -243,15 +1092,321 @@ pub async fn keep_db_up_to_date(
             },
             Err(e) => {
                 warn!("Failed to prune db. Error encountered: {e}");
+                raise(
+                    &alerts,
+                    Alert::Db {
+                        task: DbTask::Pull,
+                        error: e.to_string(),
+                    },
+                );
+                db_failed = true;
             }
         };
+        if db_failed {
+            db_alert_raised = true;
+        } else if db_alert_raised {
+            resolve(&alerts, AlertKey::Db(DbTask::Pull));
+            db_alert_raised = false;
+        };
+        // report overlapping bookings, if enabled
+        if let Some(frequency) = config.global.overlap_report_frequency {
+            let overlap_report_due = match last_overlap_report {
+                Some(t) => Utc::now() - t >= chrono::TimeDelta::minutes(frequency as i64),
+                None => true,
+            };
+            if overlap_report_due {
+                match report_overlapping_bookings(&config).await {
+                    Ok(0) => debug!("Found no overlapping bookings."),
+                    Ok(x) => info!("Found {x} pairs of overlapping bookings."),
+                    Err(e) => warn!("Failed to report overlapping bookings. Error encountered: {e}"),
+                };
+                last_overlap_report = Some(Utc::now());
+            };
+        };
         // stop on cancellation or continue after the next tick
         tokio::select! {
             _ = watcher.changed() => {
                 debug!("Shutting down data gatherer now.");
                 return;
             }
-            _ = interval.tick() => {}
+            _ = schedule.tick() => {}
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::test_ct_config;
+
+    #[test]
+    fn parse_ct_version() {
+        let version: CTVersion = "3.105.2".parse().unwrap();
+        assert_eq!(
+            version,
+            CTVersion {
+                major: 3,
+                minor: 105,
+                patch: 2
+            }
+        );
+        assert!(version.bookings_api_compatible());
+    }
+
+    #[test]
+    fn parse_ct_version_incompatible() {
+        let version: CTVersion = "4.0".parse().unwrap();
+        assert!(!version.bookings_api_compatible());
+        assert!("not-a-version".parse::<CTVersion>().is_err());
+    }
+
+    #[test]
+    fn deserialize_error_contains_path() {
+        let text = r#"{"data": [{"id": 1, "parentId": "x"}]}"#;
+        let res: Result<CTResourcesResponse, _> = deserialize_ct_response(text);
+        let Err(CTApiError::Deserialize(e)) = res else {
+            panic!("expected a deserialize error");
+        };
+        assert_eq!(e.path().to_string(), "data[0].parentId");
+    }
+
+    fn booking(booking_id: i64, resource_id: i64, start: &str, end: &str) -> Booking {
+        Booking::new(
+            booking_id,
+            resource_id,
+            DateTime::parse_from_rfc3339(start).unwrap().into(),
+            DateTime::parse_from_rfc3339(end).unwrap().into(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn find_overlapping_bookings() {
+        let bookings = vec![
+            booking(1, 10, "2021-03-26T15:00:00+00:00", "2021-03-26T17:00:00+00:00"),
+            // overlaps with 1
+            booking(2, 10, "2021-03-26T16:00:00+00:00", "2021-03-26T18:00:00+00:00"),
+            // directly after 2 - no overlap
+            booking(3, 10, "2021-03-26T18:00:00+00:00", "2021-03-26T19:00:00+00:00"),
+            // same time as 1, but other resource
+            booking(4, 11, "2021-03-26T15:00:00+00:00", "2021-03-26T17:00:00+00:00"),
+        ];
+        let overlaps = overlapping_bookings(&bookings);
+        assert_eq!(overlaps, vec![(&bookings[0], &bookings[1])]);
+    }
+
+    #[test]
+    fn changes_are_detected_by_modified_date() {
+        let stored = booking(1, 10, "2021-03-26T15:00:00+00:00", "2021-03-26T17:00:00+00:00");
+        let from_ct = booking(1, 10, "2021-03-26T15:00:00+00:00", "2021-03-26T18:00:00+00:00");
+        // without a modification date, the times are compared
+        assert!(booking_changed(&stored, &from_ct));
+        let modified = DateTime::parse_from_rfc3339("2021-03-20T10:00:00+00:00").unwrap();
+        let stored = stored.with_modified_at(Some(modified.into()));
+        let from_ct = from_ct.with_modified_at(Some(modified.into()));
+        assert!(!booking_changed(&stored, &from_ct));
+        let from_ct =
+            from_ct.with_modified_at(Some((modified + chrono::TimeDelta::minutes(1)).into()));
+        assert!(booking_changed(&stored, &from_ct));
+        // stored before the requested temperature was derived
+        let from_ct = from_ct
+            .with_modified_at(Some(modified.into()))
+            .with_requested_temperature(Some(180));
+        assert!(booking_changed(&stored, &from_ct));
+    }
+
+    #[test]
+    fn deleted_bookings_in_progress() {
+        let b = booking(1, 10, "2021-03-26T15:00:00+00:00", "2021-03-26T18:30:00+00:00");
+        let now: DateTime<Utc> = DateTime::parse_from_rfc3339("2021-03-26T16:20:00+00:00")
+            .unwrap()
+            .into();
+        assert_eq!(
+            end_of_deleted_booking(DeletedBookingPolicy::TurnOff, &b, now),
+            None
+        );
+        assert_eq!(
+            end_of_deleted_booking(DeletedBookingPolicy::FinishHour, &b, now),
+            Some(DateTime::parse_from_rfc3339("2021-03-26T17:00:00+00:00").unwrap().into())
+        );
+        assert_eq!(
+            end_of_deleted_booking(DeletedBookingPolicy::KeepUntilEnd, &b, now),
+            Some(b.end_time())
+        );
+        // the hour is not finished past the original end
+        let late = now + TimeDelta::hours(2);
+        assert_eq!(
+            end_of_deleted_booking(DeletedBookingPolicy::FinishHour, &b, late),
+            Some(b.end_time())
+        );
+        // bookings not in progress are always deleted
+        let before = now - TimeDelta::hours(2);
+        assert_eq!(
+            end_of_deleted_booking(DeletedBookingPolicy::KeepUntilEnd, &b, before),
+            None
+        );
+    }
+
+    #[test]
+    fn implausible_dates() {
+        let mut ct = test_ct_config();
+        let now = DateTime::parse_from_rfc3339("2025-01-12T10:00:00Z")
+            .unwrap()
+            .into();
+        let sunday = booking(1, 2, "2025-01-12T09:00:00Z", "2025-01-12T12:00:00Z");
+        assert!(!is_implausible(&ct, &sunday, now));
+        // longer bookings cannot be built at all
+        let epoch = booking(1, 2, "1970-01-01T00:00:00Z", "1970-01-01T03:00:00Z");
+        assert!(is_implausible(&ct, &epoch, now));
+        let typo = booking(1, 2, "2099-01-12T09:00:00Z", "2099-01-12T12:00:00Z");
+        assert!(is_implausible(&ct, &typo, now));
+        let next_year = booking(1, 2, "2026-01-11T09:00:00Z", "2026-01-11T12:00:00Z");
+        assert!(!is_implausible(&ct, &next_year, now));
+        ct.plausible_days = Some(30);
+        assert!(is_implausible(&ct, &next_year, now));
+    }
+
+    #[test]
+    fn bookings_can_opt_out_of_heating() {
+        let ct = ChurchToolsConfig {
+            no_heating_keyword: Some("No Heating".to_owned()),
+            no_heating_field: Some("noHeating".to_owned()),
+            ..test_ct_config()
+        };
+        let base = |json: &str| serde_json::from_str::<BookingsDataBase>(json).unwrap();
+        assert!(!opts_out_of_heating(
+            &ct,
+            &base(r#"{"id": 1, "resource": {"id": 2}, "note": "choir practice"}"#)
+        ));
+        assert!(opts_out_of_heating(
+            &ct,
+            &base(r#"{"id": 1, "resource": {"id": 2}, "note": "setup only, no heating"}"#)
+        ));
+        assert!(opts_out_of_heating(
+            &ct,
+            &base(r#"{"id": 1, "resource": {"id": 2}, "noHeating": true}"#)
+        ));
+        assert!(!opts_out_of_heating(
+            &ct,
+            &base(r#"{"id": 1, "resource": {"id": 2}, "noHeating": "0"}"#)
+        ));
+    }
+
+    #[test]
+    fn parse_temperature_requests() {
+        assert_eq!(parse_requested_temperature("choir practice"), None);
+        assert_eq!(parse_requested_temperature("#temp:21"), Some(Ok(210)));
+        assert_eq!(
+            parse_requested_temperature("Konzert #Temp:20,5, bitte warm"),
+            Some(Ok(205))
+        );
+        assert_eq!(
+            parse_requested_temperature("#heating #temp:19.5."),
+            Some(Ok(195))
+        );
+        assert!(matches!(
+            parse_requested_temperature("#temp:warm"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            parse_requested_temperature("#temp:2100"),
+            Some(Err(_))
+        ));
+    }
+
+    #[test]
+    fn truncate_long_body() {
+        let text = "ä".repeat(MAX_LOGGED_BODY_LEN);
+        let truncated = truncate_body(&text);
+        assert!(truncated.len() <= MAX_LOGGED_BODY_LEN);
+        assert!(text.starts_with(truncated));
+        assert_eq!(truncate_body("short"), "short");
+    }
+
+    #[test]
+    fn open_ended_bookings() {
+        let mut ct = ChurchToolsConfig {
+            open_ended_booking_hours: Some(3),
+            ..test_ct_config()
+        };
+        let data = |end: &str| {
+            serde_json::from_str::<BookingsData>(&format!(
+                r#"{{"base": {{"id": 1, "resource": {{"id": 2}}}},
+                "calculated": {{"startDate": "2024-01-07T10:20:00Z"{end}}}}}"#
+            ))
+            .unwrap()
+        };
+        let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-07T10:20:00Z")
+            .unwrap()
+            .into();
+        // bookings with an end are not affected
+        let b = booking_from_ct(&ct, data(r#", "endDate": "2024-01-07T12:00:00Z""#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(b.end_time() - b.start_time(), TimeDelta::minutes(100));
+        // an absent or null end both parse
+        assert!(booking_from_ct(&ct, data("")).unwrap().is_some());
+        assert!(booking_from_ct(&ct, data(r#", "endDate": null"#)).unwrap().is_some());
+        assert_eq!(
+            end_of_open_ended_booking(&ct, start),
+            Some(start + TimeDelta::minutes(13 * 60 + 40))
+        );
+        ct.open_ended_bookings = OpenEndedBookingPolicy::Cap;
+        assert_eq!(
+            booking_from_ct(&ct, data("")).unwrap().unwrap().end_time(),
+            start + TimeDelta::hours(3)
+        );
+        ct.open_ended_bookings = OpenEndedBookingPolicy::Ignore;
+        assert_eq!(booking_from_ct(&ct, data("")).unwrap(), None);
+    }
+
+    #[test]
+    fn broken_bookings_are_skipped() {
+        let ct = test_ct_config();
+        let response: CTBookingsResponse = serde_json::from_str(
+            r#"{"data": [
+                {"base": {"id": 1, "resource": {"id": 2}},
+                 "calculated": {"startDate": "2024-01-07T10:00:00Z", "endDate": "2024-01-07T12:00:00Z"}},
+                {"base": {"id": 2, "resource": {"id": 2}},
+                 "calculated": {"startDate": "yesterday", "endDate": "2024-01-07T12:00:00Z"}},
+                {"base": {"id": 3},
+                 "calculated": {"startDate": "2024-01-07T10:00:00Z", "endDate": "2024-01-07T12:00:00Z"}},
+                {"base": {"resource": {"id": 2}}},
+                {"base": {"id": 4, "resource": {"id": 2}},
+                 "calculated": {"startDate": "2024-01-07T12:00:00Z", "endDate": "2024-01-07T10:00:00Z"}},
+                {"base": {"id": 5, "resource": {"id": 2}},
+                 "calculated": {"startDate": "2024-01-07T10:00:00Z", "endDate": "2024-01-07T12:00:00Z"},
+                 "meta": {"modifiedDate": "last tuesday"}}
+            ]}"#,
+        )
+        .unwrap();
+        let relevant = parse_bookings(&ct, response.data);
+        assert_eq!(
+            relevant
+                .bookings
+                .iter()
+                .map(|b| b.booking_id())
+                .collect::<Vec<_>>(),
+            vec![1, 5]
+        );
+        // a broken modification date does not drop the booking
+        assert_eq!(relevant.bookings[1].modified_at(), None);
+        assert_eq!(relevant.broken, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn bookings_are_cached_with_validators() {
+        let data = vec![serde_json::json!({"base": {"id": 1}})];
+        let mut headers = reqwest::header::HeaderMap::new();
+        // CT does not support conditional requests for this query
+        assert!(CachedBookings::from_response(&headers, &data).is_none());
+        headers.insert(reqwest::header::ETAG, "\"abc\"".parse().unwrap());
+        let cached = CachedBookings::from_response(&headers, &data).unwrap();
+        assert_eq!(cached.data, data);
+        assert_eq!(
+            cached.conditional_headers(),
+            vec![(reqwest::header::IF_NONE_MATCH, "\"abc\"")]
+        );
+    }
+}
@@ -1,15 +1,31 @@
 //! Push the state from DB to CMIs
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
 
-use chrono::{TimeDelta, Utc};
-use tokio::{net::UdpSocket, sync::RwLock};
-use tracing::{debug, info, trace, warn};
+use chrono::{DateTime, TimeDelta, Utc};
+use tokio::{net::UdpSocket, sync::Mutex};
+use tracing::{debug, info, warn};
 
 use crate::{
-    config::Config,
-    db::{get_bookings_in_timeframe, DBError},
-    InShutdown,
+    alert::{raise, resolve, Alert, AlertEvent, AlertKey, DbTask},
+    coe_sender::CoeSender,
+    config::{AssociatedRoomConfig, CMIConfig, Config, SharedRoomDecision},
+    db::{
+        get_booking_history_in_timeframe, get_bookings_in_timeframe, get_forecasts_in_timeframe,
+        get_last_successful_pull, get_resource_parents, get_rooms_in_maintenance, DBError,
+        ForecastSample,
+    },
+    feedback::FeedbackTracker,
+    forecast::{forecast_at, irradiance_at},
+    metrics::{Metrics, Task},
+    preheat::{cheapest_start, circuit_conflicts, limit_preheating, Conditions, HeatingCandidate},
+    read_ext_temp::ExternalTemperatures,
+    resource_hierarchy::implying_resources,
+    schedule::Schedule,
+    Booking, InShutdown,
 };
 
 /// All the things that can go wrong while emiting COE Packets
@@ -38,90 +54,1282 @@ impl std::fmt::Display for COEEmitError {
     }
 }
 
+/// The end of `booking` as far as heating is concerned: at most `global.max_heating_hours` after
+/// its start
+pub(crate) fn capped_end(config: &Config, booking: &Booking) -> DateTime<Utc> {
+    match config.global.max_heating_hours {
+        Some(hours) => booking
+            .end_time()
+            .min(booking.start_time() + TimeDelta::hours(hours.into())),
+        None => booking.end_time(),
+    }
+}
+
+/// The time `room` heats for `booking`, including preheat, preshutdown and overrun.
+///
+/// Bookings longer than `global.max_heating_hours` are heated for as if they ended then.
+/// Preheating uses the conditions expected when it would start. Rooms deferred for others on
+/// the same circuit or by max_preheating_rooms start later.
+pub(crate) fn heating_window(
+    config: &Config,
+    room: &AssociatedRoomConfig,
+    booking: &Booking,
+    forecasts: &[ForecastSample],
+    ext_temp: Option<i32>,
+    unused_for: Option<TimeDelta>,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    let preheat_start = booking.start_time() - TimeDelta::minutes(room.preheat_minutes.into());
+    let conditions = Conditions {
+        preheat_temp: forecast_at(forecasts, preheat_start).or(ext_temp),
+        external_temp: ext_temp,
+        preheat_irradiance: irradiance_at(forecasts, preheat_start),
+        unused_for,
+    };
+    let (mut new_start, new_stop) = room.apply_preheat_and_preshutdown(
+        booking.start_time(),
+        capped_end(config, booking),
+        &conditions,
+    );
+    if let Some(prices) = &config.energy_prices {
+        new_start = cheapest_start(
+            new_start,
+            booking.start_time() - new_start,
+            TimeDelta::minutes(room.price_flexibility_minutes.into()),
+            |t| prices.price_at(t),
+        );
+    };
+    (new_start, new_stop)
+}
+
+/// Get the current and archived bookings needed to tell how long rooms were unused before the
+/// bookings starting within [start, end].
+///
+/// Empty if no room has a cold_start.
+pub(crate) async fn get_booking_history(
+    config: &Config,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<Booking>, DBError> {
+    let Some(lookback) = config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        .filter_map(|room| room.cold_start.map(|x| x.lookback()))
+        .max()
+    else {
+        return Ok(vec![]);
+    };
+    get_booking_history_in_timeframe(&config.db, (start - lookback).naive_utc(), end.naive_utc())
+        .await
+}
+
+/// How long the `implying` resources of `room` were not booked before `booking`.
+///
+/// Rooms not booked within the lookback of their cold_start count as unused for all of it.
+/// None if the room has no cold_start.
+pub(crate) fn unused_for(
+    room: &AssociatedRoomConfig,
+    history: &[Booking],
+    implying: &[i64],
+    booking: &Booking,
+) -> Option<TimeDelta> {
+    let cold_start = room.cold_start?;
+    let last_use = history
+        .iter()
+        .filter(|b| {
+            implying.contains(&b.resource_id())
+                && b.booking_id() != booking.booking_id()
+                && b.start_time() < booking.start_time()
+        })
+        .map(|b| b.end_time().min(booking.start_time()))
+        .max();
+    Some(match last_use {
+        Some(last_use) => (booking.start_time() - last_use).min(cold_start.lookback()),
+        None => cold_start.lookback(),
+    })
+}
+
+/// Wake up this long after a transition, so it is seen even if the timer fires a bit early
+const TRANSITION_SLACK: TimeDelta = TimeDelta::seconds(1);
+
+/// The first time after `now` at which one of the heating `windows` starts or ends
+fn next_transition(
+    windows: impl IntoIterator<Item = (DateTime<Utc>, DateTime<Utc>)>,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    windows
+        .into_iter()
+        .flat_map(|(start, stop)| [start, stop])
+        .filter(|&t| t > now)
+        .min()
+}
+
+/// Get the bookings that heat `room` at `now`, including preheat, preshutdown and overrun.
+///
+/// Also returns the next time a booking starts or stops heating the room.
+/// `history` are the bookings to tell how long the room was unused, see [get_booking_history].
+#[allow(clippy::too_many_arguments)]
+fn bookings_heating<'a>(
+    config: &Config,
+    room: &AssociatedRoomConfig,
+    bookings: &'a [Booking],
+    history: &[Booking],
+    parents: &HashMap<i64, i64>,
+    forecasts: &[ForecastSample],
+    ext_temp: Option<i32>,
+    now: DateTime<Utc>,
+) -> (Vec<&'a Booking>, Option<DateTime<Utc>>) {
+    // bookings of parents or children may heat this room as well
+    let implying = implying_resources(
+        config.resource_hierarchy.as_ref(),
+        parents,
+        room.churchtools_id,
+    );
+    let windows = bookings
+        .iter()
+        .filter(|b| implying.contains(&b.resource_id()))
+        .map(|b| {
+            let unused_for = unused_for(room, history, &implying, b);
+            (
+                b,
+                heating_window(config, room, b, forecasts, ext_temp, unused_for),
+            )
+        })
+        .collect::<Vec<_>>();
+    let heating = windows
+        .iter()
+        .filter(|(_, (new_start, new_stop))| (new_start..=new_stop).contains(&&now))
+        .map(|(b, _)| *b)
+        .collect();
+    (
+        heating,
+        next_transition(windows.iter().map(|(_, window)| *window), now),
+    )
+}
+
+/// Whether a booking cools `room` at `now`, or None if the room is heated instead.
+///
+/// Rooms are cooled if they have a cooling output and it is hot enough outside (see
+/// [crate::config::Cooling]). `cooled_before` is whether bookings cooled the room on the last
+/// push. The risk of condensation is not checked here. Like heating, cooling stops at most
+/// `global.max_heating_hours` after the start of a booking.
+/// Also returns the next time a booking starts or stops cooling the room.
+fn bookings_cooling(
+    config: &Config,
+    room: &AssociatedRoomConfig,
+    bookings: &[Booking],
+    parents: &HashMap<i64, i64>,
+    ext_temp: Option<i32>,
+    cooled_before: bool,
+    now: DateTime<Utc>,
+) -> Option<(bool, Option<DateTime<Utc>>)> {
+    let cooling = room
+        .cooling
+        .filter(|x| x.active(ext_temp, cooled_before))?;
+    let implying = implying_resources(
+        config.resource_hierarchy.as_ref(),
+        parents,
+        room.churchtools_id,
+    );
+    let windows = bookings
+        .iter()
+        .filter(|b| implying.contains(&b.resource_id()))
+        .map(|b| cooling.window(b.start_time(), capped_end(config, b)))
+        .collect::<Vec<_>>();
+    let cooling_now = windows
+        .iter()
+        .any(|(start, stop)| (start..=stop).contains(&&now));
+    Some((cooling_now, next_transition(windows, now)))
+}
+
+/// Whether no pull from CT succeeded for longer than `fallback_after`, so the fallback schedules
+/// of rooms apply.
+///
+/// Without any successful pull recorded, there are no bookings from CT to go on either.
+async fn in_ct_outage(config: &Config, now: DateTime<Utc>) -> Result<bool, DBError> {
+    if config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        .all(|room| room.fallback_schedule.is_empty())
+    {
+        return Ok(false);
+    };
+    let fallback_after = TimeDelta::minutes(config.global.fallback_after.unwrap_or(120) as i64);
+    Ok(match get_last_successful_pull(&config.db).await? {
+        Some(last) => now - last > fallback_after,
+        None => true,
+    })
+}
+
+/// What the last push decided, so rooms keep their place and changes are logged once
+#[derive(Debug, Default)]
+struct LastPush {
+    /// rooms heated for their bookings
+    heating: HashSet<String>,
+    /// rooms that had to wait for others
+    deferred: HashSet<String>,
+    /// rooms whose bookings cooled instead of heated
+    cooling_mode: HashSet<String>,
+    /// rooms not cooled for their bookings to avoid condensation
+    condensation: HashSet<String>,
+    /// rooms heated by their fallback_schedule
+    fallback: HashSet<String>,
+}
+
+/// Get the rooms that may not heat now, because a room on the same circuit goes first or
+/// max_preheating_rooms is reached.
+///
+/// `bookings_per_room` are the bookings heating each room of each CMI now.
+fn rooms_to_defer<'a>(
+    config: &'a Config,
+    bookings_per_room: &[Vec<Vec<&Booking>>],
+    in_maintenance: &HashSet<String>,
+    last_push: &LastPush,
+) -> HashSet<&'a str> {
+    let candidates = config
+        .cmis
+        .iter()
+        .zip(bookings_per_room)
+        .flat_map(|(cmi, rooms_bookings)| {
+            cmi.rooms
+                .iter()
+                .zip(rooms_bookings)
+                .filter(|(room, _)| !in_maintenance.contains(&room.name))
+                .filter_map(|(room, bookings)| {
+                    Some(HeatingCandidate {
+                        room: &room.name,
+                        site: cmi.site.as_deref(),
+                        circuit: room.circuit.as_deref(),
+                        priority: room.priority,
+                        first_start: bookings.iter().map(|b| b.start_time()).min()?,
+                        heating: last_push.heating.contains(&room.name),
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+    let mut deferred = circuit_conflicts(&candidates);
+    for room in deferred.iter().filter(|x| !last_push.deferred.contains(**x)) {
+        info!("Room {room} shares its circuit with a room of higher priority. It has to wait.");
+    }
+
+    let site_limits = config
+        .sites
+        .iter()
+        .filter_map(|(name, site)| site.max_preheating_rooms.map(|x| (name.clone(), x)))
+        .collect::<HashMap<_, _>>();
+    if config.global.max_preheating_rooms.is_none() && site_limits.is_empty() {
+        return deferred;
+    };
+    let now = Utc::now();
+    // only rooms heating up for a booking are limited, not those in use
+    let preheating = candidates
+        .into_iter()
+        .filter(|c| c.first_start > now && !deferred.contains(c.room))
+        .collect::<Vec<_>>();
+    let too_many = limit_preheating(
+        &preheating,
+        config.global.max_preheating_rooms,
+        &site_limits,
+    );
+    for room in too_many.iter().filter(|x| !last_push.deferred.contains(**x)) {
+        info!("Too many rooms are preheating. Room {room} has to wait.");
+    }
+    deferred.extend(too_many);
+    deferred
+}
+
+/// Send `payloads` to `cmi` via its host or, if that fails, via each of its backup_hosts in turn.
+///
+/// Only local errors (no route, DNS) fail a send. UDP does not tell whether the CMI got the
+/// packets.
+/// Returns the number of packets sent and the address that worked.
+async fn send_to_cmi<'a>(
+    sender: &mut CoeSender,
+    cmi: &'a CMIConfig,
+    payloads: &[coe::Payload],
+) -> Result<(usize, &'a str), std::io::Error> {
+    let mut packets_sent = 0;
+    let mut last_error = None;
+    for host in cmi.hosts() {
+        let mut failed = None;
+        for packet in crate::coe_sender::packets(payloads) {
+            if let Err(e) = sender.send_to(packet, host, cmi.ip_version).await {
+                failed = Some(e);
+                break;
+            };
+            packets_sent += 1;
+        }
+        match failed {
+            None => {
+                if host != cmi.host {
+                    info!("Reached CMI {} via its backup host {host}.", cmi.host);
+                };
+                return Ok((packets_sent, host));
+            }
+            Some(e) => {
+                if !cmi.backup_hosts.is_empty() {
+                    warn!("Unable to reach CMI {} via {host}: {e}", cmi.host);
+                };
+                last_error = Some(e);
+            }
+        };
+    }
+    Err(last_error.expect("a CMI has at least one host"))
+}
+
+/// Give each room listed under several CMIs the same bookings on all of them, as `decision` says,
+/// so all its CMIs get the same values.
+///
+/// `bookings_per_room` are the bookings heating each room of each CMI now.
+fn share_room_bookings(
+    config: &Config,
+    bookings_per_room: &mut [Vec<Vec<&Booking>>],
+    decision: SharedRoomDecision,
+) {
+    // room -> (CMI index, room index), in the order of the config
+    let mut places = HashMap::<&str, Vec<(usize, usize)>>::new();
+    for (cmi_index, cmi) in config.cmis.iter().enumerate() {
+        for (room_index, room) in cmi.rooms.iter().enumerate() {
+            places
+                .entry(&room.name)
+                .or_default()
+                .push((cmi_index, room_index));
+        }
+    }
+    for places in places.values().filter(|places| places.len() > 1) {
+        let lists = places
+            .iter()
+            .map(|(cmi_index, room_index)| &bookings_per_room[*cmi_index][*room_index])
+            .collect::<Vec<_>>();
+        let shared = match decision {
+            SharedRoomDecision::First => lists[0].clone(),
+            SharedRoomDecision::All if lists.iter().any(|x| x.is_empty()) => vec![],
+            SharedRoomDecision::Any | SharedRoomDecision::All => {
+                let mut union = Vec::<&Booking>::new();
+                for booking in lists.into_iter().flatten() {
+                    if !union.iter().any(|x| x.booking_id() == booking.booking_id()) {
+                        union.push(booking);
+                    };
+                }
+                union
+            }
+        };
+        for (cmi_index, room_index) in places {
+            bookings_per_room[*cmi_index][*room_index] = shared.clone();
+        }
+    }
+}
+
+/// The CMIs of each room listed under several CMIs that could not be reached, for rooms where
+/// some but not all of them were
+fn partially_reached_rooms(
+    config: &Config,
+    unreached: &HashSet<&str>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut hosts = BTreeMap::<&str, Vec<&str>>::new();
+    for cmi in &config.cmis {
+        for room in &cmi.rooms {
+            hosts.entry(&room.name).or_default().push(&cmi.host);
+        }
+    }
+    hosts
+        .into_iter()
+        .filter_map(|(room, hosts)| {
+            let missed = hosts
+                .iter()
+                .filter(|host| unreached.contains(*host))
+                .map(|host| host.to_string())
+                .collect::<Vec<_>>();
+            (!missed.is_empty() && missed.len() < hosts.len()).then(|| (room.to_owned(), missed))
+        })
+        .collect()
+}
+
 /// Send CoE packets to all cmis, updating them on the state of all their assigned rooms
-async fn emit_coe(config: &Config, ext_temp: Option<i32>) -> Result<(), COEEmitError> {
-    // get all bookings from the db that intersect now and now + 30 mins
-    let start = Utc::now().naive_utc();
-    let end = start + TimeDelta::minutes(30);
+///
+/// Rooms in maintenance mode get their maintenance value once and are skipped afterwards.
+/// `maintenance_sent` tracks the (cmi host, room name) pairs that already got it.
+/// `last_push` is updated with what this push decided.
+/// Returns the number of packets sent and the next time a room starts or stops heating, if that
+/// is known from the bookings up to `lookahead` in the future.
+/// With `last_sent` (cmi host -> payloads), CMIs whose payloads did not change since are skipped.
+/// With `metrics`, the state sent to each room is recorded there.
+/// A CMI that cannot be reached does not keep the others from getting their packets. The first
+/// error is returned after all CMIs were tried.
+#[allow(clippy::too_many_arguments)]
+async fn emit_coe(
+    config: &Config,
+    sender: &mut CoeSender,
+    ext_temps: &ExternalTemperatures,
+    maintenance_sent: &mut HashSet<(String, String)>,
+    last_push: &mut LastPush,
+    feedback: &Mutex<FeedbackTracker>,
+    alerts: &tokio::sync::mpsc::Sender<AlertEvent>,
+    lookahead: TimeDelta,
+    mut last_sent: Option<&mut HashMap<String, Vec<coe::Payload>>>,
+    metrics: Option<&Metrics>,
+) -> Result<(usize, Option<DateTime<Utc>>), COEEmitError> {
+    let in_maintenance = get_rooms_in_maintenance(&config.db).await?;
+    // send the maintenance value again when a room reenters maintenance mode
+    maintenance_sent.retain(|(_, room)| in_maintenance.contains(room));
+
+    // get all bookings from the db that intersect now - max overrun and now + max preheat
+    // (or the cooling post-run and pre-run, if longer)
+    let max_preheat = config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        // preheating may start earlier for cheaper energy
+        .map(|room| {
+            (u16::from(room.max_preheat_minutes()) + u16::from(room.price_flexibility_minutes))
+                .max(room.cooling.map(|x| x.pre_run_minutes.into()).unwrap_or(0))
+        })
+        .max()
+        .unwrap_or(0)
+        .max(30);
+    let now = Utc::now();
+    let start = now.naive_utc() - config.max_overrun();
+    // bookings that start heating before the next run are needed for the next transition
+    let end = now.naive_utc() + TimeDelta::minutes(max_preheat.into()) + lookahead;
     let bookings = get_bookings_in_timeframe(&config.db, start, end).await?;
+    let history = get_booking_history(config, start.and_utc(), end.and_utc()).await?;
+    let parents = if config.resource_hierarchy.is_some() {
+        get_resource_parents(&config.db).await?
+    } else {
+        HashMap::new()
+    };
+    // forecasts around the time preheating may start
+    let forecasts = if config.forecast.is_some() {
+        get_forecasts_in_timeframe(
+            &config.db,
+            now.naive_utc() - TimeDelta::hours(1),
+            end + TimeDelta::hours(1),
+        )
+        .await?
+    } else {
+        vec![]
+    };
 
-    let sock = UdpSocket::bind((config.global.emiter_bind_addr.clone(), 0)).await?;
-    // for each CMI: send either on or off for the rooms we care about
+    let in_outage = in_ct_outage(config, now).await?;
+    let mut transition = config.next_season_change(now);
+
+    // the bookings heating each room of each CMI now
+    let mut bookings_per_room = vec![];
+    // rooms heated by their fallback_schedule now
+    let mut on_fallback = HashSet::new();
+    // rooms cooled for a booking now
+    let mut cooling = HashSet::new();
+    // rooms whose bookings cool instead of heat now
+    let mut cooling_mode = HashSet::new();
+    // rooms not cooled now to avoid condensation
+    let mut condensation = HashSet::new();
     for cmi in &config.cmis {
-        // calculate their preheating-times and cooldown-times
-        //  use this to filter out the really relevant ones
-        let payloads = cmi
+        let ext_temp = *ext_temps.for_site(cmi.site.as_deref()).read().await;
+        let dew_point = ext_temps.dew_point(config, cmi.site.as_deref()).await;
+        let in_season = config.in_heating_season(cmi.site.as_deref(), now);
+        if !in_season {
+            debug!(
+                "Outside of the heating season. Sending NOT HEATING to all rooms of CMI {}.",
+                cmi.host
+            );
+        };
+        let mut rooms_bookings = vec![];
+        for room in &cmi.rooms {
+            let cooled = bookings_cooling(
+                config,
+                room,
+                &bookings,
+                &parents,
+                ext_temp,
+                last_push.cooling_mode.contains(&room.name),
+                now,
+            );
+            if let Some((mut cooling_now, next)) = cooled {
+                cooling_mode.insert(room.name.clone());
+                if cooling_now && room.cooling.is_some_and(|x| x.condensation_risk(dew_point)) {
+                    if !last_push.condensation.contains(&room.name) {
+                        info!(
+                            "Not cooling room {}: the dew point outside is {} °C.",
+                            room.name,
+                            dew_point.unwrap_or_default() as f32 / 10_f32
+                        );
+                    };
+                    condensation.insert(room.name.clone());
+                    cooling_now = false;
+                };
+                if cooling_now {
+                    cooling.insert(room.name.as_str());
+                };
+                transition = transition.into_iter().chain(next).min();
+            };
+            // outside of the heating season or while cooling, no booking heats the room
+            if !in_season || cooled.is_some() {
+                rooms_bookings.push(vec![]);
+                continue;
+            };
+            let (heating, next) = bookings_heating(
+                config, room, &bookings, &history, &parents, &forecasts, ext_temp, now,
+            );
+            let fallback = if in_outage {
+                room.fallback_schedule
+                    .iter()
+                    .flat_map(|slot| slot.windows_around(now))
+                    .collect::<Vec<_>>()
+            } else {
+                vec![]
+            };
+            if fallback
+                .iter()
+                .any(|(start, end)| (start..=end).contains(&&now))
+            {
+                on_fallback.insert(room.name.as_str());
+            };
+            transition = transition
+                .into_iter()
+                .chain(next)
+                .chain(next_transition(fallback, now))
+                .min();
+            rooms_bookings.push(heating);
+        }
+        bookings_per_room.push(rooms_bookings);
+    }
+    share_room_bookings(
+        config,
+        &mut bookings_per_room,
+        config.global.shared_room_decision,
+    );
+    let deferred = rooms_to_defer(config, &bookings_per_room, &in_maintenance, last_push);
+    // rooms heated for their bookings now
+    let mut heating_rooms = HashSet::new();
+    if let Some(metrics) = metrics {
+        metrics.set_dew_points(ext_temps.dew_points(config).await);
+    };
+
+    let mut packets_sent = 0;
+    // hosts of the CMIs that could not be reached, and why the first could not
+    let mut unreached = HashSet::new();
+    let mut first_error = None;
+    // for each CMI: send either on or off for the rooms we care about
+    for (cmi, rooms_bookings) in config.cmis.iter().zip(&bookings_per_room) {
+        let in_season = config.in_heating_season(cmi.site.as_deref(), now);
+        // states sent to rooms with feedback
+        let mut commanded = vec![];
+        // analogue setpoints of rooms that have one
+        let mut setpoints = vec![];
+        // cooling outputs of rooms that have one
+        let mut cooling_outputs = vec![];
+        // rooms getting their maintenance value now, marked as sent once the CMI was reached
+        let mut maintenance_now = vec![];
+        let mut payloads = cmi
             .rooms
             .iter()
-            .map(|room| {
-                let num_of_bookings_in_room = bookings
-                    .iter()
-                    .filter(|&b| {
-                        if b.resource_id != room.churchtools_id {
-                            return false;
-                        };
-                        let (new_start, new_stop) =
-                            room.apply_preheat_and_preshutdown(b.start_time, b.end_time, ext_temp);
-                        let now = Utc::now();
-                        (new_start..=new_stop).contains(&now)
-                    })
-                    .count();
+            .zip(rooms_bookings)
+            .filter_map(|(room, room_bookings)| {
+                if in_maintenance.contains(&room.name) {
+                    let key = (cmi.host.clone(), room.name.clone());
+                    if maintenance_sent.contains(&key) {
+                        return None;
+                    };
+                    maintenance_now.push(key);
+                    info!(
+                        "Room {} is in maintenance mode. Sending {} once.",
+                        room.name,
+                        if room.maintenance_value {
+                            "HEATING"
+                        } else {
+                            "NOT HEATING"
+                        }
+                    );
+                    if room.feedback_pdo_index.is_some() {
+                        commanded.push((room.name.clone(), room.maintenance_value));
+                    };
+                    if let Some(metrics) = metrics {
+                        metrics.set_room_heating(
+                            &cmi.host,
+                            &room.name,
+                            room.pdo_index + 1,
+                            room.maintenance_value,
+                        );
+                    };
+                    return Some(coe::Payload::new(
+                        room.can_id,
+                        room.pdo_index,
+                        coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(room.maintenance_value)),
+                    ));
+                };
+                // wait for other rooms to finish preheating
+                let bookings_in_room = if deferred.contains(room.name.as_str()) {
+                    vec![]
+                } else {
+                    room_bookings.clone()
+                };
+                let num_of_bookings_in_room = bookings_in_room.len();
+                if num_of_bookings_in_room != 0 {
+                    heating_rooms.insert(room.name.clone());
+                };
+                let fallback = on_fallback.contains(room.name.as_str());
+                if let Some(setpoint) = &room.setpoint {
+                    // the warmest request wins if bookings overlap
+                    let requested = bookings_in_room
+                        .iter()
+                        .filter_map(|b| b.requested_temperature())
+                        .max();
+                    setpoints.push(coe::Payload::new(
+                        room.can_id,
+                        setpoint.pdo_index,
+                        coe::COEValue::Analogue(coe::AnalogueCOEValue::DegreeCentigrade_Tens(
+                            // the lowest setpoint keeps the room from freezing
+                            if in_season {
+                                setpoint.value(requested)
+                            } else {
+                                setpoint.min
+                            },
+                        )),
+                    ));
+                };
+                if let Some(cooling_config) = &room.cooling {
+                    let cooling_now = cooling.contains(room.name.as_str());
+                    if cooling_now {
+                        info!("Now sending COOLING status for room {}.", room.name);
+                    };
+                    cooling_outputs.push(coe::Payload::new(
+                        room.can_id,
+                        cooling_config.pdo_index,
+                        coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(cooling_now)),
+                    ));
+                };
                 if num_of_bookings_in_room != 0 {
                     info!("Now sending HEATING status for room {}.", room.name);
+                } else if fallback && !last_push.fallback.contains(&room.name) {
+                    info!(
+                        "CT is unreachable. Now sending HEATING status for room {} from its fallback_schedule.",
+                        room.name
+                    );
+                };
+                // heat the room, if at least one booking is currently in the room
+                let heating = num_of_bookings_in_room >= 1 || fallback;
+                if room.feedback_pdo_index.is_some() {
+                    commanded.push((room.name.clone(), heating));
+                };
+                if let Some(metrics) = metrics {
+                    metrics.set_room_heating(&cmi.host, &room.name, room.pdo_index + 1, heating);
                 };
-                // only heat, if Utc::now() is between
+                Some(coe::Payload::new(
+                    room.can_id,
+                    room.pdo_index,
+                    coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(heating)),
+                ))
+            })
+            .collect::<Vec<_>>();
+        payloads.extend(setpoints);
+        payloads.extend(cooling_outputs);
+        let mut reached = true;
+        let unchanged = last_sent
+            .as_ref()
+            .is_some_and(|sent| sent.get(&cmi.host) == Some(&payloads));
+        if unchanged {
+            debug!("Nothing changed for CMI {}, not sending.", cmi.host);
+        } else {
+            match send_to_cmi(sender, cmi, &payloads).await {
+                Ok((sent, host)) => {
+                    packets_sent += sent;
+                    if let Some(metrics) = metrics {
+                        metrics.set_cmi_send_path(&cmi.host, host);
+                    };
+                    if let Some(sent) = last_sent.as_deref_mut() {
+                        sent.insert(cmi.host.clone(), payloads);
+                    };
+                }
+                Err(e) => {
+                    warn!("Unable to send CoE to CMI {}: {e}", cmi.host);
+                    unreached.insert(cmi.host.as_str());
+                    first_error.get_or_insert(e);
+                    reached = false;
+                }
+            };
+        };
+        if !reached {
+            continue;
+        };
+        maintenance_sent.extend(maintenance_now);
+        let mut feedback = feedback.lock().await;
+        for (room, state) in commanded {
+            feedback.set_commanded((cmi.host.clone(), room), state, Utc::now());
+        }
+    }
+    for room in last_push
+        .fallback
+        .iter()
+        .filter(|x| !on_fallback.contains(x.as_str()))
+    {
+        info!("Room {room} is no longer heated from its fallback_schedule.");
+    }
+    *last_push = LastPush {
+        heating: heating_rooms,
+        deferred: deferred.into_iter().map(str::to_owned).collect(),
+        cooling_mode,
+        condensation,
+        fallback: on_fallback.into_iter().map(str::to_owned).collect(),
+    };
+    let mut feedback = feedback.lock().await;
+    for (cmi, room) in feedback.check(Utc::now()) {
+        raise(alerts, Alert::FeedbackMismatch { cmi, room });
+    }
+    for (cmi, room) in feedback.take_resolved() {
+        resolve(alerts, AlertKey::FeedbackMismatch { cmi, room });
+    }
+    let partially_reached = partially_reached_rooms(config, &unreached);
+    for (room, hosts) in &partially_reached {
+        warn!(
+            "Room {room} only got its state on some of its CMIs. Not reached: {}",
+            hosts.join(", ")
+        );
+    }
+    if let Some(metrics) = metrics {
+        metrics.set_partially_reached_rooms(partially_reached);
+    };
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok((packets_sent, transition)),
+    }
+}
+
+/// Sleep until `time`, or forever if there is none
+async fn sleep_until(time: Option<DateTime<Utc>>) {
+    match time {
+        Some(time) => tokio::time::sleep((time - Utc::now()).to_std().unwrap_or_default()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Send `value` to all rooms not in maintenance mode, ignoring their bookings.
+///
+/// Outside of the heating season, OFF is sent instead.
+/// Used before the first successful pull from CT, see `not_ready_value` in the config.
+/// Returns the number of packets sent.
+/// A CMI that cannot be reached does not keep the others from getting the value. The first error
+/// is returned after all CMIs were tried.
+async fn emit_not_ready_value(
+    config: &Config,
+    sender: &mut CoeSender,
+    value: bool,
+) -> Result<usize, COEEmitError> {
+    let in_maintenance = get_rooms_in_maintenance(&config.db).await?;
+    let mut packets_sent = 0;
+    let mut first_error = None;
+    for cmi in &config.cmis {
+        let value = value && config.in_heating_season(cmi.site.as_deref(), Utc::now());
+        let payloads = cmi
+            .rooms
+            .iter()
+            .filter(|room| !in_maintenance.contains(&room.name))
+            .map(|room| {
                 coe::Payload::new(
-                    cmi.our_virtual_can_id,
+                    room.can_id,
                     room.pdo_index,
-                    // heat the room, if at least one booking is currently in the room
-                    coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(
-                        num_of_bookings_in_room >= 1,
-                    )),
+                    coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(value)),
                 )
             })
             .collect::<Vec<_>>();
-        let packets = coe::packets_from_payloads(&payloads);
-        // send all packets.
-        for packet in packets {
-            sock.send_to(&Into::<Vec<u8>>::into(packet), (cmi.host.as_str(), 5442))
-                .await?;
-            trace!("Sent a CoE packet to {}", cmi.host);
-        }
+        match send_to_cmi(sender, cmi, &payloads).await {
+            Ok((sent, _)) => packets_sent += sent,
+            Err(e) => {
+                warn!(
+                    "Unable to send the not_ready_value to CMI {}: {e}",
+                    cmi.host
+                );
+                first_error.get_or_insert(e);
+            }
+        };
     }
-    Ok(())
+    match first_error {
+        Some(e) => Err(e.into()),
+        None => Ok(packets_sent),
+    }
+}
+
+/// Push data from the db to all CMIs once, for `--once`.
+///
+/// Nothing is remembered between runs, so rooms in maintenance mode get their value every time.
+/// If the pull failed (`pulled` is false), the `not_ready_value` is sent, if configured.
+pub async fn push_once(
+    config: &Config,
+    ext_temps: &ExternalTemperatures,
+    pulled: bool,
+) -> Result<usize, COEEmitError> {
+    let mut sender = CoeSender::new(
+        config.global.emiter_bind_addr.clone(),
+        config.global.emiter_bind_port.unwrap_or(0),
+        tokio::time::Duration::from_secs(config.global.cmi_dns_refresh.unwrap_or(10) * 60),
+    );
+    let feedback = Mutex::new(FeedbackTracker::new(TimeDelta::minutes(
+        config.global.feedback_timeout.unwrap_or(10) as i64,
+    )));
+    if let (false, Some(value)) = (pulled, config.global.not_ready_value) {
+        warn!("Not pulled from CT. Sending the not_ready_value to all rooms.");
+        return emit_not_ready_value(config, &mut sender, value).await;
+    };
+    // the notifier does not run, so alerts are dropped
+    let (alerts, _) = tokio::sync::mpsc::channel(1);
+    emit_coe(
+        config,
+        &mut sender,
+        ext_temps,
+        &mut HashSet::new(),
+        &mut LastPush::default(),
+        &feedback,
+        &alerts,
+        TimeDelta::zero(),
+        None,
+        None,
+    )
+    .await
+    .map(|(packets_sent, _)| packets_sent)
 }
 
 /// Continually push data from the db to CMIs.
+///
+/// The first push waits for `ready`, which is set after the first successful pull.
+/// With `ta_keepalive_frequency`, pushes are event-driven: CMIs only get packets when a room
+/// switches, the heating season begins or ends, or bookings changed (pulls, local bookings,
+/// maintenance mode), and all states are resent at the keepalive frequency.
+#[allow(clippy::too_many_arguments)]
 pub async fn push_coe(
     config: Arc<Config>,
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
-    ext_temp: Arc<RwLock<Option<i32>>>,
+    ext_temps: ExternalTemperatures,
+    feedback: Arc<Mutex<FeedbackTracker>>,
+    alerts: tokio::sync::mpsc::Sender<AlertEvent>,
+    metrics: Arc<Metrics>,
+    shared_socket: Option<Arc<UdpSocket>>,
+    mut ready: tokio::sync::watch::Receiver<bool>,
 ) {
     info!("Starting DB -> TA COE emitter task");
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
-        config.global.ta_push_frequency * 60,
-    ));
-    interval.tick().await;
+    let keepalive = config.global.ta_keepalive_frequency;
+    let mut schedule = match keepalive {
+        Some(minutes) => {
+            info!("Pushing event-driven, with keepalives every {minutes} minutes.");
+            Schedule::new(
+                tokio::time::Duration::from_secs(minutes * 60),
+                None,
+                config.global.ta_push_second,
+            )
+        }
+        None => Schedule::new(
+            tokio::time::Duration::from_secs(config.global.ta_push_frequency * 60),
+            config.global.ta_push_schedule.as_deref(),
+            config.global.ta_push_second,
+        ),
+    };
+    schedule.tick().await;
+    // the socket is kept between runs, some CMI firewalls require a fixed source port
+    let mut sender = CoeSender::new(
+        config.global.emiter_bind_addr.clone(),
+        config.global.emiter_bind_port.unwrap_or(0),
+        tokio::time::Duration::from_secs(config.global.cmi_dns_refresh.unwrap_or(10) * 60),
+    );
+    if let Some(sock) = shared_socket {
+        sender = sender.with_socket(sock);
+    };
+    let mut maintenance_sent = HashSet::new();
+    let mut last_push = LastPush::default();
+    let lookahead = TimeDelta::minutes(keepalive.unwrap_or(config.global.ta_push_frequency) as i64);
+    // the payloads last sent to each CMI, when event-driven
+    let mut last_sent = HashMap::new();
+    // cleared on keepalives, so everything is sent
+    let mut keepalive_due = true;
+    // the gatherer may stop before us while shutting down
+    let mut pulls_running = true;
+    // a fresh DB has no bookings, do not turn off the heating of ongoing events
+    let first_pull_timeout =
+        tokio::time::Duration::from_secs(config.global.first_pull_timeout.unwrap_or(120));
+    tokio::select! {
+        _ = watcher.changed() => {
+            debug!("Shutting down data emiter now.");
+            return;
+        }
+        res = tokio::time::timeout(first_pull_timeout, ready.wait_for(|x| *x)) => {
+            match res {
+                Ok(Ok(_)) => debug!("The first pull succeeded. Starting to emit."),
+                // the gatherer stopped, we are shutting down
+                Ok(Err(_)) => {}
+                Err(_) => warn!(
+                    "No successful pull from CT within {}s. Starting to emit anyway.",
+                    first_pull_timeout.as_secs()
+                ),
+            };
+        }
+    };
     loop {
         debug!("Emitter starting new run.");
-        let current_temp = *ext_temp.read().await;
         // send data from state once
-        let res = emit_coe(&config, current_temp).await;
+        let push_start = std::time::Instant::now();
+        let not_ready_value = config.global.not_ready_value.filter(|_| !*ready.borrow());
+        let mut transition = None;
+        if std::mem::take(&mut keepalive_due) {
+            last_sent.clear();
+        };
+        let res = match not_ready_value {
+            Some(value) => {
+                warn!(
+                    "Still no successful pull from CT. Sending the not_ready_value to all rooms."
+                );
+                emit_not_ready_value(&config, &mut sender, value).await
+            }
+            None => emit_coe(
+                &config,
+                &mut sender,
+                &ext_temps,
+                &mut maintenance_sent,
+                &mut last_push,
+                &feedback,
+                &alerts,
+                lookahead,
+                keepalive.is_some().then_some(&mut last_sent),
+                Some(&metrics),
+            )
+            .await
+            .map(|(packets_sent, next)| {
+                transition = next;
+                packets_sent
+            }),
+        };
+        metrics.set_send_failures(sender.consecutive_failures());
+        metrics
+            .record(
+                &config,
+                Task::Push,
+                push_start.elapsed(),
+                *res.as_ref().unwrap_or(&0),
+                res.is_ok(),
+            )
+            .await;
         match res {
-            Ok(()) => {
+            Ok(_) => {
                 debug!("Successfully emitted all required CoE packets");
+                // the DB works (again)
+                resolve(&alerts, AlertKey::Db(DbTask::Push));
             }
             Err(e) => {
                 warn!("An Error occured while emitting CoE packets: {e}");
+                if let COEEmitError::Db(e) = e {
+                    raise(
+                        &alerts,
+                        Alert::Db {
+                            task: DbTask::Push,
+                            error: e.to_string(),
+                        },
+                    );
+                };
             }
         }
-        // stop on cancellation or continue after the next tick
+        // stop on cancellation or continue after the next tick or when a room switches
+        let transition = transition.map(|t| t + TRANSITION_SLACK);
         tokio::select! {
             _ = watcher.changed() => {
                 debug!("Shutting down data emiter now.");
                 return;
             }
-            _ = interval.tick() => {}
+            _ = schedule.tick() => {
+                keepalive_due = true;
+            }
+            _ = sleep_until(transition) => {
+                debug!("A room switches now. Emitting before the next regular run.");
+            }
+            _ = config.bookings_changed.notified() => {
+                debug!("Bookings changed. Emitting what changed.");
+            }
+            res = ready.changed(), if keepalive.is_some() && pulls_running => {
+                match res {
+                    Ok(()) => debug!("Pulled from CT. Emitting what changed."),
+                    Err(_) => pulls_running = false,
+                };
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+
+    use crate::config::{test_room, ColdStart};
+
+    #[test]
+    fn next_transition_is_first_future_edge() {
+        let at = |h, min| Utc.with_ymd_and_hms(2025, 1, 12, h, min, 0).unwrap();
+        let windows = [(at(8, 0), at(10, 0)), (at(9, 30), at(12, 0))];
+        assert_eq!(next_transition(windows, at(7, 0)), Some(at(8, 0)));
+        assert_eq!(next_transition(windows, at(8, 0)), Some(at(9, 30)));
+        assert_eq!(next_transition(windows, at(9, 45)), Some(at(10, 0)));
+        assert_eq!(next_transition(windows, at(12, 0)), None);
+    }
+
+    #[test]
+    fn unused_for_since_last_booking() {
+        let at = |d, h| Utc.with_ymd_and_hms(2025, 1, d, h, 0, 0).unwrap();
+        let mut room = AssociatedRoomConfig {
+            churchtools_id: 1,
+            preheat_minutes: 60,
+            ..test_room("")
+        };
+        let booking = Booking::new(3, 1, at(12, 10), at(12, 12)).unwrap();
+        let history = vec![
+            Booking::new(1, 1, at(9, 10), at(9, 12)).unwrap(),
+            // another room
+            Booking::new(2, 2, at(11, 10), at(11, 12)).unwrap(),
+            Booking::new(3, 1, at(12, 10), at(12, 12)).unwrap(),
+        ];
+        assert_eq!(unused_for(&room, &history, &[1], &booking), None);
+
+        room.cold_start = Some(ColdStart {
+            extra_per_day: 0.25,
+            max_extra: 1.0,
+        });
+        assert_eq!(
+            unused_for(&room, &history, &[1], &booking),
+            Some(TimeDelta::hours(70))
+        );
+        // a booking of a parent resource counts as use
+        assert_eq!(
+            unused_for(&room, &history, &[1, 2], &booking),
+            Some(TimeDelta::hours(22))
+        );
+        // not booked within the lookback
+        assert_eq!(
+            unused_for(&room, &history[2..], &[1], &booking),
+            Some(TimeDelta::days(4))
+        );
+        // still in use
+        let overlapping = Booking::new(4, 1, at(12, 11), at(12, 14)).unwrap();
+        assert_eq!(
+            unused_for(&room, &history, &[1], &overlapping),
+            Some(TimeDelta::zero())
+        );
+    }
+
+    fn shared_room_config() -> Config {
+        let yaml = "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms:
+  hall:
+    churchtools_id: 1
+  office:
+    churchtools_id: 2
+cmis:
+  - host: cmi-a.local
+    our_virtual_can_id: 59
+    rooms:
+      - name: hall
+        pdo_index: 1
+      - name: office
+        pdo_index: 2
+  - host: cmi-b.local
+    our_virtual_can_id: 59
+    rooms:
+      - name: hall
+        pdo_index: 5
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  bind_addr: 127.0.0.1
+  can_id: 1
+  pdo_index: 1
+  timeout: 5
+";
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        crate::config::test_config(yaml, db)
+    }
+
+    #[tokio::test]
+    async fn shared_rooms_get_the_same_bookings() {
+        let config = shared_room_config();
+        let at = |h| Utc.with_ymd_and_hms(2025, 1, 12, h, 0, 0).unwrap();
+        let morning = Booking::new(1, 1, at(9), at(10)).unwrap();
+        let evening = Booking::new(2, 1, at(18), at(20)).unwrap();
+        let office = Booking::new(3, 2, at(9), at(10)).unwrap();
+        let ids = |bookings: &[Vec<Vec<&Booking>>]| {
+            bookings
+                .iter()
+                .map(|rooms| {
+                    rooms
+                        .iter()
+                        .map(|x| x.iter().map(|b| b.booking_id()).collect::<Vec<_>>())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        // cmi-b is out of its heating season, so it has no bookings
+        let bookings = || vec![vec![vec![&morning, &evening], vec![&office]], vec![vec![]]];
+
+        let mut any = bookings();
+        share_room_bookings(&config, &mut any, SharedRoomDecision::Any);
+        assert_eq!(ids(&any), [vec![vec![1, 2], vec![3]], vec![vec![1, 2]]]);
+        let mut all = bookings();
+        share_room_bookings(&config, &mut all, SharedRoomDecision::All);
+        assert_eq!(ids(&all), [vec![vec![], vec![3]], vec![vec![]]]);
+        let mut first = vec![vec![vec![], vec![&office]], vec![vec![&morning, &morning]]];
+        share_room_bookings(&config, &mut first, SharedRoomDecision::First);
+        assert_eq!(ids(&first), [vec![vec![], vec![3]], vec![vec![]]]);
+        // the same booking on both CMIs is heated for once
+        let mut twice = vec![vec![vec![&morning], vec![]], vec![vec![&morning]]];
+        share_room_bookings(&config, &mut twice, SharedRoomDecision::Any);
+        assert_eq!(ids(&twice), [vec![vec![1], vec![]], vec![vec![1]]]);
+    }
+
+    #[tokio::test]
+    async fn rooms_reached_on_some_cmis() {
+        let config = shared_room_config();
+        assert!(partially_reached_rooms(&config, &HashSet::new()).is_empty());
+        assert_eq!(
+            partially_reached_rooms(&config, &HashSet::from(["cmi-b.local"])),
+            BTreeMap::from([("hall".to_owned(), vec!["cmi-b.local".to_owned()])])
+        );
+        // office is only on cmi-a, it is not reached at all
+        assert_eq!(
+            partially_reached_rooms(&config, &HashSet::from(["cmi-a.local"])),
+            BTreeMap::from([("hall".to_owned(), vec!["cmi-a.local".to_owned()])])
+        );
+        assert!(
+            partially_reached_rooms(&config, &HashSet::from(["cmi-a.local", "cmi-b.local"]))
+                .is_empty()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backup_host_is_used_when_host_fails() {
+        let mut config = shared_room_config();
+        let cmi = &mut config.cmis[0];
+        // sending to broadcast fails without SO_BROADCAST
+        cmi.host = "255.255.255.255".to_owned();
+        cmi.backup_hosts = vec!["127.0.0.1".to_owned()];
+        let mut sender = CoeSender::new("127.0.0.1".to_owned(), 0, Duration::from_secs(600));
+        let payloads = [coe::Payload::new(
+            59,
+            0,
+            coe::COEValue::Digital(coe::DigitalCOEValue::OnOff(true)),
+        )];
+        let cmi = &config.cmis[0];
+        assert_eq!(
+            send_to_cmi(&mut sender, cmi, &payloads).await.unwrap(),
+            (1, "127.0.0.1")
+        );
+        let without_backup = CMIConfig {
+            backup_hosts: vec![],
+            ..config.cmis.remove(0)
+        };
+        assert!(send_to_cmi(&mut sender, &without_backup, &payloads)
+            .await
+            .is_err());
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn maintenance_value_is_resent_to_unreached_cmis(pool: sqlx::SqlitePool) {
+        let mut config = shared_room_config();
+        config.db = pool;
+        // sending to broadcast fails without SO_BROADCAST
+        config.cmis[0].host = "255.255.255.255".to_owned();
+        config.cmis[1].host = "127.0.0.1".to_owned();
+        sqlx::query("INSERT INTO room_maintenance (room, since) VALUES ('hall', 0);")
+            .execute(&config.db)
+            .await
+            .unwrap();
+        let mut sender = CoeSender::new("127.0.0.1".to_owned(), 0, Duration::from_secs(600));
+        let (alerts, _alerts_rx) = tokio::sync::mpsc::channel(16);
+        let mut maintenance_sent = HashSet::new();
+        let res = emit_coe(
+            &config,
+            &mut sender,
+            &ExternalTemperatures::new(&config),
+            &mut maintenance_sent,
+            &mut LastPush::default(),
+            &Mutex::new(FeedbackTracker::new(TimeDelta::minutes(10))),
+            &alerts,
+            TimeDelta::minutes(2),
+            None,
+            None,
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(
+            maintenance_sent,
+            HashSet::from([("127.0.0.1".to_owned(), "hall".to_owned())])
+        );
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    fn not_ready_value_reaches_cmis_after_an_unreached_one(pool: sqlx::SqlitePool) {
+        let mut config = shared_room_config();
+        config.db = pool;
+        // sending to broadcast fails without SO_BROADCAST
+        config.cmis[0].host = "255.255.255.255".to_owned();
+        config.cmis[1].host = "127.0.0.2".to_owned();
+        let cmi_b = tokio::net::UdpSocket::bind(("127.0.0.2", crate::coe_sender::COE_PORT))
+            .await
+            .unwrap();
+        let mut sender = CoeSender::new("127.0.0.1".to_owned(), 0, Duration::from_secs(600));
+        assert!(emit_not_ready_value(&config, &mut sender, true)
+            .await
+            .is_err());
+        let mut buf = [0_u8; 256];
+        let received = tokio::time::timeout(Duration::from_secs(5), cmi_b.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received > 0);
+    }
+
+    #[tokio::test]
+    async fn heating_stops_after_max_heating_hours() {
+        let mut config = shared_room_config();
+        let at = |d, h| Utc.with_ymd_and_hms(2025, 1, d, h, 0, 0).unwrap();
+        // entered as ending next week
+        let booking = Booking::new(1, 1, at(12, 9), at(19, 11)).unwrap();
+        assert_eq!(capped_end(&config, &booking), at(19, 11));
+        config.global.max_heating_hours = Some(12);
+        assert_eq!(capped_end(&config, &booking), at(12, 21));
+        let short = Booking::new(2, 1, at(12, 9), at(12, 11)).unwrap();
+        assert_eq!(capped_end(&config, &short), at(12, 11));
+        let room = &config.cmis[0].rooms[0];
+        let (_, end) = heating_window(&config, room, &booking, &[], None, None);
+        assert!(end <= at(12, 21));
+
+        // cooling stops as well
+        let room = AssociatedRoomConfig {
+            churchtools_id: 1,
+            cooling: Some(crate::config::Cooling {
+                pdo_index: 3,
+                above: 250,
+                below: 250,
+                pre_run_minutes: 0,
+                post_run_minutes: 0,
+                max_dew_point: None,
+            }),
+            ..test_room("hall")
+        };
+        let bookings = [booking];
+        let cooling = |now| {
+            bookings_cooling(
+                &config,
+                &room,
+                &bookings,
+                &HashMap::new(),
+                Some(300),
+                false,
+                now,
+            )
+        };
+        assert_eq!(cooling(at(12, 20)), Some((true, Some(at(12, 21)))));
+        assert_eq!(cooling(at(13, 9)), Some((false, None)));
+    }
+}
@@ -1,116 +1,654 @@
 //! Read the external temperature from a CMI sending that information.
+//!
+//! Each configured source (CoE, MQTT) forwards raw temperatures through a channel. They are
+//! filtered, recorded and timed out here. The relative humidity, if a sensor sends one, is only
+//! used for the dew point.
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+};
 
-use coe::{AnalogueCOEValue, COEValue, Packet};
-use tokio::{net::UdpSocket, sync::RwLock};
-use tracing::{debug, error, info, trace, warn};
+use chrono::{DateTime, TimeDelta, Utc};
+use coe::AnalogueCOEValue;
+use tokio::sync::{mpsc, Notify, RwLock};
+#[cfg(feature = "mqtt")]
+use tokio::task::JoinSet;
+#[cfg(feature = "mqtt")]
+use tracing::Instrument;
+use tracing::{debug, info, trace, warn};
 
-use crate::{config::Config, InShutdown};
+use crate::{
+    alert::{raise, resolve, Alert, AlertEvent, AlertKey, DbTask},
+    config::{Config, ExtTempFilterConfig, ExtTempFilterKind, TemperatureUnit},
+    db::{DBError, ExternalTemperatureSample},
+    InShutdown,
+};
 
-pub async fn read_next_ext_temp_packet(sock: &UdpSocket, can_id: u8, pdo_index: u8) -> i32 {
-    // all well-formed COE packets are at most 252 bytes long
-    let mut buf = [0_u8; 252];
-    loop {
-        let bytes = sock.recv_from(&mut buf).await;
-        match bytes {
-            Ok(x) => {
-                trace!("Received a CoE packet of {} bytes", x.0);
-                let parse_res = TryInto::<Packet>::try_into(&buf[0..x.0]);
-                match parse_res {
-                    Ok(packet) => {
-                        for payload in packet.iter() {
-                            if payload.node() == can_id && payload.pdo_index() == pdo_index {
-                                if let COEValue::Analogue(
-                                    AnalogueCOEValue::DegreeCentigrade_Tens(x),
-                                ) = payload.value()
-                                {
-                                    debug!("Got the external temperature: {} °C", x as f32 / 10_f32);
-                                    return x;
-                                } else {
-                                    trace!("Got Payload for correct ID and Index, but the Unit was not Degree Centigrade ({}).", payload.unit_id());
-                                }
-                            } else {
-                                debug!("Got a well-formed COE packet, but it was for the wrong CAN-ID or pdo_index.");
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        trace!("Packet received, but not parsable as CoE: {e}");
-                    }
+/// After this many consecutive rejected samples, we assume the temperature really changed this much.
+const MAX_CONSECUTIVE_REJECTIONS: u8 = 3;
+
+/// Smooths received temperatures and rejects implausible jumps
+#[derive(Debug)]
+struct TemperatureFilter<'a> {
+    config: Option<&'a ExtTempFilterConfig>,
+    /// the last accepted raw samples, newest last
+    samples: VecDeque<i32>,
+    /// number of samples rejected since the last accepted one
+    rejected_in_a_row: u8,
+}
+impl<'a> TemperatureFilter<'a> {
+    fn new(config: Option<&'a ExtTempFilterConfig>) -> Self {
+        Self {
+            config,
+            samples: VecDeque::new(),
+            rejected_in_a_row: 0,
+        }
+    }
+
+    /// Forget all samples, e.g. after a timeout
+    fn reset(&mut self) {
+        self.samples.clear();
+        self.rejected_in_a_row = 0;
+    }
+
+    /// Add a new sample (in tenths of a Degree Centigrade).
+    ///
+    /// Returns the filtered temperature, or None if the sample was rejected as an outlier.
+    fn push(&mut self, temp: i32) -> Option<i32> {
+        let Some(config) = self.config else {
+            return Some(temp);
+        };
+        if let (Some(max_jump), Some(&last)) = (config.max_jump, self.samples.back()) {
+            if temp.abs_diff(last) as f64 > max_jump * 10_f64 {
+                self.rejected_in_a_row += 1;
+                if self.rejected_in_a_row < MAX_CONSECUTIVE_REJECTIONS {
+                    return None;
                 };
+                info!("Received {MAX_CONSECUTIVE_REJECTIONS} implausible external temperatures in a row. Assuming the temperature really changed.");
+                self.samples.clear();
+            };
+        };
+        self.rejected_in_a_row = 0;
+        self.samples.push_back(temp);
+        while self.samples.len() > config.window.max(1) {
+            self.samples.pop_front();
+        }
+        Some(match config.kind {
+            ExtTempFilterKind::MovingAverage => {
+                let sum: i64 = self.samples.iter().map(|&x| x as i64).sum();
+                (sum as f64 / self.samples.len() as f64).round() as i32
             }
-            Err(e) => {
-                trace!("Failed to read a CoE packet: {e}");
+            ExtTempFilterKind::Median => {
+                let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+                sorted.sort_unstable();
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 0 {
+                    ((sorted[mid - 1] as f64 + sorted[mid] as f64) / 2_f64).round() as i32
+                } else {
+                    sorted[mid]
+                }
             }
+        })
+    }
+}
+
+/// Normalize a CoE value to tenths of a Degree Centigrade, if its unit is accepted
+pub(crate) fn normalize_temperature(
+    value: AnalogueCOEValue,
+    accepted_units: &[TemperatureUnit],
+) -> Option<i32> {
+    let (unit, temp) = match value {
+        AnalogueCOEValue::DegreeCentigrade_Tens(x) => (TemperatureUnit::CelsiusTenths, x),
+        // 0 °C is 273.15 K. Values this far off are garbage anyway.
+        AnalogueCOEValue::DegreeKelvin_Tens(x) => {
+            (TemperatureUnit::KelvinTenths, x.checked_sub(2732)?)
+        }
+        AnalogueCOEValue::Dimensionless(x) => {
+            (TemperatureUnit::Dimensionless, x.saturating_mul(10))
         }
+        AnalogueCOEValue::Dimensionless_Tens(x) => (TemperatureUnit::DimensionlessTenths, x),
+        _ => return None,
+    };
+    if accepted_units.contains(&unit) {
+        Some(temp)
+    } else {
+        None
     }
 }
 
-#[derive(Debug)]
-pub enum ReadExtTempError {
-    Udp(std::io::Error),
+/// The dew point at `temp` (tenths of a Degree Centigrade) and relative `humidity` (tenths of a
+/// percent), in tenths of a Degree Centigrade.
+///
+/// Uses the Magnus formula, which is accurate to a few tenths between -45 and 60 °C.
+/// None if the humidity is not within (0, 100] percent.
+pub(crate) fn dew_point(temp: i32, humidity: i32) -> Option<i32> {
+    if !(1..=1000).contains(&humidity) {
+        return None;
+    };
+    const B: f64 = 17.62;
+    const C: f64 = 243.12;
+    let temp = temp as f64 / 10_f64;
+    let gamma = (humidity as f64 / 1000_f64).ln() + B * temp / (C + temp);
+    Some((C * gamma / (B - gamma) * 10_f64).round() as i32)
 }
-impl std::fmt::Display for ReadExtTempError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Udp(x) => write!(f, "Udp Error: {x}"),
+
+/// The last relative humidity received by a sensor (tenths of a percent) and when it was received
+pub type Humidity = Arc<RwLock<Option<(i32, DateTime<Utc>)>>>;
+
+/// Record the external temperature in the db, if the last record is older than the history interval.
+///
+/// Returns the time of the last record.
+async fn record_ext_temp(
+    config: &Config,
+    temp: i32,
+    last_recorded: Option<DateTime<Utc>>,
+) -> Result<Option<DateTime<Utc>>, DBError> {
+    let now = Utc::now();
+    let interval = TimeDelta::minutes(config.external_temperature_sensor.history_interval as i64);
+    if last_recorded.is_some_and(|t| now - t < interval) {
+        return Ok(last_recorded);
+    };
+    crate::db::insert_external_temperature(
+        &config.db,
+        &ExternalTemperatureSample {
+            recorded_at: now,
+            temperature: temp,
+        },
+    )
+    .await?;
+    trace!("Recorded external temperature {} °C.", temp as f32 / 10_f32);
+    let pruned = crate::db::prune_old_external_temperatures(
+        &config.db,
+        TimeDelta::days(config.external_temperature_sensor.history_retention as i64),
+    )
+    .await?;
+    if pruned != 0 {
+        debug!("Pruned {pruned} old external temperature samples.");
+    };
+    Ok(Some(now))
+}
+
+/// The current external temperature of each sensor, in tenths of a Degree Centigrade
+#[derive(Clone, Debug, Default)]
+pub struct ExternalTemperatures {
+    default: Arc<RwLock<Option<i32>>>,
+    /// sites with their own sensor
+    sites: HashMap<String, Arc<RwLock<Option<i32>>>>,
+    /// sensors receiving the humidity, by site (None is the default sensor)
+    humidity: HashMap<Option<String>, Humidity>,
+    /// woken whenever a temperature changes
+    changed: Arc<Notify>,
+}
+impl ExternalTemperatures {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            default: Arc::new(RwLock::new(None)),
+            sites: config
+                .sites
+                .iter()
+                .filter(|(_, site)| site.external_temperature_sensor.is_some())
+                .map(|(name, _)| (name.clone(), Arc::new(RwLock::new(None))))
+                .collect(),
+            humidity: std::iter::once(None)
+                .chain(config.sites.keys().map(|name| Some(name.as_str())))
+                .filter(|site| config.sensor_site(*site) == *site)
+                .filter(|site| {
+                    config
+                        .ext_temp_sensor(*site)
+                        .coe
+                        .as_ref()
+                        .is_some_and(|coe| coe.humidity_pdo_index.is_some())
+                })
+                .map(|site| (site.map(str::to_owned), Arc::new(RwLock::new(None))))
+                .collect(),
+            changed: Arc::new(Notify::new()),
         }
     }
-}
-impl From<std::io::Error> for ReadExtTempError {
-    fn from(value: std::io::Error) -> Self {
-        Self::Udp(value)
+
+    /// Like [ExternalTemperatures::new], with the last temperature recorded by the default sensor
+    /// if it is more recent than the sensor timeout.
+    ///
+    /// `--once` exits before any sensor sends a value.
+    pub async fn from_history(config: &Config) -> Result<Self, DBError> {
+        let ext_temps = Self::new(config);
+        let now = Utc::now();
+        let timeout = TimeDelta::minutes(config.external_temperature_sensor.timeout as i64);
+        let samples = crate::db::get_external_temperatures_in_timeframe(
+            &config.db,
+            (now - timeout).naive_utc(),
+            now.naive_utc(),
+        )
+        .await?;
+        *ext_temps.default.write().await = samples.last().map(|x| x.temperature);
+        Ok(ext_temps)
+    }
+
+    /// The temperature relevant for the CMIs of `site`, see [Config::sensor_site]
+    pub fn for_site(&self, site: Option<&str>) -> Arc<RwLock<Option<i32>>> {
+        site.and_then(|s| self.sites.get(s))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    /// Set the temperature of the sensor of `site`
+    pub async fn set(&self, site: Option<&str>, temp: Option<i32>) {
+        let previous = std::mem::replace(&mut *self.for_site(site).write().await, temp);
+        if previous != temp {
+            self.changed.notify_waiters();
+        };
+    }
+
+    /// Wakes up once any temperature changes.
+    ///
+    /// Changes are only seen by futures that were polled or enabled before the change.
+    #[cfg(feature = "http")]
+    pub fn changed(&self) -> tokio::sync::futures::Notified<'_> {
+        self.changed.notified()
+    }
+
+    /// The temperature of each sensor, by site ("default" for the default sensor)
+    #[cfg(feature = "http")]
+    pub async fn by_sensor(&self) -> BTreeMap<String, Option<i32>> {
+        let mut temps = BTreeMap::from([("default".to_owned(), *self.default.read().await)]);
+        for (site, temp) in &self.sites {
+            temps.insert(site.clone(), *temp.read().await);
+        }
+        temps
+    }
+
+    /// The humidity of the sensor of `site` (see [Config::sensor_site]), if it receives one
+    pub fn humidity(&self, site: Option<&str>) -> Option<Humidity> {
+        self.humidity.get(&site.map(str::to_owned)).cloned()
+    }
+
+    /// The dew point relevant for the CMIs of `site`, in tenths of a Degree Centigrade.
+    ///
+    /// None if the temperature or humidity is unknown, or the humidity is older than the timeout
+    /// of the sensor.
+    pub async fn dew_point(&self, config: &Config, site: Option<&str>) -> Option<i32> {
+        let sensor_site = config.sensor_site(site);
+        let (humidity, received) = (*self.humidity(sensor_site)?.read().await)?;
+        let timeout = TimeDelta::minutes(config.ext_temp_sensor(site).timeout as i64);
+        if Utc::now() - received > timeout {
+            return None;
+        };
+        let temp = (*self.for_site(site).read().await)?;
+        dew_point(temp, humidity)
+    }
+
+    /// The dew point of each sensor receiving the humidity, by site ("default" for the default
+    /// sensor), in tenths of a Degree Centigrade
+    pub async fn dew_points(&self, config: &Config) -> BTreeMap<String, Option<i32>> {
+        let mut dew_points = BTreeMap::new();
+        for site in self.humidity.keys() {
+            dew_points.insert(
+                site.clone().unwrap_or_else(|| "default".to_owned()),
+                self.dew_point(config, site.as_deref()).await,
+            );
+        }
+        dew_points
     }
 }
-impl std::error::Error for ReadExtTempError {}
 
-/// Update the external temperature whenever a corresponding value is received from a CMI.
+/// Update the external temperature of the sensor of `site` whenever a corresponding value is
+/// received from any of its sources.
+///
+/// After the timeout of the sensor, the External Temperature is set to None.
+/// Only the default sensor (site None) records its history.
 ///
-/// After config.external_temperature_sensor.timeout minutes, the External Temperature is set to
-/// None
+/// `tx` and `rx` are two ends of the same channel. The [CoeHub](crate::coe_hub::CoeHub) and
+/// other tasks (e.g. the HTTP server) inject temperatures through it.
 pub async fn read_ext_temp(
     config: Arc<Config>,
-    ext_temp: Arc<RwLock<Option<i32>>>,
+    site: Option<String>,
+    ext_temps: ExternalTemperatures,
+    alerts: mpsc::Sender<AlertEvent>,
+    tx: mpsc::Sender<i32>,
+    mut rx: mpsc::Receiver<i32>,
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
-    shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
-) -> Result<(), ReadExtTempError> {
-    info!("Starting external temperature receiver");
-    // crate Udp socket
-    let sock =
-        match UdpSocket::bind((config.external_temperature_sensor.bind_addr.clone(), 5442)).await {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Unable to open Udp Socket to listen for incoming external temperature.");
-                shutdown_tx.send_replace(InShutdown::Yes);
-                return Err(e.into());
-            }
-        };
+) {
+    let sensor = config.ext_temp_sensor(site.as_deref());
+    let sensor_name = site.as_deref().unwrap_or("default");
+    info!("Starting external temperature receiver for the {sensor_name} sensor");
+    // the sources are aborted when this is dropped
+    #[cfg(feature = "mqtt")]
+    let mut sources = JoinSet::new();
+    #[cfg(feature = "mqtt")]
+    if sensor.mqtt.is_some() {
+        sources.spawn(
+            crate::mqtt::mqtt_source(config.clone(), site.clone(), tx.clone()).in_current_span(),
+        );
+    };
+    #[cfg(not(feature = "mqtt"))]
+    if sensor.mqtt.is_some() {
+        warn!(
+            "The {sensor_name} sensor has mqtt configured, but this build does not have the mqtt feature."
+        );
+    };
+    drop(tx);
 
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
-        config.external_temperature_sensor.timeout as u64 * 60,
-    ));
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(sensor.timeout as u64 * 60));
     interval.tick().await;
+    let mut filter = TemperatureFilter::new(sensor.filter.as_ref());
+    let mut last_recorded = None;
+    // alert if no temperature was received for too long
+    let mut last_received = Utc::now();
+    let mut missing_alert_raised = false;
+    let mut db_alert_raised = false;
     loop {
         tokio::select! {
             // we got a temperature value in time
-            temp = read_next_ext_temp_packet(&sock, config.external_temperature_sensor.can_id, config.external_temperature_sensor.pdo_index) => {
-                {
-                    let mut lock = ext_temp.write().await;
-                    *lock = Some(temp);
+            Some(temp) = rx.recv() => {
+                if let Some(filtered) = filter.push(temp) {
+                    trace!("Filtered external temperature: {} °C", filtered as f32 / 10_f32);
+                    ext_temps.set(site.as_deref(), Some(filtered)).await;
+                    interval.reset();
+                    last_received = Utc::now();
+                    if missing_alert_raised {
+                        resolve(&alerts, AlertKey::ExtTempMissing(site.clone()));
+                        missing_alert_raised = false;
+                    };
+                    if site.is_none() {
+                        match record_ext_temp(&config, filtered, last_recorded).await {
+                            Ok(x) => {
+                                last_recorded = x;
+                                if db_alert_raised {
+                                    resolve(&alerts, AlertKey::Db(DbTask::ExtTemp));
+                                    db_alert_raised = false;
+                                };
+                            }
+                            Err(e) => {
+                                warn!("Failed to record the external temperature. Error encountered: {e}");
+                                raise(
+                                    &alerts,
+                                    Alert::Db {
+                                        task: DbTask::ExtTemp,
+                                        error: e.to_string(),
+                                    },
+                                );
+                                db_alert_raised = true;
+                            }
+                        };
+                    };
+                } else {
+                    warn!("Rejected implausible external temperature {} °C.", temp as f32 / 10_f32);
                 }
-                interval.reset();
             }
             // timeout: no correct temp value received
             _ = interval.tick() => {
-                warn!("Got no external temperature within timeout. Now setting it to unknown.");
-                let mut lock = ext_temp.write().await;
-                *lock = None;
+                warn!("Got no external temperature from the {sensor_name} sensor within timeout. Now setting it to unknown.");
+                filter.reset();
+                ext_temps.set(site.as_deref(), None).await;
+                let minutes = (Utc::now() - last_received).num_minutes();
+                if let Some(alerting) = &config.alerting {
+                    if !missing_alert_raised && minutes >= alerting.ext_temp_missing as i64 {
+                        raise(&alerts, Alert::ExtTempMissing { site: site.clone(), minutes });
+                        missing_alert_raised = true;
+                    };
+                };
             }
             _ = watcher.changed() => {
-                debug!("Shutting down the temperature receiver now");
-                return Ok(());
+                debug!("Shutting down the temperature receiver for the {sensor_name} sensor now");
+                return;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tracing::Instrument;
+
+    use crate::config::test_config;
+
+    const CONFIG: &str = "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms: {}
+cmis: []
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  bind_addr: 127.0.0.1
+  can_id: 1
+  pdo_index: 1
+  humidity_pdo_index: 2
+  timeout: 5
+sites:
+  hall:
+    external_temperature_sensor:
+      timeout: 5
+      filter:
+        kind: median
+        window: 1
+        max_jump: 15
+alerting:
+  ext_temp_missing: 0
+  channels: []
+";
+
+    fn config() -> Arc<Config> {
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        Arc::new(test_config(CONFIG, db))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn temperature_times_out() {
+        let config = config();
+        let ext_temps = ExternalTemperatures::new(&config);
+        let ext_temp = ext_temps.for_site(Some("hall"));
+        let (alert_tx, mut alert_rx) = mpsc::channel(8);
+        let (tx, rx) = mpsc::channel(8);
+        let (shutdown_tx, watcher) = tokio::sync::watch::channel(InShutdown::No);
+        let receiver = tokio::spawn(
+            read_ext_temp(
+                config,
+                Some("hall".to_owned()),
+                ext_temps,
+                alert_tx,
+                tx.clone(),
+                rx,
+                watcher,
+            )
+            .in_current_span(),
+        );
+        tx.send(-52).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*ext_temp.read().await, Some(-52));
+        // the filter of the site rejects jumps of more than 15 K
+        tx.send(500).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*ext_temp.read().await, Some(-52));
+
+        // 5 minutes after the last accepted sample
+        tokio::time::sleep(Duration::from_secs(4 * 60)).await;
+        assert_eq!(*ext_temp.read().await, None);
+        assert!(matches!(
+            alert_rx.try_recv(),
+            Ok(AlertEvent::Raised(Alert::ExtTempMissing { site: Some(x), .. })) if x == "hall"
+        ));
+        // the filter starts over after a timeout
+        tx.send(500).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(*ext_temp.read().await, Some(500));
+        assert_eq!(
+            alert_rx.try_recv(),
+            Ok(AlertEvent::Resolved(AlertKey::ExtTempMissing(Some(
+                "hall".to_owned()
+            ))))
+        );
+
+        shutdown_tx.send_replace(InShutdown::Yes);
+        receiver.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sites_without_own_sensor_use_the_default() {
+        let temps = ExternalTemperatures {
+            default: Arc::new(RwLock::new(Some(50))),
+            sites: HashMap::from([("hall".to_owned(), Arc::new(RwLock::new(Some(-20))))]),
+            humidity: HashMap::new(),
+            changed: Arc::default(),
+        };
+        assert_eq!(*temps.for_site(None).read().await, Some(50));
+        assert_eq!(*temps.for_site(Some("church")).read().await, Some(50));
+        assert_eq!(*temps.for_site(Some("hall")).read().await, Some(-20));
+    }
+
+    #[test]
+    fn dew_point_magnus() {
+        assert_eq!(dew_point(200, 1000), Some(200));
+        assert_eq!(dew_point(250, 600), Some(167));
+        assert_eq!(dew_point(-50, 800), Some(-79));
+        assert_eq!(dew_point(200, 0), None);
+        assert_eq!(dew_point(200, 1001), None);
+    }
+
+    #[tokio::test]
+    async fn dew_point_needs_recent_humidity() {
+        let config = config();
+        let temps = ExternalTemperatures::new(&config);
+        // only the default sensor receives the humidity
+        assert!(temps.humidity(Some("hall")).is_none());
+        let humidity = temps.humidity(None).unwrap();
+        *temps.for_site(None).write().await = Some(250);
+        assert_eq!(temps.dew_point(&config, None).await, None);
+
+        *humidity.write().await = Some((600, Utc::now()));
+        assert_eq!(temps.dew_point(&config, None).await, Some(167));
+        assert_eq!(temps.dew_point(&config, Some("church")).await, Some(167));
+        assert_eq!(temps.dew_point(&config, Some("hall")).await, None);
+        assert_eq!(
+            temps.dew_points(&config).await,
+            BTreeMap::from([("default".to_owned(), Some(167))])
+        );
+
+        *humidity.write().await = Some((600, Utc::now() - TimeDelta::minutes(6)));
+        assert_eq!(temps.dew_point(&config, None).await, None);
+    }
+
+    #[sqlx::test(fixtures("002_empty"))]
+    async fn recent_temperature_from_history(db: sqlx::SqlitePool) {
+        let config = test_config(CONFIG, db);
+        for (minutes_ago, temperature) in [(20, -30), (3, -40)] {
+            crate::db::insert_external_temperature(
+                &config.db,
+                &ExternalTemperatureSample {
+                    recorded_at: Utc::now() - TimeDelta::minutes(minutes_ago),
+                    temperature,
+                },
+            )
+            .await
+            .unwrap();
+        }
+        let temps = ExternalTemperatures::from_history(&config).await.unwrap();
+        assert_eq!(*temps.for_site(None).read().await, Some(-40));
+        // sites with their own sensor do not record a history
+        assert_eq!(*temps.for_site(Some("hall")).read().await, None);
+    }
+
+    #[test]
+    fn normalize_accepted_units() {
+        let all = [
+            TemperatureUnit::CelsiusTenths,
+            TemperatureUnit::KelvinTenths,
+            TemperatureUnit::Dimensionless,
+            TemperatureUnit::DimensionlessTenths,
+        ];
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::DegreeCentigrade_Tens(-52), &all),
+            Some(-52)
+        );
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::DegreeKelvin_Tens(2680), &all),
+            Some(-52)
+        );
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::Dimensionless(-5), &all),
+            Some(-50)
+        );
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::Dimensionless_Tens(-52), &all),
+            Some(-52)
+        );
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::Percent_Tens(500), &all),
+            None
+        );
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::DegreeKelvin_Tens(i32::MIN), &all),
+            None
+        );
+    }
+
+    #[test]
+    fn filter_median() {
+        let config = ExtTempFilterConfig {
+            kind: ExtTempFilterKind::Median,
+            window: 3,
+            max_jump: None,
+        };
+        let mut filter = TemperatureFilter::new(Some(&config));
+        assert_eq!(filter.push(10), Some(10));
+        assert_eq!(filter.push(30), Some(20));
+        assert_eq!(filter.push(500), Some(30));
+        assert_eq!(filter.push(40), Some(40));
+        assert_eq!(filter.push(35), Some(40));
+    }
+
+    #[test]
+    fn filter_moving_average() {
+        let config = ExtTempFilterConfig {
+            kind: ExtTempFilterKind::MovingAverage,
+            window: 2,
+            max_jump: None,
+        };
+        let mut filter = TemperatureFilter::new(Some(&config));
+        assert_eq!(filter.push(10), Some(10));
+        assert_eq!(filter.push(20), Some(15));
+        assert_eq!(filter.push(40), Some(30));
+    }
+
+    #[test]
+    fn filter_rejects_jumps() {
+        let config = ExtTempFilterConfig {
+            kind: ExtTempFilterKind::Median,
+            window: 1,
+            max_jump: Some(15.0),
+        };
+        let mut filter = TemperatureFilter::new(Some(&config));
+        assert_eq!(filter.push(-50), Some(-50));
+        assert_eq!(filter.push(200), None);
+        assert_eq!(filter.push(-40), Some(-40));
+        // the temperature really jumped
+        assert_eq!(filter.push(200), None);
+        assert_eq!(filter.push(200), None);
+        assert_eq!(filter.push(200), Some(200));
+        // after a reset, anything goes
+        filter.reset();
+        assert_eq!(filter.push(-100), Some(-100));
+        // extreme values do not overflow
+        filter.reset();
+        assert_eq!(filter.push(i32::MIN), Some(i32::MIN));
+        assert_eq!(filter.push(i32::MAX), None);
+    }
+
+    #[test]
+    fn normalize_rejects_other_units() {
+        let only_celsius = [TemperatureUnit::CelsiusTenths];
+        assert_eq!(
+            normalize_temperature(AnalogueCOEValue::DegreeKelvin_Tens(2680), &only_celsius),
+            None
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Parent and child resources in CT.
+//!
+//! A booking of a parent resource (e.g. "Hall") may imply heating of its children (e.g. "Hall
+//! stage"), and vice versa. The hierarchy is pulled from CT and kept in the DB.
+
+use std::collections::HashMap;
+
+use crate::config::ResourceHierarchyConfig;
+
+/// All resources whose bookings cause `resource` to be heated, including itself
+pub fn implying_resources(
+    config: Option<&ResourceHierarchyConfig>,
+    parents: &HashMap<i64, i64>,
+    resource: i64,
+) -> Vec<i64> {
+    let mut implying = vec![resource];
+    let Some(config) = config else {
+        return implying;
+    };
+    if config.parent_implies_children {
+        let mut current = resource;
+        // the hierarchy comes from CT, so do not trust it to be free of cycles
+        while let Some(&parent) = parents.get(&current) {
+            if implying.contains(&parent) {
+                break;
+            };
+            implying.push(parent);
+            current = parent;
+        }
+    };
+    if config.children_imply_parent {
+        let mut to_visit = vec![resource];
+        while let Some(current) = to_visit.pop() {
+            for (&child, _) in parents.iter().filter(|(_, &p)| p == current) {
+                if !implying.contains(&child) {
+                    implying.push(child);
+                    to_visit.push(child);
+                };
+            }
+        }
+    };
+    implying
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hierarchy(
+        parent_implies_children: bool,
+        children_imply_parent: bool,
+    ) -> ResourceHierarchyConfig {
+        ResourceHierarchyConfig {
+            parent_implies_children,
+            children_imply_parent,
+        }
+    }
+
+    #[test]
+    fn follows_the_configured_directions() {
+        // 1 is the hall, 2 its stage, 3 the stage's backstage area
+        let parents = HashMap::from([(2, 1), (3, 2)]);
+        assert_eq!(implying_resources(None, &parents, 2), vec![2]);
+        assert_eq!(
+            implying_resources(Some(&hierarchy(true, false)), &parents, 3),
+            vec![3, 2, 1]
+        );
+        let mut implying = implying_resources(Some(&hierarchy(false, true)), &parents, 1);
+        implying.sort();
+        assert_eq!(implying, vec![1, 2, 3]);
+        let mut implying = implying_resources(Some(&hierarchy(true, true)), &parents, 2);
+        implying.sort();
+        assert_eq!(implying, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn survives_cycles() {
+        let parents = HashMap::from([(1, 2), (2, 1)]);
+        assert_eq!(
+            implying_resources(Some(&hierarchy(true, true)), &parents, 1),
+            vec![1, 2]
+        );
+    }
+}
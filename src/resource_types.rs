@@ -0,0 +1,243 @@
+//! Rooms for all CT resources of a type, so new rooms are synced without listing them in the
+//! config.
+//!
+//! The resources are pulled from CT at startup. Each gets a pdo index from the pdo_indices of
+//! its type or the next free one of auto_pdo_indices. Handed out indices are stored in the DB, so
+//! they do not change when resources are added later.
+
+use std::collections::{HashMap, HashSet};
+
+use tracing::{info, warn};
+
+use crate::{
+    config::{AssociatedRoomConfig, Config, ResourceTypeRooms},
+    db::{DBError, ResourceTypeRoom},
+};
+
+/// Add a room for each of `resources` (resource id, name) of `rooms` to `cmi_rooms`.
+///
+/// Resources configured as rooms are skipped. `remembered` are the pdo indices handed out on
+/// earlier starts, by resource id. Returns the rooms added.
+fn add_rooms(
+    cmi_rooms: &mut Vec<AssociatedRoomConfig>,
+    rooms: &ResourceTypeRooms,
+    resources: &[(i64, String)],
+    remembered: &HashMap<i64, u8>,
+) -> Vec<ResourceTypeRoom> {
+    let mut used = cmi_rooms
+        .iter()
+        .map(|room| room.pdo_index)
+        .collect::<HashSet<_>>();
+    // indices of resources missing from CT for now, so they get theirs back when they return
+    let reserved = remembered
+        .values()
+        .chain(rooms.pdo_indices.values())
+        .copied()
+        .collect::<HashSet<_>>();
+    // resources with a fixed index first, so auto indices do not take theirs
+    let (fixed, others): (Vec<_>, Vec<_>) = resources
+        .iter()
+        .filter(|(id, _)| !cmi_rooms.iter().any(|room| room.churchtools_id == *id))
+        .partition(|(id, _)| rooms.pdo_indices.contains_key(id) || remembered.contains_key(id));
+    let mut auto = rooms.auto_pdo_indices.iter().copied();
+    let mut added = vec![];
+    for (id, name) in fixed.into_iter().chain(others) {
+        let pdo_index = match rooms.pdo_indices.get(id).or(remembered.get(id)) {
+            Some(&x) => x,
+            None => match auto.find(|x| !used.contains(x) && !reserved.contains(x)) {
+                Some(x) => x,
+                None => {
+                    warn!(
+                        "Resource {name} ({id}) of type {} has no free pdo index. Add one to auto_pdo_indices.",
+                        rooms.type_id
+                    );
+                    continue;
+                }
+            },
+        };
+        if !used.insert(pdo_index) {
+            warn!(
+                "pdo index {} of resource {name} ({id}) is already used on this CMI. Skipping it.",
+                pdo_index + 1
+            );
+            continue;
+        };
+        // room names identify rooms, so they need to be unique
+        let name = if cmi_rooms.iter().any(|room| &room.name == name) {
+            format!("{name} ({id})")
+        } else {
+            name.clone()
+        };
+        cmi_rooms.push(AssociatedRoomConfig {
+            name: name.clone(),
+            churchtools_id: *id,
+            pdo_index,
+            ..rooms.template.clone()
+        });
+        added.push(ResourceTypeRoom {
+            resource_id: *id,
+            type_id: rooms.type_id,
+            name,
+            pdo_index,
+        });
+    }
+    added
+}
+
+/// Add the rooms of all resource types to their CMIs.
+///
+/// If CT cannot be reached (or `offline` is set), the rooms added on earlier starts are used.
+pub async fn add_resource_type_rooms(config: &mut Config, offline: bool) -> Result<(), DBError> {
+    let mut cmis = std::mem::take(&mut config.cmis);
+    for cmi in &mut cmis {
+        let remembered = crate::db::get_resource_type_rooms(&config.db, &cmi.host).await?;
+        for rooms in &cmi.resource_types {
+            let resources = if offline {
+                None
+            } else {
+                crate::pull_from_ct::get_resources_of_type(config, rooms.type_id)
+                    .await
+                    .inspect_err(|e| {
+                        warn!(
+                            "Failed to get the resources of type {} from CT. Using those known from earlier starts. Error encountered: {e}",
+                            rooms.type_id
+                        );
+                    })
+                    .ok()
+            };
+            let resources = resources.unwrap_or_else(|| {
+                remembered
+                    .iter()
+                    .filter(|room| room.type_id == rooms.type_id)
+                    .map(|room| (room.resource_id, room.name.clone()))
+                    .collect()
+            });
+            let added = add_rooms(
+                &mut cmi.rooms,
+                rooms,
+                &resources,
+                &remembered
+                    .iter()
+                    .map(|room| (room.resource_id, room.pdo_index))
+                    .collect(),
+            );
+            info!(
+                "Added {} rooms of resource type {} to CMI {}.",
+                added.len(),
+                rooms.type_id,
+                cmi.host
+            );
+            for room in &added {
+                crate::db::set_resource_type_room(&config.db, &cmi.host, room).await?;
+            }
+        }
+    }
+    config.cmis = cmis;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::config::test_config;
+
+    const CONFIG: &str = "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms:
+  Hall:
+    churchtools_id: 1
+  template:
+    churchtools_id: 0
+    preheat_minutes: 45
+cmis:
+  - host: cmi
+    our_virtual_can_id: 16
+    rooms:
+      - name: Hall
+        pdo_index: 1
+    resource_types:
+      - type_id: 3
+        template: template
+        pdo_indices:
+          12: 5
+        auto_pdo_indices: [1, 2, 3, 4]
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  timeout: 5
+";
+
+    #[tokio::test]
+    async fn resources_get_stable_pdo_indices() {
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let mut config = test_config(CONFIG, db);
+        let cmi = &mut config.cmis[0];
+        let resources = [
+            (1, "Hall".to_owned()),
+            (10, "Chapel".to_owned()),
+            (11, "Hall".to_owned()),
+            (12, "Office".to_owned()),
+            (13, "Kitchen".to_owned()),
+        ];
+        // the kitchen got its index on an earlier start
+        let remembered = HashMap::from([(13, 1)]);
+        let added = add_rooms(
+            &mut cmi.rooms,
+            &cmi.resource_types[0],
+            &resources,
+            &remembered,
+        );
+        let indices = added
+            .iter()
+            .map(|room| (room.name.as_str(), room.pdo_index))
+            .collect::<Vec<_>>();
+        // Hall (1) is configured as a room, so its index 0 is taken
+        assert_eq!(
+            indices,
+            vec![
+                ("Office", 4),
+                ("Kitchen", 1),
+                ("Chapel", 2),
+                ("Hall (11)", 3)
+            ]
+        );
+        let chapel = cmi.rooms.iter().find(|room| room.name == "Chapel").unwrap();
+        assert_eq!(chapel.churchtools_id, 10);
+        assert_eq!(chapel.preheat_minutes, 45);
+
+        // no more free indices
+        let added = add_rooms(
+            &mut cmi.rooms,
+            &cmi.resource_types[0],
+            &[(14, "Attic".to_owned())],
+            &remembered,
+        );
+        assert!(added.is_empty());
+    }
+
+    #[tokio::test]
+    async fn indices_of_missing_resources_are_kept() {
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let mut config = test_config(CONFIG, db);
+        let cmi = &mut config.cmis[0];
+        // the kitchen got index 2 on an earlier start, but is missing from CT for now
+        let remembered = HashMap::from([(13, 2)]);
+        let added = add_rooms(
+            &mut cmi.rooms,
+            &cmi.resource_types[0],
+            &[(10, "Chapel".to_owned()), (14, "Attic".to_owned())],
+            &remembered,
+        );
+        let indices = added
+            .iter()
+            .map(|room| (room.name.as_str(), room.pdo_index))
+            .collect::<Vec<_>>();
+        assert_eq!(indices, vec![("Chapel", 1), ("Attic", 3)]);
+    }
+}
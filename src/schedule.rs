@@ -0,0 +1,339 @@
+//! Schedules for recurring tasks: a fixed interval, optionally aligned to the wall clock, or cron
+//! expressions in local time.
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use serde::Deserialize;
+
+/// A field of a cron expression, as a bit set of the allowed values
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CronField {
+    allowed: u64,
+    /// whether the field was `*` (matters for day of month and day of week)
+    any: bool,
+}
+impl CronField {
+    /// Parse a field with values in `min..=max`, e.g. `*`, `*/5`, `1,3`, `6-22` or `0-30/10`
+    fn parse(field: &str, min: u8, max: u8) -> Result<Self, String> {
+        let mut allowed = 0_u64;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (
+                    range,
+                    step.parse::<u8>()
+                        .ok()
+                        .filter(|x| *x > 0)
+                        .ok_or_else(|| format!("invalid step in {part}"))?,
+                ),
+                None => (part, 1),
+            };
+            let (from, to) = if range == "*" {
+                (min, max)
+            } else {
+                let parse = |x: &str| {
+                    x.parse::<u8>()
+                        .ok()
+                        .filter(|x| (min..=max).contains(x))
+                        .ok_or_else(|| format!("{x} is not within {min}-{max}"))
+                };
+                match range.split_once('-') {
+                    Some((from, to)) => (parse(from)?, parse(to)?),
+                    // `5/10` means from 5 to the end
+                    None if step > 1 => (parse(range)?, max),
+                    None => (parse(range)?, parse(range)?),
+                }
+            };
+            if from > to {
+                return Err(format!("range {range} is empty"));
+            }
+            for x in (from..=to).step_by(step.into()) {
+                allowed |= 1 << x;
+            }
+        }
+        Ok(Self {
+            allowed,
+            any: field == "*",
+        })
+    }
+
+    fn contains(&self, x: u32) -> bool {
+        self.allowed & (1 << x) != 0
+    }
+}
+
+/// A cron expression (minute, hour, day of month, month, day of week), evaluated in local time
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub(crate) struct CronExpr {
+    minutes: CronField,
+    hours: CronField,
+    days: CronField,
+    months: CronField,
+    weekdays: CronField,
+}
+impl TryFrom<String> for CronExpr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let fields = value.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "cron expression `{value}` does not have 5 fields (minute hour day month weekday)"
+            ));
+        };
+        let invalid = |e| format!("invalid cron expression `{value}`: {e}");
+        let mut weekdays = CronField::parse(weekdays, 0, 7).map_err(invalid)?;
+        // 7 is sunday as well
+        if weekdays.contains(7) {
+            weekdays.allowed |= 1;
+        };
+        Ok(Self {
+            minutes: CronField::parse(minutes, 0, 59).map_err(invalid)?,
+            hours: CronField::parse(hours, 0, 23).map_err(invalid)?,
+            days: CronField::parse(days, 1, 31).map_err(invalid)?,
+            months: CronField::parse(months, 1, 12).map_err(invalid)?,
+            weekdays,
+        })
+    }
+}
+impl CronExpr {
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days.contains(time.day());
+        let weekday = self
+            .weekdays
+            .contains(time.weekday().num_days_from_sunday());
+        // like in cron, a day matches if either is set and matches
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        };
+        day_matches && self.months.contains(time.month())
+    }
+
+    /// The first time matching this expression after `time`
+    pub fn next_after<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let start = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut next = start;
+        // every expression matches at least once within 8 years (Feb 29 on a given weekday)
+        while next < start + Duration::days(8 * 366) {
+            if !self.matches_day(&next) {
+                next = next.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !self.hours.contains(next.hour()) {
+                next = next.with_minute(0)? + Duration::hours(1);
+            } else if !self.minutes.contains(next.minute()) {
+                next += Duration::minutes(1);
+            } else {
+                // skip times that do not exist because of a DST change
+                match time.timezone().from_local_datetime(&next).earliest() {
+                    Some(x) => return Some(x),
+                    None => next += Duration::minutes(1),
+                };
+            }
+        }
+        None
+    }
+}
+
+/// Times of a schedule on the wall clock
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum WallClock {
+    /// every `period` since local midnight
+    Aligned { period: Duration },
+    /// at the minutes matching any of the expressions
+    Cron(Vec<CronExpr>),
+}
+impl WallClock {
+    /// The first time after `time`, `second` seconds into the minute
+    fn next_after<Tz: TimeZone>(&self, time: &DateTime<Tz>, second: u8) -> Option<DateTime<Tz>> {
+        let second = Duration::seconds(second.into());
+        match self {
+            Self::Aligned { period } => {
+                let now = time.naive_local();
+                let midnight = now.date().and_hms_opt(0, 0, 0)?;
+                let periods = (now - midnight - second)
+                    .num_seconds()
+                    .div_euclid(period.num_seconds())
+                    + 1;
+                let mut next = midnight + second + *period * periods.try_into().ok()?;
+                // start over at midnight if the period does not divide the day
+                if next.date() != now.date() {
+                    next = next.date().and_hms_opt(0, 0, 0)? + second;
+                };
+                let tz = time.timezone();
+                // skip times that do not exist because of a DST change
+                tz.from_local_datetime(&next).earliest().or_else(|| {
+                    tz.from_local_datetime(&(next + Duration::hours(1)))
+                        .earliest()
+                })
+            }
+            Self::Cron(exprs) => exprs
+                .iter()
+                .filter_map(|x| x.next_after(&(time.clone() - second)))
+                .min()
+                .map(|x| x + second),
+        }
+    }
+}
+
+/// When a recurring task runs
+pub(crate) enum Schedule {
+    Interval(tokio::time::Interval),
+    WallClock {
+        times: WallClock,
+        /// seconds into the minute
+        second: u8,
+        first: bool,
+    },
+}
+impl Schedule {
+    /// Run every `interval`, or on `cron` if it is set.
+    ///
+    /// With `second`, runs are aligned to the wall clock: they start `second` seconds into the
+    /// minute, and intervals count from midnight.
+    pub fn new(
+        interval: std::time::Duration,
+        cron: Option<&[CronExpr]>,
+        second: Option<u8>,
+    ) -> Self {
+        let times = match cron {
+            Some(exprs) if !exprs.is_empty() => WallClock::Cron(exprs.to_vec()),
+            _ => match (second, Duration::from_std(interval)) {
+                (Some(_), Ok(period)) if period.num_seconds() > 0 => WallClock::Aligned { period },
+                _ => return Self::Interval(tokio::time::interval(interval)),
+            },
+        };
+        Self::WallClock {
+            times,
+            second: second.unwrap_or(0),
+            first: true,
+        }
+    }
+
+    /// Wait until the task is due. The first tick completes immediately.
+    pub async fn tick(&mut self) {
+        match self {
+            Self::Interval(interval) => {
+                interval.tick().await;
+            }
+            Self::WallClock {
+                times,
+                second,
+                first,
+            } => {
+                if std::mem::take(first) {
+                    return;
+                };
+                let now = Local::now();
+                let Some(next) = times.next_after(&now, *second) else {
+                    // nothing matches, so never run again
+                    std::future::pending::<()>().await;
+                    return;
+                };
+                tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    fn at(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<chrono::Utc> {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn cron_next_after() {
+        let cron = |x: &str| CronExpr::try_from(x.to_owned()).unwrap();
+        // every 2 min during the day
+        let day = cron("*/2 6-22 * * *");
+        assert_eq!(
+            day.next_after(&at(2025, 1, 6, 12, 1)),
+            Some(at(2025, 1, 6, 12, 2))
+        );
+        assert_eq!(
+            day.next_after(&at(2025, 1, 6, 12, 2)),
+            Some(at(2025, 1, 6, 12, 4))
+        );
+        assert_eq!(
+            day.next_after(&at(2025, 1, 6, 22, 58)),
+            Some(at(2025, 1, 7, 6, 0))
+        );
+        // every 30 min at night
+        let night = cron("0,30 23,0-5 * * *");
+        assert_eq!(
+            night.next_after(&at(2025, 1, 6, 22, 58)),
+            Some(at(2025, 1, 6, 23, 0))
+        );
+        assert_eq!(
+            night.next_after(&at(2025, 12, 31, 23, 45)),
+            Some(at(2026, 1, 1, 0, 0))
+        );
+        // sundays (7) and the first of the month
+        let sundays = cron("15 10 1 * 7");
+        assert_eq!(
+            sundays.next_after(&at(2025, 1, 6, 12, 0)),
+            Some(at(2025, 1, 12, 10, 15))
+        );
+        assert_eq!(
+            sundays.next_after(&at(2025, 1, 26, 12, 0)),
+            Some(at(2025, 2, 1, 10, 15))
+        );
+        assert_eq!(
+            cron("0 0 29 2 *").next_after(&at(2025, 1, 1, 0, 0)),
+            Some(at(2028, 2, 29, 0, 0))
+        );
+        assert_eq!(cron("0 0 31 2 *").next_after(&at(2025, 1, 1, 0, 0)), None);
+
+        assert!(CronExpr::try_from("* * * *".to_owned()).is_err());
+        assert!(CronExpr::try_from("60 * * * *".to_owned()).is_err());
+        assert!(CronExpr::try_from("*/0 * * * *".to_owned()).is_err());
+        assert!(CronExpr::try_from("* 5-3 * * *".to_owned()).is_err());
+    }
+
+    #[test]
+    fn wall_clock_alignment() {
+        let every_2_min = WallClock::Aligned {
+            period: Duration::minutes(2),
+        };
+        let late = at(2025, 1, 6, 12, 1) + Duration::seconds(40);
+        assert_eq!(
+            every_2_min.next_after(&late, 30),
+            Some(at(2025, 1, 6, 12, 2) + Duration::seconds(30))
+        );
+        assert_eq!(
+            every_2_min.next_after(&at(2025, 1, 6, 12, 0), 30),
+            Some(at(2025, 1, 6, 12, 0) + Duration::seconds(30))
+        );
+        assert_eq!(
+            every_2_min.next_after(&(at(2025, 1, 6, 23, 58) + Duration::seconds(30)), 30),
+            Some(at(2025, 1, 7, 0, 0) + Duration::seconds(30))
+        );
+        // 7 min do not divide the day, so the first run of a day is at midnight
+        let every_7_min = WallClock::Aligned {
+            period: Duration::minutes(7),
+        };
+        assert_eq!(
+            every_7_min.next_after(&at(2025, 1, 6, 23, 55), 0),
+            Some(at(2025, 1, 7, 0, 0))
+        );
+
+        let cron = WallClock::Cron(vec![CronExpr::try_from("*/5 * * * *".to_owned()).unwrap()]);
+        assert_eq!(
+            cron.next_after(&(at(2025, 1, 6, 12, 5) + Duration::seconds(10)), 30),
+            Some(at(2025, 1, 6, 12, 5) + Duration::seconds(30))
+        );
+        assert_eq!(
+            cron.next_after(&(at(2025, 1, 6, 12, 5) + Duration::seconds(30)), 30),
+            Some(at(2025, 1, 6, 12, 10) + Duration::seconds(30))
+        );
+    }
+}
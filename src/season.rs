@@ -0,0 +1,103 @@
+//! The heating season: the parts of the year in which rooms may be heated at all.
+
+use chrono::{Datelike, NaiveDate};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+/// A day of the year, e.g. 10-01 for the first of October
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub(crate) struct MonthDay {
+    month: u32,
+    day: u32,
+}
+impl TryFrom<String> for MonthDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("`{value}` is not a day of the year like 10-01 (month-day)");
+        let (month, day) = value.split_once('-').ok_or_else(invalid)?;
+        let month = month.parse::<u32>().map_err(|_| invalid())?;
+        let day = day.parse::<u32>().map_err(|_| invalid())?;
+        // a leap year, so that 02-29 is valid
+        NaiveDate::from_ymd_opt(2000, month, day).ok_or_else(invalid)?;
+        Ok(Self { month, day })
+    }
+}
+impl MonthDay {
+    fn of(date: NaiveDate) -> Self {
+        Self {
+            month: date.month(),
+            day: date.day(),
+        }
+    }
+}
+
+/// A part of the year in which rooms may be heated, e.g. from 10-01 until 04-30.
+///
+/// Both days are included. If `until` is before `from`, the range spans the new year.
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub(crate) struct SeasonRange {
+    /// first day, e.g. 10-01
+    #[schemars(with = "String")]
+    pub from: MonthDay,
+    /// last day, e.g. 04-30
+    #[schemars(with = "String")]
+    pub until: MonthDay,
+}
+impl SeasonRange {
+    fn contains(&self, date: NaiveDate) -> bool {
+        let day = MonthDay::of(date);
+        if self.from <= self.until {
+            self.from <= day && day <= self.until
+        } else {
+            self.from <= day || day <= self.until
+        }
+    }
+}
+
+/// Whether `date` is within any of the `ranges`
+pub(crate) fn in_season(ranges: &[SeasonRange], date: NaiveDate) -> bool {
+    ranges.iter().any(|range| range.contains(date))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(from: &str, until: &str) -> SeasonRange {
+        SeasonRange {
+            from: MonthDay::try_from(from.to_owned()).unwrap(),
+            until: MonthDay::try_from(until.to_owned()).unwrap(),
+        }
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    #[test]
+    fn parse_month_day() {
+        assert!(MonthDay::try_from("02-29".to_owned()).is_ok());
+        assert!(MonthDay::try_from("02-30".to_owned()).is_err());
+        assert!(MonthDay::try_from("13-01".to_owned()).is_err());
+        assert!(MonthDay::try_from("10".to_owned()).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let winter = [range("10-01", "04-30")];
+        assert!(in_season(&winter, date("2025-10-01")));
+        assert!(in_season(&winter, date("2025-12-31")));
+        assert!(in_season(&winter, date("2026-01-01")));
+        assert!(in_season(&winter, date("2026-04-30")));
+        assert!(!in_season(&winter, date("2026-05-01")));
+        assert!(!in_season(&winter, date("2026-09-30")));
+
+        // a cold spell in June is heated for as well
+        let with_june = [range("01-01", "04-30"), range("06-01", "06-14")];
+        assert!(in_season(&with_june, date("2026-06-14")));
+        assert!(!in_season(&with_june, date("2026-06-15")));
+        assert!(!in_season(&[], date("2026-01-01")));
+    }
+}
@@ -0,0 +1,329 @@
+//! Commissioning checks: can this installation reach everything it needs?
+//!
+//! Each check is run once and reported as PASS or FAIL with a short detail, so an installer can
+//! see at a glance what is missing before starting the daemon.
+
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use coe::{AnalogueCOEValue, COEValue, Packet, Payload};
+use tokio::net::UdpSocket;
+
+use crate::{
+    coe_hub::read_next_packet,
+    coe_sender::{packets, CoeSender, COE_PORT},
+    config::{CMIConfig, Config},
+};
+
+/// What to send to the CMIs
+#[derive(Debug)]
+pub(crate) struct SelftestOptions {
+    /// PDO index (1-64) the test value is sent on
+    pub pdo_index: u8,
+    /// the test value, sent as dimensionless analogue value
+    pub value: i32,
+    /// PDO index (1-64) each CMI echoes the test value back on from its feedback_can_id
+    pub loopback_pdo_index: Option<u8>,
+    /// wait at most this long for each echo
+    pub timeout: Duration,
+}
+
+/// The outcome of a single check
+#[derive(Debug)]
+pub(crate) struct Check {
+    pub name: String,
+    /// the detail to print, Err if the check failed
+    pub result: Result<String, String>,
+}
+impl Check {
+    fn new(name: impl Into<String>, result: Result<String, String>) -> Self {
+        Self {
+            name: name.into(),
+            result,
+        }
+    }
+}
+
+/// Run all checks
+pub(crate) async fn run(config: &Config, options: &SelftestOptions) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    // bind the sockets receiving CoE first. The echos of the CMIs are received on them.
+    let mut sockets = BTreeMap::new();
+    let bind_addrs = std::iter::once(&config.external_temperature_sensor)
+        .chain(
+            config
+                .sites
+                .values()
+                .filter_map(|site| site.external_temperature_sensor.as_ref()),
+        )
+        .filter_map(|sensor| sensor.coe.as_ref())
+        .map(|coe| coe.bind_addr.clone());
+    for bind_addr in bind_addrs {
+        if sockets.contains_key(&bind_addr) {
+            continue;
+        };
+        let name = format!("bind {bind_addr}:{COE_PORT}");
+        match UdpSocket::bind((bind_addr.clone(), COE_PORT)).await {
+            Ok(sock) => {
+                sockets.insert(bind_addr, Arc::new(sock));
+                checks.push(Check::new(name, Ok("bound".to_owned())));
+            }
+            Err(e) => checks.push(Check::new(
+                name,
+                Err(format!("{e}. Is the daemon still running?")),
+            )),
+        };
+    }
+
+    let bind_port = config.global.emiter_bind_port.unwrap_or(0);
+    let mut sender = CoeSender::new(
+        config.global.emiter_bind_addr.clone(),
+        bind_port,
+        Duration::from_secs(config.global.cmi_dns_refresh.unwrap_or(10) * 60),
+    );
+    if bind_port == COE_PORT {
+        if let Some(sock) = sockets.get(&config.global.emiter_bind_addr) {
+            sender = sender.with_socket(sock.clone());
+        };
+    };
+    let feedback_socket = config
+        .external_temperature_sensor
+        .coe
+        .as_ref()
+        .and_then(|coe| sockets.get(&coe.bind_addr));
+    for cmi in &config.cmis {
+        if let Some(room) = room_on_output(cmi, options.pdo_index - 1) {
+            checks.push(Check::new(
+                format!("send to {}", cmi.host),
+                Err(format!(
+                    "CAN id {} PDO {} is an output of room {room}. Choose another --pdo.",
+                    cmi.our_virtual_can_id, options.pdo_index
+                )),
+            ));
+            continue;
+        };
+        let payload = Payload::new(
+            cmi.our_virtual_can_id,
+            options.pdo_index - 1,
+            COEValue::Analogue(AnalogueCOEValue::Dimensionless(options.value)),
+        );
+        let mut sent = Ok(());
+        for packet in packets(&[payload]) {
+            sent = sent.and(sender.send_to(packet, &cmi.host, cmi.ip_version).await);
+        }
+        let sent_ok = sent.is_ok();
+        checks.push(Check::new(
+            format!("send to {}", cmi.host),
+            sent.map(|()| {
+                format!(
+                    "{} on CAN id {} PDO {}",
+                    options.value, cmi.our_virtual_can_id, options.pdo_index
+                )
+            })
+            .map_err(|e| e.to_string()),
+        ));
+
+        let Some(loopback_pdo_index) = options.loopback_pdo_index else {
+            continue;
+        };
+        // the echo would be taken for the state of that room
+        if let Some(room) = cmi
+            .rooms
+            .iter()
+            .find(|room| room.feedback_pdo_index == Some(loopback_pdo_index - 1))
+        {
+            checks.push(Check::new(
+                format!("loopback from {}", cmi.host),
+                Err(format!(
+                    "PDO {loopback_pdo_index} is the feedback of room {}. Choose another --loopback-pdo.",
+                    room.name
+                )),
+            ));
+            continue;
+        };
+        let result = match (sent_ok, cmi.feedback_can_id, feedback_socket) {
+            (false, _, _) => Err("nothing was sent".to_owned()),
+            (_, None, _) => Err("the CMI has no feedback_can_id".to_owned()),
+            (_, _, None) => Err("no socket to receive CoE on".to_owned()),
+            (true, Some(can_id), Some(sock)) => {
+                let echo = wait_for_echo(
+                    sock,
+                    can_id,
+                    loopback_pdo_index - 1,
+                    options.value,
+                    options.timeout,
+                )
+                .await;
+                if echo {
+                    Ok(format!(
+                        "{} on CAN id {can_id} PDO {loopback_pdo_index}",
+                        options.value
+                    ))
+                } else {
+                    Err(format!(
+                        "no echo on CAN id {can_id} PDO {loopback_pdo_index} within {}s",
+                        options.timeout.as_secs()
+                    ))
+                }
+            }
+        };
+        checks.push(Check::new(format!("loopback from {}", cmi.host), result));
+    }
+
+    checks.push(Check::new(
+        "ChurchTools",
+        crate::pull_from_ct::check_reachable(config)
+            .await
+            .map(|(version, resources)| format!("version {version}, {resources} resources"))
+            .map_err(|e| e.to_string()),
+    ));
+    checks.push(Check::new(
+        "DB writable",
+        crate::db::check_writable(&config.db)
+            .await
+            .map(|()| config.db_path.display().to_string())
+            .map_err(|e| e.to_string()),
+    ));
+    checks
+}
+
+/// Wait until `sock` receives `value` from `can_id` on `pdo_index` or `timeout` has passed
+async fn wait_for_echo(
+    sock: &Arc<UdpSocket>,
+    can_id: u8,
+    pdo_index: u8,
+    value: i32,
+    timeout: Duration,
+) -> bool {
+    tokio::time::timeout(timeout, async {
+        while !is_echo(&read_next_packet(sock).await, can_id, pdo_index, value) {}
+    })
+    .await
+    .is_ok()
+}
+
+/// The room whose state, cooling or setpoint `cmi` gets on `pdo_index` (0-63) of
+/// our_virtual_can_id, if any. Sending the test value there would drive that output.
+fn room_on_output(cmi: &CMIConfig, pdo_index: u8) -> Option<&str> {
+    cmi.rooms
+        .iter()
+        .filter(|room| room.can_id == cmi.our_virtual_can_id)
+        .find(|room| {
+            std::iter::once(room.pdo_index)
+                .chain(room.cooling.map(|x| x.pdo_index))
+                .chain(room.setpoint.map(|x| x.pdo_index))
+                .any(|x| x == pdo_index)
+        })
+        .map(|room| room.name.as_str())
+}
+
+/// Whether `packet` contains `value` from `can_id` on `pdo_index`
+fn is_echo(packet: &Packet, can_id: u8, pdo_index: u8, value: i32) -> bool {
+    packet.iter().any(|payload| {
+        payload.node() == can_id
+            && payload.pdo_index() == pdo_index
+            && payload.value() == COEValue::Analogue(AnalogueCOEValue::Dimensionless(value))
+    })
+}
+
+/// Format the checks as table
+pub(crate) fn render(checks: &[Check]) -> String {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    checks
+        .iter()
+        .map(|check| {
+            let (status, detail) = match &check.result {
+                Ok(detail) => ("PASS", detail),
+                Err(detail) => ("FAIL", detail),
+            };
+            format!("{:width$}  {status}  {detail}\n", check.name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn analogue(node: u8, pdo_index: u8, value: AnalogueCOEValue) -> Payload {
+        Payload::new(node, pdo_index, COEValue::Analogue(value))
+    }
+
+    #[test]
+    fn echo_needs_same_can_id_pdo_and_value() {
+        let packet = packets(&[
+            analogue(2, 4, AnalogueCOEValue::Dimensionless(42)),
+            analogue(3, 5, AnalogueCOEValue::DegreeCentigrade_Tens(42)),
+        ])
+        .remove(0);
+        assert!(is_echo(&packet, 2, 4, 42));
+        assert!(!is_echo(&packet, 2, 4, 43));
+        assert!(!is_echo(&packet, 2, 5, 42));
+        assert!(!is_echo(&packet, 1, 4, 42));
+        // the value has to be passed through without a unit
+        assert!(!is_echo(&packet, 3, 5, 42));
+    }
+
+    #[tokio::test]
+    async fn test_value_must_not_drive_an_output() {
+        let yaml = "
+global:
+  ct_pull_frequency: 300
+  ta_push_frequency: 2
+  log_level: debug
+  emiter_bind_addr: 0.0.0.0
+rooms:
+  hall:
+    churchtools_id: 1
+    setpoint:
+      default: 20
+      min: 16
+      max: 22
+  office:
+    churchtools_id: 2
+    setpoint:
+      default: 20
+      min: 16
+      max: 22
+cmis:
+  - host: cmi.local
+    our_virtual_can_id: 59
+    rooms:
+      - name: hall
+        pdo_index: 1
+        setpoint_pdo_index: 64
+      - name: office
+        pdo_index: 2
+        virtual_can_id: 58
+        setpoint_pdo_index: 63
+ct:
+  host: example.church.tools
+  login_token: NOT_THE_LOGIN_TOKEN
+external_temperature_sensor:
+  bind_addr: 127.0.0.1
+  can_id: 1
+  pdo_index: 1
+  timeout: 5
+";
+        let db = sqlx::SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let config = crate::config::test_config(yaml, db);
+        let cmi = &config.cmis[0];
+        assert_eq!(room_on_output(cmi, 63), Some("hall"));
+        assert_eq!(room_on_output(cmi, 0), Some("hall"));
+        // office gets its outputs on another CAN id
+        assert_eq!(room_on_output(cmi, 62), None);
+        assert_eq!(room_on_output(cmi, 1), None);
+    }
+
+    #[test]
+    fn render_table() {
+        let checks = [
+            Check::new("send to cmi", Ok("42 on CAN id 1 PDO 64".to_owned())),
+            Check::new("DB writable", Err("read-only".to_owned())),
+        ];
+        assert_eq!(
+            render(&checks),
+            "send to cmi  PASS  42 on CAN id 1 PDO 64\nDB writable  FAIL  read-only\n"
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! Synthetic bookings, a synthetic external temperature and a fake CMI.
+//!
+//! New installations can run the whole pipeline without CT data (`--simulate`) and without
+//! sending to the real CMIs (`--fake-cmi`, required by `--simulate`), e.g. to soak-test it before
+//! connecting real CT data.
+
+use std::{collections::HashMap, f64::consts::PI, sync::Arc};
+
+use chrono::{DateTime, DurationRound, TimeDelta, Timelike, Utc};
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    coe_hub::read_next_packet,
+    coe_sender::COE_PORT,
+    config::Config,
+    metrics::{Metrics, Task},
+    schedule::Schedule,
+    Booking, InShutdown,
+};
+
+/// The fake CMI listens on this address
+const FAKE_CMI_ADDR: &str = "127.0.0.1";
+
+/// A small xorshift PRNG. Synthetic bookings do not need more.
+#[derive(Debug)]
+struct Rng(u64);
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        Self(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// uniform in 0.0..1.0
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    /// uniform in 0..n
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// Generate bookings starting within `from..until` for all `resources`.
+///
+/// Each resource gets `density` bookings per day on average, starting at a quarter hour and
+/// lasting 1 - 3 hours.
+fn synthetic_bookings(
+    resources: &[i64],
+    from: DateTime<Utc>,
+    until: DateTime<Utc>,
+    density: f64,
+    rng: &mut Rng,
+) -> Vec<Booking> {
+    let mut bookings = vec![];
+    let mut hour = from
+        .duration_trunc(TimeDelta::hours(1))
+        .expect("an hour is a valid duration");
+    while hour < until {
+        for &resource_id in resources {
+            if rng.next_f64() >= density / 24_f64 {
+                continue;
+            };
+            let start_time = hour + TimeDelta::minutes(15 * rng.below(4) as i64);
+            if start_time < from || start_time >= until {
+                continue;
+            };
+            bookings.push(
+                Booking::new(
+                    (rng.next_u64() >> 1) as i64,
+                    resource_id,
+                    start_time,
+                    start_time + TimeDelta::hours(1 + rng.below(3) as i64),
+                )
+                .expect("synthetic bookings last 1 to 3 hours"),
+            );
+        }
+        hour += TimeDelta::hours(1);
+    }
+    bookings
+}
+
+/// The synthetic external temperature at `time`, in tenths of a Degree Centigrade.
+///
+/// It follows a daily curve between -5 °C at 3:00 and 10 °C at 15:00 (UTC).
+fn synthetic_temperature(time: DateTime<Utc>) -> i32 {
+    let hour = time.hour() as f64 + time.minute() as f64 / 60_f64;
+    (25_f64 + 75_f64 * (2_f64 * PI * (hour - 9_f64) / 24_f64).sin()).round() as i32
+}
+
+/// Keep the DB filled with synthetic bookings for the next day, instead of pulling from CT
+pub async fn keep_db_simulated(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    metrics: Arc<Metrics>,
+    density: f64,
+    ready: tokio::sync::watch::Sender<bool>,
+) {
+    info!("Starting synthetic booking task with {density} bookings per room and day");
+    let mut schedule = Schedule::new(
+        tokio::time::Duration::from_secs(config.global.ct_pull_frequency),
+        config.global.ct_pull_schedule.as_deref(),
+        None,
+    );
+    schedule.tick().await;
+    let mut resources = config
+        .cmis
+        .iter()
+        .flat_map(|cmi| &cmi.rooms)
+        .map(|room| room.churchtools_id)
+        .collect::<Vec<_>>();
+    resources.sort_unstable();
+    resources.dedup();
+    let mut rng = Rng::new(Utc::now().timestamp_micros() as u64);
+    let mut generated_until = Utc::now();
+    loop {
+        let run_start = std::time::Instant::now();
+        let until = Utc::now() + TimeDelta::days(1);
+        let bookings = synthetic_bookings(&resources, generated_until, until, density, &mut rng);
+        let res = crate::db::insert_bookings(&config.db, bookings.iter()).await;
+        metrics
+            .record(
+                &config,
+                Task::Pull,
+                run_start.elapsed(),
+                bookings.len(),
+                res.is_ok(),
+            )
+            .await;
+        match res {
+            Ok(()) => {
+                debug!("Generated {} synthetic bookings.", bookings.len());
+                generated_until = until;
+                ready.send_replace(true);
+            }
+            Err(e) => warn!("Failed to store synthetic bookings. Error encountered: {e}"),
+        };
+        if let Err(e) = crate::db::prune_old_bookings(&config.db, config.max_overrun()).await {
+            warn!("Failed to prune old bookings. Error encountered: {e}");
+        };
+        // stop on cancellation or continue after the next tick
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down synthetic booking task now.");
+                return;
+            }
+            _ = schedule.tick() => {}
+        }
+    }
+}
+
+/// Send the synthetic external temperature every minute, like a sensor would
+pub async fn simulate_ext_temp(
+    tx: mpsc::Sender<i32>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    info!("Starting synthetic external temperature task");
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+    loop {
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down synthetic external temperature task now.");
+                return;
+            }
+            _ = interval.tick() => {
+                if tx.send(synthetic_temperature(Utc::now())).await.is_err() {
+                    return;
+                };
+            }
+        }
+    }
+}
+
+/// Send all CoE to the fake CMI instead of the configured CMIs.
+///
+/// The fake CMI needs the CoE port, so the external temperature is not received via CoE, room
+/// feedback is disabled and CoE is not sent from the CoE port.
+pub fn use_fake_cmi(config: &mut Config) {
+    warn!("Sending CoE to the fake CMI on {FAKE_CMI_ADDR} instead of the configured CMIs.");
+    if config.global.emiter_bind_port == Some(COE_PORT) {
+        config.global.emiter_bind_port = None;
+    };
+    for cmi in &mut config.cmis {
+        cmi.host = FAKE_CMI_ADDR.to_owned();
+        cmi.feedback_can_id = None;
+        for room in &mut cmi.rooms {
+            room.feedback_pdo_index = None;
+        }
+    }
+    config.external_temperature_sensor.coe = None;
+    for site in config.sites.values_mut() {
+        if let Some(sensor) = &mut site.external_temperature_sensor {
+            sensor.coe = None;
+        };
+    }
+}
+
+/// Receive CoE like a CMI and log every output that changes
+pub async fn fake_cmi(
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
+) -> Result<(), std::io::Error> {
+    let sock = match UdpSocket::bind((FAKE_CMI_ADDR, COE_PORT)).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Unable to open the Udp Socket of the fake CMI: {e}");
+            shutdown_tx.send_replace(InShutdown::Yes);
+            return Err(e);
+        }
+    };
+    info!("Starting fake CMI on {FAKE_CMI_ADDR}:{COE_PORT}");
+    // (CAN id, pdo index) -> last value
+    let mut outputs = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down fake CMI now.");
+                return Ok(());
+            }
+            packet = read_next_packet(&sock) => {
+                for payload in packet.iter() {
+                    let value = format!("{:?}", payload.value());
+                    let key = (payload.node(), payload.pdo_index());
+                    if outputs.get(&key) != Some(&value) {
+                        info!(
+                            "Fake CMI: CAN id {} output {} is now {value}.",
+                            key.0,
+                            key.1 + 1
+                        );
+                        outputs.insert(key, value);
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bookings_are_within_the_timeframe() {
+        let from: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-01-07T10:20:00+00:00")
+            .unwrap()
+            .into();
+        let until = from + TimeDelta::days(30);
+        let mut rng = Rng::new(42);
+        let bookings = synthetic_bookings(&[1, 2], from, until, 3.0, &mut rng);
+        assert!(bookings.iter().all(|b| from <= b.start_time()
+            && b.start_time() < until
+            && b.end_time() > b.start_time()));
+        // about 3 bookings per room and day
+        assert!((120..240).contains(&bookings.len()));
+        assert!(synthetic_bookings(&[1, 2], from, until, 0.0, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn temperature_follows_the_day() {
+        let at = |time: &str| {
+            synthetic_temperature(
+                DateTime::parse_from_rfc3339(&format!("2024-01-07T{time}+00:00"))
+                    .unwrap()
+                    .into(),
+            )
+        };
+        assert_eq!(at("03:00:00"), -50);
+        assert_eq!(at("15:00:00"), 100);
+        assert_eq!(at("09:00:00"), 25);
+    }
+}
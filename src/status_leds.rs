@@ -0,0 +1,226 @@
+//! Status LEDs on GPIO pins, for headless installations (e.g. a Raspberry Pi in the boiler room).
+//!
+//! The pins are driven via the sysfs GPIO interface, so no additional libraries are needed.
+//! The sysfs files are written on the blocking thread pool.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::StatusLedsConfig,
+    metrics::{Metrics, Task},
+    InShutdown,
+};
+
+/// The heartbeat LED toggles this often
+const HEARTBEAT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// A single GPIO pin used as output
+#[derive(Debug)]
+struct Led {
+    gpio_path: PathBuf,
+    pin: u32,
+    /// whether the last write failed, so failures are logged once
+    failing: AtomicBool,
+}
+impl Led {
+    /// Export `pin` and set it up as output
+    fn setup(gpio_path: &std::path::Path, pin: u32) -> Result<Self, std::io::Error> {
+        let led = Self {
+            gpio_path: gpio_path.to_owned(),
+            pin,
+            failing: AtomicBool::new(false),
+        };
+        if !led.pin_path().exists() {
+            std::fs::write(gpio_path.join("export"), pin.to_string())?;
+        };
+        std::fs::write(led.pin_path().join("direction"), "out")?;
+        Ok(led)
+    }
+
+    fn pin_path(&self) -> PathBuf {
+        self.gpio_path.join(format!("gpio{}", self.pin))
+    }
+
+    fn value_path(&self) -> PathBuf {
+        self.pin_path().join("value")
+    }
+
+    fn set(&self, on: bool) {
+        match std::fs::write(self.value_path(), if on { "1" } else { "0" }) {
+            Ok(()) => {
+                if self.failing.swap(false, Ordering::Relaxed) {
+                    info!("The LED on GPIO {} can be set again.", self.pin);
+                };
+            }
+            Err(e) => {
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    warn!("Unable to set the LED on GPIO {}: {e}", self.pin);
+                };
+            }
+        };
+    }
+
+    /// Turn the LED off and give the pin back
+    fn teardown(&self) {
+        self.set(false);
+        if let Err(e) = std::fs::write(self.gpio_path.join("unexport"), self.pin.to_string()) {
+            debug!("Unable to unexport GPIO {}: {e}", self.pin);
+        };
+    }
+}
+
+/// All configured status LEDs
+#[derive(Debug)]
+pub struct StatusLeds {
+    heartbeat: Option<Led>,
+    ct_reachable: Option<Led>,
+    cmi_ok: Option<Led>,
+    error: Option<Led>,
+}
+impl StatusLeds {
+    /// Set up all configured pins. This may need privileges, so do it before dropping them.
+    pub fn setup(config: &StatusLedsConfig) -> Result<Self, std::io::Error> {
+        let setup = |pin: Option<u32>| pin.map(|x| Led::setup(&config.gpio_path, x)).transpose();
+        Ok(Self {
+            heartbeat: setup(config.heartbeat)?,
+            ct_reachable: setup(config.ct_reachable)?,
+            cmi_ok: setup(config.cmi_ok)?,
+            error: setup(config.error)?,
+        })
+    }
+
+    /// The files written while running. They have to stay writable after dropping privileges.
+    pub fn value_paths(&self) -> Vec<PathBuf> {
+        self.leds().map(Led::value_path).collect()
+    }
+
+    fn leds(&self) -> impl Iterator<Item = &Led> {
+        [
+            &self.heartbeat,
+            &self.ct_reachable,
+            &self.cmi_ok,
+            &self.error,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Show the state of the last pull and push. The LEDs are off until the first run.
+    fn show(&self, metrics: &Metrics) {
+        let pull_ok = metrics.last_run_ok(Task::Pull);
+        let push_ok = metrics.last_run_ok(Task::Push);
+        if let Some(led) = &self.ct_reachable {
+            led.set(pull_ok == Some(true));
+        };
+        if let Some(led) = &self.cmi_ok {
+            led.set(push_ok == Some(true));
+        };
+        if let Some(led) = &self.error {
+            led.set(pull_ok == Some(false) || push_ok == Some(false));
+        };
+    }
+
+    /// Drive the LEDs until shutdown, then turn them off
+    pub async fn run(
+        self,
+        metrics: Arc<Metrics>,
+        mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    ) {
+        info!("Starting status LEDs");
+        let leds = Arc::new(self);
+        let mut interval = tokio::time::interval(HEARTBEAT);
+        let mut heartbeat = false;
+        loop {
+            tokio::select! {
+                _ = watcher.changed() => {
+                    debug!("Shutting down status LEDs now.");
+                    break;
+                }
+                _ = interval.tick() => {
+                    heartbeat = !heartbeat;
+                    let leds = leds.clone();
+                    let metrics = metrics.clone();
+                    in_blocking_pool(move || {
+                        if let Some(led) = &leds.heartbeat {
+                            led.set(heartbeat);
+                        };
+                        leds.show(&metrics);
+                    })
+                    .await;
+                }
+            }
+        }
+        in_blocking_pool(move || {
+            for led in leds.leds() {
+                led.teardown();
+            }
+        })
+        .await;
+    }
+}
+
+/// Run `f` on the blocking thread pool, in the current span
+async fn in_blocking_pool(f: impl FnOnce() + Send + 'static) {
+    let span = tracing::Span::current();
+    if let Err(e) = tokio::task::spawn_blocking(move || span.in_scope(f)).await {
+        warn!("Unable to set the status LEDs: {e}");
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leds_show_the_last_runs() {
+        let gpio_path =
+            std::env::temp_dir().join(format!("ct-ta-sync-gpio-{}", std::process::id()));
+        // sysfs creates the pin directory on export
+        for pin in [17, 27] {
+            std::fs::create_dir_all(gpio_path.join(format!("gpio{pin}"))).unwrap();
+        }
+        let leds = StatusLeds::setup(&StatusLedsConfig {
+            heartbeat: None,
+            ct_reachable: Some(17),
+            cmi_ok: None,
+            error: Some(27),
+            gpio_path: gpio_path.clone(),
+        })
+        .unwrap();
+        let value =
+            |pin: u32| std::fs::read_to_string(gpio_path.join(format!("gpio{pin}/value"))).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(gpio_path.join("gpio17/direction")).unwrap(),
+            "out"
+        );
+
+        let metrics = Metrics::default();
+        leds.show(&metrics);
+        assert_eq!((value(17), value(27)), ("0".to_owned(), "0".to_owned()));
+        metrics.count(Task::Pull, 0, 3, true);
+        leds.show(&metrics);
+        assert_eq!((value(17), value(27)), ("1".to_owned(), "0".to_owned()));
+        metrics.count(Task::Push, 0, 0, false);
+        leds.show(&metrics);
+        assert_eq!((value(17), value(27)), ("1".to_owned(), "1".to_owned()));
+
+        // a pin that cannot be written is remembered, so it is only logged once
+        let error = leds.error.as_ref().unwrap();
+        std::fs::remove_dir_all(gpio_path.join("gpio27")).unwrap();
+        leds.show(&metrics);
+        assert!(error.failing.load(Ordering::Relaxed));
+        std::fs::create_dir_all(gpio_path.join("gpio27")).unwrap();
+        leds.show(&metrics);
+        assert!(!error.failing.load(Ordering::Relaxed));
+
+        std::fs::remove_dir_all(gpio_path).unwrap();
+    }
+}
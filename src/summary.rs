@@ -0,0 +1,338 @@
+//! A summary of the previous day for the wardens, logged every night at global.daily_summary_time
+//! and sent to the alert channels if alerting.daily_summary is set.
+//!
+//! Heated hours are the heating windows of the bookings of that day, as computed for the average
+//! outdoor temperature of the day. They do not include maintenance, fallback schedules or
+//! overrides.
+
+use std::{collections::HashSet, sync::Arc};
+
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeDelta, Utc};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    db::{CycleStat, DBError, ExternalTemperatureSample},
+    i18n::Language,
+    metrics::Task,
+    push_to_ta::{get_booking_history, heating_window, unused_for},
+    resource_hierarchy::implying_resources,
+    InShutdown,
+};
+
+/// What a room did on the day
+#[derive(Debug, PartialEq)]
+struct RoomSummary {
+    room: String,
+    /// time within the day the room was heated for bookings
+    heated: TimeDelta,
+    /// bookings the room was heated for within the day
+    bookings: usize,
+}
+
+/// Runs of the pull or push task on the day
+#[derive(Debug, Default, PartialEq)]
+struct TaskSummary {
+    runs: usize,
+    errors: i64,
+}
+impl TaskSummary {
+    fn of(stats: &[CycleStat], task: Task) -> Self {
+        stats.iter().filter(|stat| stat.task == task.name()).fold(
+            Self::default(),
+            |summary, stat| Self {
+                runs: summary.runs + 1,
+                errors: summary.errors + stat.errors,
+            },
+        )
+    }
+}
+
+/// Everything reported for a day
+#[derive(Debug, PartialEq)]
+struct DailySummary {
+    day: NaiveDate,
+    rooms: Vec<RoomSummary>,
+    /// min and average outdoor temperature of the default sensor in tenths of a Degree
+    /// Centigrade, None if nothing was recorded
+    outdoor: Option<(i32, i32)>,
+    pull: TaskSummary,
+    push: TaskSummary,
+}
+
+/// The start of `day` in the local time of the host
+fn local_midnight(day: NaiveDate) -> DateTime<Utc> {
+    let midnight = day.and_hms_opt(0, 0, 0).expect("midnight exists");
+    midnight
+        .and_local_timezone(Local)
+        .earliest()
+        .map_or(midnight.and_utc(), |midnight| midnight.to_utc())
+}
+
+/// The total time covered by `windows` within [start, end), counting overlaps once
+fn heated_time(
+    windows: &[(DateTime<Utc>, DateTime<Utc>)],
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> TimeDelta {
+    let mut clipped = windows
+        .iter()
+        .map(|(from, to)| ((*from).max(start), (*to).min(end)))
+        .filter(|(from, to)| from < to)
+        .collect::<Vec<_>>();
+    clipped.sort();
+    let mut total = TimeDelta::zero();
+    let mut covered_until = start;
+    for (from, to) in clipped {
+        let from = from.max(covered_until);
+        if from < to {
+            total += to - from;
+            covered_until = to;
+        };
+    }
+    total
+}
+
+/// Min and rounded average of `samples`
+fn outdoor(samples: &[ExternalTemperatureSample]) -> Option<(i32, i32)> {
+    let min = samples.iter().map(|x| x.temperature).min()?;
+    let sum = samples.iter().map(|x| x.temperature as f64).sum::<f64>();
+    Some((min, (sum / samples.len() as f64).round() as i32))
+}
+
+/// Summarize the local day `day` from the DB
+async fn summarize(config: &Config, day: NaiveDate) -> Result<DailySummary, DBError> {
+    let start = local_midnight(day);
+    let end = local_midnight(day + TimeDelta::days(1));
+    let temperatures = crate::db::get_external_temperatures_in_timeframe(
+        &config.db,
+        start.naive_utc(),
+        end.naive_utc(),
+    )
+    .await?;
+    let outdoor = outdoor(&temperatures);
+    let stats =
+        crate::db::get_cycle_stats_in_timeframe(&config.db, start.naive_utc(), end.naive_utc())
+            .await?;
+
+    // bookings of the day may have started the day before. Past bookings are in the archive.
+    let bookings = crate::db::get_booking_history_in_timeframe(
+        &config.db,
+        (start - TimeDelta::days(1)).naive_utc(),
+        end.naive_utc(),
+    )
+    .await?;
+    let history = get_booking_history(config, start - TimeDelta::days(1), end).await?;
+    let parents = if config.resource_hierarchy.is_some() {
+        crate::db::get_resource_parents(&config.db).await?
+    } else {
+        Default::default()
+    };
+    let mut rooms = vec![];
+    // rooms may be on multiple CMIs, but are configured the same on all of them
+    let mut seen = HashSet::new();
+    for cmi in &config.cmis {
+        for room in cmi.rooms.iter().filter(|room| seen.insert(&room.name)) {
+            let implying = implying_resources(
+                config.resource_hierarchy.as_ref(),
+                &parents,
+                room.churchtools_id,
+            );
+            let windows = bookings
+                .iter()
+                .filter(|b| implying.contains(&b.resource_id()))
+                .map(|b| {
+                    let unused_for = unused_for(room, &history, &implying, b);
+                    heating_window(config, room, b, &[], outdoor.map(|x| x.1), unused_for)
+                })
+                .filter(|(from, to)| {
+                    *from < end
+                        && *to > start
+                        && config.in_heating_season(cmi.site.as_deref(), *from)
+                })
+                .collect::<Vec<_>>();
+            rooms.push(RoomSummary {
+                room: room.name.clone(),
+                heated: heated_time(&windows, start, end),
+                bookings: windows.len(),
+            });
+        }
+    }
+    Ok(DailySummary {
+        day,
+        rooms,
+        outdoor,
+        pull: TaskSummary::of(&stats, Task::Pull),
+        push: TaskSummary::of(&stats, Task::Push),
+    })
+}
+
+/// The title and text of `summary`, one line per room
+fn render(summary: &DailySummary, language: Language) -> (String, String) {
+    let hours = |heated: TimeDelta| heated.num_minutes() as f64 / 60_f64;
+    let celsius = |temp: i32| temp as f64 / 10_f64;
+    let day = summary.day.format("%Y-%m-%d");
+    let mut lines = summary
+        .rooms
+        .iter()
+        .map(|room| match language {
+            Language::En => format!(
+                "{}: heated {:.1} h, {} bookings",
+                room.room,
+                hours(room.heated),
+                room.bookings
+            ),
+            Language::De => format!(
+                "{}: {:.1} h geheizt, {} Buchungen",
+                room.room,
+                hours(room.heated),
+                room.bookings
+            ),
+        })
+        .collect::<Vec<_>>();
+    lines.push(match (language, summary.outdoor) {
+        (Language::En, Some((min, avg))) => format!(
+            "Outdoor: min {:.1} °C, avg {:.1} °C",
+            celsius(min),
+            celsius(avg)
+        ),
+        (Language::De, Some((min, avg))) => format!(
+            "Außen: min. {:.1} °C, Mittel {:.1} °C",
+            celsius(min),
+            celsius(avg)
+        ),
+        (Language::En, None) => "Outdoor: no temperature recorded".to_owned(),
+        (Language::De, None) => "Außen: keine Temperatur aufgezeichnet".to_owned(),
+    });
+    lines.push(match language {
+        Language::En => format!(
+            "Errors: {} pulling from CT ({} runs), {} pushing to the CMIs ({} runs)",
+            summary.pull.errors, summary.pull.runs, summary.push.errors, summary.push.runs
+        ),
+        Language::De => format!(
+            "Fehler: {} beim Abruf aus CT ({} Läufe), {} beim Senden an die CMIs ({} Läufe)",
+            summary.pull.errors, summary.pull.runs, summary.push.errors, summary.push.runs
+        ),
+    });
+    let title = match language {
+        Language::En => format!("Summary of {day}"),
+        Language::De => format!("Zusammenfassung vom {day}"),
+    };
+    (title, lines.join("\n"))
+}
+
+/// The next time after `now` that is `time` in the local time of the host
+fn next_run(time: NaiveTime, now: DateTime<Local>) -> DateTime<Utc> {
+    let today = now.date_naive();
+    [
+        today,
+        today + TimeDelta::days(1),
+        today + TimeDelta::days(2),
+    ]
+    .into_iter()
+    .filter_map(|day| day.and_time(time).and_local_timezone(Local).earliest())
+    .find(|run| *run > now)
+    .map_or(now.to_utc() + TimeDelta::days(1), |run| run.to_utc())
+}
+
+/// Log (and send) a summary of the previous day every day at global.daily_summary_time until
+/// shutdown
+pub async fn send_daily_summaries(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+) {
+    let Some(time) = config.global.daily_summary_time else {
+        debug!("No daily summary configured.");
+        return;
+    };
+    info!("Starting daily summary task");
+    #[cfg(feature = "alerting")]
+    let client = reqwest::Client::new();
+    loop {
+        let wait = (next_run(time, Local::now()) - Utc::now())
+            .to_std()
+            .unwrap_or_default();
+        tokio::select! {
+            _ = watcher.changed() => {
+                debug!("Shutting down daily summary task now.");
+                return;
+            }
+            _ = tokio::time::sleep(wait) => {}
+        }
+        let yesterday = Local::now().date_naive() - TimeDelta::days(1);
+        let summary = match summarize(&config, yesterday).await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to summarize {yesterday}. Error encountered: {e}");
+                continue;
+            }
+        };
+        info!("{}", render(&summary, Language::En).1.replace('\n', "; "));
+        #[cfg(feature = "alerting")]
+        if let Some(alerting) = config.alerting.as_ref().filter(|x| x.daily_summary) {
+            let (title, text) = render(&summary, config.global.language);
+            crate::notifier::send_to_all(&client, alerting, &title, &text).await;
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(h: u32, min: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2025, 1, 12)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn overlapping_windows_count_once() {
+        let windows = [
+            (at(9, 0), at(11, 0)),
+            (at(10, 0), at(12, 0)),
+            (at(10, 30), at(11, 0)),
+            (at(20, 0), at(23, 59)),
+        ];
+        assert_eq!(
+            heated_time(&windows, at(0, 0), at(21, 0)),
+            TimeDelta::hours(4)
+        );
+        assert_eq!(heated_time(&[], at(0, 0), at(21, 0)), TimeDelta::zero());
+    }
+
+    #[test]
+    fn render_summary() {
+        let sample = |temperature| ExternalTemperatureSample {
+            recorded_at: at(0, 0),
+            temperature,
+        };
+        let summary = DailySummary {
+            day: NaiveDate::from_ymd_opt(2025, 1, 12).unwrap(),
+            rooms: vec![RoomSummary {
+                room: "Hall".to_owned(),
+                heated: TimeDelta::minutes(210),
+                bookings: 2,
+            }],
+            outdoor: outdoor(&[sample(-21), sample(10), sample(30)]),
+            pull: TaskSummary {
+                runs: 96,
+                errors: 1,
+            },
+            push: TaskSummary::default(),
+        };
+        assert_eq!(summary.outdoor, Some((-21, 6)));
+        let (title, text) = render(&summary, Language::En);
+        assert_eq!(title, "Summary of 2025-01-12");
+        assert_eq!(
+            text,
+            "Hall: heated 3.5 h, 2 bookings\n\
+             Outdoor: min -2.1 °C, avg 0.6 °C\n\
+             Errors: 1 pulling from CT (96 runs), 0 pushing to the CMIs (0 runs)"
+        );
+        let (_, text) = render(&summary, Language::De);
+        assert!(text.starts_with("Hall: 3.5 h geheizt, 2 Buchungen\nAußen: min. -2.1 °C"));
+    }
+}